@@ -1,33 +1,59 @@
 use crate::{
     kdf::{Kdf, LabeledExpand},
+    kem::Kem as KemTrait,
     kex::{Marshallable, Unmarshallable},
+    prelude::*,
     setup::ExporterSecret,
+    util::{full_suite_id, FullSuiteId},
     HpkeError,
 };
 
-use core::u8;
-
 use aead::{Aead as BaseAead, NewAead as BaseNewAead};
-use digest::generic_array::GenericArray;
+use byteorder::{BigEndian, ByteOrder};
+use digest::generic_array::{typenum, GenericArray};
 use hkdf::Hkdf;
+use zeroize::{Zeroize, Zeroizing};
+
+/// A growable byte buffer, used by `seal_in_place` and `open_in_place`. This is re-exported from
+/// the `aead` crate, which implements it for `Vec<u8>` (and for `heapless::Vec` behind its
+/// `heapless` feature).
+pub use aead::Buffer;
 
-/// Represents authenticated encryption functionality
+/// Represents an AEAD algorithm as defined in RFC 9180 §7.3. This includes the export-only
+/// "AEAD", which can't encrypt anything. See `AeadCipher` for the AEADs that can.
 pub trait Aead {
     /// The underlying AEAD implementation
     type AeadImpl: BaseAead + BaseNewAead + Clone;
 
     /// The algorithm identifier for an AEAD implementation
     const AEAD_ID: u16;
+
+    /// The default maximum number of messages a single context may seal or open. See
+    /// `UsageLimits`.
+    const DEFAULT_MAX_MESSAGES: u64;
+
+    /// The default maximum number of plaintext bytes a single context may seal or open. See
+    /// `UsageLimits`.
+    const DEFAULT_MAX_BYTES: u64;
 }
 
+/// Represents authenticated encryption functionality. This is implemented by every `Aead` except
+/// `ExportOnly`, and it's what makes `seal` and `open` available on an encryption context.
+pub trait AeadCipher: Aead {}
+
 /// The implementation of AES-GCM-128
 pub struct AesGcm128 {}
 
 impl Aead for AesGcm128 {
     type AeadImpl = aes_gcm::Aes128Gcm;
 
-    // draft02 §8.3: AES-GCM-128
+    // RFC 9180 §7.3: AES-128-GCM
     const AEAD_ID: u16 = 0x0001;
+
+    // RFC 9147 §4.5.3 allows 2^36 forgery attempts, and RFC 8446 §5.5 allows about 2^24.5
+    // records of 2^14 bytes, which we round down to 2^38 bytes
+    const DEFAULT_MAX_MESSAGES: u64 = 1 << 36;
+    const DEFAULT_MAX_BYTES: u64 = 1 << 38;
 }
 
 /// The implementation of AES-GCM-128
@@ -36,8 +62,12 @@ pub struct AesGcm256 {}
 impl Aead for AesGcm256 {
     type AeadImpl = aes_gcm::Aes256Gcm;
 
-    // draft02 §8.3: AES-GCM-256
+    // RFC 9180 §7.3: AES-256-GCM
     const AEAD_ID: u16 = 0x0002;
+
+    // Same as AES-128-GCM. The bounds come from the block size, not the key size.
+    const DEFAULT_MAX_MESSAGES: u64 = 1 << 36;
+    const DEFAULT_MAX_BYTES: u64 = 1 << 38;
 }
 
 /// The implementation of ChaCha20-Poly1305
@@ -46,8 +76,72 @@ pub struct ChaCha20Poly1305 {}
 impl Aead for ChaCha20Poly1305 {
     type AeadImpl = chacha20poly1305::ChaCha20Poly1305;
 
-    // draft02 §8.3: ChaCha20Poly1305
+    // RFC 9180 §7.3: ChaCha20Poly1305
     const AEAD_ID: u16 = 0x0003;
+
+    // RFC 9147 §4.5.3 allows 2^36 forgery attempts. There is no practical limit on the number of
+    // bytes.
+    const DEFAULT_MAX_MESSAGES: u64 = 1 << 36;
+    const DEFAULT_MAX_BYTES: u64 = u64::MAX;
+}
+
+impl AeadCipher for AesGcm128 {}
+impl AeadCipher for AesGcm256 {}
+impl AeadCipher for ChaCha20Poly1305 {}
+
+/// The export-only mode of HPKE. A context with this AEAD can only be used to `export` secrets.
+/// It has no `seal` or `open` methods.
+pub struct ExportOnly {}
+
+impl Aead for ExportOnly {
+    type AeadImpl = EmptyAeadImpl;
+
+    // RFC 9180 §7.3: Export-only
+    const AEAD_ID: u16 = 0xFFFF;
+
+    // Nothing can be sealed or opened anyway
+    const DEFAULT_MAX_MESSAGES: u64 = 0;
+    const DEFAULT_MAX_BYTES: u64 = 0;
+}
+
+/// The underlying "AEAD" of `ExportOnly`. Its key, nonce, and tag are all empty, and it can't
+/// encrypt or decrypt anything. This is never actually called, since `ExportOnly` contexts don't
+/// expose `seal` or `open`.
+#[doc(hidden)]
+#[derive(Clone)]
+pub struct EmptyAeadImpl;
+
+impl BaseNewAead for EmptyAeadImpl {
+    type KeySize = typenum::U0;
+
+    fn new(_key: GenericArray<u8, Self::KeySize>) -> Self {
+        EmptyAeadImpl
+    }
+}
+
+impl BaseAead for EmptyAeadImpl {
+    type NonceSize = typenum::U0;
+    type TagSize = typenum::U0;
+    type CiphertextOverhead = typenum::U0;
+
+    fn encrypt_in_place_detached(
+        &self,
+        _nonce: &GenericArray<u8, Self::NonceSize>,
+        _associated_data: &[u8],
+        _buffer: &mut [u8],
+    ) -> Result<GenericArray<u8, Self::TagSize>, aead::Error> {
+        Err(aead::Error)
+    }
+
+    fn decrypt_in_place_detached(
+        &self,
+        _nonce: &GenericArray<u8, Self::NonceSize>,
+        _associated_data: &[u8],
+        _buffer: &mut [u8],
+        _tag: &GenericArray<u8, Self::TagSize>,
+    ) -> Result<(), aead::Error> {
+        Err(aead::Error)
+    }
 }
 
 /// Treats the given seq (which is a bytestring) as a big-endian integer, and increments it
@@ -73,10 +167,10 @@ fn increment_seq<A: Aead>(arr: &mut Seq<A>) -> Result<(), ()> {
     Err(())
 }
 
-// From draft02 §6.6
-//     def Context.Nonce(seq):
-//       encSeq = encode_big_endian(seq, len(self.nonce))
-//       return xor(self.nonce, encSeq)
+// RFC 9180 §5.2
+// def Context<ROLE>.ComputeNonce(seq):
+//   seq_bytes = I2OSP(seq, Nn)
+//   return xor(self.base_nonce, seq_bytes)
 /// Derives a nonce from the given nonce and a "sequence number". The sequence number is treated as
 /// a big-endian integer with length equal to the nonce length.
 fn mix_nonce<A: Aead>(base_nonce: &AeadNonce<A>, seq: &Seq<A>) -> AeadNonce<A> {
@@ -92,6 +186,29 @@ fn mix_nonce<A: Aead>(base_nonce: &AeadNonce<A>, seq: &Seq<A>) -> AeadNonce<A> {
     GenericArray::from_exact_iter(new_nonce_iter).unwrap()
 }
 
+/// Makes a sequence counter with the given value. The value is written big-endian into the low
+/// bytes of the counter.
+fn seq_from_u64<A: Aead>(value: u64) -> Seq<A> {
+    let mut seq = <Seq<A> as Default>::default();
+    let value_bytes = value.to_be_bytes();
+
+    // Nonces are at least 8 bytes for every AEAD that can encrypt, but be careful anyway
+    let num_bytes = core::cmp::min(seq.0.len(), value_bytes.len());
+    let seq_len = seq.0.len();
+    seq.0[seq_len - num_bytes..].copy_from_slice(&value_bytes[value_bytes.len() - num_bytes..]);
+
+    seq
+}
+
+/// Reads the low 8 bytes of a sequence counter as a big-endian integer. The higher bytes can only
+/// be nonzero after 2^64 messages, so we don't bother with them.
+fn seq_to_u64<A: Aead>(seq: &Seq<A>) -> u64 {
+    let num_bytes = core::cmp::min(seq.0.len(), 8);
+    seq.0[seq.0.len() - num_bytes..]
+        .iter()
+        .fold(0u64, |acc, &byte| (acc << 8) | u64::from(byte))
+}
+
 // A nonce is the same thing as a sequence counter. But you never increment a nonce.
 pub(crate) type AeadNonce<A> = GenericArray<u8, <<A as Aead>::AeadImpl as BaseAead>::NonceSize>;
 pub(crate) type AeadKey<A> = GenericArray<u8, <<A as Aead>::AeadImpl as aead::NewAead>::KeySize>;
@@ -117,6 +234,13 @@ impl<A: Aead> Clone for Seq<A> {
 /// An authenticated encryption tag
 pub struct AeadTag<A: Aead>(GenericArray<u8, <A::AeadImpl as BaseAead>::TagSize>);
 
+// We can't use #[derive(Clone)] because the compiler thinks that A has to be Clone
+impl<A: Aead> Clone for AeadTag<A> {
+    fn clone(&self) -> AeadTag<A> {
+        AeadTag(self.0.clone())
+    }
+}
+
 impl<A: Aead> Marshallable for AeadTag<A> {
     type OutputSize = <A::AeadImpl as BaseAead>::TagSize;
 
@@ -138,10 +262,126 @@ impl<A: Aead> Unmarshallable for AeadTag<A> {
     }
 }
 
+#[cfg(feature = "serde")]
+impl<A: Aead> serde::Serialize for AeadTag<A> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::serde_impls::serialize_marshallable(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, A: Aead> serde::Deserialize<'de> for AeadTag<A> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::serde_impls::deserialize_unmarshallable(deserializer)
+    }
+}
+
+/// The number of sequence numbers below the highest one seen that a `ReplayWindow` keeps track of
+pub const REPLAY_WINDOW_SIZE: u64 = 64;
+
+/// A sliding window over sequence numbers, used with `AeadCtxR::open_at_with_window` to reject
+/// replayed messages when messages can arrive out of order. This remembers which of the
+/// `REPLAY_WINDOW_SIZE` sequence numbers at or below the highest one seen have been opened.
+/// Anything older than that is rejected outright.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayWindow {
+    /// The highest sequence number opened so far, if any
+    highest: Option<u64>,
+    /// Bit i is set iff `highest - i` has been opened
+    bitmap: u64,
+}
+
+impl ReplayWindow {
+    /// Makes an empty replay window
+    pub fn new() -> ReplayWindow {
+        ReplayWindow::default()
+    }
+
+    /// Checks whether the given sequence number may be opened
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if `seq` has not been seen and is not too old. Otherwise, returns
+    /// `Err(HpkeError::Replayed)`.
+    pub fn check(&self, seq: u64) -> Result<(), HpkeError> {
+        let highest = match self.highest {
+            None => return Ok(()),
+            Some(h) => h,
+        };
+
+        if seq > highest {
+            Ok(())
+        } else {
+            let offset = highest - seq;
+            if offset >= REPLAY_WINDOW_SIZE || self.bitmap & (1 << offset) != 0 {
+                Err(HpkeError::Replayed)
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Marks the given sequence number as seen. This MUST only be called after `seq` has passed
+    /// `check` and the message has been authenticated.
+    fn mark(&mut self, seq: u64) {
+        match self.highest {
+            Some(highest) if seq <= highest => {
+                self.bitmap |= 1 << (highest - seq);
+            }
+            Some(highest) => {
+                // Slide the window up. Shifting by the full width or more clears everything.
+                let shift = seq - highest;
+                self.bitmap = if shift >= REPLAY_WINDOW_SIZE {
+                    0
+                } else {
+                    self.bitmap << shift
+                };
+                self.bitmap |= 1;
+                self.highest = Some(seq);
+            }
+            None => {
+                self.bitmap = 1;
+                self.highest = Some(seq);
+            }
+        }
+    }
+}
+
+// The usage counters and limits in a serialized context are four u64s
+const SERIALIZED_USAGE_LEN: usize = 4 * 8;
+
+/// Limits on how much a single encryption context may be used. Once a context has sealed or
+/// opened `max_messages` messages, or `max_bytes` bytes of plaintext, it refuses to do any more
+/// and returns `HpkeError::LimitExceeded`. On the receiving side, every call to open counts as a
+/// message, whether or not it succeeds, since forgery attempts are what use up a receiver's
+/// message budget. But only successfully opened ciphertexts count toward `max_bytes`, so that
+/// garbage sent by an attacker can't use up the receiver's byte budget.
+///
+/// Every context starts out with `UsageLimits::default_for::<A>()`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct UsageLimits {
+    /// The maximum number of messages
+    pub max_messages: u64,
+    /// The maximum total number of plaintext bytes
+    pub max_bytes: u64,
+}
+
+impl UsageLimits {
+    /// Returns the default limits for the given AEAD
+    pub fn default_for<A: Aead>() -> UsageLimits {
+        UsageLimits {
+            max_messages: A::DEFAULT_MAX_MESSAGES,
+            max_bytes: A::DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
 /// The HPKE encryption context. This is what you use to `seal` plaintexts and `open` ciphertexts.
 pub(crate) struct AeadCtx<A: Aead, K: Kdf> {
     /// Records whether the nonce sequence counter has overflowed
     overflowed: bool,
+    /// The AEAD key. This is only kept around so that the context can be serialized.
+    key: AeadKey<A>,
     /// The underlying AEAD instance. This also does decryption.
     encryptor: A::AeadImpl,
     /// The base nonce which we XOR with sequence numbers
@@ -150,6 +390,14 @@ pub(crate) struct AeadCtx<A: Aead, K: Kdf> {
     exporter_secret: ExporterSecret<K>,
     /// The running sequence number
     seq: Seq<A>,
+    /// The full ciphersuite ID, used as binding context in the `export()` method
+    suite_id: FullSuiteId,
+    /// The limits on how much this context may be used
+    limits: UsageLimits,
+    /// The number of messages sealed or opened so far
+    num_messages: u64,
+    /// The number of plaintext bytes sealed or opened so far
+    num_bytes: u64,
 }
 
 // Necessary for test_setup_soundness
@@ -158,32 +406,54 @@ impl<A: Aead, K: Kdf> Clone for AeadCtx<A, K> {
     fn clone(&self) -> AeadCtx<A, K> {
         AeadCtx {
             overflowed: self.overflowed,
+            key: self.key.clone(),
             encryptor: self.encryptor.clone(),
             nonce: self.nonce.clone(),
             exporter_secret: self.exporter_secret.clone(),
             seq: self.seq.clone(),
+            suite_id: self.suite_id,
+            limits: self.limits,
+            num_messages: self.num_messages,
+            num_bytes: self.num_bytes,
         }
     }
 }
 
+// Erase the secrets when the context goes away
+impl<A: Aead, K: Kdf> Drop for AeadCtx<A, K> {
+    fn drop(&mut self) {
+        self.key.as_mut_slice().zeroize();
+        self.nonce.as_mut_slice().zeroize();
+        self.exporter_secret.as_mut_slice().zeroize();
+    }
+}
+
 impl<A: Aead, K: Kdf> AeadCtx<A, K> {
     /// Makes an AeadCtx from a raw key and nonce
     pub(crate) fn new(
         key: AeadKey<A>,
         nonce: AeadNonce<A>,
         exporter_secret: ExporterSecret<K>,
+        suite_id: FullSuiteId,
     ) -> AeadCtx<A, K> {
         AeadCtx {
             overflowed: false,
-            encryptor: <A::AeadImpl as aead::NewAead>::new(key),
+            encryptor: <A::AeadImpl as aead::NewAead>::new(key.clone()),
+            key,
             nonce,
             exporter_secret,
             seq: <Seq<A> as Default>::default(),
+            suite_id,
+            limits: UsageLimits::default_for::<A>(),
+            num_messages: 0,
+            num_bytes: 0,
         }
     }
 
+    // RFC 9180 §5.3
     // def Context.Export(exporter_context, L):
-    //     return Expand(self.exporter_secret, exporter_context, L)
+    //   return LabeledExpand(self.exporter_secret, "sec",
+    //                        exporter_context, L)
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
     /// does not depend on sequence number, so it is constant for the lifetime of this context.
     pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
@@ -195,9 +465,221 @@ impl<A: Aead, K: Kdf> AeadCtx<A, K> {
         // This call either succeeds or returns hkdf::InvalidLength (iff the buffer length is more
         // than 255x the digest size of the underlying hash function)
         hkdf_ctx
-            .labeled_expand(b"sec", info, out_buf)
+            .labeled_expand(&self.suite_id, b"sec", info, out_buf)
             .map_err(|_| HpkeError::InvalidKdfLength)
     }
+
+    /// Derives the context for the given rekeying epoch. The new context has a key and base nonce
+    /// derived from the exporter secret and the epoch, a sequence number of 0, the same limits as
+    /// this one, and no usage. The exporter secret is unchanged, so exports are the same across
+    /// epochs.
+    fn rekeyed(&self, epoch: u64) -> AeadCtx<A, K> {
+        // Use our exporter secret as the PRK. Same reasoning as in export() for the unwrap().
+        let hkdf_ctx = Hkdf::<K::HashImpl>::from_prk(self.exporter_secret.as_slice()).unwrap();
+
+        // The labels differ from the one export() uses, so these can never collide with an
+        // exported secret
+        let epoch_bytes = epoch.to_be_bytes();
+        let mut key = AeadKey::<A>::default();
+        let mut nonce = AeadNonce::<A>::default();
+        hkdf_ctx
+            .labeled_expand(
+                &self.suite_id,
+                b"rekey_key",
+                &epoch_bytes,
+                key.as_mut_slice(),
+            )
+            .expect("aead key len is way too big");
+        hkdf_ctx
+            .labeled_expand(
+                &self.suite_id,
+                b"rekey_base_nonce",
+                &epoch_bytes,
+                nonce.as_mut_slice(),
+            )
+            .expect("nonce len is way too big");
+
+        let mut ctx = AeadCtx::new(
+            key.clone(),
+            nonce,
+            self.exporter_secret.clone(),
+            self.suite_id,
+        );
+        ctx.limits = self.limits;
+        key.as_mut_slice().zeroize();
+
+        ctx
+    }
+
+    /// Checks that one more message of `len` bytes fits in this context's limits
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((num_messages, num_bytes))`, the usage counts including this message, if the
+    /// message fits. Otherwise, returns `Err(HpkeError::LimitExceeded)`.
+    fn check_limits(&self, len: usize) -> Result<(u64, u64), HpkeError> {
+        let num_messages = self.num_messages.checked_add(1);
+        let num_bytes = self.num_bytes.checked_add(len as u64);
+
+        match (num_messages, num_bytes) {
+            (Some(m), Some(b)) if m <= self.limits.max_messages && b <= self.limits.max_bytes => {
+                Ok((m, b))
+            }
+            _ => Err(HpkeError::LimitExceeded),
+        }
+    }
+
+    /// Counts one message of `len` bytes against this context's limits
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` if the message fits. Otherwise, returns `Err(HpkeError::LimitExceeded)`
+    /// and counts nothing.
+    fn spend(&mut self, len: usize) -> Result<(), HpkeError> {
+        let (num_messages, num_bytes) = self.check_limits(len)?;
+        self.num_messages = num_messages;
+        self.num_bytes = num_bytes;
+
+        Ok(())
+    }
+
+    /// Opens `ciphertext` in place with the given nonce, counting the attempt against this
+    /// context's limits. The attempt counts as a message whether or not it succeeds, but the
+    /// bytes only count if it does.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the message doesn't fit in this context's limits, returns
+    /// `Err(HpkeError::LimitExceeded)`, counts nothing, and leaves `ciphertext` unmodified. If the
+    /// tag fails to validate, returns `Err(HpkeError::InvalidTag)`.
+    fn open_counted(
+        &mut self,
+        nonce: &AeadNonce<A>,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        let (num_messages, num_bytes) = self.check_limits(ciphertext.len())?;
+        self.num_messages = num_messages;
+
+        self.encryptor
+            .decrypt_in_place_detached(nonce, aad, ciphertext, &tag.0)
+            .map_err(|_| HpkeError::InvalidTag)?;
+        self.num_bytes = num_bytes;
+
+        Ok(())
+    }
+
+    /// Returns the running sequence number
+    fn seq(&self) -> u64 {
+        seq_to_u64(&self.seq)
+    }
+
+    /// Moves the running sequence number forward to `seq`. Moving it backward is not allowed,
+    /// since that would reuse nonces (for a sender) or accept replays (for a receiver).
+    fn set_seq(&mut self, seq: u64) -> Result<(), HpkeError> {
+        if self.overflowed {
+            Err(HpkeError::SeqOverflow)
+        } else if seq < self.seq() {
+            Err(HpkeError::InvalidSeq)
+        } else {
+            self.seq = seq_from_u64::<A>(seq);
+            Ok(())
+        }
+    }
+
+    /// Serializes this context as
+    /// `suite_id || key || base_nonce || exporter_secret || overflowed || seq || usage`, where
+    /// `overflowed` is a single byte that is 0 or 1, `seq` is as long as a nonce, and `usage` is
+    /// `num_messages || num_bytes || max_messages || max_bytes`, each as a big-endian u64
+    fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        let mut buf = Zeroizing::new(Vec::with_capacity(
+            self.suite_id.len()
+                + self.key.len()
+                + self.nonce.len()
+                + self.exporter_secret.len()
+                + 1
+                + self.seq.0.len()
+                + SERIALIZED_USAGE_LEN,
+        ));
+        buf.extend_from_slice(&self.suite_id);
+        buf.extend_from_slice(&self.key);
+        buf.extend_from_slice(&self.nonce);
+        buf.extend_from_slice(&self.exporter_secret);
+        buf.push(self.overflowed as u8);
+        buf.extend_from_slice(&self.seq.0);
+        for &n in &[
+            self.num_messages,
+            self.num_bytes,
+            self.limits.max_messages,
+            self.limits.max_bytes,
+        ] {
+            buf.extend_from_slice(&n.to_be_bytes());
+        }
+
+        buf
+    }
+
+    /// Deserializes a context serialized with `to_bytes`, checking that it was made under the
+    /// ciphersuite given by `suite_id`
+    fn from_bytes(suite_id: FullSuiteId, encoded: &[u8]) -> Result<AeadCtx<A, K>, HpkeError> {
+        let mut key = AeadKey::<A>::default();
+        let mut nonce = AeadNonce::<A>::default();
+        let mut exporter_secret = <ExporterSecret<K> as Default>::default();
+        let mut seq = <Seq<A> as Default>::default();
+
+        // Check the suite first. Contexts from other suites generally have a different length, and
+        // they should still be reported as a suite mismatch.
+        if encoded.len() < suite_id.len() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let (encoded_suite_id, rest) = encoded.split_at(suite_id.len());
+        if encoded_suite_id != suite_id {
+            return Err(HpkeError::SuiteMismatch);
+        }
+
+        let expected_len = key.len()
+            + nonce.len()
+            + exporter_secret.len()
+            + 1
+            + seq.0.len()
+            + SERIALIZED_USAGE_LEN;
+        if rest.len() != expected_len {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        // Parse everything else
+        let (key_bytes, rest) = rest.split_at(key.len());
+        let (nonce_bytes, rest) = rest.split_at(nonce.len());
+        let (exporter_secret_bytes, rest) = rest.split_at(exporter_secret.len());
+        let (overflowed_byte, rest) = rest.split_at(1);
+        let (seq_bytes, usage_bytes) = rest.split_at(seq.0.len());
+        let overflowed = match overflowed_byte[0] {
+            0 => false,
+            1 => true,
+            _ => return Err(HpkeError::InvalidEncoding),
+        };
+        key.copy_from_slice(key_bytes);
+        nonce.copy_from_slice(nonce_bytes);
+        exporter_secret.copy_from_slice(exporter_secret_bytes);
+        seq.0.copy_from_slice(seq_bytes);
+
+        let mut ctx = AeadCtx::new(key.clone(), nonce, exporter_secret.clone(), suite_id);
+        ctx.overflowed = overflowed;
+        ctx.seq = seq;
+        ctx.num_messages = BigEndian::read_u64(&usage_bytes[0..8]);
+        ctx.num_bytes = BigEndian::read_u64(&usage_bytes[8..16]);
+        ctx.limits = UsageLimits {
+            max_messages: BigEndian::read_u64(&usage_bytes[16..24]),
+            max_bytes: BigEndian::read_u64(&usage_bytes[24..32]),
+        };
+
+        // Clear the temporaries
+        key.as_mut_slice().zeroize();
+        exporter_secret.as_mut_slice().zeroize();
+
+        Ok(ctx)
+    }
 }
 
 /// The HPKE receiver's context. This is what you use to `open` ciphertexts.
@@ -218,11 +700,12 @@ impl<A: Aead, K: Kdf> Clone for AeadCtxR<A, K> {
     }
 }
 
-impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
-    // def Context.Open(aad, ct):
-    //   pt = Open(self.key, self.Nonce(self.seq), aad, ct)
+impl<A: AeadCipher, K: Kdf> AeadCtxR<A, K> {
+    // RFC 9180 §5.2
+    // def ContextR.Open(aad, ct):
+    //   pt = Open(self.key, self.ComputeNonce(self.seq), aad, ct)
     //   if pt == OpenError:
-    //     return OpenError
+    //     raise OpenError
     //   self.IncrementSeq()
     //   return pt
     /// Does a "detached open in place", meaning it overwrites `ciphertext` with the resulting
@@ -231,7 +714,8 @@ impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
     /// Return Value
     /// ============
     /// Returns `Ok(())` on success.  If this context has been used for so many encryptions that
-    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`. If this happens,
+    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`. If this context has
+    /// used up its `UsageLimits`, returns `Err(HpkeError::LimitExceeded)`. In either case,
     /// `plaintext` will be unmodified. If the tag fails to validate, returns
     /// `Err(HpkeError::InvalidTag)`. If this happens, `plaintext` is in an undefined state.
     pub fn open(
@@ -244,17 +728,10 @@ impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
             // If the sequence counter overflowed, we've been used for far too long. Shut down.
             Err(HpkeError::SeqOverflow)
         } else {
-            // Compute the nonce and do the encryption in place
+            // Compute the nonce and do the decryption in place. This fails if the tag is bad or
+            // we're out of budget.
             let nonce = mix_nonce(&self.0.nonce, &self.0.seq);
-            let decrypt_res = self
-                .0
-                .encryptor
-                .decrypt_in_place_detached(&nonce, &aad, ciphertext, &tag.0);
-
-            if decrypt_res.is_err() {
-                // Opening failed due to a bad tag
-                return Err(HpkeError::InvalidTag);
-            }
+            self.0.open_counted(&nonce, ciphertext, aad, tag)?;
 
             // Opening was a success
             // Try to increment the sequence counter. If it fails, this was our last
@@ -267,6 +744,106 @@ impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
         }
     }
 
+    /// Does an "attached open", meaning it takes a ciphertext of the form `ciphertext || tag`, as
+    /// output by `AeadCtxS::seal_to_vec`, and returns the resulting plaintext in a new buffer
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If `ciphertext` is too short to contain a tag, returns
+    /// `Err(HpkeError::InvalidEncoding)`. Otherwise, this fails in the same ways as `open`.
+    pub fn open_to_vec(&mut self, aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        // Split the input into ciphertext || tag
+        let tag_size = AeadTag::<A>::size();
+        if ciphertext.len() < tag_size {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let (ciphertext, tag_bytes) = ciphertext.split_at(ciphertext.len() - tag_size);
+        let tag = AeadTag::<A>::unmarshal(tag_bytes)?;
+
+        // Decrypt a copy of the ciphertext in place
+        let mut plaintext = ciphertext.to_vec();
+        self.open(&mut plaintext, aad, &tag)?;
+
+        Ok(plaintext)
+    }
+
+    /// Does an "attached open in place", meaning it takes a buffer containing `ciphertext || tag`,
+    /// as output by `AeadCtxS::seal_in_place`, and overwrites it with the resulting plaintext. The
+    /// tag is stripped from the end of the buffer.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `buffer` is too short to contain a tag, returns
+    /// `Err(HpkeError::InvalidEncoding)`. In this case, `buffer` is unmodified. Otherwise, this
+    /// fails in the same ways as `open`.
+    pub fn open_in_place<B: Buffer>(
+        &mut self,
+        buffer: &mut B,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        // Split the buffer into ciphertext || tag
+        let tag_size = AeadTag::<A>::size();
+        if buffer.len() < tag_size {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let ciphertext_len = buffer.len() - tag_size;
+        let tag = AeadTag::<A>::unmarshal(&buffer.as_ref()[ciphertext_len..])?;
+
+        // Decrypt the ciphertext portion in place, then drop the tag
+        self.open(&mut buffer.as_mut()[..ciphertext_len], aad, &tag)?;
+        buffer.truncate(ciphertext_len);
+
+        Ok(())
+    }
+
+    /// Does a "detached open in place" using the nonce for the given sequence number, rather than
+    /// the context's running sequence number. This is for transports that can drop or reorder
+    /// messages. It does not read or change the running sequence number, and it does not check
+    /// for replays. Use `open_at_with_window` for that.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If this context has used up its `UsageLimits`, returns
+    /// `Err(HpkeError::LimitExceeded)`. If this happens, `ciphertext` will be unmodified. If the
+    /// tag fails to validate, returns `Err(HpkeError::InvalidTag)`. If this happens, `ciphertext`
+    /// is in an undefined state.
+    pub fn open_at(
+        &mut self,
+        seq: u64,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        let nonce = mix_nonce(&self.0.nonce, &seq_from_u64::<A>(seq));
+        self.0.open_counted(&nonce, ciphertext, aad, tag)
+    }
+
+    /// Does the same thing as `open_at`, but first checks `seq` against the given replay window,
+    /// and records it in the window once the ciphertext is authenticated. A window MUST only be
+    /// used with one context.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `seq` was already opened or is too old for the window,
+    /// returns `Err(HpkeError::Replayed)`. In this case, `ciphertext` is unmodified. Otherwise,
+    /// this fails in the same ways as `open_at`, and the window is unmodified.
+    pub fn open_at_with_window(
+        &mut self,
+        window: &mut ReplayWindow,
+        seq: u64,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        window.check(seq)?;
+        self.open_at(seq, ciphertext, aad, tag)?;
+        window.mark(seq);
+
+        Ok(())
+    }
+}
+
+impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
     /// does not depend on sequence number, so it is constant for the lifetime of this context.
     ///
@@ -278,6 +855,72 @@ impl<A: Aead, K: Kdf> AeadCtxR<A, K> {
         // Pass to AeadCtx
         self.0.export(info, out_buf)
     }
+
+    /// Returns the sequence number of the next message to be opened, i.e., the number of messages
+    /// opened so far
+    pub fn seq(&self) -> u64 {
+        self.0.seq()
+    }
+
+    /// Sets the sequence number of the next message to be opened. The sequence number can only
+    /// move forward.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `seq` is less than the current sequence number, returns
+    /// `Err(HpkeError::InvalidSeq)`. If this context has been used for so many encryptions that
+    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`.
+    pub fn set_seq(&mut self, seq: u64) -> Result<(), HpkeError> {
+        self.0.set_seq(seq)
+    }
+
+    /// Returns the limits on how much this context may be used
+    pub fn limits(&self) -> UsageLimits {
+        self.0.limits
+    }
+
+    /// Sets the limits on how much this context may be used. Messages already opened still count
+    /// toward the new limits.
+    pub fn set_limits(&mut self, limits: UsageLimits) {
+        self.0.limits = limits;
+    }
+
+    /// Returns the number of messages opened so far, as counted toward this context's limits
+    pub fn num_messages(&self) -> u64 {
+        self.0.num_messages
+    }
+
+    /// Returns the number of plaintext bytes opened so far, as counted toward this context's
+    /// limits
+    pub fn num_bytes(&self) -> u64 {
+        self.0.num_bytes
+    }
+
+    /// Serializes this context, including its secrets, sequence number, and usage counts, so that
+    /// it can be restored with `from_bytes`. The output is zeroized when dropped.
+    ///
+    /// Restoring an old serialized receiver context lets an attacker replay the messages opened
+    /// since it was serialized.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        self.0.to_bytes()
+    }
+
+    /// Restores a context serialized with `to_bytes`. `Kem` MUST be the KEM the context was
+    /// originally set up with.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ctx)` on success. If the context was serialized under a different ciphersuite,
+    /// returns `Err(HpkeError::SuiteMismatch)`. If `encoded` is otherwise malformed, returns
+    /// `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes<Kem: KemTrait>(encoded: &[u8]) -> Result<AeadCtxR<A, K>, HpkeError> {
+        AeadCtx::from_bytes(full_suite_id::<A, K, Kem>(), encoded).map(AeadCtxR)
+    }
+
+    /// Derives the context for the given rekeying epoch. See `ratchet`.
+    pub(crate) fn rekeyed(&self, epoch: u64) -> AeadCtxR<A, K> {
+        AeadCtxR(self.0.rekeyed(epoch))
+    }
 }
 
 /// The HPKE senders's context. This is what you use to `seal` plaintexts.
@@ -298,9 +941,10 @@ impl<A: Aead, K: Kdf> Clone for AeadCtxS<A, K> {
     }
 }
 
-impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
-    // def Context.Seal(aad, pt):
-    //   ct = Seal(self.key, self.Nonce(self.seq), aad, pt)
+impl<A: AeadCipher, K: Kdf> AeadCtxS<A, K> {
+    // RFC 9180 §5.2
+    // def ContextS.Seal(aad, pt):
+    //   ct = Seal(self.key, self.ComputeNonce(self.seq), aad, pt)
     //   self.IncrementSeq()
     //   return ct
     /// Does a "detached seal in place", meaning it overwrites `plaintext` with the resulting
@@ -309,7 +953,8 @@ impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
     /// Return Value
     /// ============
     /// Returns `Ok(tag)` on success.  If this context has been used for so many encryptions that
-    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`. If this happens,
+    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`. If this context has
+    /// used up its `UsageLimits`, returns `Err(HpkeError::LimitExceeded)`. In either case,
     /// `plaintext` will be unmodified. If an unspecified error happened during encryption, returns
     /// `Err(HpkeError::Encryption)`. If this happens, the contents of `plaintext` is undefined.
     pub fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<AeadTag<A>, HpkeError> {
@@ -317,12 +962,15 @@ impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
             // If the sequence counter overflowed, we've been used for far too long. Shut down.
             Err(HpkeError::SeqOverflow)
         } else {
+            // Make sure we're within our limits
+            self.0.spend(plaintext.len())?;
+
             // Compute the nonce and do the encryption in place
             let nonce = mix_nonce(&self.0.nonce, &self.0.seq);
             let tag_res = self
                 .0
                 .encryptor
-                .encrypt_in_place_detached(&nonce, &aad, plaintext);
+                .encrypt_in_place_detached(&nonce, aad, plaintext);
 
             // Check if an error occurred when encrypting
             let tag = match tag_res {
@@ -340,6 +988,64 @@ impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
         }
     }
 
+    /// Does an "attached seal", meaning it returns the ciphertext in a new buffer with the tag
+    /// appended, i.e., `ciphertext || tag`. This is the format `AeadCtxR::open_to_vec` expects.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext_and_tag)` on success. This fails in the same ways as `seal`.
+    pub fn seal_to_vec(&mut self, aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        // Encrypt a copy of the plaintext in place, then stick the tag on the end
+        let mut ciphertext = plaintext.to_vec();
+        let tag = self.seal(&mut ciphertext, aad)?;
+        ciphertext.extend_from_slice(&tag.marshal());
+
+        Ok(ciphertext)
+    }
+
+    /// Does an "attached seal in place", meaning it overwrites the plaintext in `buffer` with the
+    /// resulting ciphertext, and appends the tag to the end of the buffer. This is the format
+    /// `AeadCtxR::open_in_place` expects.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer can't be extended to fit the tag, returns
+    /// `Err(HpkeError::Encryption)`. If this happens, the contents of `buffer` is undefined, and
+    /// the sequence number has still been used up. Otherwise, this fails in the same ways as
+    /// `seal`.
+    pub fn seal_in_place<B: Buffer>(
+        &mut self,
+        buffer: &mut B,
+        aad: &[u8],
+    ) -> Result<(), HpkeError> {
+        let tag = self.seal(buffer.as_mut(), aad)?;
+        buffer
+            .extend_from_slice(&tag.marshal())
+            .map_err(|_| HpkeError::Encryption)
+    }
+
+    /// Does a "detached seal in place" using the nonce for the given sequence number. This is for
+    /// transports that can drop or reorder messages, where the sequence number is sent alongside
+    /// the ciphertext. A sender never needs to go backward, so this is the same as calling
+    /// `set_seq(seq)` and then `seal`. Afterwards, the running sequence number is `seq + 1`.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(tag)` on success. If `seq` is less than the running sequence number, returns
+    /// `Err(HpkeError::InvalidSeq)`. In this case, the context and `plaintext` are unmodified.
+    /// Otherwise, this fails in the same ways as `seal`.
+    pub fn seal_at(
+        &mut self,
+        seq: u64,
+        plaintext: &mut [u8],
+        aad: &[u8],
+    ) -> Result<AeadTag<A>, HpkeError> {
+        self.0.set_seq(seq)?;
+        self.seal(plaintext, aad)
+    }
+}
+
+impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
     /// Fills a given buffer with secret bytes derived from this encryption context. This value
     /// does not depend on sequence number, so it is constant for the lifetime of this context.
     ///
@@ -351,14 +1057,84 @@ impl<A: Aead, K: Kdf> AeadCtxS<A, K> {
         // Pass to AeadCtx
         self.0.export(info, out_buf)
     }
+
+    /// Returns the sequence number of the next message to be sealed, i.e., the number of messages
+    /// sealed so far
+    pub fn seq(&self) -> u64 {
+        self.0.seq()
+    }
+
+    /// Sets the sequence number of the next message to be sealed. The sequence number can only
+    /// move forward.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `seq` is less than the current sequence number, returns
+    /// `Err(HpkeError::InvalidSeq)`. If this context has been used for so many encryptions that
+    /// the sequence number overflowed, returns `Err(HpkeError::SeqOverflow)`.
+    pub fn set_seq(&mut self, seq: u64) -> Result<(), HpkeError> {
+        self.0.set_seq(seq)
+    }
+
+    /// Returns the limits on how much this context may be used
+    pub fn limits(&self) -> UsageLimits {
+        self.0.limits
+    }
+
+    /// Sets the limits on how much this context may be used. Messages already sealed still count
+    /// toward the new limits.
+    pub fn set_limits(&mut self, limits: UsageLimits) {
+        self.0.limits = limits;
+    }
+
+    /// Returns the number of messages sealed so far, as counted toward this context's limits
+    pub fn num_messages(&self) -> u64 {
+        self.0.num_messages
+    }
+
+    /// Returns the number of plaintext bytes sealed so far, as counted toward this context's
+    /// limits
+    pub fn num_bytes(&self) -> u64 {
+        self.0.num_bytes
+    }
+
+    /// Serializes this context, including its secrets, sequence number, and usage counts, so that
+    /// it can be restored with `from_bytes`. The output is zeroized when dropped.
+    ///
+    /// Restoring the same serialized sender context twice reuses nonces, which breaks the
+    /// security of the AEAD. So a sender MUST NOT seal anything with a restored context unless it
+    /// is sure the state it restored from is the most recent one.
+    pub fn to_bytes(&self) -> Zeroizing<Vec<u8>> {
+        self.0.to_bytes()
+    }
+
+    /// Restores a context serialized with `to_bytes`. `Kem` MUST be the KEM the context was
+    /// originally set up with.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ctx)` on success. If the context was serialized under a different ciphersuite,
+    /// returns `Err(HpkeError::SuiteMismatch)`. If `encoded` is otherwise malformed, returns
+    /// `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes<Kem: KemTrait>(encoded: &[u8]) -> Result<AeadCtxS<A, K>, HpkeError> {
+        AeadCtx::from_bytes(full_suite_id::<A, K, Kem>(), encoded).map(AeadCtxS)
+    }
+
+    /// Derives the context for the given rekeying epoch. See `ratchet`.
+    pub(crate) fn rekeyed(&self, epoch: u64) -> AeadCtxS<A, K> {
+        AeadCtxS(self.0.rekeyed(epoch))
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use super::{AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, Seq};
-    use crate::{kdf::HkdfSha256, kex::Unmarshallable, test_util::gen_ctx_simple_pair, HpkeError};
-
-    use core::u8;
+    use super::{
+        AeadCtxR, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ReplayWindow, Seq, UsageLimits,
+        REPLAY_WINDOW_SIZE,
+    };
+    use crate::{
+        kdf::HkdfSha256, kex::Unmarshallable, prelude::*, test_util::gen_ctx_simple_pair, HpkeError,
+    };
 
     /// Tests that encryption context secret export does not change behavior based on the
     /// underlying sequence number
@@ -489,6 +1265,340 @@ mod test {
         };
     }
 
+    /// Tests that `open_to_vec()` can decrypt things encrypted with `seal_to_vec()`, and that it
+    /// rejects truncated and modified ciphertexts
+    #[test]
+    fn test_ctx_to_vec_correctness() {
+        let (mut sender_ctx, mut receiver_ctx) =
+            gen_ctx_simple_pair::<ChaCha20Poly1305, HkdfSha256>();
+
+        let msg = b"it's a gift and a curse";
+        let aad = b"stack it up";
+
+        // The output is the ciphertext followed by the 16-byte tag
+        let ciphertext = sender_ctx
+            .seal_to_vec(aad, msg)
+            .expect("seal_to_vec() failed");
+        assert_eq!(ciphertext.len(), msg.len() + 16);
+
+        // Anything shorter than a tag can't be a ciphertext
+        match receiver_ctx.clone().open_to_vec(aad, &ciphertext[..15]) {
+            Err(HpkeError::InvalidEncoding) => {}
+            _ => panic!("open_to_vec() should have rejected a short ciphertext"),
+        }
+
+        // A modified ciphertext shouldn't open
+        let mut bad_ciphertext = ciphertext.clone();
+        bad_ciphertext[0] ^= 1;
+        match receiver_ctx.clone().open_to_vec(aad, &bad_ciphertext) {
+            Err(HpkeError::InvalidTag) => {}
+            _ => panic!("open_to_vec() should have rejected a modified ciphertext"),
+        }
+
+        let decrypted = receiver_ctx
+            .open_to_vec(aad, &ciphertext)
+            .expect("open_to_vec() failed");
+        assert_eq!(&decrypted[..], &msg[..]);
+    }
+
+    /// Tests that `open_in_place()` can decrypt things encrypted with `seal_in_place()`, and that
+    /// the tag gets appended and stripped
+    #[test]
+    fn test_ctx_in_place_correctness() {
+        let (mut sender_ctx, mut receiver_ctx) =
+            gen_ctx_simple_pair::<ChaCha20Poly1305, HkdfSha256>();
+
+        let msg = b"don't let me get in my zone";
+        let aad = b"too late";
+
+        // Vec<u8> is the Buffer we have on hand
+        let mut buf = msg.to_vec();
+        sender_ctx
+            .seal_in_place(&mut buf, aad)
+            .expect("seal_in_place() failed");
+        assert_eq!(buf.len(), msg.len() + 16);
+        assert!(buf[..msg.len()] != msg[..]);
+
+        // This should be the same format as seal_to_vec
+        let plaintext = receiver_ctx
+            .clone()
+            .open_to_vec(aad, &buf)
+            .expect("open_to_vec() failed");
+        assert_eq!(&plaintext[..], &msg[..]);
+
+        // A buffer shorter than a tag can't be opened, and shouldn't be touched
+        let mut short_buf = vec![0u8; 15];
+        match receiver_ctx.clone().open_in_place(&mut short_buf, aad) {
+            Err(HpkeError::InvalidEncoding) => assert_eq!(short_buf, vec![0u8; 15]),
+            _ => panic!("open_in_place() should have rejected a short buffer"),
+        }
+
+        receiver_ctx
+            .open_in_place(&mut buf, aad)
+            .expect("open_in_place() failed");
+        assert_eq!(&buf[..], &msg[..]);
+    }
+
+    /// Tests that `open_at()` can decrypt things out of order, that it agrees with `seal()`, and
+    /// that the replay window rejects duplicates and stale sequence numbers
+    #[test]
+    fn test_ctx_at_correctness() {
+        let (mut sender_ctx, mut receiver_ctx) =
+            gen_ctx_simple_pair::<ChaCha20Poly1305, HkdfSha256>();
+
+        let msg = b"are you receiving me";
+        let aad = b"over";
+
+        // Seal a few messages with the running sequence number, and a few at explicit ones
+        let mut ciphertexts = Vec::new();
+        for _ in 0..3 {
+            let mut ciphertext = *msg;
+            let tag = sender_ctx.seal(&mut ciphertext[..], aad).unwrap();
+            ciphertexts.push((ciphertext, tag));
+        }
+        for seq in 3..100u64 {
+            let mut ciphertext = *msg;
+            let tag = sender_ctx.seal_at(seq, &mut ciphertext[..], aad).unwrap();
+            ciphertexts.push((ciphertext, tag));
+        }
+        assert_eq!(sender_ctx.seq(), 100);
+
+        // Sealing at an already used sequence number would reuse a nonce, so it's not allowed
+        let mut ciphertext = *msg;
+        match sender_ctx.seal_at(50, &mut ciphertext[..], aad) {
+            Err(HpkeError::InvalidSeq) => {}
+            _ => panic!("seal_at() should have refused to reuse a sequence number"),
+        }
+        assert_eq!(&ciphertext, msg);
+
+        // Dropping and reordering is fine with open_at
+        for &seq in &[2u64, 0, 50] {
+            let (mut ciphertext, tag) = ciphertexts[seq as usize].clone();
+            receiver_ctx
+                .open_at(seq, &mut ciphertext[..], aad, &tag)
+                .expect("open_at() failed");
+            assert_eq!(&ciphertext, msg);
+        }
+
+        // The wrong sequence number doesn't open
+        let (mut ciphertext, tag) = ciphertexts[1].clone();
+        match receiver_ctx.open_at(2, &mut ciphertext[..], aad, &tag) {
+            Err(HpkeError::InvalidTag) => {}
+            _ => panic!("open_at() should have rejected the wrong sequence number"),
+        }
+
+        // Now use a replay window
+        let mut window = ReplayWindow::new();
+        let open_with_window = |ctx: &mut AeadCtxR<ChaCha20Poly1305, HkdfSha256>,
+                                window: &mut ReplayWindow,
+                                seq: u64| {
+            let (mut ciphertext, tag) = ciphertexts[seq as usize].clone();
+            ctx.open_at_with_window(window, seq, &mut ciphertext[..], aad, &tag)
+        };
+
+        // Out of order is fine, but a repeat isn't
+        open_with_window(&mut receiver_ctx, &mut window, 5).unwrap();
+        open_with_window(&mut receiver_ctx, &mut window, 3).unwrap();
+        open_with_window(&mut receiver_ctx, &mut window, 4).unwrap();
+        match open_with_window(&mut receiver_ctx, &mut window, 3) {
+            Err(HpkeError::Replayed) => {}
+            _ => panic!("replay window should have rejected a repeat"),
+        }
+
+        // A failed open doesn't use up a sequence number
+        let (mut ciphertext, tag) = ciphertexts[6].clone();
+        ciphertext[0] ^= 1;
+        assert!(receiver_ctx
+            .open_at_with_window(&mut window, 6, &mut ciphertext[..], aad, &tag)
+            .is_err());
+        open_with_window(&mut receiver_ctx, &mut window, 6).unwrap();
+
+        // Jumping ahead makes old sequence numbers too stale to open, even unseen ones
+        open_with_window(&mut receiver_ctx, &mut window, 99).unwrap();
+        match open_with_window(&mut receiver_ctx, &mut window, 10) {
+            Err(HpkeError::Replayed) => {}
+            _ => panic!("replay window should have rejected a stale sequence number"),
+        }
+        open_with_window(&mut receiver_ctx, &mut window, 99 - REPLAY_WINDOW_SIZE + 1).unwrap();
+    }
+
+    /// Tests that the sequence number getters and setters agree with `seal()` and `open()`, and
+    /// that the sequence number can't move backward
+    #[test]
+    fn test_ctx_seq() {
+        let (mut sender_ctx, mut receiver_ctx) =
+            gen_ctx_simple_pair::<ChaCha20Poly1305, HkdfSha256>();
+        assert_eq!(sender_ctx.seq(), 0);
+
+        let mut ciphertext = *b"one";
+        sender_ctx.seal(&mut ciphertext[..], b"").unwrap();
+        assert_eq!(sender_ctx.seq(), 1);
+
+        // Skip ahead on both sides. The next message should be sealed and opened at seq 1000.
+        sender_ctx.set_seq(1000).unwrap();
+        receiver_ctx.set_seq(1000).unwrap();
+        let mut ciphertext = *b"two";
+        let tag = sender_ctx.seal(&mut ciphertext[..], b"").unwrap();
+        receiver_ctx.open(&mut ciphertext[..], b"", &tag).unwrap();
+        assert_eq!(&ciphertext, b"two");
+        assert_eq!(receiver_ctx.seq(), 1001);
+
+        // Sealing at an explicit sequence number moves the running one past it, and the receiver
+        // can open it at that number
+        let mut ciphertext = *b"three";
+        let tag = sender_ctx.seal_at(2000, &mut ciphertext[..], b"").unwrap();
+        assert_eq!(sender_ctx.seq(), 2001);
+        receiver_ctx
+            .open_at(2000, &mut ciphertext[..], b"", &tag)
+            .unwrap();
+        assert_eq!(&ciphertext, b"three");
+
+        // Going backward isn't allowed
+        match sender_ctx.set_seq(1000) {
+            Err(HpkeError::InvalidSeq) => {}
+            _ => panic!("set_seq() should have refused to move backward"),
+        }
+        assert_eq!(sender_ctx.seq(), 2001);
+    }
+
+    /// Tests that contexts stop working once they hit their message or byte limits
+    #[test]
+    fn test_ctx_limits() {
+        let (mut sender_ctx, mut receiver_ctx) = gen_ctx_simple_pair::<AesGcm128, HkdfSha256>();
+        assert_eq!(sender_ctx.limits(), UsageLimits::default_for::<AesGcm128>());
+
+        // Allow two messages of up to 10 bytes total
+        let limits = UsageLimits {
+            max_messages: 2,
+            max_bytes: 10,
+        };
+        sender_ctx.set_limits(limits);
+
+        let ciphertext1 = sender_ctx.seal_to_vec(b"", b"123456").unwrap();
+        assert_eq!(sender_ctx.num_bytes(), 6);
+
+        // Too many bytes. This shouldn't count toward anything.
+        match sender_ctx.seal_to_vec(b"", b"12345") {
+            Err(HpkeError::LimitExceeded) => {}
+            _ => panic!("seal() should have hit the byte limit"),
+        }
+        assert_eq!(sender_ctx.num_messages(), 1);
+        let ciphertext2 = sender_ctx.seal_to_vec(b"", b"1234").unwrap();
+
+        // Too many messages
+        match sender_ctx.seal_to_vec(b"", b"") {
+            Err(HpkeError::LimitExceeded) => {}
+            _ => panic!("seal() should have hit the message limit"),
+        }
+        match sender_ctx.seal_at(100, &mut [], b"") {
+            Err(HpkeError::LimitExceeded) => {}
+            _ => panic!("seal_at() should have hit the message limit"),
+        }
+
+        // A failed open counts toward the receiver's message limit too, but not its byte limit.
+        // Garbage doesn't use up the byte budget, no matter how much of it there is.
+        receiver_ctx.set_limits(UsageLimits {
+            max_messages: 2,
+            max_bytes: 6,
+        });
+        let mut bad_ciphertext = ciphertext1.clone();
+        bad_ciphertext[0] ^= 1;
+        assert!(matches!(
+            receiver_ctx.open_to_vec(b"", &bad_ciphertext),
+            Err(HpkeError::InvalidTag)
+        ));
+        assert_eq!(receiver_ctx.num_messages(), 1);
+        assert_eq!(receiver_ctx.num_bytes(), 0);
+        receiver_ctx.open_to_vec(b"", &ciphertext1).unwrap();
+        assert_eq!(receiver_ctx.num_bytes(), 6);
+        match receiver_ctx.open_to_vec(b"", &ciphertext2) {
+            Err(HpkeError::LimitExceeded) => {}
+            _ => panic!("open() should have hit the message limit"),
+        }
+
+        // Raising the limits lets the receiver continue
+        receiver_ctx.set_limits(UsageLimits {
+            max_messages: 3,
+            max_bytes: 10,
+        });
+        receiver_ctx.open_to_vec(b"", &ciphertext2).unwrap();
+    }
+
+    /// Tests that serialized contexts pick up where they left off, and can't be restored under a
+    /// different suite
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_ctx_persistence() {
+        use super::AeadCtxS;
+        use crate::{
+            kdf::HkdfSha384,
+            kem::{Kem as KemTrait, X25519HkdfSha256},
+            op_mode::{OpModeR, OpModeS},
+            setup::{setup_receiver, setup_sender},
+        };
+        use rand::{rngs::StdRng, SeedableRng};
+
+        type A = AesGcm256;
+        type Kdf = HkdfSha256;
+        type Kem = X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+        let (encapped_key, mut sender_ctx) =
+            setup_sender::<A, Kdf, Kem, _>(&OpModeS::Base, &pk_recip, b"", &mut csprng).unwrap();
+        let mut receiver_ctx =
+            setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, &sk_recip, &encapped_key, b"").unwrap();
+
+        // Use the contexts a bit, then save them
+        for _ in 0..3 {
+            let ciphertext = sender_ctx.seal_to_vec(b"", b"hello").unwrap();
+            receiver_ctx.open_to_vec(b"", &ciphertext).unwrap();
+        }
+        let saved_sender = sender_ctx.to_bytes();
+        let saved_receiver = receiver_ctx.to_bytes();
+
+        // Restore them and make sure they're in the same state
+        let mut restored_sender = AeadCtxS::<A, Kdf>::from_bytes::<Kem>(&saved_sender).unwrap();
+        let mut restored_receiver = AeadCtxR::<A, Kdf>::from_bytes::<Kem>(&saved_receiver).unwrap();
+        assert_eq!(restored_sender.seq(), 3);
+        assert_eq!(restored_receiver.seq(), 3);
+
+        let ciphertext = restored_sender.seal_to_vec(b"", b"I'm back").unwrap();
+        let plaintext = receiver_ctx.open_to_vec(b"", &ciphertext).unwrap();
+        assert_eq!(&plaintext[..], b"I'm back");
+        let ciphertext = sender_ctx.seal_to_vec(b"", b"so am I").unwrap();
+        let plaintext = restored_receiver.open_to_vec(b"", &ciphertext).unwrap();
+        assert_eq!(&plaintext[..], b"so am I");
+
+        let mut secret1 = [0u8; 32];
+        let mut secret2 = [0u8; 32];
+        sender_ctx.export(b"exp", &mut secret1).unwrap();
+        restored_receiver.export(b"exp", &mut secret2).unwrap();
+        assert_eq!(secret1, secret2);
+
+        // A different AEAD, KDF, or KEM can't restore it
+        assert!(matches!(
+            AeadCtxS::<AesGcm128, Kdf>::from_bytes::<Kem>(&saved_sender),
+            Err(HpkeError::SuiteMismatch)
+        ));
+        assert!(matches!(
+            AeadCtxS::<A, HkdfSha384>::from_bytes::<Kem>(&saved_sender),
+            Err(HpkeError::SuiteMismatch)
+        ));
+        let mut wrong_suite = saved_sender.clone();
+        wrong_suite[5] ^= 1;
+        assert!(matches!(
+            AeadCtxS::<A, Kdf>::from_bytes::<Kem>(&wrong_suite),
+            Err(HpkeError::SuiteMismatch)
+        ));
+
+        // Neither can a truncated encoding
+        assert!(matches!(
+            AeadCtxS::<A, Kdf>::from_bytes::<Kem>(&saved_sender[..saved_sender.len() - 1]),
+            Err(HpkeError::InvalidEncoding)
+        ));
+    }
+
     // The hash function and DH impl shouldn't really matter
     test_ctx_correctness!(test_ctx_correctness_aes128, AesGcm128);
     test_ctx_correctness!(test_ctx_correctness_aes256, AesGcm256);
@@ -0,0 +1,957 @@
+//! Runtime ciphersuite agility. Instead of picking the AEAD, KDF, and KEM at the type level, you
+//! pick them with the `AeadAlg`, `KdfAlg`, and `KemAlg` enums (e.g., from algorithm identifiers
+//! read off the wire), and keys, encapsulated keys, and encryption contexts are passed around in
+//! type-erased form. Every entry point checks that the algorithms of its inputs agree before
+//! dispatching to the typed `setup_sender`/`setup_receiver`.
+//!
+//! Algorithms whose crate features aren't enabled still have identifiers here. Using one results
+//! in `AgileHpkeError::AlgNotEnabled`.
+
+// With no KEMs enabled, there's nothing to dispatch to, and most of this module goes unused
+#![cfg_attr(
+    not(any(
+        feature = "p256",
+        feature = "p384",
+        feature = "p521",
+        feature = "x25519-dalek",
+        feature = "x448",
+        feature = "ml-kem"
+    )),
+    allow(dead_code, unused_variables)
+)]
+
+use crate::prelude::*;
+use crate::{
+    aead::{Aead, AeadCipher, AeadCtxR, AeadCtxS, AeadTag, ExportOnly},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Marshallable, Unmarshallable},
+    op_mode::{OpModeR, OpModeS, Psk, PskBundle},
+    setup::{setup_receiver, setup_sender},
+    HpkeError,
+};
+
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroizing;
+
+/// Describes things that can go wrong when using the agile API
+#[derive(Clone, Copy, Debug)]
+pub enum AgileHpkeError {
+    /// Two inputs were meant for different algorithms. Error is of the form
+    /// `((alg1, alg1_location), (alg2, alg2_location))`.
+    AlgMismatch((&'static str, &'static str), (&'static str, &'static str)),
+    /// An algorithm identifier wasn't recognized. Error is of the form `(alg_kind, given_id)`.
+    UnknownAlgIdent(&'static str, u16),
+    /// The algorithm is known, but the crate feature that implements it isn't enabled
+    AlgNotEnabled(&'static str),
+    /// `seal` or `open` was called on an export-only context
+    ExportOnly,
+    /// Represents an error in the rest of this crate
+    HpkeError(HpkeError),
+}
+
+// This just wraps the HpkeError
+impl From<HpkeError> for AgileHpkeError {
+    fn from(e: HpkeError) -> AgileHpkeError {
+        AgileHpkeError::HpkeError(e)
+    }
+}
+
+impl core::fmt::Display for AgileHpkeError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            AgileHpkeError::AlgMismatch((alg1, loc1), (alg2, loc2)) => write!(
+                f,
+                "Algorithm mismatch: {} at {} vs {} at {}",
+                alg1, loc1, alg2, loc2
+            ),
+            AgileHpkeError::UnknownAlgIdent(kind, id) => {
+                write!(f, "Unknown {} identifier: {:#06x}", kind, id)
+            }
+            AgileHpkeError::AlgNotEnabled(alg) => write!(f, "Algorithm not enabled: {}", alg),
+            AgileHpkeError::ExportOnly => f.write_str("Context is export-only"),
+            AgileHpkeError::HpkeError(e) => core::fmt::Display::fmt(e, f),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for AgileHpkeError {}
+
+/// An AEAD, chosen at runtime. See RFC 9180 §7.3 for the identifiers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum AeadAlg {
+    AesGcm128,
+    AesGcm256,
+    ChaCha20Poly1305,
+    ExportOnly,
+}
+
+impl AeadAlg {
+    /// Returns the name of this algorithm
+    pub fn name(&self) -> &'static str {
+        match self {
+            AeadAlg::AesGcm128 => "AesGcm128",
+            AeadAlg::AesGcm256 => "AesGcm256",
+            AeadAlg::ChaCha20Poly1305 => "ChaCha20Poly1305",
+            AeadAlg::ExportOnly => "ExportOnly",
+        }
+    }
+
+    /// Looks up the AEAD with the given identifier
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Err(AgileHpkeError::UnknownAlgIdent)` if the identifier isn't recognized.
+    pub fn try_from_u16(id: u16) -> Result<AeadAlg, AgileHpkeError> {
+        let res = match id {
+            0x0001 => AeadAlg::AesGcm128,
+            0x0002 => AeadAlg::AesGcm256,
+            0x0003 => AeadAlg::ChaCha20Poly1305,
+            0xFFFF => AeadAlg::ExportOnly,
+            _ => return Err(AgileHpkeError::UnknownAlgIdent("AeadAlg", id)),
+        };
+
+        Ok(res)
+    }
+
+    /// Returns this algorithm's identifier
+    pub fn to_u16(self) -> u16 {
+        match self {
+            AeadAlg::AesGcm128 => 0x0001,
+            AeadAlg::AesGcm256 => 0x0002,
+            AeadAlg::ChaCha20Poly1305 => 0x0003,
+            AeadAlg::ExportOnly => 0xFFFF,
+        }
+    }
+}
+
+/// A KDF, chosen at runtime. See RFC 9180 §7.2 for the identifiers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KdfAlg {
+    HkdfSha256,
+    HkdfSha384,
+    HkdfSha512,
+}
+
+impl KdfAlg {
+    /// Returns the name of this algorithm
+    pub fn name(&self) -> &'static str {
+        match self {
+            KdfAlg::HkdfSha256 => "HkdfSha256",
+            KdfAlg::HkdfSha384 => "HkdfSha384",
+            KdfAlg::HkdfSha512 => "HkdfSha512",
+        }
+    }
+
+    /// Looks up the KDF with the given identifier
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Err(AgileHpkeError::UnknownAlgIdent)` if the identifier isn't recognized.
+    pub fn try_from_u16(id: u16) -> Result<KdfAlg, AgileHpkeError> {
+        let res = match id {
+            0x0001 => KdfAlg::HkdfSha256,
+            0x0002 => KdfAlg::HkdfSha384,
+            0x0003 => KdfAlg::HkdfSha512,
+            _ => return Err(AgileHpkeError::UnknownAlgIdent("KdfAlg", id)),
+        };
+
+        Ok(res)
+    }
+
+    /// Returns this algorithm's identifier
+    pub fn to_u16(self) -> u16 {
+        match self {
+            KdfAlg::HkdfSha256 => 0x0001,
+            KdfAlg::HkdfSha384 => 0x0002,
+            KdfAlg::HkdfSha512 => 0x0003,
+        }
+    }
+
+    /// Returns the digest length `Nh` of the underlying hash function
+    pub fn get_digest_len(&self) -> usize {
+        match self {
+            KdfAlg::HkdfSha256 => 32,
+            KdfAlg::HkdfSha384 => 48,
+            KdfAlg::HkdfSha512 => 64,
+        }
+    }
+}
+
+/// A KEM, chosen at runtime. See RFC 9180 §7.1 and draft-ietf-hpke-pq §4 for the identifiers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum KemAlg {
+    DhP256HkdfSha256,
+    DhP384HkdfSha384,
+    DhP521HkdfSha512,
+    X25519HkdfSha256,
+    X448HkdfSha512,
+    MlKem768,
+    MlKem1024,
+    XWing,
+}
+
+impl KemAlg {
+    /// Returns the name of this algorithm
+    pub fn name(&self) -> &'static str {
+        match self {
+            KemAlg::DhP256HkdfSha256 => "DhP256HkdfSha256",
+            KemAlg::DhP384HkdfSha384 => "DhP384HkdfSha384",
+            KemAlg::DhP521HkdfSha512 => "DhP521HkdfSha512",
+            KemAlg::X25519HkdfSha256 => "X25519HkdfSha256",
+            KemAlg::X448HkdfSha512 => "X448HkdfSha512",
+            KemAlg::MlKem768 => "MlKem768",
+            KemAlg::MlKem1024 => "MlKem1024",
+            KemAlg::XWing => "XWing",
+        }
+    }
+
+    /// Looks up the KEM with the given identifier
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Err(AgileHpkeError::UnknownAlgIdent)` if the identifier isn't recognized.
+    pub fn try_from_u16(id: u16) -> Result<KemAlg, AgileHpkeError> {
+        let res = match id {
+            0x0010 => KemAlg::DhP256HkdfSha256,
+            0x0011 => KemAlg::DhP384HkdfSha384,
+            0x0012 => KemAlg::DhP521HkdfSha512,
+            0x0020 => KemAlg::X25519HkdfSha256,
+            0x0021 => KemAlg::X448HkdfSha512,
+            0x0041 => KemAlg::MlKem768,
+            0x0042 => KemAlg::MlKem1024,
+            0x647a => KemAlg::XWing,
+            _ => return Err(AgileHpkeError::UnknownAlgIdent("KemAlg", id)),
+        };
+
+        Ok(res)
+    }
+
+    /// Returns this algorithm's identifier
+    pub fn to_u16(self) -> u16 {
+        match self {
+            KemAlg::DhP256HkdfSha256 => 0x0010,
+            KemAlg::DhP384HkdfSha384 => 0x0011,
+            KemAlg::DhP521HkdfSha512 => 0x0012,
+            KemAlg::X25519HkdfSha256 => 0x0020,
+            KemAlg::X448HkdfSha512 => 0x0021,
+            KemAlg::MlKem768 => 0x0041,
+            KemAlg::MlKem1024 => 0x0042,
+            KemAlg::XWing => 0x647a,
+        }
+    }
+
+    /// Returns whether the crate feature that implements this KEM is enabled
+    pub fn is_enabled(&self) -> bool {
+        match self {
+            KemAlg::DhP256HkdfSha256 => cfg!(feature = "p256"),
+            KemAlg::DhP384HkdfSha384 => cfg!(feature = "p384"),
+            KemAlg::DhP521HkdfSha512 => cfg!(feature = "p521"),
+            KemAlg::X25519HkdfSha256 => cfg!(feature = "x25519-dalek"),
+            KemAlg::X448HkdfSha512 => cfg!(feature = "x448"),
+            KemAlg::MlKem768 | KemAlg::MlKem1024 => cfg!(feature = "ml-kem"),
+            KemAlg::XWing => cfg!(feature = "xwing"),
+        }
+    }
+}
+
+// Runs $body with $aead_ty set to the AEAD type corresponding to the given AeadAlg
+macro_rules! with_aead {
+    ($aead_alg:expr, $aead_ty:ident, $body:expr) => {
+        match $aead_alg {
+            AeadAlg::AesGcm128 => {
+                type $aead_ty = crate::aead::AesGcm128;
+                $body
+            }
+            AeadAlg::AesGcm256 => {
+                type $aead_ty = crate::aead::AesGcm256;
+                $body
+            }
+            AeadAlg::ChaCha20Poly1305 => {
+                type $aead_ty = crate::aead::ChaCha20Poly1305;
+                $body
+            }
+            AeadAlg::ExportOnly => {
+                type $aead_ty = crate::aead::ExportOnly;
+                $body
+            }
+        }
+    };
+}
+
+// Runs $body with $kdf_ty set to the KDF type corresponding to the given KdfAlg
+macro_rules! with_kdf {
+    ($kdf_alg:expr, $kdf_ty:ident, $body:expr) => {
+        match $kdf_alg {
+            KdfAlg::HkdfSha256 => {
+                type $kdf_ty = crate::kdf::HkdfSha256;
+                $body
+            }
+            KdfAlg::HkdfSha384 => {
+                type $kdf_ty = crate::kdf::HkdfSha384;
+                $body
+            }
+            KdfAlg::HkdfSha512 => {
+                type $kdf_ty = crate::kdf::HkdfSha512;
+                $body
+            }
+        }
+    };
+}
+
+// Runs $body with $kem_ty set to the KEM type corresponding to the given KemAlg. If that KEM's
+// feature isn't enabled, evaluates to Err(AgileHpkeError::AlgNotEnabled).
+macro_rules! with_kem {
+    ($kem_alg:expr, $kem_ty:ident, $body:expr) => {
+        match $kem_alg {
+            #[cfg(feature = "p256")]
+            KemAlg::DhP256HkdfSha256 => {
+                type $kem_ty = crate::kem::DhP256HkdfSha256;
+                $body
+            }
+            #[cfg(feature = "p384")]
+            KemAlg::DhP384HkdfSha384 => {
+                type $kem_ty = crate::kem::DhP384HkdfSha384;
+                $body
+            }
+            #[cfg(feature = "p521")]
+            KemAlg::DhP521HkdfSha512 => {
+                type $kem_ty = crate::kem::DhP521HkdfSha512;
+                $body
+            }
+            #[cfg(feature = "x25519-dalek")]
+            KemAlg::X25519HkdfSha256 => {
+                type $kem_ty = crate::kem::X25519HkdfSha256;
+                $body
+            }
+            #[cfg(feature = "x448")]
+            KemAlg::X448HkdfSha512 => {
+                type $kem_ty = crate::kem::X448HkdfSha512;
+                $body
+            }
+            #[cfg(feature = "ml-kem")]
+            KemAlg::MlKem768 => {
+                type $kem_ty = crate::kem::MlKem768;
+                $body
+            }
+            #[cfg(feature = "ml-kem")]
+            KemAlg::MlKem1024 => {
+                type $kem_ty = crate::kem::MlKem1024;
+                $body
+            }
+            #[cfg(feature = "xwing")]
+            KemAlg::XWing => {
+                type $kem_ty = crate::kem::XWing;
+                $body
+            }
+            // This is unreachable iff every KEM feature is enabled
+            #[allow(unreachable_patterns)]
+            other => Err(AgileHpkeError::AlgNotEnabled(other.name())),
+        }
+    };
+}
+
+/// A type-erased public key
+#[derive(Clone)]
+pub struct AgilePublicKey {
+    /// The KEM this key belongs to
+    pub kem_alg: KemAlg,
+    /// The marshalled public key
+    pub pubkey_bytes: Vec<u8>,
+}
+
+impl AgilePublicKey {
+    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::PublicKey, AgileHpkeError> {
+        Kem::PublicKey::unmarshal(&self.pubkey_bytes).map_err(|e| e.into())
+    }
+}
+
+/// A type-erased private key. The key bytes are zeroed on drop.
+#[derive(Clone)]
+pub struct AgilePrivateKey {
+    /// The KEM this key belongs to
+    pub kem_alg: KemAlg,
+    /// The marshalled private key
+    pub privkey_bytes: Zeroizing<Vec<u8>>,
+}
+
+impl AgilePrivateKey {
+    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::PrivateKey, AgileHpkeError> {
+        Kem::PrivateKey::unmarshal(&self.privkey_bytes).map_err(|e| e.into())
+    }
+}
+
+/// A type-erased encapsulated key
+#[derive(Clone)]
+pub struct AgileEncappedKey {
+    /// The KEM this encapsulated key belongs to
+    pub kem_alg: KemAlg,
+    /// The marshalled encapsulated key
+    pub encapped_key_bytes: Vec<u8>,
+}
+
+impl AgileEncappedKey {
+    fn try_lift<Kem: KemTrait>(&self) -> Result<Kem::EncappedKey, AgileHpkeError> {
+        Kem::EncappedKey::unmarshal(&self.encapped_key_bytes).map_err(|e| e.into())
+    }
+}
+
+/// A type-erased keypair
+#[derive(Clone)]
+pub struct AgileKeypair(pub AgilePrivateKey, pub AgilePublicKey);
+
+impl AgileKeypair {
+    fn try_lift<Kem: KemTrait>(&self) -> Result<(Kem::PrivateKey, Kem::PublicKey), AgileHpkeError> {
+        Ok((self.0.try_lift::<Kem>()?, self.1.try_lift::<Kem>()?))
+    }
+
+    /// Checks that both halves of this keypair belong to `kem_alg`
+    fn validate(&self, kem_alg: KemAlg) -> Result<(), AgileHpkeError> {
+        check_kem_alg(kem_alg, self.0.kem_alg, "AgileKeypair::privkey")?;
+        check_kem_alg(kem_alg, self.1.kem_alg, "AgileKeypair::pubkey")
+    }
+}
+
+/// A type-erased PSK bundle. The PSK bytes are zeroed on drop.
+#[derive(Clone)]
+pub struct AgilePskBundle {
+    /// The preshared key
+    pub psk_bytes: Zeroizing<Vec<u8>>,
+    /// An bytestring that uniquely identifies this PSK
+    pub psk_id: Vec<u8>,
+}
+
+impl AgilePskBundle {
+    fn lift<Kdf: KdfTrait>(&self) -> PskBundle<Kdf> {
+        PskBundle {
+            psk: Psk::<Kdf>::from_bytes(self.psk_bytes.to_vec()),
+            psk_id: self.psk_id.clone(),
+        }
+    }
+}
+
+/// The type-erased version of `OpModeS`
+#[derive(Clone)]
+pub enum AgileOpModeS {
+    /// No extra information included
+    Base,
+    /// A preshared key known to the sender and receiver
+    Psk(AgilePskBundle),
+    /// The identity keypair of the sender
+    Auth(AgileKeypair),
+    /// Both of the above
+    AuthPsk(AgileKeypair, AgilePskBundle),
+}
+
+impl AgileOpModeS {
+    fn try_lift<Kem: KemTrait, Kdf: KdfTrait>(&self) -> Result<OpModeS<Kem, Kdf>, AgileHpkeError> {
+        let res = match self {
+            AgileOpModeS::Base => OpModeS::Base,
+            AgileOpModeS::Psk(bundle) => OpModeS::Psk(bundle.lift::<Kdf>()),
+            AgileOpModeS::Auth(keypair) => OpModeS::Auth(keypair.try_lift::<Kem>()?),
+            AgileOpModeS::AuthPsk(keypair, bundle) => {
+                OpModeS::AuthPsk(keypair.try_lift::<Kem>()?, bundle.lift::<Kdf>())
+            }
+        };
+
+        Ok(res)
+    }
+
+    /// Checks that the sender identity keypair, if any, belongs to `kem_alg`
+    fn validate(&self, kem_alg: KemAlg) -> Result<(), AgileHpkeError> {
+        match self {
+            AgileOpModeS::Auth(keypair) | AgileOpModeS::AuthPsk(keypair, _) => {
+                keypair.validate(kem_alg)
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// The type-erased version of `OpModeR`
+#[derive(Clone)]
+pub enum AgileOpModeR {
+    /// No extra information included
+    Base,
+    /// A preshared key known to the sender and receiver
+    Psk(AgilePskBundle),
+    /// The identity public key of the sender
+    Auth(AgilePublicKey),
+    /// Both of the above
+    AuthPsk(AgilePublicKey, AgilePskBundle),
+}
+
+impl AgileOpModeR {
+    fn try_lift<Kem: KemTrait, Kdf: KdfTrait>(&self) -> Result<OpModeR<Kem, Kdf>, AgileHpkeError> {
+        let res = match self {
+            AgileOpModeR::Base => OpModeR::Base,
+            AgileOpModeR::Psk(bundle) => OpModeR::Psk(bundle.lift::<Kdf>()),
+            AgileOpModeR::Auth(pk) => OpModeR::Auth(pk.try_lift::<Kem>()?),
+            AgileOpModeR::AuthPsk(pk, bundle) => {
+                OpModeR::AuthPsk(pk.try_lift::<Kem>()?, bundle.lift::<Kdf>())
+            }
+        };
+
+        Ok(res)
+    }
+
+    /// Checks that the sender identity pubkey, if any, belongs to `kem_alg`
+    fn validate(&self, kem_alg: KemAlg) -> Result<(), AgileHpkeError> {
+        match self {
+            AgileOpModeR::Auth(pk) | AgileOpModeR::AuthPsk(pk, _) => {
+                check_kem_alg(kem_alg, pk.kem_alg, "AgileOpModeR::pk_sender_id")
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Returns an `AlgMismatch` error if `given` isn't `expected`
+fn check_kem_alg(
+    expected: KemAlg,
+    given: KemAlg,
+    location: &'static str,
+) -> Result<(), AgileHpkeError> {
+    if expected != given {
+        Err(AgileHpkeError::AlgMismatch(
+            (expected.name(), "kem_alg"),
+            (given.name(), location),
+        ))
+    } else {
+        Ok(())
+    }
+}
+
+/// The type-erased version of `AeadCtxS`
+pub trait AgileAeadCtxS {
+    /// Does a detached seal in place. See `AeadCtxS::seal`. The returned tag is marshalled.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(tag_bytes)` on success. If this is an export-only context, returns
+    /// `Err(AgileHpkeError::ExportOnly)`. Otherwise, this fails in the same ways as
+    /// `AeadCtxS::seal`.
+    fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError>;
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. See
+    /// `AeadCtxS::export`.
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+}
+
+/// The type-erased version of `AeadCtxR`
+pub trait AgileAeadCtxR {
+    /// Does a detached open in place. See `AeadCtxR::open`. The tag is given marshalled.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If this is an export-only context, returns
+    /// `Err(AgileHpkeError::ExportOnly)`. If `tag_bytes` is the wrong length, returns
+    /// `Err(AgileHpkeError::HpkeError(HpkeError::InvalidEncoding))`. Otherwise, this fails in the
+    /// same ways as `AeadCtxR::open`.
+    fn open(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag_bytes: &[u8],
+    ) -> Result<(), AgileHpkeError>;
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. See
+    /// `AeadCtxR::export`.
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError>;
+}
+
+impl<A: AeadCipher, Kdf: KdfTrait> AgileAeadCtxS for AeadCtxS<A, Kdf> {
+    fn seal(&mut self, plaintext: &mut [u8], aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        let tag = AeadCtxS::seal(self, plaintext, aad)?;
+        Ok(tag.marshal().to_vec())
+    }
+
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxS::export(self, info, out_buf).map_err(|e| e.into())
+    }
+}
+
+impl<A: AeadCipher, Kdf: KdfTrait> AgileAeadCtxR for AeadCtxR<A, Kdf> {
+    fn open(
+        &mut self,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag_bytes: &[u8],
+    ) -> Result<(), AgileHpkeError> {
+        let tag = AeadTag::<A>::unmarshal(tag_bytes)?;
+        AeadCtxR::open(self, ciphertext, aad, &tag).map_err(|e| e.into())
+    }
+
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxR::export(self, info, out_buf).map_err(|e| e.into())
+    }
+}
+
+// Export-only contexts have no seal(), so that's a runtime error here
+impl<Kdf: KdfTrait> AgileAeadCtxS for AeadCtxS<ExportOnly, Kdf> {
+    fn seal(&mut self, _plaintext: &mut [u8], _aad: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        Err(AgileHpkeError::ExportOnly)
+    }
+
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxS::export(self, info, out_buf).map_err(|e| e.into())
+    }
+}
+
+// Export-only contexts have no open(), so that's a runtime error here
+impl<Kdf: KdfTrait> AgileAeadCtxR for AeadCtxR<ExportOnly, Kdf> {
+    fn open(
+        &mut self,
+        _ciphertext: &mut [u8],
+        _aad: &[u8],
+        _tag_bytes: &[u8],
+    ) -> Result<(), AgileHpkeError> {
+        Err(AgileHpkeError::ExportOnly)
+    }
+
+    fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), AgileHpkeError> {
+        AeadCtxR::export(self, info, out_buf).map_err(|e| e.into())
+    }
+}
+
+// The leg work of agile_gen_keypair
+fn do_gen_keypair<Kem: KemTrait, R: CryptoRng + RngCore>(
+    kem_alg: KemAlg,
+    csprng: &mut R,
+) -> Result<AgileKeypair, AgileHpkeError> {
+    let (sk, pk) = Kem::gen_keypair(csprng);
+    let sk = AgilePrivateKey {
+        kem_alg,
+        privkey_bytes: Zeroizing::new(sk.marshal().to_vec()),
+    };
+    let pk = AgilePublicKey {
+        kem_alg,
+        pubkey_bytes: pk.marshal().to_vec(),
+    };
+
+    Ok(AgileKeypair(sk, pk))
+}
+
+/// Generates a random keypair for the given KEM
+///
+/// Return Value
+/// ============
+/// Returns `Ok(keypair)` on success. If the KEM's feature isn't enabled, returns
+/// `Err(AgileHpkeError::AlgNotEnabled)`.
+pub fn agile_gen_keypair<R: CryptoRng + RngCore>(
+    kem_alg: KemAlg,
+    csprng: &mut R,
+) -> Result<AgileKeypair, AgileHpkeError> {
+    with_kem!(kem_alg, Kem, do_gen_keypair::<Kem, R>(kem_alg, csprng))
+}
+
+// The leg work of agile_setup_sender
+fn do_setup_sender<A, Kdf, Kem, R>(
+    kem_alg: KemAlg,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError>
+where
+    A: 'static + Aead,
+    Kdf: 'static + KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+    AeadCtxS<A, Kdf>: AgileAeadCtxS,
+{
+    let mode = mode.try_lift::<Kem, Kdf>()?;
+    let pk_recip = pk_recip.try_lift::<Kem>()?;
+
+    let (encapped_key, aead_ctx) = setup_sender::<A, Kdf, Kem, R>(&mode, &pk_recip, info, csprng)?;
+    let encapped_key = AgileEncappedKey {
+        kem_alg,
+        encapped_key_bytes: encapped_key.marshal().to_vec(),
+    };
+
+    Ok((encapped_key, Box::new(aead_ctx)))
+}
+
+/// Does a `setup_sender` with the ciphersuite chosen at runtime. See `setup::setup_sender` for
+/// more detail.
+///
+/// Return Value
+/// ============
+/// On success, returns an encapsulated key and a boxed encryption context. If `pk_recip` or a key
+/// in `mode` doesn't belong to `kem_alg`, returns `Err(AgileHpkeError::AlgMismatch)`. If the KEM's
+/// feature isn't enabled, returns `Err(AgileHpkeError::AlgNotEnabled)`. Otherwise, this fails in
+/// the same ways as `setup_sender`, or if a key fails to unmarshal.
+pub fn agile_setup_sender<R: CryptoRng + RngCore>(
+    aead_alg: AeadAlg,
+    kdf_alg: KdfAlg,
+    kem_alg: KemAlg,
+    mode: &AgileOpModeS,
+    pk_recip: &AgilePublicKey,
+    info: &[u8],
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, Box<dyn AgileAeadCtxS>), AgileHpkeError> {
+    // Do all the necessary validation
+    mode.validate(kem_alg)?;
+    check_kem_alg(kem_alg, pk_recip.kem_alg, "pk_recip")?;
+
+    // Now dispatch on all three algorithms
+    with_aead!(
+        aead_alg,
+        A,
+        with_kdf!(
+            kdf_alg,
+            Kdf,
+            with_kem!(
+                kem_alg,
+                Kem,
+                do_setup_sender::<A, Kdf, Kem, R>(kem_alg, mode, pk_recip, info, csprng)
+            )
+        )
+    )
+}
+
+// The leg work of agile_setup_receiver
+fn do_setup_receiver<A, Kdf, Kem>(
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError>
+where
+    A: 'static + Aead,
+    Kdf: 'static + KdfTrait,
+    Kem: KemTrait,
+    AeadCtxR<A, Kdf>: AgileAeadCtxR,
+{
+    let mode = mode.try_lift::<Kem, Kdf>()?;
+    let sk_recip = sk_recip.try_lift::<Kem>()?;
+    let encapped_key = encapped_key.try_lift::<Kem>()?;
+
+    let aead_ctx = setup_receiver::<A, Kdf, Kem>(&mode, &sk_recip, &encapped_key, info)?;
+    Ok(Box::new(aead_ctx))
+}
+
+/// Does a `setup_receiver` with the ciphersuite chosen at runtime. See `setup::setup_receiver`
+/// for more detail.
+///
+/// Return Value
+/// ============
+/// On success, returns a boxed encryption context. If `sk_recip`, `encapped_key`, or a key in
+/// `mode` doesn't belong to `kem_alg`, returns `Err(AgileHpkeError::AlgMismatch)`. If the KEM's
+/// feature isn't enabled, returns `Err(AgileHpkeError::AlgNotEnabled)`. Otherwise, this fails in
+/// the same ways as `setup_receiver`, or if a key fails to unmarshal.
+pub fn agile_setup_receiver(
+    aead_alg: AeadAlg,
+    kdf_alg: KdfAlg,
+    kem_alg: KemAlg,
+    mode: &AgileOpModeR,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+    info: &[u8],
+) -> Result<Box<dyn AgileAeadCtxR>, AgileHpkeError> {
+    // Do all the necessary validation
+    mode.validate(kem_alg)?;
+    check_kem_alg(kem_alg, sk_recip.kem_alg, "sk_recip")?;
+    check_kem_alg(kem_alg, encapped_key.kem_alg, "encapped_key")?;
+
+    // Now dispatch on all three algorithms
+    with_aead!(
+        aead_alg,
+        A,
+        with_kdf!(
+            kdf_alg,
+            Kdf,
+            with_kem!(
+                kem_alg,
+                Kem,
+                do_setup_receiver::<A, Kdf, Kem>(mode, sk_recip, encapped_key, info)
+            )
+        )
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    const AEAD_ALGS: &[AeadAlg] = &[
+        AeadAlg::AesGcm128,
+        AeadAlg::AesGcm256,
+        AeadAlg::ChaCha20Poly1305,
+        AeadAlg::ExportOnly,
+    ];
+    const KDF_ALGS: &[KdfAlg] = &[KdfAlg::HkdfSha256, KdfAlg::HkdfSha384, KdfAlg::HkdfSha512];
+    const KEM_ALGS: &[KemAlg] = &[
+        KemAlg::DhP256HkdfSha256,
+        KemAlg::DhP384HkdfSha384,
+        KemAlg::DhP521HkdfSha512,
+        KemAlg::X25519HkdfSha256,
+        KemAlg::X448HkdfSha512,
+        KemAlg::MlKem768,
+        KemAlg::MlKem1024,
+        KemAlg::XWing,
+    ];
+
+    /// Tests that the identifiers round-trip, and unknown ones are rejected
+    #[test]
+    fn test_alg_ids() {
+        for &aead_alg in AEAD_ALGS {
+            assert_eq!(AeadAlg::try_from_u16(aead_alg.to_u16()).unwrap(), aead_alg);
+        }
+        for &kdf_alg in KDF_ALGS {
+            assert_eq!(KdfAlg::try_from_u16(kdf_alg.to_u16()).unwrap(), kdf_alg);
+        }
+        for &kem_alg in KEM_ALGS {
+            assert_eq!(KemAlg::try_from_u16(kem_alg.to_u16()).unwrap(), kem_alg);
+        }
+
+        assert!(matches!(
+            AeadAlg::try_from_u16(0x0004),
+            Err(AgileHpkeError::UnknownAlgIdent("AeadAlg", 0x0004))
+        ));
+        assert!(matches!(
+            KdfAlg::try_from_u16(0x0000),
+            Err(AgileHpkeError::UnknownAlgIdent("KdfAlg", 0x0000))
+        ));
+        assert!(matches!(
+            KemAlg::try_from_u16(0x0030),
+            Err(AgileHpkeError::UnknownAlgIdent("KemAlg", 0x0030))
+        ));
+    }
+
+    /// Tests a Base and Psk mode round trip for every enabled combination of algorithms, and
+    /// that export-only contexts refuse to seal and open
+    #[test]
+    fn test_agile_correctness() {
+        let mut csprng = StdRng::from_entropy();
+
+        let info = b"we're gonna agile him in his clavicle";
+        let msg = b"paper boy paper boy";
+        let aad = b"all about that paper, boy";
+
+        let psk_bundle = AgilePskBundle {
+            psk_bytes: Zeroizing::new(vec![7u8; 32]),
+            psk_id: b"preshared key attempt #5, take 2. action".to_vec(),
+        };
+        let modes = [
+            (AgileOpModeS::Base, AgileOpModeR::Base),
+            (
+                AgileOpModeS::Psk(psk_bundle.clone()),
+                AgileOpModeR::Psk(psk_bundle),
+            ),
+        ];
+
+        for &kem_alg in KEM_ALGS {
+            // Skip the KEMs whose features aren't enabled
+            let (sk_recip, pk_recip) = match agile_gen_keypair(kem_alg, &mut csprng) {
+                Ok(AgileKeypair(sk, pk)) => (sk, pk),
+                Err(AgileHpkeError::AlgNotEnabled(_)) => continue,
+                Err(e) => panic!("agile_gen_keypair failed: {}", e),
+            };
+
+            for &aead_alg in AEAD_ALGS {
+                for &kdf_alg in KDF_ALGS {
+                    for (mode_s, mode_r) in modes.iter() {
+                        let (encapped_key, mut ctx_s) = agile_setup_sender(
+                            aead_alg,
+                            kdf_alg,
+                            kem_alg,
+                            mode_s,
+                            &pk_recip,
+                            info,
+                            &mut csprng,
+                        )
+                        .unwrap();
+                        let mut ctx_r = agile_setup_receiver(
+                            aead_alg,
+                            kdf_alg,
+                            kem_alg,
+                            mode_r,
+                            &sk_recip,
+                            &encapped_key,
+                            info,
+                        )
+                        .unwrap();
+
+                        // Exports should agree no matter what
+                        let mut secret_s = [0u8; 32];
+                        let mut secret_r = [0u8; 32];
+                        ctx_s.export(b"export", &mut secret_s).unwrap();
+                        ctx_r.export(b"export", &mut secret_r).unwrap();
+                        assert_eq!(secret_s, secret_r);
+
+                        let mut buf = *msg;
+                        if aead_alg == AeadAlg::ExportOnly {
+                            assert!(matches!(
+                                ctx_s.seal(&mut buf, aad),
+                                Err(AgileHpkeError::ExportOnly)
+                            ));
+                            assert!(matches!(
+                                ctx_r.open(&mut buf, aad, &[]),
+                                Err(AgileHpkeError::ExportOnly)
+                            ));
+                        } else {
+                            let tag = ctx_s.seal(&mut buf, aad).unwrap();
+                            ctx_r.open(&mut buf, aad, &tag).unwrap();
+                            assert_eq!(&buf, msg);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Tests that keys for the wrong KEM are rejected before anything gets unmarshalled
+    #[cfg(all(feature = "p256", feature = "x25519-dalek"))]
+    #[test]
+    fn test_agile_alg_mismatch() {
+        let mut csprng = StdRng::from_entropy();
+
+        let AgileKeypair(sk_recip, pk_recip) =
+            agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let sender_keypair = agile_gen_keypair(KemAlg::DhP256HkdfSha256, &mut csprng).unwrap();
+
+        // The recipient's key is X25519 but the KEM is P256
+        let res = agile_setup_sender(
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            KemAlg::DhP256HkdfSha256,
+            &AgileOpModeS::Base,
+            &pk_recip,
+            b"",
+            &mut csprng,
+        );
+        assert!(matches!(res, Err(AgileHpkeError::AlgMismatch(..))));
+
+        // The sender's identity key is P256 but the KEM is X25519
+        let res = agile_setup_sender(
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            KemAlg::X25519HkdfSha256,
+            &AgileOpModeS::Auth(sender_keypair),
+            &pk_recip,
+            b"",
+            &mut csprng,
+        );
+        assert!(matches!(res, Err(AgileHpkeError::AlgMismatch(..))));
+
+        // The encapped key is labeled P256 but the KEM is X25519
+        let encapped_key = AgileEncappedKey {
+            kem_alg: KemAlg::DhP256HkdfSha256,
+            encapped_key_bytes: vec![0u8; 65],
+        };
+        let res = agile_setup_receiver(
+            AeadAlg::ChaCha20Poly1305,
+            KdfAlg::HkdfSha256,
+            KemAlg::X25519HkdfSha256,
+            &AgileOpModeR::Base,
+            &sk_recip,
+            &encapped_key,
+            b"",
+        );
+        assert!(matches!(res, Err(AgileHpkeError::AlgMismatch(..))));
+    }
+}
@@ -0,0 +1,572 @@
+//! HPKE for TLS Encrypted Client Hello (ECH). The client seals its encoded ClientHelloInner in a
+//! Base mode context whose info string is `"tls ech" || 0x00 || ECHConfig`, where the ECHConfig
+//! is the one the client picked from the server's `ECHConfigList`.
+//!
+//! If the server answers with a HelloRetryRequest, the client does not set up a new context. It
+//! seals its second ClientHelloInner with the same [`EchSenderCtx`] and sends an empty `enc`, and
+//! the server opens it with the same [`EchReceiverCtx`]. The context's sequence number keeps the
+//! two nonces distinct.
+//!
+//! An `ECHConfigList` can name any KEM, KDF, and AEAD, so everything here uses the runtime
+//! algorithm choices of the [`agility`](crate::agility) module.
+
+use crate::prelude::*;
+use crate::{
+    agility::{
+        agile_setup_receiver, agile_setup_sender, AeadAlg, AgileAeadCtxR, AgileAeadCtxS,
+        AgileEncappedKey, AgileHpkeError, AgileOpModeR, AgileOpModeS, AgilePrivateKey,
+        AgilePublicKey, KdfAlg, KemAlg,
+    },
+    ohttp::SymmetricSuite,
+    HpkeError,
+};
+
+use rand::{CryptoRng, RngCore};
+
+/// The ECHConfig version this module understands
+pub const ECH_VERSION: u16 = 0xfe0d;
+
+// draft-ietf-tls-esni §6.1: the info string is "tls ech" || 0x00 || ECHConfig
+const INFO_LABEL: &[u8] = b"tls ech";
+
+// Every AEAD that ECH can use (i.e., everything but ExportOnly) has a 16-byte tag
+const TAG_LEN: usize = 16;
+
+// Extension types with the high bit set are mandatory. A client has to skip configs with
+// mandatory extensions it doesn't understand, and this module understands none.
+const MANDATORY_EXTENSION_BIT: u16 = 0x8000;
+
+/// An extension in an ECHConfig
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchConfigExtension {
+    /// The extension type
+    pub ext_type: u16,
+    /// The extension's contents
+    pub data: Vec<u8>,
+}
+
+// draft-ietf-tls-esni §4
+// struct {
+//     HpkeKeyConfig key_config;
+//     uint8 maximum_name_length;
+//     opaque public_name<1..255>;
+//     ECHConfigExtension extensions<0..2^16-1>;
+// } ECHConfigContents;
+//
+// struct {
+//     uint8 config_id;
+//     HpkeKemId kem_id;
+//     HpkePublicKey public_key;
+//     HpkeSymmetricCipherSuite cipher_suites<4..2^16-4>;
+// } HpkeKeyConfig;
+/// A version 0xfe0d ECHConfig
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EchConfig {
+    /// The identifier the server uses to pick its private key
+    pub config_id: u8,
+    /// The HPKE KEM ID of `public_key`. This need not be a KEM this crate implements.
+    pub kem_id: u16,
+    /// The server's marshalled public key
+    pub public_key: Vec<u8>,
+    /// The KDF and AEAD pairs the server accepts. This cannot be empty.
+    pub cipher_suites: Vec<SymmetricSuite>,
+    /// The longest server name the client should expect to pad to
+    pub maximum_name_length: u8,
+    /// The name the client puts in ClientHelloOuter. This cannot be empty.
+    pub public_name: Vec<u8>,
+    /// The config's extensions
+    pub extensions: Vec<EchConfigExtension>,
+}
+
+/// The algorithms a client and server use for ECH
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EchSuite {
+    /// The KEM, as given by the config's `kem_id`
+    pub kem_alg: KemAlg,
+    /// The KDF
+    pub kdf_alg: KdfAlg,
+    /// The AEAD
+    pub aead_alg: AeadAlg,
+}
+
+// A cursor over a byte slice that reads TLS presentation language values. Every read fails with
+// InvalidEncoding if there aren't enough bytes.
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], HpkeError> {
+        if self.0.len() < len {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let (out, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(out)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, HpkeError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, HpkeError> {
+        let bytes = self.read_bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // Reads an opaque<..2^8-1>
+    fn read_vec8(&mut self) -> Result<&'a [u8], HpkeError> {
+        let len = self.read_u8()? as usize;
+        self.read_bytes(len)
+    }
+
+    // Reads an opaque<..2^16-1>
+    fn read_vec16(&mut self) -> Result<&'a [u8], HpkeError> {
+        let len = self.read_u16()? as usize;
+        self.read_bytes(len)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+// Writes an opaque<..2^8-1>. Panics if it's too long.
+fn write_vec8(out: &mut Vec<u8>, bytes: &[u8]) {
+    assert!(bytes.len() <= 0xFF, "vector too long");
+    out.push(bytes.len() as u8);
+    out.extend_from_slice(bytes);
+}
+
+// Writes an opaque<..2^16-1>. Panics if it's too long.
+fn write_vec16(out: &mut Vec<u8>, bytes: &[u8]) {
+    assert!(bytes.len() <= 0xFFFF, "vector too long");
+    out.extend_from_slice(&(bytes.len() as u16).to_be_bytes());
+    out.extend_from_slice(bytes);
+}
+
+impl EchConfig {
+    /// Serializes this config as an ECHConfig, i.e., `version || length || contents`
+    ///
+    /// Panics
+    /// ======
+    /// Panics if any field is too long for its length prefix
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut contents = Vec::new();
+        contents.push(self.config_id);
+        contents.extend_from_slice(&self.kem_id.to_be_bytes());
+        write_vec16(&mut contents, &self.public_key);
+        let suites: Vec<u8> = self
+            .cipher_suites
+            .iter()
+            .flat_map(|s| [s.kdf_id.to_be_bytes(), s.aead_id.to_be_bytes()].concat())
+            .collect();
+        write_vec16(&mut contents, &suites);
+        contents.push(self.maximum_name_length);
+        write_vec8(&mut contents, &self.public_name);
+        let mut extensions = Vec::new();
+        for ext in self.extensions.iter() {
+            extensions.extend_from_slice(&ext.ext_type.to_be_bytes());
+            write_vec16(&mut extensions, &ext.data);
+        }
+        write_vec16(&mut contents, &extensions);
+
+        let mut out = ECH_VERSION.to_be_bytes().to_vec();
+        write_vec16(&mut out, &contents);
+        out
+    }
+
+    /// Parses a single ECHConfig
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(config)` on success. If the version isn't `ECH_VERSION`, the encoding is
+    /// malformed, or there are trailing bytes, returns `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<EchConfig, HpkeError> {
+        let mut reader = Reader(bytes);
+        let config = Self::read(&mut reader)?.ok_or(HpkeError::InvalidEncoding)?;
+        if !reader.is_empty() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        Ok(config)
+    }
+
+    // Reads one ECHConfig off the reader. Returns Ok(None) if it's a well-formed config of a
+    // version we don't know.
+    fn read(reader: &mut Reader<'_>) -> Result<Option<EchConfig>, HpkeError> {
+        let version = reader.read_u16()?;
+        let mut contents = Reader(reader.read_vec16()?);
+        if version != ECH_VERSION {
+            return Ok(None);
+        }
+
+        let config_id = contents.read_u8()?;
+        let kem_id = contents.read_u16()?;
+        let public_key = contents.read_vec16()?.to_vec();
+        let suites = contents.read_vec16()?;
+        if public_key.is_empty() || suites.is_empty() || !suites.len().is_multiple_of(4) {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let cipher_suites = suites
+            .chunks(4)
+            .map(|s| SymmetricSuite {
+                kdf_id: u16::from_be_bytes([s[0], s[1]]),
+                aead_id: u16::from_be_bytes([s[2], s[3]]),
+            })
+            .collect();
+
+        let maximum_name_length = contents.read_u8()?;
+        let public_name = contents.read_vec8()?.to_vec();
+        if public_name.is_empty() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        let mut ext_reader = Reader(contents.read_vec16()?);
+        let mut extensions = Vec::new();
+        while !ext_reader.is_empty() {
+            let ext_type = ext_reader.read_u16()?;
+            let data = ext_reader.read_vec16()?.to_vec();
+            extensions.push(EchConfigExtension { ext_type, data });
+        }
+
+        if !contents.is_empty() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        Ok(Some(EchConfig {
+            config_id,
+            kem_id,
+            public_key,
+            cipher_suites,
+            maximum_name_length,
+            public_name,
+            extensions,
+        }))
+    }
+
+    /// Picks the first of this config's cipher suites that this build of the crate can use. The
+    /// KEM has to be recognized and its feature enabled, the AEAD can't be export-only, and the
+    /// config can't have mandatory extensions.
+    pub fn select_suite(&self) -> Option<EchSuite> {
+        if self
+            .extensions
+            .iter()
+            .any(|ext| ext.ext_type & MANDATORY_EXTENSION_BIT != 0)
+        {
+            return None;
+        }
+        let kem_alg = KemAlg::try_from_u16(self.kem_id).ok()?;
+        if !kem_alg.is_enabled() {
+            return None;
+        }
+
+        self.cipher_suites.iter().find_map(|suite| {
+            let kdf_alg = KdfAlg::try_from_u16(suite.kdf_id).ok()?;
+            let aead_alg = AeadAlg::try_from_u16(suite.aead_id).ok()?;
+            if aead_alg == AeadAlg::ExportOnly {
+                return None;
+            }
+
+            Some(EchSuite {
+                kem_alg,
+                kdf_alg,
+                aead_alg,
+            })
+        })
+    }
+
+    // Checks that this config lists the given suite
+    fn check_suite(&self, suite: &EchSuite) -> Result<(), AgileHpkeError> {
+        let listed = SymmetricSuite {
+            kdf_id: suite.kdf_alg.to_u16(),
+            aead_id: suite.aead_alg.to_u16(),
+        };
+        if suite.kem_alg.to_u16() != self.kem_id || !self.cipher_suites.contains(&listed) {
+            return Err(HpkeError::SuiteMismatch.into());
+        }
+
+        Ok(())
+    }
+
+    // draft-ietf-tls-esni §6.1
+    //   pkR = DeserializePublicKey(ECHConfig.contents.public_key)
+    //   enc, context = SetupBaseS(pkR,
+    //                             "tls ech" || 0x00 || ECHConfig)
+    fn info(&self) -> Vec<u8> {
+        [INFO_LABEL, &[0u8], &self.to_bytes()].concat()
+    }
+}
+
+/// Serializes the given configs as an `ECHConfigList`
+///
+/// Panics
+/// ======
+/// Panics if the list or any field is too long for its length prefix
+pub fn serialize_ech_config_list(configs: &[EchConfig]) -> Vec<u8> {
+    let body: Vec<u8> = configs.iter().flat_map(|c| c.to_bytes()).collect();
+    let mut out = Vec::with_capacity(2 + body.len());
+    write_vec16(&mut out, &body);
+    out
+}
+
+/// Parses an `ECHConfigList`. Configs with versions other than `ECH_VERSION` are skipped.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(configs)` on success. If the list or any config in it is malformed, or there are
+/// trailing bytes, returns `Err(HpkeError::InvalidEncoding)`.
+pub fn parse_ech_config_list(bytes: &[u8]) -> Result<Vec<EchConfig>, HpkeError> {
+    let mut outer = Reader(bytes);
+    let mut reader = Reader(outer.read_vec16()?);
+    if !outer.is_empty() || reader.is_empty() {
+        return Err(HpkeError::InvalidEncoding);
+    }
+
+    let mut configs = Vec::new();
+    while !reader.is_empty() {
+        if let Some(config) = EchConfig::read(&mut reader)? {
+            configs.push(config);
+        }
+    }
+
+    Ok(configs)
+}
+
+/// Picks the first config in the list that has a usable suite. See [`EchConfig::select_suite`].
+pub fn select_ech_config(configs: &[EchConfig]) -> Option<(&EchConfig, EchSuite)> {
+    configs
+        .iter()
+        .find_map(|config| config.select_suite().map(|suite| (config, suite)))
+}
+
+/// The client's ECH encryption context
+pub struct EchSenderCtx(Box<dyn AgileAeadCtxS>);
+
+impl EchSenderCtx {
+    /// Seals an encoded ClientHelloInner with the ClientHelloOuterAAD as associated data. Call
+    /// this again on the same context for the ClientHello that follows a HelloRetryRequest.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(payload)` on success, where `payload` is `ciphertext || tag`. Otherwise, fails
+    /// in the same ways as `AgileAeadCtxS::seal`.
+    pub fn seal(&mut self, aad: &[u8], encoded_inner: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        let mut payload = encoded_inner.to_vec();
+        let tag = self.0.seal(&mut payload, aad)?;
+        payload.extend_from_slice(&tag);
+        Ok(payload)
+    }
+}
+
+/// The server's ECH decryption context
+pub struct EchReceiverCtx(Box<dyn AgileAeadCtxR>);
+
+impl EchReceiverCtx {
+    /// Opens an ECH payload with the ClientHelloOuterAAD as associated data. Call this again on
+    /// the same context for the ClientHello that follows a HelloRetryRequest.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(encoded_inner)` on success. If `payload` is shorter than a tag, returns
+    /// `Err(AgileHpkeError::HpkeError(HpkeError::InvalidEncoding))`. Otherwise, fails in the same
+    /// ways as `AgileAeadCtxR::open`.
+    pub fn open(&mut self, aad: &[u8], payload: &[u8]) -> Result<Vec<u8>, AgileHpkeError> {
+        if payload.len() < TAG_LEN {
+            return Err(HpkeError::InvalidEncoding.into());
+        }
+        let (ciphertext, tag) = payload.split_at(payload.len() - TAG_LEN);
+
+        let mut encoded_inner = ciphertext.to_vec();
+        self.0.open(&mut encoded_inner, aad, tag)?;
+        Ok(encoded_inner)
+    }
+}
+
+/// Sets up the client's context for the given config and suite
+///
+/// Return Value
+/// ============
+/// On success, returns the `enc` for the ECH extension and the sealing context. If `config`
+/// doesn't list `suite`, returns `Err(AgileHpkeError::HpkeError(HpkeError::SuiteMismatch))`.
+/// Otherwise, fails in the same ways as `agile_setup_sender`.
+pub fn ech_setup_sender<R: CryptoRng + RngCore>(
+    config: &EchConfig,
+    suite: &EchSuite,
+    csprng: &mut R,
+) -> Result<(AgileEncappedKey, EchSenderCtx), AgileHpkeError> {
+    config.check_suite(suite)?;
+    let pk_recip = AgilePublicKey {
+        kem_alg: suite.kem_alg,
+        pubkey_bytes: config.public_key.clone(),
+    };
+
+    let (encapped_key, ctx) = agile_setup_sender(
+        suite.aead_alg,
+        suite.kdf_alg,
+        suite.kem_alg,
+        &AgileOpModeS::Base,
+        &pk_recip,
+        &config.info(),
+        csprng,
+    )?;
+    Ok((encapped_key, EchSenderCtx(ctx)))
+}
+
+/// Sets up the server's context for the config the client named and the suite it used. `sk_recip`
+/// is the private key for `config`.
+///
+/// Return Value
+/// ============
+/// On success, returns the opening context. If `config` doesn't list `suite`, returns
+/// `Err(AgileHpkeError::HpkeError(HpkeError::SuiteMismatch))`. Otherwise, fails in the same ways
+/// as `agile_setup_receiver`.
+pub fn ech_setup_receiver(
+    config: &EchConfig,
+    suite: &EchSuite,
+    sk_recip: &AgilePrivateKey,
+    encapped_key: &AgileEncappedKey,
+) -> Result<EchReceiverCtx, AgileHpkeError> {
+    config.check_suite(suite)?;
+    let ctx = agile_setup_receiver(
+        suite.aead_alg,
+        suite.kdf_alg,
+        suite.kem_alg,
+        &AgileOpModeR::Base,
+        sk_recip,
+        encapped_key,
+        &config.info(),
+    )?;
+    Ok(EchReceiverCtx(ctx))
+}
+
+#[cfg(all(test, feature = "x25519-dalek"))]
+mod test {
+    use super::*;
+    use crate::agility::agile_gen_keypair;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    fn make_config(config_id: u8, pk: &AgilePublicKey) -> EchConfig {
+        EchConfig {
+            config_id,
+            kem_id: pk.kem_alg.to_u16(),
+            public_key: pk.pubkey_bytes.clone(),
+            cipher_suites: vec![
+                // An unknown KDF, which selection has to skip
+                SymmetricSuite {
+                    kdf_id: 0x7777,
+                    aead_id: 0x0001,
+                },
+                SymmetricSuite {
+                    kdf_id: 0x0001,
+                    aead_id: 0x0003,
+                },
+            ],
+            maximum_name_length: 32,
+            public_name: b"public.example".to_vec(),
+            extensions: vec![EchConfigExtension {
+                ext_type: 0x0a0a,
+                data: vec![1, 2, 3],
+            }],
+        }
+    }
+
+    /// Tests that config lists round trip, that unknown versions are skipped, and that malformed
+    /// lists are rejected
+    #[test]
+    fn test_ech_config_list() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let configs = vec![make_config(1, &keypair.1), make_config(2, &keypair.1)];
+
+        let list = serialize_ech_config_list(&configs);
+        assert_eq!(parse_ech_config_list(&list).unwrap(), configs);
+        for config in configs.iter() {
+            assert_eq!(EchConfig::from_bytes(&config.to_bytes()).unwrap(), *config);
+        }
+
+        // Put a config with an unknown version in front. It should be skipped.
+        let mut body = vec![0xfe, 0x0c, 0x00, 0x03, 0xaa, 0xbb, 0xcc];
+        body.extend_from_slice(&list[2..]);
+        let mut with_unknown = (body.len() as u16).to_be_bytes().to_vec();
+        with_unknown.extend_from_slice(&body);
+        assert_eq!(parse_ech_config_list(&with_unknown).unwrap(), configs);
+
+        // Truncations, trailing bytes, and empty lists are rejected
+        assert!(parse_ech_config_list(&list[..list.len() - 1]).is_err());
+        let mut trailing = list.clone();
+        trailing.push(0);
+        assert!(parse_ech_config_list(&trailing).is_err());
+        assert!(parse_ech_config_list(&[0, 0]).is_err());
+    }
+
+    /// Tests suite selection
+    #[test]
+    fn test_ech_select_suite() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let config = make_config(1, &keypair.1);
+
+        // The unknown KDF is skipped
+        let expected = EchSuite {
+            kem_alg: KemAlg::X25519HkdfSha256,
+            kdf_alg: KdfAlg::HkdfSha256,
+            aead_alg: AeadAlg::ChaCha20Poly1305,
+        };
+        assert_eq!(config.select_suite(), Some(expected));
+
+        // A config with an unknown mandatory extension or an unknown KEM is unusable, so
+        // selection moves on to the next config
+        let mut mandatory = make_config(2, &keypair.1);
+        mandatory.extensions[0].ext_type |= MANDATORY_EXTENSION_BIT;
+        let mut unknown_kem = make_config(3, &keypair.1);
+        unknown_kem.kem_id = 0x7777;
+        assert_eq!(mandatory.select_suite(), None);
+        assert_eq!(unknown_kem.select_suite(), None);
+
+        let configs = [mandatory, unknown_kem, config];
+        let (selected, suite) = select_ech_config(&configs).unwrap();
+        assert_eq!(selected.config_id, 1);
+        assert_eq!(suite, expected);
+    }
+
+    /// Tests that the client and server agree, including on the ClientHello after a
+    /// HelloRetryRequest, and that the config is bound into the context
+    #[test]
+    fn test_ech_correctness() {
+        let mut csprng = StdRng::from_entropy();
+        let keypair = agile_gen_keypair(KemAlg::X25519HkdfSha256, &mut csprng).unwrap();
+        let config = make_config(1, &keypair.1);
+        let suite = config.select_suite().unwrap();
+
+        let (enc, mut sender_ctx) = ech_setup_sender(&config, &suite, &mut csprng).unwrap();
+        let mut receiver_ctx = ech_setup_receiver(&config, &suite, &keypair.0, &enc).unwrap();
+
+        // The first ClientHello, then the one after the HelloRetryRequest
+        let payload1 = sender_ctx.seal(b"outer aad 1", b"inner hello 1").unwrap();
+        let payload2 = sender_ctx.seal(b"outer aad 2", b"inner hello 2").unwrap();
+        assert_eq!(
+            receiver_ctx.open(b"outer aad 1", &payload1).unwrap(),
+            b"inner hello 1"
+        );
+        assert_eq!(
+            receiver_ctx.open(b"outer aad 2", &payload2).unwrap(),
+            b"inner hello 2"
+        );
+
+        // A server that thinks the client used a different config can't open
+        let mut other_config = config.clone();
+        other_config.public_name = b"other.example".to_vec();
+        let mut other_ctx = ech_setup_receiver(&other_config, &suite, &keypair.0, &enc).unwrap();
+        assert!(other_ctx.open(b"outer aad 1", &payload1).is_err());
+
+        // A suite the config doesn't list is refused
+        let unlisted = EchSuite {
+            aead_alg: AeadAlg::AesGcm256,
+            ..suite
+        };
+        assert!(matches!(
+            ech_setup_sender(&config, &unlisted, &mut csprng),
+            Err(AgileHpkeError::HpkeError(HpkeError::SuiteMismatch))
+        ));
+    }
+}
@@ -1,18 +1,22 @@
 use crate::prelude::*;
 use crate::{
-    aead::{Aead, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305},
+    aead::{
+        Aead, AeadCipher, AeadCtxR, AeadTag, AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnly,
+    },
     kdf::{HkdfSha256, HkdfSha384, HkdfSha512, Kdf as KdfTrait},
-    kem::{encap_with_eph, DhP256HkdfSha256, Kem as KemTrait, X25519HkdfSha256},
-    kex::{KeyExchange, Marshallable, Unmarshallable},
+    kem::{
+        DeterministicEncap, DhP256HkdfSha256, DhP384HkdfSha384, DhP521HkdfSha512, Kem as KemTrait,
+        MlKem1024, MlKem768, X25519HkdfSha256, X448HkdfSha512, XWing,
+    },
+    kex::{Marshallable, Unmarshallable},
+    mls::{decrypt_with_label, HpkeCiphertext},
     op_mode::{OpModeR, Psk, PskBundle},
     setup::setup_receiver,
 };
 
 use std::fs::File;
 
-use hex;
 use serde::{de::Error as SError, Deserialize, Deserializer};
-use serde_json;
 
 // Tells serde how to deserialize bytes from the hex representation
 fn bytes_from_hex<'de, D>(deserializer: D) -> Result<Vec<u8>, D::Error>
@@ -32,7 +36,7 @@ fn bytes_from_hex_opt<'de, D>(deserializer: D) -> Result<Option<Vec<u8>>, D::Err
 where
     D: Deserializer<'de>,
 {
-    bytes_from_hex(deserializer).map(|v| Some(v))
+    bytes_from_hex(deserializer).map(Some)
 }
 
 // Each individual test case looks like this
@@ -40,25 +44,30 @@ where
 struct MainTestVector {
     // Parameters
     mode: u8,
-    #[serde(rename = "kemID")]
     kem_id: u16,
-    #[serde(rename = "kdfID")]
     kdf_id: u16,
-    #[serde(rename = "aeadID")]
     aead_id: u16,
     #[serde(deserialize_with = "bytes_from_hex")]
     info: Vec<u8>,
 
-    // Private keys
+    // Keying material
+    #[serde(rename = "ikmR", deserialize_with = "bytes_from_hex")]
+    ikm_recip: Vec<u8>,
+    #[serde(default, rename = "ikmS", deserialize_with = "bytes_from_hex_opt")]
+    ikm_sender: Option<Vec<u8>>,
+    #[serde(rename = "ikmE", deserialize_with = "bytes_from_hex")]
+    ikm_eph: Vec<u8>,
+
+    // Private keys. Only DHKEMs have an ephemeral keypair.
     #[serde(rename = "skRm", deserialize_with = "bytes_from_hex")]
     sk_recip: Vec<u8>,
     #[serde(default, rename = "skSm", deserialize_with = "bytes_from_hex_opt")]
     sk_sender: Option<Vec<u8>>,
-    #[serde(rename = "skEm", deserialize_with = "bytes_from_hex")]
-    sk_eph: Vec<u8>,
+    #[serde(default, rename = "skEm", deserialize_with = "bytes_from_hex_opt")]
+    sk_eph: Option<Vec<u8>>,
     #[serde(default, deserialize_with = "bytes_from_hex_opt")]
     psk: Option<Vec<u8>>,
-    #[serde(default, rename = "pskID", deserialize_with = "bytes_from_hex_opt")]
+    #[serde(default, deserialize_with = "bytes_from_hex_opt")]
     psk_id: Option<Vec<u8>>,
 
     // Public Keys
@@ -66,23 +75,27 @@ struct MainTestVector {
     pk_recip: Vec<u8>,
     #[serde(default, rename = "pkSm", deserialize_with = "bytes_from_hex_opt")]
     pk_sender: Option<Vec<u8>>,
-    #[serde(rename = "pkEm", deserialize_with = "bytes_from_hex")]
-    pk_eph: Vec<u8>,
+    #[serde(default, rename = "pkEm", deserialize_with = "bytes_from_hex_opt")]
+    pk_eph: Option<Vec<u8>>,
 
-    // Key schedule inputs and computations
+    // Key schedule inputs and computations. The PQ test vectors omit the intermediate values.
     #[serde(rename = "enc", deserialize_with = "bytes_from_hex")]
     encapped_key: Vec<u8>,
-    #[serde(rename = "zz", deserialize_with = "bytes_from_hex")]
+    #[serde(deserialize_with = "bytes_from_hex")]
     shared_secret: Vec<u8>,
-    #[serde(rename = "key_schedule_context", deserialize_with = "bytes_from_hex")]
+    #[serde(
+        default,
+        rename = "key_schedule_context",
+        deserialize_with = "bytes_from_hex"
+    )]
     _hpke_context: Vec<u8>,
-    #[serde(rename = "secret", deserialize_with = "bytes_from_hex")]
+    #[serde(default, rename = "secret", deserialize_with = "bytes_from_hex")]
     _key_schedule_secret: Vec<u8>,
     #[serde(rename = "key", deserialize_with = "bytes_from_hex")]
     _aead_key: Vec<u8>,
-    #[serde(rename = "nonce", deserialize_with = "bytes_from_hex")]
-    _aead_nonce: Vec<u8>,
-    #[serde(rename = "exporterSecret", deserialize_with = "bytes_from_hex")]
+    #[serde(rename = "base_nonce", deserialize_with = "bytes_from_hex")]
+    _aead_base_nonce: Vec<u8>,
+    #[serde(rename = "exporter_secret", deserialize_with = "bytes_from_hex")]
     _exporter_secret: Vec<u8>,
 
     encryptions: Vec<EncryptionTestVector>,
@@ -91,55 +104,75 @@ struct MainTestVector {
 
 #[derive(Clone, Deserialize, Debug)]
 struct EncryptionTestVector {
-    #[serde(deserialize_with = "bytes_from_hex")]
+    #[serde(rename = "pt", deserialize_with = "bytes_from_hex")]
     plaintext: Vec<u8>,
     #[serde(deserialize_with = "bytes_from_hex")]
     aad: Vec<u8>,
     #[serde(rename = "nonce", deserialize_with = "bytes_from_hex")]
     _nonce: Vec<u8>,
-    #[serde(deserialize_with = "bytes_from_hex")]
+    #[serde(rename = "ct", deserialize_with = "bytes_from_hex")]
     ciphertext: Vec<u8>,
 }
 
 #[derive(Clone, Deserialize, Debug)]
 struct ExporterTestVector {
-    #[serde(rename = "exportContext", deserialize_with = "bytes_from_hex")]
+    #[serde(rename = "exporter_context", deserialize_with = "bytes_from_hex")]
     info: Vec<u8>,
-    #[serde(rename = "exportLength")]
+    #[serde(rename = "L")]
     export_len: usize,
-    #[serde(rename = "exportValue", deserialize_with = "bytes_from_hex")]
+    #[serde(rename = "exported_value", deserialize_with = "bytes_from_hex")]
     export_val: Vec<u8>,
 }
 
-/// Returns a KEX keypair given the secret bytes and pubkey bytes, and ensures that the pubkey does
+/// Returns a KEM keypair given the secret bytes and pubkey bytes, and ensures that the pubkey does
 /// indeed correspond to that secret key
-fn get_and_assert_keypair<Kex: KeyExchange>(
+fn get_and_assert_keypair<Kem: KemTrait>(
     sk_bytes: &[u8],
     pk_bytes: &[u8],
-) -> (Kex::PrivateKey, Kex::PublicKey) {
+) -> (Kem::PrivateKey, Kem::PublicKey) {
     // Unmarshall the secret key
-    let sk = <Kex as KeyExchange>::PrivateKey::unmarshal(sk_bytes).unwrap();
+    let sk = <Kem as KemTrait>::PrivateKey::unmarshal(sk_bytes).unwrap();
     // Unmarshall the pubkey
-    let pk = <Kex as KeyExchange>::PublicKey::unmarshal(pk_bytes).unwrap();
+    let pk = <Kem as KemTrait>::PublicKey::unmarshal(pk_bytes).unwrap();
 
     // Make sure the derived pubkey matches the given pubkey
-    assert_eq!(pk.marshal(), Kex::sk_to_pk(&sk).marshal());
+    assert_eq!(pk.marshal(), Kem::sk_to_pk(&sk).marshal());
 
     (sk, pk)
 }
 
+/// Derives a KEM keypair from the given keying material, and ensures that it matches the given
+/// secret key and pubkey
+fn assert_derived_keypair<Kem: KemTrait>(ikm: &[u8], sk_bytes: &[u8], pk_bytes: &[u8]) {
+    let (sk, pk) = Kem::derive_keypair(ikm);
+
+    // Unmarshal the expected secret key. We compare the marshalled values, since unmarshalling
+    // might normalize the key (e.g., X25519 clamping)
+    let expected_sk = <Kem as KemTrait>::PrivateKey::unmarshal(sk_bytes).unwrap();
+
+    assert_eq!(
+        sk.marshal(),
+        expected_sk.marshal(),
+        "derived privkeys don't match"
+    );
+    assert_eq!(
+        pk.marshal().as_slice(),
+        pk_bytes,
+        "derived pubkeys don't match"
+    );
+}
+
 /// Constructs an `OpModeR` from the given components. The variant constructed is determined solely
 /// by `mode_id`. This will panic if there is insufficient data to construct the variants specified
 /// by `mode_id`.
-fn make_op_mode_r<Kex: KeyExchange, Kdf: KdfTrait>(
+fn make_op_mode_r<Kem: KemTrait, Kdf: KdfTrait>(
     mode_id: u8,
     pk_sender_bytes: Option<Vec<u8>>,
     psk: Option<Vec<u8>>,
     psk_id: Option<Vec<u8>>,
-) -> OpModeR<Kex, Kdf> {
+) -> OpModeR<Kem, Kdf> {
     // Unmarshal the optional pubkey
-    let pk =
-        pk_sender_bytes.map(|bytes| <Kex as KeyExchange>::PublicKey::unmarshal(&bytes).unwrap());
+    let pk = pk_sender_bytes.map(|bytes| <Kem as KemTrait>::PublicKey::unmarshal(&bytes).unwrap());
     // Unmarshal the optinoal bundle
     let bundle = psk.map(|bytes| PskBundle::<Kdf> {
         psk: Psk::<Kdf>::from_bytes(bytes),
@@ -156,33 +189,111 @@ fn make_op_mode_r<Kex: KeyExchange, Kdf: KdfTrait>(
     }
 }
 
+// The export-only AEAD has no `open()`, so checking a test vector's encryptions is dispatched
+// through this trait
+trait KatAead: Aead + Sized {
+    fn assert_encryptions<Kdf: KdfTrait>(
+        aead_ctx: &mut AeadCtxR<Self, Kdf>,
+        encryptions: Vec<EncryptionTestVector>,
+    );
+}
+
+impl<A: AeadCipher> KatAead for A {
+    // Go through all the plaintext-ciphertext pairs of this test vector and assert the ciphertext
+    // decrypts to the corresponding plaintext
+    fn assert_encryptions<Kdf: KdfTrait>(
+        aead_ctx: &mut AeadCtxR<A, Kdf>,
+        encryptions: Vec<EncryptionTestVector>,
+    ) {
+        for enc_packet in encryptions {
+            let aad = enc_packet.aad;
+
+            // The test vector's ciphertext is of the form ciphertext || tag. Break it up into two
+            // pieces so we can call open() on it.
+            let (mut ciphertext, tag) = {
+                let mut ciphertext_and_tag = enc_packet.ciphertext;
+                let total_len = ciphertext_and_tag.len();
+
+                let tag_size = AeadTag::<A>::size();
+                let (ciphertext_bytes, tag_bytes) =
+                    ciphertext_and_tag.split_at_mut(total_len - tag_size);
+
+                (
+                    ciphertext_bytes.to_vec(),
+                    AeadTag::unmarshal(tag_bytes).unwrap(),
+                )
+            };
+
+            // Open the ciphertext in place and assert that this succeeds
+            aead_ctx
+                .open(&mut ciphertext, &aad, &tag)
+                .expect("open failed");
+            // Rename for clarity
+            let plaintext = ciphertext;
+
+            // Assert the plaintext equals the expected plaintext
+            assert_eq!(
+                plaintext,
+                enc_packet.plaintext.as_slice(),
+                "plaintexts don't match"
+            );
+        }
+    }
+}
+
+impl KatAead for ExportOnly {
+    // Export-only test vectors have no encryptions
+    fn assert_encryptions<Kdf: KdfTrait>(
+        _aead_ctx: &mut AeadCtxR<ExportOnly, Kdf>,
+        encryptions: Vec<EncryptionTestVector>,
+    ) {
+        assert!(
+            encryptions.is_empty(),
+            "export-only test vector has encryptions"
+        );
+    }
+}
+
 // This does all the legwork
-fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
+fn test_case<A: KatAead, Kdf: KdfTrait, Kem: DeterministicEncap>(tv: MainTestVector) {
     // First, unmarshall all the relevant keys so we can reconstruct the encapped key
-    let (sk_recip, pk_recip) = get_and_assert_keypair::<Kem::Kex>(&tv.sk_recip, &tv.pk_recip);
-    let (sk_eph, _) = get_and_assert_keypair::<Kem::Kex>(&tv.sk_eph, &tv.pk_eph);
+    let (sk_recip, pk_recip) = get_and_assert_keypair::<Kem>(&tv.sk_recip, &tv.pk_recip);
+
+    // Make sure the keys we just unmarshalled are derived from the given keying material
+    assert_derived_keypair::<Kem>(&tv.ikm_recip, &tv.sk_recip, &tv.pk_recip);
+    if let (Some(sk_eph), Some(pk_eph)) = (&tv.sk_eph, &tv.pk_eph) {
+        // If the ephemeral keypair is set, it should be derived from the ephemeral keying material
+        get_and_assert_keypair::<Kem>(sk_eph, pk_eph);
+        assert_derived_keypair::<Kem>(&tv.ikm_eph, sk_eph, pk_eph);
+    }
+    if let Some(ikm_sender) = &tv.ikm_sender {
+        // If the sender IKM is set, then so are the sender's keys
+        let sk_sender = tv.sk_sender.as_ref().unwrap();
+        let pk_sender = tv.pk_sender.as_ref().unwrap();
+        assert_derived_keypair::<Kem>(ikm_sender, sk_sender, pk_sender);
+    }
 
     let sk_sender = tv
         .sk_sender
-        .map(|bytes| <Kem::Kex as KeyExchange>::PrivateKey::unmarshal(&bytes).unwrap());
+        .map(|bytes| <Kem as KemTrait>::PrivateKey::unmarshal(&bytes).unwrap());
     let pk_sender = tv
         .pk_sender
         .clone()
-        .map(|bytes| <Kem::Kex as KeyExchange>::PublicKey::unmarshal(&bytes).unwrap());
+        .map(|bytes| <Kem as KemTrait>::PublicKey::unmarshal(&bytes).unwrap());
     // If sk_sender is Some, then so is pk_sender
     let sender_keypair = sk_sender.map(|sk| (sk, pk_sender.unwrap()));
 
     // Now derive the encapped key with the deterministic encap function, using all the inputs
     // above
-    let (zz, encapped_key) =
-        encap_with_eph::<Kem>(&pk_recip, sender_keypair.as_ref(), sk_eph.clone())
+    let (shared_secret, encapped_key) =
+        Kem::encap_deterministic(&pk_recip, sender_keypair.as_ref(), &tv.ikm_eph)
             .expect("encap failed");
 
     // Assert that the derived shared secret key is identical to the one provided
     assert_eq!(
-        zz.as_slice(),
+        shared_secret.as_slice(),
         tv.shared_secret.as_slice(),
-        "zz doesn't match"
+        "shared secrets don't match"
     );
 
     // Assert that the derived encapped key is identical to the one provided
@@ -197,41 +308,8 @@ fn test_case<A: Aead, Kdf: KdfTrait, Kem: KemTrait>(tv: MainTestVector) {
     let mut aead_ctx = setup_receiver::<A, Kdf, Kem>(&mode, &sk_recip, &encapped_key, &tv.info)
         .expect("setup_receiver failed");
 
-    // Go through all the plaintext-ciphertext pairs of this test vector and assert the
-    // ciphertext decrypts to the corresponding plaintext
-    for enc_packet in tv.encryptions {
-        let aad = enc_packet.aad;
-
-        // The test vector's ciphertext is of the form ciphertext || tag. Break it up into two
-        // pieces so we can call open() on it.
-        let (mut ciphertext, tag) = {
-            let mut ciphertext_and_tag = enc_packet.ciphertext;
-            let total_len = ciphertext_and_tag.len();
-
-            let tag_size = AeadTag::<A>::size();
-            let (ciphertext_bytes, tag_bytes) =
-                ciphertext_and_tag.split_at_mut(total_len - tag_size);
-
-            (
-                ciphertext_bytes.to_vec(),
-                AeadTag::unmarshal(tag_bytes).unwrap(),
-            )
-        };
-
-        // Open the ciphertext in place and assert that this succeeds
-        aead_ctx
-            .open(&mut ciphertext, &aad, &tag)
-            .expect("open failed");
-        // Rename for clarity
-        let plaintext = ciphertext;
-
-        // Assert the plaintext equals the expected plaintext
-        assert_eq!(
-            plaintext,
-            enc_packet.plaintext.as_slice(),
-            "plaintexts don't match"
-        );
-    }
+    // Check the encryptions, if this AEAD can do any
+    A::assert_encryptions(&mut aead_ctx, tv.encryptions);
 
     // Now check that AeadCtx::export returns the expected values
     for export in tv.exports {
@@ -291,21 +369,53 @@ macro_rules! dispatch_testcase {
 
 #[test]
 fn kat_test() {
-    let file = File::open("test-vectors-f0be13a.json").unwrap();
-    let tvs: Vec<MainTestVector> = serde_json::from_reader(file).unwrap();
+    // The RFC 9180 test vectors, followed by the draft-ietf-hpke-pq test vectors
+    let mut tvs: Vec<MainTestVector> = Vec::new();
+    for filename in &["test-vectors-5f503c5.json", "test-vectors-pq-6433c8f.json"] {
+        let file = File::open(filename).unwrap();
+        let file_tvs: Vec<MainTestVector> = serde_json::from_reader(file).unwrap();
+        tvs.extend(file_tvs);
+    }
 
     for tv in tvs.into_iter() {
-        // Ignore everything that doesn't use X25519 or P256, since that's all we support right now
-        if tv.kem_id != DhP256HkdfSha256::KEM_ID && tv.kem_id != X25519HkdfSha256::KEM_ID {
+        // Ignore everything that doesn't use X25519, X448, P256, P384, P521, ML-KEM-768,
+        // ML-KEM-1024, or X-Wing, since that's all we support right now
+        let supported_kem_ids = [
+            X25519HkdfSha256::KEM_ID,
+            X448HkdfSha512::KEM_ID,
+            DhP256HkdfSha256::KEM_ID,
+            DhP384HkdfSha384::KEM_ID,
+            DhP521HkdfSha512::KEM_ID,
+            MlKem768::KEM_ID,
+            MlKem1024::KEM_ID,
+            XWing::KEM_ID,
+        ];
+        if !supported_kem_ids.contains(&tv.kem_id) {
+            continue;
+        }
+
+        // Ignore the KDFs other than HKDF (e.g., the SHAKE-based ones in the PQ test vectors),
+        // since we don't support those right now
+        let supported_kdf_ids = [HkdfSha256::KDF_ID, HkdfSha384::KDF_ID, HkdfSha512::KDF_ID];
+        if !supported_kdf_ids.contains(&tv.kdf_id) {
             continue;
         }
 
-        // This unrolls into 18 `if let` statements
+        // This unrolls into 96 `if let` statements
         dispatch_testcase!(
             tv,
-            (AesGcm128, AesGcm256, ChaCha20Poly1305),
+            (AesGcm128, AesGcm256, ChaCha20Poly1305, ExportOnly),
             (HkdfSha256, HkdfSha384, HkdfSha512),
-            (X25519HkdfSha256, DhP256HkdfSha256)
+            (
+                X25519HkdfSha256,
+                X448HkdfSha512,
+                DhP256HkdfSha256,
+                DhP384HkdfSha384,
+                DhP521HkdfSha512,
+                MlKem768,
+                MlKem1024,
+                XWing
+            )
         );
 
         // The above macro has a `continue` in every branch. We only get to this line if it failed
@@ -316,3 +426,74 @@ fn kat_test() {
         );
     }
 }
+
+// The MLS crypto-basics test vectors have one of these per ciphersuite. We only look at the
+// EncryptWithLabel part.
+#[derive(Clone, Deserialize, Debug)]
+struct MlsCryptoBasicsTestVector {
+    cipher_suite: u16,
+    encrypt_with_label: MlsEncryptWithLabelTestVector,
+}
+
+#[derive(Clone, Deserialize, Debug)]
+struct MlsEncryptWithLabelTestVector {
+    #[serde(rename = "priv", deserialize_with = "bytes_from_hex")]
+    sk_recip: Vec<u8>,
+    #[serde(rename = "pub", deserialize_with = "bytes_from_hex")]
+    pk_recip: Vec<u8>,
+    label: String,
+    #[serde(deserialize_with = "bytes_from_hex")]
+    context: Vec<u8>,
+    #[serde(deserialize_with = "bytes_from_hex")]
+    plaintext: Vec<u8>,
+    #[serde(deserialize_with = "bytes_from_hex")]
+    kem_output: Vec<u8>,
+    #[serde(deserialize_with = "bytes_from_hex")]
+    ciphertext: Vec<u8>,
+}
+
+/// Checks that `decrypt_with_label` opens the test vector's ciphertext. The vectors don't give the
+/// EncryptContext bytes, but they're the HPKE info string, so any difference in them fails the
+/// open.
+fn mls_test_case<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait>(tv: MlsEncryptWithLabelTestVector) {
+    let (sk_recip, _) = get_and_assert_keypair::<Kem>(&tv.sk_recip, &tv.pk_recip);
+    let ciphertext = HpkeCiphertext {
+        kem_output: tv.kem_output,
+        ciphertext: tv.ciphertext,
+    };
+
+    let plaintext =
+        decrypt_with_label::<A, Kdf, Kem>(&sk_recip, tv.label.as_bytes(), &tv.context, &ciphertext)
+            .expect("decrypt_with_label failed");
+    assert_eq!(plaintext, tv.plaintext, "plaintexts don't match");
+}
+
+// The vectors are test-vectors/crypto-basics.json from github.com/mlswg/mls-implementations. They
+// aren't checked in yet, so this only runs with --ignored once they are.
+#[test]
+#[ignore = "needs test-vectors-mls-crypto-basics.json from mlswg/mls-implementations"]
+fn mls_kat_test() {
+    let file = File::open("test-vectors-mls-crypto-basics.json").unwrap();
+    let tvs: Vec<MlsCryptoBasicsTestVector> = serde_json::from_reader(file).unwrap();
+
+    let mut tested = [false; 7];
+    for tv in tvs.into_iter() {
+        // RFC 9420 §17.1: the HPKE algorithms of each MLS ciphersuite
+        let ewl = tv.encrypt_with_label;
+        match tv.cipher_suite {
+            0x0001 => mls_test_case::<AesGcm128, HkdfSha256, X25519HkdfSha256>(ewl),
+            0x0002 => mls_test_case::<AesGcm128, HkdfSha256, DhP256HkdfSha256>(ewl),
+            0x0003 => mls_test_case::<ChaCha20Poly1305, HkdfSha256, X25519HkdfSha256>(ewl),
+            0x0004 => mls_test_case::<AesGcm256, HkdfSha512, X448HkdfSha512>(ewl),
+            0x0005 => mls_test_case::<AesGcm256, HkdfSha512, DhP521HkdfSha512>(ewl),
+            0x0006 => mls_test_case::<ChaCha20Poly1305, HkdfSha512, X448HkdfSha512>(ewl),
+            0x0007 => mls_test_case::<AesGcm256, HkdfSha384, DhP384HkdfSha384>(ewl),
+            // Ignore the ciphersuites outside RFC 9420
+            _ => continue,
+        }
+        tested[tv.cipher_suite as usize - 1] = true;
+    }
+
+    // Make sure we actually tested every ciphersuite
+    assert!(tested.iter().all(|&t| t), "missing MLS ciphersuites");
+}
@@ -1,11 +1,12 @@
-use crate::{prelude::*, util::static_zeros};
+use crate::{prelude::*, util::KemSuiteId};
 
 use byteorder::{BigEndian, ByteOrder};
 use digest::{generic_array::GenericArray, BlockInput, Digest, FixedOutput, Input, Reset};
 use sha2::{Sha256, Sha384, Sha512};
 
-// This has a space because LabeledExtract calls for a space between the RFC string and the label
-const RFC_STR: &[u8] = b"RFCXXXX ";
+// RFC 9180 §4: The string literal "HPKE-v1" used in LabeledExtract and LabeledExpand ensures
+// that any secrets derived in HPKE are bound to the scheme's name and version
+const HPKE_VERSION_ID: &[u8] = b"HPKE-v1";
 
 // Pretty much all the KDF functionality is covered by the hkdf crate
 
@@ -27,7 +28,7 @@ pub struct HkdfSha256 {}
 impl KdfTrait for HkdfSha256 {
     type HashImpl = Sha256;
 
-    // draft02 §8.2: HKDF-SHA256
+    // RFC 9180 §7.2: HKDF-SHA256
     const KDF_ID: u16 = 0x0001;
 }
 
@@ -37,7 +38,7 @@ pub struct HkdfSha384 {}
 impl KdfTrait for HkdfSha384 {
     type HashImpl = Sha384;
 
-    // draft02 §8.2: HKDF-SHA384
+    // RFC 9180 §7.2: HKDF-SHA384
     const KDF_ID: u16 = 0x0002;
 }
 
@@ -47,34 +48,38 @@ pub struct HkdfSha512 {}
 impl KdfTrait for HkdfSha512 {
     type HashImpl = Sha512;
 
-    // draft02 §8.2: HKDF-SHA512
+    // RFC 9180 §7.2: HKDF-SHA512
     const KDF_ID: u16 = 0x0003;
 }
 
-// def ExtractAndExpand(dh, kemContext):
-//   prk = LabeledExtract(zero(Nh), "dh", dh)
-//   return LabeledExpand(prk, "prk", kemContext, Nzz)
-/// Uses the given IKM to extract a secret, and then uses that secret, plus the given info string,
-/// to expand to the output buffer
+// RFC 9180 §4.1
+// def ExtractAndExpand(dh, kem_context):
+//   eae_prk = LabeledExtract("", "eae_prk", dh)
+//   shared_secret = LabeledExpand(eae_prk, "shared_secret",
+//                                 kem_context, Nsecret)
+//   return shared_secret
+/// Uses the given IKM to extract a secret, and then uses that secret, plus the given suite ID and
+/// info string, to expand to the output buffer
 pub(crate) fn extract_and_expand<Kdf: KdfTrait>(
     ikm: &[u8],
+    suite_id: &KemSuiteId,
     info: &[u8],
     out: &mut [u8],
 ) -> Result<(), hkdf::InvalidLength> {
-    // The salt is a zero array of length Nh
-    let salt = static_zeros::<Kdf>();
-    // Extract using given IKM
-    let (_, hkdf_ctx) = labeled_extract::<Kdf>(&salt, b"dh", ikm);
+    // Extract using given IKM and an empty salt
+    let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"eae_prk", ikm);
     // Expand using given info string
-    hkdf_ctx.labeled_expand(b"prk", info, out)
+    hkdf_ctx.labeled_expand(suite_id, b"shared_secret", info, out)
 }
 
-// def LabeledExtract(salt, label, IKM):
-//   labeledIKM = concat("RFCXXXX ", label, IKM)
-//   return Extract(salt, labeledIKM)
-/// Returns the HKDF context derived from `(salt=salt, ikm= "RFCXXXX"||label||ikm)`
+// RFC 9180 §4
+// def LabeledExtract(salt, label, ikm):
+//   labeled_ikm = concat("HPKE-v1", suite_id, label, ikm)
+//   return Extract(salt, labeled_ikm)
+/// Returns the HKDF context derived from `(salt=salt, ikm="HPKE-v1"||suite_id||label||ikm)`
 pub(crate) fn labeled_extract<Kdf: KdfTrait>(
     salt: &[u8],
+    suite_id: &[u8],
     label: &[u8],
     ikm: &[u8],
 ) -> (
@@ -82,15 +87,64 @@ pub(crate) fn labeled_extract<Kdf: KdfTrait>(
     hkdf::Hkdf<Kdf::HashImpl>,
 ) {
     // Concat the inputs to create a new IKM
-    let labeled_ikm: Vec<u8> = [RFC_STR, label, ikm].concat();
+    let labeled_ikm: Vec<u8> = [HPKE_VERSION_ID, suite_id, label, ikm].concat();
     // Extract and the HKDF context
-    hkdf::Hkdf::<Kdf::HashImpl>::extract(Some(&salt), &labeled_ikm)
+    hkdf::Hkdf::<Kdf::HashImpl>::extract(Some(salt), &labeled_ikm)
+}
+
+// draft-ietf-hpke-hpke-02 §4
+// def LabeledDerive(ikm, label, context, L):
+//   labeled_ikm = concat(
+//     ikm,
+//     "HPKE-v1",
+//     suite_id,
+//     lengthPrefixed(label),
+//     I2OSP(L, 2),
+//     context,
+//   )
+//   return Derive(labeled_ikm, L)
+/// Fills `out` with the output of SHAKE256 on `ikm||"HPKE-v1"||suite_id||len(label)||label||L||
+/// context`. This is the one-stage KDF that the ML-KEM KEMs use in `DeriveKeyPair`.
+#[cfg(feature = "ml-kem")]
+pub(crate) fn labeled_derive_shake256(
+    suite_id: &[u8],
+    ikm: &[u8],
+    label: &[u8],
+    context: &[u8],
+    out: &mut [u8],
+) {
+    use sha3::digest::{ExtendableOutput, Update, XofReader};
+
+    // We need to write the lengths as u16s, so that's the de-facto upper bound on length
+    assert!(label.len() <= u16::MAX as usize);
+    assert!(out.len() <= u16::MAX as usize);
+
+    // Encode the label length and the output length
+    let mut label_len_buf = [0u8; 2];
+    BigEndian::write_u16(&mut label_len_buf, label.len() as u16);
+    let mut out_len_buf = [0u8; 2];
+    BigEndian::write_u16(&mut out_len_buf, out.len() as u16);
+
+    let mut hasher = sha3::Shake256::default();
+    for part in &[
+        ikm,
+        HPKE_VERSION_ID,
+        suite_id,
+        &label_len_buf,
+        label,
+        &out_len_buf,
+        context,
+    ] {
+        hasher.update(part);
+    }
+    hasher.finalize_xof().read(out);
 }
 
 // This trait only exists so I can implement it for hkdf::Hkdf
 pub(crate) trait LabeledExpand {
     fn labeled_expand(
         &self,
+        suite_id: &[u8],
         label: &[u8],
         info: &[u8],
         out: &mut [u8],
@@ -100,12 +154,14 @@ pub(crate) trait LabeledExpand {
 impl<D: Input + BlockInput + FixedOutput + Reset + Default + Clone> LabeledExpand
     for hkdf::Hkdf<D>
 {
-    // def LabeledExpand(PRK, label, info, L):
-    //   labeledInfo = concat(encode_big_endian(L, 2),
-    //                         "RFCXXXX ", label, info)
-    //   return Expand(PRK, labeledInfo, L)
+    // RFC 9180 §4
+    // def LabeledExpand(prk, label, info, L):
+    //   labeled_info = concat(I2OSP(L, 2), "HPKE-v1", suite_id,
+    //                         label, info)
+    //   return Expand(prk, labeled_info, L)
     fn labeled_expand(
         &self,
+        suite_id: &[u8],
         label: &[u8],
         info: &[u8],
         out: &mut [u8],
@@ -117,7 +173,7 @@ impl<D: Input + BlockInput + FixedOutput + Reset + Default + Clone> LabeledExpan
         let mut len_buf = [0u8; 2];
         BigEndian::write_u16(&mut len_buf, out.len() as u16);
 
-        let labeled_info: Vec<u8> = [&len_buf, RFC_STR, label, info].concat();
+        let labeled_info: Vec<u8> = [&len_buf, HPKE_VERSION_ID, suite_id, label, info].concat();
         self.expand(&labeled_info, out)
     }
 }
@@ -1,268 +1,219 @@
 use crate::{
-    kdf::{extract_and_expand, HkdfSha256, Kdf as KdfTrait},
-    kex::{KeyExchange, Marshallable, Unmarshallable},
+    kex::{Marshallable, Unmarshallable},
     HpkeError,
 };
-use digest::{generic_array::GenericArray, FixedOutput};
-use rand::{CryptoRng, RngCore};
 
-/// Defines a combination of key exchange mechanism and a KDF, which together form a KEM
-pub trait Kem {
-    type Kex: KeyExchange;
-    type Kdf: KdfTrait;
+use digest::generic_array::{ArrayLength, GenericArray};
+use rand::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+mod dhkem;
+pub use dhkem::{DhEncappedKey, DhKem, DhKemId};
+
+#[cfg(feature = "ml-kem")]
+mod mlkem;
+#[cfg(feature = "ml-kem")]
+pub use mlkem::{MlKem1024, MlKem768};
+
+#[cfg(feature = "xwing")]
+mod xwing;
+#[cfg(feature = "xwing")]
+pub use xwing::XWing;
+
+/// Represents a key encapsulation mechanism. This is what HPKE uses to establish a shared secret
+/// between a sender and a recipient. See RFC 9180 §4 for the interface this captures.
+pub trait Kem: Sized {
+    /// The recipient's (and, in auth modes, the sender's) public key
+    type PublicKey: Clone + Marshallable + Unmarshallable;
+    /// The recipient's (and, in auth modes, the sender's) private key
+    type PrivateKey: Clone + Marshallable + Unmarshallable;
+    /// The encapsulated key that the sender transmits to the recipient
+    type EncappedKey: Clone + Marshallable + Unmarshallable;
+    /// The length `Nsecret` of the shared secret produced by encapsulation
+    type NSecret: ArrayLength<u8>;
 
     const KEM_ID: u16;
+
+    /// Generates a random keypair using the given RNG
+    fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (Self::PrivateKey, Self::PublicKey);
+
+    /// Deterministically derives a keypair from the given input keying material. This is the
+    /// `DeriveKeyPair` function defined in RFC 9180 §7.1.3. The keying material SHOULD have as
+    /// many bits of entropy as the bit length of a secret key.
+    fn derive_keypair(ikm: &[u8]) -> (Self::PrivateKey, Self::PublicKey);
+
+    /// Computes the public key corresponding to the given private key
+    fn sk_to_pk(sk: &Self::PrivateKey) -> Self::PublicKey;
+
+    /// Derives a shared secret and an encapsulated key that the owner of `pk_recip` can use to
+    /// derive the same shared secret. This is the `Encap` function defined in RFC 9180 §4.1.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret and encapped key on success. If an error happened during key
+    /// exchange, returns `Err(HpkeError::InvalidKeyExchange)`.
+    fn encap<R: CryptoRng + RngCore>(
+        pk_recip: &Self::PublicKey,
+        csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError>;
+
+    /// Like `encap`, but also ties the sender's identity keypair to the shared secret. This is the
+    /// `AuthEncap` function defined in RFC 9180 §4.1.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret and encapped key on success. If an error happened during key
+    /// exchange, returns `Err(HpkeError::InvalidKeyExchange)`.
+    fn auth_encap<R: CryptoRng + RngCore>(
+        pk_recip: &Self::PublicKey,
+        sender_id_keypair: &(Self::PrivateKey, Self::PublicKey),
+        csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError>;
+
+    /// Derives the shared secret that was encapsulated to `sk_recip`'s public key. This is the
+    /// `Decap` function defined in RFC 9180 §4.1.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret on success. If an error happened during key exchange, returns
+    /// `Err(HpkeError::InvalidKeyExchange)`.
+    fn decap(
+        sk_recip: &Self::PrivateKey,
+        encapped_key: &Self::EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError>;
+
+    /// Like `decap`, but also checks that the shared secret was encapsulated by the holder of
+    /// `pk_sender_id`. This is the `AuthDecap` function defined in RFC 9180 §4.1. Note that a wrong
+    /// sender pubkey does not cause an error. It just results in a different shared secret.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret on success. If an error happened during key exchange, returns
+    /// `Err(HpkeError::InvalidKeyExchange)`.
+    fn auth_decap(
+        sk_recip: &Self::PrivateKey,
+        pk_sender_id: &Self::PublicKey,
+        encapped_key: &Self::EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError>;
 }
 
 // Kem is also used as a type parameter everywhere. To avoid confusion, alias it
 use Kem as KemTrait;
 
-#[cfg(feature = "x25519-dalek")]
-/// Represents DHKEM(Curve25519, HKDF-SHA256)
-pub struct X25519HkdfSha256 {}
+/// Implemented by KEMs that can encapsulate with caller-chosen ephemeral randomness instead of an
+/// RNG. This is only used to reproduce the `enc` values in the known-answer tests.
+// The known-answer tests only run when every KEM is enabled, so this is unused otherwise
+#[cfg(test)]
+#[allow(dead_code)]
+pub(crate) trait DeterministicEncap: KemTrait {
+    /// Does an `encap`, or an `auth_encap` if `sender_id_keypair` is set, deriving all the
+    /// ephemeral values from `ikm_eph`
+    fn encap_deterministic(
+        pk_recip: &Self::PublicKey,
+        sender_id_keypair: Option<&(Self::PrivateKey, Self::PublicKey)>,
+        ikm_eph: &[u8],
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError>;
+}
 
 #[cfg(feature = "x25519-dalek")]
-impl Kem for X25519HkdfSha256 {
-    type Kex = crate::kex::X25519;
-    type Kdf = HkdfSha256;
+/// Represents DHKEM(X25519, HKDF-SHA256)
+pub type X25519HkdfSha256 = DhKem<crate::kex::X25519, crate::kdf::HkdfSha256>;
 
-    // Section 7.1: DHKEM(Curve25519, HKDF-SHA256)
+#[cfg(feature = "x25519-dalek")]
+impl DhKemId for X25519HkdfSha256 {
+    // RFC 9180 §7.1: DHKEM(X25519, HKDF-SHA256)
     const KEM_ID: u16 = 0x0020;
 }
 
+#[cfg(feature = "x448")]
+/// Represents DHKEM(X448, HKDF-SHA512)
+pub type X448HkdfSha512 = DhKem<crate::kex::X448, crate::kdf::HkdfSha512>;
+
+#[cfg(feature = "x448")]
+impl DhKemId for X448HkdfSha512 {
+    // RFC 9180 §7.1: DHKEM(X448, HKDF-SHA512)
+    const KEM_ID: u16 = 0x0021;
+}
+
 #[cfg(feature = "p256")]
 /// Represents DHKEM(P256, HKDF-SHA256)
-pub struct DhP256HkdfSha256 {}
+pub type DhP256HkdfSha256 = DhKem<crate::kex::DhP256, crate::kdf::HkdfSha256>;
 
 #[cfg(feature = "p256")]
-impl Kem for DhP256HkdfSha256 {
-    type Kex = crate::kex::DhP256;
-    type Kdf = HkdfSha256;
-
-    // Section 7.1: DHKEM(P256, HKDF-SHA256)
+impl DhKemId for DhP256HkdfSha256 {
+    // RFC 9180 §7.1: DHKEM(P-256, HKDF-SHA256)
     const KEM_ID: u16 = 0x0010;
 }
 
-/// Convenience types representing public/private keys corresponding to a KEM's underlying DH alg
-type KemPubkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PublicKey;
-type KemPrivkey<Kem> = <<Kem as KemTrait>::Kex as KeyExchange>::PrivateKey;
+#[cfg(feature = "p384")]
+/// Represents DHKEM(P384, HKDF-SHA384)
+pub type DhP384HkdfSha384 = DhKem<crate::kex::DhP384, crate::kdf::HkdfSha384>;
 
-/// This holds the content of an encapsulated secret. It is output by the `encap` and `encap_auth`
-/// functions.
-// This just wraps a pubkey, because that's all an encapsulated key is in a DH-KEM
-pub struct EncappedKey<Kex: KeyExchange>(Kex::PublicKey);
+#[cfg(feature = "p384")]
+impl DhKemId for DhP384HkdfSha384 {
+    // RFC 9180 §7.1: DHKEM(P-384, HKDF-SHA384)
+    const KEM_ID: u16 = 0x0011;
+}
 
-// EncappedKeys need to be serializable, since they're gonna be sent over the wire. Underlyingly,
-// they're just DH pubkeys, so we just serialize them the same way
-impl<Kex: KeyExchange> Marshallable for EncappedKey<Kex> {
-    type OutputSize = <Kex::PublicKey as Marshallable>::OutputSize;
+#[cfg(feature = "p521")]
+/// Represents DHKEM(P521, HKDF-SHA512)
+pub type DhP521HkdfSha512 = DhKem<crate::kex::DhP521, crate::kdf::HkdfSha512>;
 
-    // Pass to underlying marshal() impl
-    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
-        self.0.marshal()
-    }
+#[cfg(feature = "p521")]
+impl DhKemId for DhP521HkdfSha512 {
+    // RFC 9180 §7.1: DHKEM(P-521, HKDF-SHA512)
+    const KEM_ID: u16 = 0x0012;
 }
 
-impl<Kex: KeyExchange> Unmarshallable for EncappedKey<Kex> {
-    // Pass to underlying unmarshal() impl
-    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
-        let pubkey = <Kex::PublicKey as Unmarshallable>::unmarshal(encoded)?;
-        Ok(EncappedKey(pubkey))
+/// A secret of length `Nsecret` that is shared between the sender and recipient of an
+/// encapsulated key. This is output by `Kem::encap` and `Kem::decap`, and their `auth_`
+/// counterparts. The bytes are zeroed when this is dropped.
+pub struct SharedSecret<Kem: KemTrait>(pub(crate) GenericArray<u8, Kem::NSecret>);
+
+impl<Kem: KemTrait> Default for SharedSecret<Kem> {
+    fn default() -> SharedSecret<Kem> {
+        SharedSecret(GenericArray::default())
     }
 }
 
-/// A convenience type representing the fixed-size byte array of the same length as a serialized
-/// `KexResult`
-pub(crate) type SharedSecret<Kem> =
-    GenericArray<u8, <<<Kem as KemTrait>::Kdf as KdfTrait>::HashImpl as FixedOutput>::OutputSize>;
-
-//  def Encap(pkR):
-//    skE, pkE = GenerateKeyPair()
-//    dh = DH(skE, pkR)
-//    enc = Marshal(pkE)
-//
-//    pkRm = Marshal(pkR)
-//    kemContext = concat(enc, pkRm)
-//
-//    zz = ExtractAndExpand(dh, kemContext)
-//    return zz, enc
-//
-// def AuthEncap(pkR, skS):
-//   skE, pkE = GenerateKeyPair()
-//   dh = concat(DH(skE, pkR), DH(skS, pkR))
-//   enc = Marshal(pkE)
-//
-//   pkRm = Marshal(pkR)
-//   pkSm = Marshal(pk(skS))
-//   kemContext = concat(enc, pkRm, pkSm)
-//
-//   zz = ExtractAndExpand(dh, kemContext)
-//   return zz, enc
-/// Derives a shared secret that the owner of the reciepint's pubkey can use to derive the same
-/// shared secret. If `sk_sender_id` is given, the sender's identity will be tied to the shared
-/// secret.
-///
-/// Return Value
-/// ============
-/// Returns a shared secret and encapped key on success. If an error happened during key exchange,
-/// returns `Err(HpkeError::InvalidKeyExchange)`.
-pub(crate) fn encap_with_eph<Kem: KemTrait>(
-    pk_recip: &KemPubkey<Kem>,
-    sender_id_keypair: Option<&(KemPrivkey<Kem>, KemPubkey<Kem>)>,
-    sk_eph: KemPrivkey<Kem>,
-) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError> {
-    // Compute the shared secret from the ephemeral inputs
-    let kex_res_eph = Kem::Kex::kex(&sk_eph, pk_recip)?;
-
-    // The encapped key is the ephemeral pubkey
-    let encapped_key = {
-        let pk_eph = Kem::Kex::sk_to_pk(&sk_eph);
-        EncappedKey(pk_eph)
-    };
-
-    // The shared secret is either gonna be kex_res_eph, or that along with another shared secret
-    // that's tied to the sender's identity.
-    let shared_secret = if let Some((sk_sender_id, pk_sender_id)) = sender_id_keypair {
-        let kem_context = [
-            encapped_key.marshal(),
-            pk_recip.marshal(),
-            pk_sender_id.marshal(),
-        ]
-        .concat();
-        // We want to do an authed encap. Do KEX between the sender identity secret key and the
-        // recipient's pubkey
-        let kex_res_identity = Kem::Kex::kex(sk_sender_id, pk_recip)?;
-        // kex_res_eph || kex_res_identity
-        let concatted_secrets = [kex_res_eph.marshal(), kex_res_identity.marshal()].concat();
-
-        // The "authed shared secret" is derived from the KEX of the ephemeral input with the
-        // recipient pubkey, and the KEX of the identity input with the recipient pubkey. The
-        // HKDF-Expand call only errors if the output values are 255x the digest size of the hash
-        // function. Since these values are fixed at compile time, we don't worry about it.
-        let mut buf = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem::Kdf>(&concatted_secrets, &kem_context, &mut buf)
-            .expect("shared secret is way too big");
-        buf
-    } else {
-        let kem_context = [encapped_key.marshal(), pk_recip.marshal()].concat();
-        // The "unauthed shared secret" is derived from just the KEX of the ephemeral input with
-        // the recipient pubkey. The HKDF-Expand call only errors if the output values are 255x the
-        // digest size of the hash function. Since these values are fixed at compile time, we don't
-        // worry about it.
-        let mut buf = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem::Kdf>(&kex_res_eph.marshal(), &kem_context, &mut buf)
-            .expect("shared secret is way too big");
-        buf
-    };
-
-    Ok((shared_secret, encapped_key))
+impl<Kem: KemTrait> SharedSecret<Kem> {
+    /// Returns the bytes of this shared secret
+    pub fn as_slice(&self) -> &[u8] {
+        self.0.as_slice()
+    }
 }
 
-/// Derives a shared secret and an ephemeral pubkey that the owner of the reciepint's pubkey can
-/// use to derive the same shared secret. If `sk_sender_id` is given, the sender's identity will be
-/// tied to the shared secret.
-/// All this does is generate an ephemeral keypair and pass to `encap_with_eph`.
-///
-/// Return Value
-/// ============
-/// Returns a shared secret and encapped key on success. If an error happened during key exchange,
-/// returns `Err(HpkeError::InvalidKeyExchange)`.
-pub(crate) fn encap<Kem: KemTrait, R>(
-    pk_recip: &KemPubkey<Kem>,
-    sender_id_keypair: Option<&(KemPrivkey<Kem>, KemPubkey<Kem>)>,
-    csprng: &mut R,
-) -> Result<(SharedSecret<Kem>, EncappedKey<Kem::Kex>), HpkeError>
-where
-    Kem: KemTrait,
-    R: CryptoRng + RngCore,
-{
-    // Generate a new ephemeral keypair
-    let (sk_eph, _) = Kem::Kex::gen_keypair(csprng);
-    // Now pass to encap_with_eph
-    encap_with_eph::<Kem>(pk_recip, sender_id_keypair, sk_eph)
+// Shared secrets are compared in constant time, since they're, well, secret
+impl<Kem: KemTrait> PartialEq for SharedSecret<Kem> {
+    fn eq(&self, other: &SharedSecret<Kem>) -> bool {
+        self.0.ct_eq(&other.0).into()
+    }
 }
 
-// def Decap(enc, skR):
-//   pkE = Unmarshal(enc)
-//   dh = DH(skR, pkE)
-//
-//   pkRm = Marshal(pk(skR))
-//   kemContext = concat(enc, pkRm)
-//
-//   zz = ExtractAndExpand(dh, kemContext)
-//   return zz
-//
-// def AuthDecap(enc, skR, pkS):
-//   pkE = Unmarshal(enc)
-//   dh = concat(DH(skR, pkE), DH(skR, pkS))
-//
-//   pkRm = Marshal(pk(skR))
-//   pkSm = Marshal(pkS)
-//   kemContext = concat(enc, pkRm, pkSm)
-//
-//   zz = ExtractAndExpand(dh, kemContext)
-//   return zz
-/// Derives a shared secret given the encapsulated key and the recipients secret key. If
-/// `pk_sender_id` is given, the sender's identity will be tied to the shared secret.
-///
-/// Return Value
-/// ============
-/// Returns a shared secret on success. If an error happened during key exchange, returns
-/// `Err(HpkeError::InvalidKeyExchange)`.
-pub(crate) fn decap<Kem: KemTrait>(
-    sk_recip: &KemPrivkey<Kem>,
-    pk_sender_id: Option<&KemPubkey<Kem>>,
-    encapped_key: &EncappedKey<Kem::Kex>,
-) -> Result<SharedSecret<Kem>, HpkeError> {
-    // Compute the shared secret from the ephemeral inputs
-    let kex_res_eph = Kem::Kex::kex(&sk_recip, &encapped_key.0)?;
-
-    // Compute the sender's pubkey from their privkey
-    let pk_recip = Kem::Kex::sk_to_pk(sk_recip);
-
-    // The shared secret is either gonna be kex_res_eph, or that along with another shared secret
-    // that's tied to the sender's identity.
-    if let Some(pk_sender_id) = pk_sender_id {
-        let kem_context = [
-            encapped_key.marshal(),
-            pk_recip.marshal(),
-            pk_sender_id.marshal(),
-        ]
-        .concat();
-        // We want to do an authed encap. Do KEX between the sender identity secret key and the
-        // recipient's pubkey
-        let kex_res_identity = Kem::Kex::kex(sk_recip, pk_sender_id)?;
-        // kex_res_eph || kex_res_identity
-        let concatted_secrets = [kex_res_eph.marshal(), kex_res_identity.marshal()].concat();
-
-        // The "authed shared secret" is derived from the KEX of the ephemeral input with the
-        // recipient pubkey, and the kex of the identity input with the recipient pubkey. The
-        // HKDF-Expand call only errors if the output values are 255x the digest size of the hash
-        // function. Since these values are fixed at compile time, we don't worry about it.
-        let mut shared_secret = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem::Kdf>(&concatted_secrets, &kem_context, &mut shared_secret)
-            .expect("shared secret is way too big");
-        Ok(shared_secret)
-    } else {
-        let kem_context = [encapped_key.marshal(), pk_recip.marshal()].concat();
-        // The "unauthed shared secret" is derived from just the KEX of the ephemeral input with the
-        // recipient pubkey. The HKDF-Expand call only errors if the output values are 255x the
-        // digest size of the hash function. Since these values are fixed at compile time, we don't
-        // worry about it.
-        let mut shared_secret = <SharedSecret<Kem> as Default>::default();
-        extract_and_expand::<Kem::Kdf>(&kex_res_eph.marshal(), &kem_context, &mut shared_secret)
-            .expect("shared secret is way too big");
-        Ok(shared_secret)
+impl<Kem: KemTrait> Drop for SharedSecret<Kem> {
+    fn drop(&mut self) {
+        self.0.as_mut_slice().zeroize();
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{decap, encap, EncappedKey, Marshallable, Unmarshallable};
-    use crate::{kem::Kem as KemTrait, kex::KeyExchange};
+    use crate::{
+        kem::Kem as KemTrait,
+        kex::{Marshallable, Unmarshallable},
+    };
 
     use rand::{rngs::StdRng, SeedableRng};
 
+    // This tests auth_encap and auth_decap, so it's only for DHKEMs
+    #[cfg(any(
+        feature = "x25519-dalek",
+        feature = "x448",
+        feature = "p256",
+        feature = "p384",
+        feature = "p521"
+    ))]
     macro_rules! test_encap_correctness {
         ($test_name:ident, $kem_ty:ty) => {
             /// Tests that encap and decap produce the same shared secret when composed
@@ -271,48 +222,56 @@ mod tests {
                 type Kem = $kem_ty;
 
                 let mut csprng = StdRng::from_entropy();
-                let (sk_recip, pk_recip) = <Kem as KemTrait>::Kex::gen_keypair(&mut csprng);
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
 
                 // Encapsulate a random shared secret
-                let (auth_shared_secret, encapped_key) =
-                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap();
+                let (shared_secret, encapped_key) = Kem::encap(&pk_recip, &mut csprng).unwrap();
 
                 // Decap it
-                let decapped_auth_shared_secret =
-                    decap::<Kem>(&sk_recip, None, &encapped_key).unwrap();
+                let decapped_shared_secret = Kem::decap(&sk_recip, &encapped_key).unwrap();
 
                 // Ensure that the encapsulated secret is what decap() derives
-                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
+                assert!(shared_secret == decapped_shared_secret);
 
                 //
                 // Now do it with the auth, i.e., using the sender's identity keys
                 //
 
                 // Make a sender identity keypair
-                let (sk_sender_id, pk_sender_id) = <Kem as KemTrait>::Kex::gen_keypair(&mut csprng);
+                let sender_id_keypair = Kem::gen_keypair(&mut csprng);
+                let pk_sender_id = sender_id_keypair.1.clone();
 
                 // Encapsulate a random shared secret
-                let (auth_shared_secret, encapped_key) = encap::<Kem, _>(
-                    &pk_recip,
-                    Some(&(sk_sender_id, pk_sender_id.clone())),
-                    &mut csprng,
-                )
-                .unwrap();
+                let (auth_shared_secret, encapped_key) =
+                    Kem::auth_encap(&pk_recip, &sender_id_keypair, &mut csprng).unwrap();
 
                 // Decap it
                 let decapped_auth_shared_secret =
-                    decap::<Kem>(&sk_recip, Some(&pk_sender_id), &encapped_key).unwrap();
+                    Kem::auth_decap(&sk_recip, &pk_sender_id, &encapped_key).unwrap();
 
                 // Ensure that the encapsulated secret is what decap() derives
-                assert_eq!(auth_shared_secret, decapped_auth_shared_secret);
+                assert!(auth_shared_secret == decapped_auth_shared_secret);
+
+                // Now decap it with the wrong sender identity. This should succeed, but give a
+                // different shared secret.
+                let (_, wrong_pk_sender_id) = Kem::gen_keypair(&mut csprng);
+                let wrong_decapped_shared_secret =
+                    Kem::auth_decap(&sk_recip, &wrong_pk_sender_id, &encapped_key).unwrap();
+                assert!(auth_shared_secret != wrong_decapped_shared_secret);
             }
         };
     }
 
     #[cfg(feature = "x25519-dalek")]
     test_encap_correctness!(test_encap_correctness_x25519, crate::kem::X25519HkdfSha256);
+    #[cfg(feature = "x448")]
+    test_encap_correctness!(test_encap_correctness_x448, crate::kem::X448HkdfSha512);
     #[cfg(feature = "p256")]
     test_encap_correctness!(test_encap_correctness_p256, crate::kem::DhP256HkdfSha256);
+    #[cfg(feature = "p384")]
+    test_encap_correctness!(test_encap_correctness_p384, crate::kem::DhP384HkdfSha384);
+    #[cfg(feature = "p521")]
+    test_encap_correctness!(test_encap_correctness_p521, crate::kem::DhP521HkdfSha512);
 
     /// Tests that an unmarshal-marshal round-trip on an encapped key ends up at the same value
     macro_rules! test_encapped_marshal {
@@ -324,17 +283,17 @@ mod tests {
                 // Encapsulate a random shared secret
                 let encapped_key = {
                     let mut csprng = StdRng::from_entropy();
-                    let (_, pk_recip) = <Kem as KemTrait>::Kex::gen_keypair(&mut csprng);
-                    encap::<Kem, _>(&pk_recip, None, &mut csprng).unwrap().1
+                    let (_, pk_recip) = Kem::gen_keypair(&mut csprng);
+                    Kem::encap(&pk_recip, &mut csprng).unwrap().1
                 };
                 // Marshal it
                 let encapped_key_bytes = encapped_key.marshal();
                 // Unmarshal it
                 let new_encapped_key =
-                    EncappedKey::<<Kem as KemTrait>::Kex>::unmarshal(&encapped_key_bytes).unwrap();
+                    <Kem as KemTrait>::EncappedKey::unmarshal(&encapped_key_bytes).unwrap();
 
                 assert!(
-                    new_encapped_key.0 == encapped_key.0,
+                    new_encapped_key.marshal() == encapped_key_bytes,
                     "encapped key doesn't marshal correctly"
                 );
             }
@@ -343,6 +302,18 @@ mod tests {
 
     #[cfg(feature = "x25519-dalek")]
     test_encapped_marshal!(test_encapped_marshal_x25519, crate::kem::X25519HkdfSha256);
+    #[cfg(feature = "x448")]
+    test_encapped_marshal!(test_encapped_marshal_x448, crate::kem::X448HkdfSha512);
     #[cfg(feature = "p256")]
     test_encapped_marshal!(test_encapped_marshal_p256, crate::kem::DhP256HkdfSha256);
+    #[cfg(feature = "p384")]
+    test_encapped_marshal!(test_encapped_marshal_p384, crate::kem::DhP384HkdfSha384);
+    #[cfg(feature = "p521")]
+    test_encapped_marshal!(test_encapped_marshal_p521, crate::kem::DhP521HkdfSha512);
+    #[cfg(feature = "ml-kem")]
+    test_encapped_marshal!(test_encapped_marshal_mlkem768, crate::kem::MlKem768);
+    #[cfg(feature = "ml-kem")]
+    test_encapped_marshal!(test_encapped_marshal_mlkem1024, crate::kem::MlKem1024);
+    #[cfg(feature = "xwing")]
+    test_encapped_marshal!(test_encapped_marshal_xwing, crate::kem::XWing);
 }
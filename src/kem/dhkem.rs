@@ -0,0 +1,338 @@
+use crate::{
+    kdf::{extract_and_expand, Kdf as KdfTrait},
+    kem::{Kem as KemTrait, SharedSecret},
+    kex::{KeyExchange, Marshallable, Unmarshallable},
+    util::kem_suite_id,
+    HpkeError,
+};
+
+use core::marker::PhantomData;
+
+use digest::{generic_array::GenericArray, FixedOutput};
+use rand::{CryptoRng, RngCore};
+
+/// Assigns a KEM ID to an instantiation of `DhKem`. The IDs of the DHKEMs defined in the spec are
+/// listed in RFC 9180 §7.1.
+pub trait DhKemId {
+    const KEM_ID: u16;
+}
+
+/// Represents DHKEM(Group, KDF), the KEM defined in RFC 9180 §4.1. It is built out of a key
+/// exchange mechanism and a KDF.
+pub struct DhKem<Kex: KeyExchange, Kdf: KdfTrait> {
+    marker: PhantomData<(Kex, Kdf)>,
+}
+
+/// This holds the content of an encapsulated secret in a DHKEM. It is output by the `encap` and
+/// `auth_encap` functions.
+// This just wraps a pubkey, because that's all an encapsulated key is in a DHKEM
+pub struct DhEncappedKey<Kex: KeyExchange>(Kex::PublicKey);
+
+// We can't use #[derive(Clone)] because the compiler thinks that Kex has to be Clone.
+impl<Kex: KeyExchange> Clone for DhEncappedKey<Kex> {
+    fn clone(&self) -> Self {
+        DhEncappedKey(self.0.clone())
+    }
+}
+
+// EncappedKeys need to be serializable, since they're gonna be sent over the wire. Underlyingly,
+// they're just DH pubkeys, so we just serialize them the same way
+impl<Kex: KeyExchange> Marshallable for DhEncappedKey<Kex> {
+    type OutputSize = <Kex::PublicKey as Marshallable>::OutputSize;
+
+    // Pass to underlying marshal() impl
+    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+        self.0.marshal()
+    }
+}
+
+impl<Kex: KeyExchange> Unmarshallable for DhEncappedKey<Kex> {
+    // Pass to underlying unmarshal() impl
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        let pubkey = <Kex::PublicKey as Unmarshallable>::unmarshal(encoded)?;
+        Ok(DhEncappedKey(pubkey))
+    }
+}
+
+// Serialized the same way as a pubkey
+#[cfg(feature = "serde")]
+impl<Kex: KeyExchange> serde::Serialize for DhEncappedKey<Kex> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        crate::serde_impls::serialize_marshallable(self, serializer)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de, Kex: KeyExchange> serde::Deserialize<'de> for DhEncappedKey<Kex> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        crate::serde_impls::deserialize_unmarshallable(deserializer)
+    }
+}
+
+impl<Kex, Kdf> KemTrait for DhKem<Kex, Kdf>
+where
+    Kex: KeyExchange,
+    Kdf: KdfTrait,
+    DhKem<Kex, Kdf>: DhKemId,
+{
+    type PublicKey = Kex::PublicKey;
+    type PrivateKey = Kex::PrivateKey;
+    type EncappedKey = DhEncappedKey<Kex>;
+    // RFC 9180 §7.1: For all the DHKEMs, Nsecret is the output size of the KDF's hash function
+    type NSecret = <Kdf::HashImpl as FixedOutput>::OutputSize;
+
+    const KEM_ID: u16 = <Self as DhKemId>::KEM_ID;
+
+    fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (Self::PrivateKey, Self::PublicKey) {
+        Kex::gen_keypair(csprng)
+    }
+
+    fn derive_keypair(ikm: &[u8]) -> (Self::PrivateKey, Self::PublicKey) {
+        let suite_id = kem_suite_id::<Self>();
+        Kex::derive_keypair::<Kdf>(&suite_id, ikm)
+    }
+
+    fn sk_to_pk(sk: &Self::PrivateKey) -> Self::PublicKey {
+        Kex::sk_to_pk(sk)
+    }
+
+    fn encap<R: CryptoRng + RngCore>(
+        pk_recip: &Self::PublicKey,
+        csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError> {
+        // Generate a new ephemeral keypair and pass to encap_with_eph
+        let (sk_eph, _) = Kex::gen_keypair(csprng);
+        Self::encap_with_eph(pk_recip, None, sk_eph)
+    }
+
+    fn auth_encap<R: CryptoRng + RngCore>(
+        pk_recip: &Self::PublicKey,
+        sender_id_keypair: &(Self::PrivateKey, Self::PublicKey),
+        csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError> {
+        // Generate a new ephemeral keypair and pass to encap_with_eph
+        let (sk_eph, _) = Kex::gen_keypair(csprng);
+        Self::encap_with_eph(pk_recip, Some(sender_id_keypair), sk_eph)
+    }
+
+    fn decap(
+        sk_recip: &Self::PrivateKey,
+        encapped_key: &Self::EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError> {
+        Self::decap_with_sender(sk_recip, None, encapped_key)
+    }
+
+    fn auth_decap(
+        sk_recip: &Self::PrivateKey,
+        pk_sender_id: &Self::PublicKey,
+        encapped_key: &Self::EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError> {
+        Self::decap_with_sender(sk_recip, Some(pk_sender_id), encapped_key)
+    }
+}
+
+impl<Kex, Kdf> DhKem<Kex, Kdf>
+where
+    Kex: KeyExchange,
+    Kdf: KdfTrait,
+    DhKem<Kex, Kdf>: DhKemId,
+{
+    // RFC 9180 §4.1
+    // def Encap(pkR):
+    //   skE, pkE = GenerateKeyPair()
+    //   dh = DH(skE, pkR)
+    //   enc = SerializePublicKey(pkE)
+    //
+    //   pkRm = SerializePublicKey(pkR)
+    //   kem_context = concat(enc, pkRm)
+    //
+    //   shared_secret = ExtractAndExpand(dh, kem_context)
+    //   return shared_secret, enc
+    //
+    // def AuthEncap(pkR, skS):
+    //   skE, pkE = GenerateKeyPair()
+    //   dh = concat(DH(skE, pkR), DH(skS, pkR))
+    //   enc = SerializePublicKey(pkE)
+    //
+    //   pkRm = SerializePublicKey(pkR)
+    //   pkSm = SerializePublicKey(pk(skS))
+    //   kem_context = concat(enc, pkRm, pkSm)
+    //
+    //   shared_secret = ExtractAndExpand(dh, kem_context)
+    //   return shared_secret, enc
+    /// Derives a shared secret that the owner of the reciepint's pubkey can use to derive the same
+    /// shared secret. If `sk_sender_id` is given, the sender's identity will be tied to the shared
+    /// secret.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret and encapped key on success. If an error happened during key
+    /// exchange, returns `Err(HpkeError::InvalidKeyExchange)`.
+    fn encap_with_eph(
+        pk_recip: &Kex::PublicKey,
+        sender_id_keypair: Option<&(Kex::PrivateKey, Kex::PublicKey)>,
+        sk_eph: Kex::PrivateKey,
+    ) -> Result<(SharedSecret<Self>, DhEncappedKey<Kex>), HpkeError> {
+        // Put together the binding context used for all KDF operations
+        let suite_id = kem_suite_id::<Self>();
+
+        // Compute the shared secret from the ephemeral inputs
+        let kex_res_eph = Kex::kex(&sk_eph, pk_recip)?;
+
+        // The encapped key is the ephemeral pubkey
+        let encapped_key = {
+            let pk_eph = Kex::sk_to_pk(&sk_eph);
+            DhEncappedKey(pk_eph)
+        };
+
+        // The shared secret is either gonna be kex_res_eph, or that along with another shared
+        // secret that's tied to the sender's identity.
+        let mut shared_secret = <SharedSecret<Self> as Default>::default();
+        if let Some((sk_sender_id, pk_sender_id)) = sender_id_keypair {
+            let kem_context = [
+                encapped_key.marshal(),
+                pk_recip.marshal(),
+                pk_sender_id.marshal(),
+            ]
+            .concat();
+            // We want to do an authed encap. Do KEX between the sender identity secret key and the
+            // recipient's pubkey
+            let kex_res_identity = Kex::kex(sk_sender_id, pk_recip)?;
+            // kex_res_eph || kex_res_identity
+            let concatted_secrets = [kex_res_eph.marshal(), kex_res_identity.marshal()].concat();
+
+            // The "authed shared secret" is derived from the KEX of the ephemeral input with the
+            // recipient pubkey, and the KEX of the identity input with the recipient pubkey. The
+            // HKDF-Expand call only errors if the output values are 255x the digest size of the
+            // hash function. Since these values are fixed at compile time, we don't worry about it.
+            extract_and_expand::<Kdf>(
+                &concatted_secrets,
+                &suite_id,
+                &kem_context,
+                &mut shared_secret.0,
+            )
+            .expect("shared secret is way too big");
+        } else {
+            let kem_context = [encapped_key.marshal(), pk_recip.marshal()].concat();
+            // The "unauthed shared secret" is derived from just the KEX of the ephemeral input with
+            // the recipient pubkey. The HKDF-Expand call only errors if the output values are 255x
+            // the digest size of the hash function. Since these values are fixed at compile time,
+            // we don't worry about it.
+            extract_and_expand::<Kdf>(
+                &kex_res_eph.marshal(),
+                &suite_id,
+                &kem_context,
+                &mut shared_secret.0,
+            )
+            .expect("shared secret is way too big");
+        }
+
+        Ok((shared_secret, encapped_key))
+    }
+
+    // RFC 9180 §4.1
+    // def Decap(enc, skR):
+    //   pkE = DeserializePublicKey(enc)
+    //   dh = DH(skR, pkE)
+    //
+    //   pkRm = SerializePublicKey(pk(skR))
+    //   kem_context = concat(enc, pkRm)
+    //
+    //   shared_secret = ExtractAndExpand(dh, kem_context)
+    //   return shared_secret
+    //
+    // def AuthDecap(enc, skR, pkS):
+    //   pkE = DeserializePublicKey(enc)
+    //   dh = concat(DH(skR, pkE), DH(skR, pkS))
+    //
+    //   pkRm = SerializePublicKey(pk(skR))
+    //   pkSm = SerializePublicKey(pkS)
+    //   kem_context = concat(enc, pkRm, pkSm)
+    //
+    //   shared_secret = ExtractAndExpand(dh, kem_context)
+    //   return shared_secret
+    /// Derives a shared secret given the encapsulated key and the recipients secret key. If
+    /// `pk_sender_id` is given, the sender's identity will be tied to the shared secret.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns a shared secret on success. If an error happened during key exchange, returns
+    /// `Err(HpkeError::InvalidKeyExchange)`.
+    fn decap_with_sender(
+        sk_recip: &Kex::PrivateKey,
+        pk_sender_id: Option<&Kex::PublicKey>,
+        encapped_key: &DhEncappedKey<Kex>,
+    ) -> Result<SharedSecret<Self>, HpkeError> {
+        // Put together the binding context used for all KDF operations
+        let suite_id = kem_suite_id::<Self>();
+
+        // Compute the shared secret from the ephemeral inputs
+        let kex_res_eph = Kex::kex(sk_recip, &encapped_key.0)?;
+
+        // Compute the sender's pubkey from their privkey
+        let pk_recip = Kex::sk_to_pk(sk_recip);
+
+        // The shared secret is either gonna be kex_res_eph, or that along with another shared
+        // secret that's tied to the sender's identity.
+        let mut shared_secret = <SharedSecret<Self> as Default>::default();
+        if let Some(pk_sender_id) = pk_sender_id {
+            let kem_context = [
+                encapped_key.marshal(),
+                pk_recip.marshal(),
+                pk_sender_id.marshal(),
+            ]
+            .concat();
+            // We want to do an authed encap. Do KEX between the sender identity secret key and the
+            // recipient's pubkey
+            let kex_res_identity = Kex::kex(sk_recip, pk_sender_id)?;
+            // kex_res_eph || kex_res_identity
+            let concatted_secrets = [kex_res_eph.marshal(), kex_res_identity.marshal()].concat();
+
+            // The "authed shared secret" is derived from the KEX of the ephemeral input with the
+            // recipient pubkey, and the kex of the identity input with the recipient pubkey. The
+            // HKDF-Expand call only errors if the output values are 255x the digest size of the
+            // hash function. Since these values are fixed at compile time, we don't worry about it.
+            extract_and_expand::<Kdf>(
+                &concatted_secrets,
+                &suite_id,
+                &kem_context,
+                &mut shared_secret.0,
+            )
+            .expect("shared secret is way too big");
+        } else {
+            let kem_context = [encapped_key.marshal(), pk_recip.marshal()].concat();
+            // The "unauthed shared secret" is derived from just the KEX of the ephemeral input with
+            // the recipient pubkey. The HKDF-Expand call only errors if the output values are 255x
+            // the digest size of the hash function. Since these values are fixed at compile time,
+            // we don't worry about it.
+            extract_and_expand::<Kdf>(
+                &kex_res_eph.marshal(),
+                &suite_id,
+                &kem_context,
+                &mut shared_secret.0,
+            )
+            .expect("shared secret is way too big");
+        }
+
+        Ok(shared_secret)
+    }
+}
+
+// For DHKEMs, the ephemeral keypair is just derived from the ephemeral keying material, and the
+// rest is a regular encap
+#[cfg(test)]
+impl<Kex, Kdf> crate::kem::DeterministicEncap for DhKem<Kex, Kdf>
+where
+    Kex: KeyExchange,
+    Kdf: KdfTrait,
+    DhKem<Kex, Kdf>: DhKemId,
+{
+    fn encap_deterministic(
+        pk_recip: &Self::PublicKey,
+        sender_id_keypair: Option<&(Self::PrivateKey, Self::PublicKey)>,
+        ikm_eph: &[u8],
+    ) -> Result<(SharedSecret<Self>, Self::EncappedKey), HpkeError> {
+        let (sk_eph, _) = Self::derive_keypair(ikm_eph);
+        Self::encap_with_eph(pk_recip, sender_id_keypair, sk_eph)
+    }
+}
@@ -0,0 +1,471 @@
+use crate::{
+    kdf::labeled_derive_shake256,
+    kem::{Kem as KemTrait, SharedSecret},
+    kex::{Marshallable, Unmarshallable},
+    util::kem_suite_id,
+    HpkeError,
+};
+
+use core::convert::TryFrom;
+
+use digest::generic_array::{typenum, GenericArray};
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+// FIPS 203 §7.2: The modulus check on an encapsulation key. An encapsulation key is
+// ByteEncode_12(t̂) || ρ, where ρ is 32 bytes. Every coefficient of t̂ must be less than q = 3329.
+/// Returns `true` iff every 12-bit coefficient in the given encoded encapsulation key is reduced
+/// mod q. The input MUST be the length of an encapsulation key.
+fn ek_modulus_check(encoded: &[u8]) -> bool {
+    const Q: u16 = 3329;
+
+    // Every 3 bytes of ByteEncode_12(t̂) holds 2 little-endian 12-bit coefficients
+    let t_hat = &encoded[..encoded.len() - 32];
+    t_hat.chunks(3).all(|c| {
+        let a = u16::from(c[0]) | (u16::from(c[1] & 0x0f) << 8);
+        let b = (u16::from(c[1]) >> 4) | (u16::from(c[2]) << 4);
+        a < Q && b < Q
+    })
+}
+
+// ML-KEM-768 and ML-KEM-1024 are implemented in the same way, using the RustCrypto ml-kem crate.
+// The only things that differ between them are the parameter set and the KEM ID. So we write the
+// implementation once, as a macro, and instantiate it per parameter set.
+macro_rules! mlkem {
+    ($kem_name_str:expr, $mod_name:ident, $kem_name:ident, $param_ty:ident, $kem_id:expr) => {
+        pub(crate) mod $mod_name {
+            use super::*;
+
+            use ::ml_kem::{
+                kem::Decapsulate, Ciphertext, EncapsulateDeterministic, EncodedSizeUser, KemCore,
+                B32,
+            };
+
+            // The underlying parameter set, and the key types it comes with
+            type Params = ::ml_kem::$param_ty;
+            type DecapsulationKey = <Params as KemCore>::DecapsulationKey;
+            type EncapsulationKey = <Params as KemCore>::EncapsulationKey;
+
+            #[doc = concat!("An ", $kem_name_str, " public key, i.e., an encapsulation key")]
+            #[derive(Clone)]
+            pub struct PublicKey(EncapsulationKey);
+
+            // The seed is kept around because it's the serialized form of the private key
+            #[doc = concat!("An ", $kem_name_str, " private key. This is stored as the 64-byte seed `d || z` alongside the decapsulation key it expands to.")]
+            #[derive(Clone)]
+            pub struct PrivateKey {
+                seed: GenericArray<u8, typenum::U64>,
+                dk: DecapsulationKey,
+            }
+
+            #[doc = concat!("An ", $kem_name_str, " encapsulated key, i.e., a ciphertext")]
+            #[derive(Clone)]
+            pub struct EncappedKey(Ciphertext<Params>);
+
+            impl PrivateKey {
+                // FIPS 203 §7.1: ML-KEM.KeyGen_internal(d, z). The seed is d || z.
+                pub(crate) fn from_seed(seed: GenericArray<u8, typenum::U64>) -> PrivateKey {
+                    // The halves are 32 bytes each, so these can't panic
+                    let d = <&B32>::try_from(&seed[..32]).unwrap();
+                    let z = <&B32>::try_from(&seed[32..]).unwrap();
+                    let (dk, _) = Params::generate_deterministic(d, z);
+
+                    PrivateKey { seed, dk }
+                }
+            }
+
+            // The decapsulation key zeroes itself on drop, but the seed is on us
+            impl Drop for PrivateKey {
+                fn drop(&mut self) {
+                    self.seed.as_mut_slice().zeroize();
+                }
+            }
+
+            // draft-ietf-hpke-pq-04 §3: SerializePublicKey is the identity on the encapsulation
+            // key
+            impl Marshallable for PublicKey {
+                type OutputSize = <EncapsulationKey as EncodedSizeUser>::EncodedSize;
+
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    GenericArray::clone_from_slice(&self.0.as_bytes())
+                }
+            }
+
+            impl Unmarshallable for PublicKey {
+                // draft-ietf-hpke-pq-04 §3: DeserializePublicKey fails if the input is the wrong
+                // length or fails the encapsulation key check of FIPS 203 §7.2
+                fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+                    if encoded.len() != Self::size() || !ek_modulus_check(encoded) {
+                        return Err(HpkeError::InvalidEncoding);
+                    }
+
+                    // This can't fail. We just checked the length.
+                    let ek_bytes = <&::ml_kem::Encoded<EncapsulationKey>>::try_from(encoded)
+                        .map_err(|_| HpkeError::InvalidEncoding)?;
+                    Ok(PublicKey(EncapsulationKey::from_bytes(ek_bytes)))
+                }
+            }
+
+            // draft-ietf-hpke-pq-04 §3: SerializePrivateKey outputs the 64-byte seed
+            impl Marshallable for PrivateKey {
+                type OutputSize = typenum::U64;
+
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    self.seed.clone()
+                }
+            }
+
+            impl Unmarshallable for PrivateKey {
+                fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+                    // Privkeys must be 64 bytes. Every 64-byte string is a valid seed.
+                    if encoded.len() != Self::size() {
+                        return Err(HpkeError::InvalidEncoding);
+                    }
+
+                    Ok(PrivateKey::from_seed(GenericArray::clone_from_slice(
+                        encoded,
+                    )))
+                }
+            }
+
+            // An encapsulated key is just a ciphertext. It's sent over the wire as-is.
+            impl Marshallable for EncappedKey {
+                type OutputSize = <Params as KemCore>::CiphertextSize;
+
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    GenericArray::clone_from_slice(&self.0)
+                }
+            }
+
+            impl Unmarshallable for EncappedKey {
+                fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+                    // FIPS 203 §7.3: The only check on a ciphertext is its length
+                    let ct = Ciphertext::<Params>::try_from(encoded)
+                        .map_err(|_| HpkeError::InvalidEncoding)?;
+                    Ok(EncappedKey(ct))
+                }
+            }
+
+            #[cfg(feature = "serde")]
+            impl_serde_via_marshal!(PublicKey);
+            #[cfg(feature = "serde")]
+            impl_serde_via_marshal!(PrivateKey);
+            #[cfg(feature = "serde")]
+            impl_serde_via_marshal!(EncappedKey);
+
+            #[doc = concat!("Represents ", $kem_name_str, ", the post-quantum KEM defined in FIPS 203. This has no authenticated encapsulation, so it can only be used in the Base and Psk modes.")]
+            pub struct $kem_name {}
+
+            impl $kem_name {
+                // draft-ietf-hpke-pq-04 §3: Encap is ML-KEM.Encaps. This is the deterministic
+                // core of it, ML-KEM.Encaps_internal(ek, m), where m is 32 random bytes.
+                pub(crate) fn encap_with_randomness(
+                    pk_recip: &PublicKey,
+                    m: &B32,
+                ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+                    // This never fails. The encapsulation key was checked when it was unmarshalled.
+                    let (ct, mut ss) = pk_recip
+                        .0
+                        .encapsulate_deterministic(m)
+                        .map_err(|_| HpkeError::InvalidKeyExchange)?;
+
+                    // Copy the shared secret out and clear the original
+                    let shared_secret = SharedSecret(GenericArray::clone_from_slice(&ss));
+                    ss.as_mut_slice().zeroize();
+
+                    Ok((shared_secret, EncappedKey(ct)))
+                }
+            }
+
+            impl KemTrait for $kem_name {
+                type PublicKey = PublicKey;
+                type PrivateKey = PrivateKey;
+                type EncappedKey = EncappedKey;
+                // draft-ietf-hpke-pq-04 §3: Nsecret is 32. The HPKE shared secret is just the
+                // ML-KEM shared secret.
+                type NSecret = typenum::U32;
+
+                // draft-ietf-hpke-pq-04 §3
+                const KEM_ID: u16 = $kem_id;
+
+                #[doc = concat!("Generates a random ", $kem_name_str, " keypair")]
+                fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (PrivateKey, PublicKey) {
+                    // FIPS 203 §7.1: ML-KEM.KeyGen samples d and z uniformly at random
+                    let mut seed = GenericArray::<u8, typenum::U64>::default();
+                    csprng.fill_bytes(&mut seed);
+
+                    let sk = PrivateKey::from_seed(seed);
+                    let pk = Self::sk_to_pk(&sk);
+
+                    (sk, pk)
+                }
+
+                // draft-ietf-hpke-pq-04 §3
+                // def DeriveKeyPair(ikm):
+                //   dk = SHAKE256.LabeledDerive(ikm, "DeriveKeyPair", "", 64)
+                //   (_expanded_dk, ek) = expandDecapsKey(dk)
+                //   return (dk, ek)
+                #[doc = concat!("Deterministically derives an ", $kem_name_str, " keypair from the given input keying material")]
+                fn derive_keypair(ikm: &[u8]) -> (PrivateKey, PublicKey) {
+                    let suite_id = kem_suite_id::<Self>();
+
+                    let mut seed = GenericArray::<u8, typenum::U64>::default();
+                    labeled_derive_shake256(&suite_id, ikm, b"DeriveKeyPair", b"", &mut seed);
+
+                    let sk = PrivateKey::from_seed(seed);
+                    let pk = Self::sk_to_pk(&sk);
+
+                    (sk, pk)
+                }
+
+                #[doc = concat!("Converts an ", $kem_name_str, " private key to a public key")]
+                fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
+                    PublicKey(sk.dk.encapsulation_key().clone())
+                }
+
+                fn encap<R: CryptoRng + RngCore>(
+                    pk_recip: &PublicKey,
+                    csprng: &mut R,
+                ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+                    // Sample the encapsulation randomness and pass to encap_with_randomness
+                    let mut m = B32::default();
+                    csprng.fill_bytes(&mut m);
+
+                    let res = Self::encap_with_randomness(pk_recip, &m);
+                    m.as_mut_slice().zeroize();
+                    res
+                }
+
+                /// ML-KEM has no `AuthEncap`. This always returns
+                /// `Err(HpkeError::AuthNotSupported)`.
+                fn auth_encap<R: CryptoRng + RngCore>(
+                    _pk_recip: &PublicKey,
+                    _sender_id_keypair: &(PrivateKey, PublicKey),
+                    _csprng: &mut R,
+                ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+                    Err(HpkeError::AuthNotSupported)
+                }
+
+                // draft-ietf-hpke-pq-04 §3
+                // def Decap(enc, skR):
+                //   (expanded_dk, _ek) = expandDecapsKey(skR)
+                //   return ML-KEM.Decaps(expanded_dk, enc)
+                fn decap(
+                    sk_recip: &PrivateKey,
+                    encapped_key: &EncappedKey,
+                ) -> Result<SharedSecret<Self>, HpkeError> {
+                    // This never fails. ML-KEM decapsulation rejects implicitly, i.e., a bad
+                    // ciphertext just results in a pseudorandom shared secret.
+                    let mut ss = sk_recip
+                        .dk
+                        .decapsulate(&encapped_key.0)
+                        .map_err(|_| HpkeError::InvalidKeyExchange)?;
+
+                    // Copy the shared secret out and clear the original
+                    let shared_secret = SharedSecret(GenericArray::clone_from_slice(&ss));
+                    ss.as_mut_slice().zeroize();
+
+                    Ok(shared_secret)
+                }
+
+                /// ML-KEM has no `AuthDecap`. This always returns
+                /// `Err(HpkeError::AuthNotSupported)`.
+                fn auth_decap(
+                    _sk_recip: &PrivateKey,
+                    _pk_sender_id: &PublicKey,
+                    _encapped_key: &EncappedKey,
+                ) -> Result<SharedSecret<Self>, HpkeError> {
+                    Err(HpkeError::AuthNotSupported)
+                }
+            }
+
+            // For ML-KEM, the ephemeral keying material in the test vectors is exactly the
+            // randomness m used in ML-KEM.Encaps
+            #[cfg(test)]
+            impl crate::kem::DeterministicEncap for $kem_name {
+                fn encap_deterministic(
+                    pk_recip: &PublicKey,
+                    sender_id_keypair: Option<&(PrivateKey, PublicKey)>,
+                    ikm_eph: &[u8],
+                ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+                    if sender_id_keypair.is_some() {
+                        return Err(HpkeError::AuthNotSupported);
+                    }
+                    let m = <&B32>::try_from(ikm_eph).map_err(|_| HpkeError::InvalidEncoding)?;
+                    Self::encap_with_randomness(pk_recip, m)
+                }
+            }
+
+            // We need this in our marshal-unmarshal tests
+            #[cfg(test)]
+            impl PartialEq for PrivateKey {
+                fn eq(&self, other: &PrivateKey) -> bool {
+                    self.seed == other.seed
+                }
+            }
+
+            // We need this in our marshal-unmarshal tests
+            #[cfg(test)]
+            impl PartialEq for PublicKey {
+                fn eq(&self, other: &PublicKey) -> bool {
+                    self.0 == other.0
+                }
+            }
+        }
+    };
+}
+
+mlkem!("ML-KEM-768", mlkem768, MlKem768, MlKem768, 0x0041);
+mlkem!("ML-KEM-1024", mlkem1024, MlKem1024, MlKem1024, 0x0042);
+
+pub use mlkem1024::MlKem1024;
+pub use mlkem768::MlKem768;
+
+#[cfg(test)]
+mod tests {
+    use super::{MlKem1024, MlKem768};
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        kex::{Marshallable, Unmarshallable},
+        op_mode::{OpModeR, OpModeS},
+        single_shot::{single_shot_open, single_shot_seal},
+        test_util::{gen_op_mode_pair, OpModeKind},
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that an unmarshal-marshal round-trip on a keypair ends up at the same values
+    macro_rules! test_mlkem_marshal_correctness {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+
+                // Make a random keypair and marshal it
+                let (sk, pk) = Kem::gen_keypair(&mut csprng);
+                let (sk_bytes, pk_bytes) = (sk.marshal(), pk.marshal());
+
+                // Now unmarshal those bytes
+                let new_sk = <Kem as KemTrait>::PrivateKey::unmarshal(&sk_bytes).unwrap();
+                let new_pk = <Kem as KemTrait>::PublicKey::unmarshal(&pk_bytes).unwrap();
+
+                // See if the unmarshalled values are the same as the initial ones
+                assert!(new_sk == sk, "private key doesn't marshal correctly");
+                assert!(new_pk == pk, "public key doesn't marshal correctly");
+            }
+        };
+    }
+
+    test_mlkem_marshal_correctness!(test_marshal_correctness_mlkem768, MlKem768);
+    test_mlkem_marshal_correctness!(test_marshal_correctness_mlkem1024, MlKem1024);
+
+    /// Tests that unmarshalling an encapsulation key with an unreduced coefficient fails
+    macro_rules! test_mlkem_modulus_check {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (_, pk) = Kem::gen_keypair(&mut csprng);
+
+                // Set the first coefficient to 0xfff, which is bigger than q
+                let mut pk_bytes = pk.marshal();
+                pk_bytes[0] = 0xff;
+                pk_bytes[1] |= 0x0f;
+
+                assert!(matches!(
+                    <Kem as KemTrait>::PublicKey::unmarshal(&pk_bytes),
+                    Err(HpkeError::InvalidEncoding)
+                ));
+            }
+        };
+    }
+
+    test_mlkem_modulus_check!(test_modulus_check_mlkem768, MlKem768);
+    test_mlkem_modulus_check!(test_modulus_check_mlkem1024, MlKem1024);
+
+    /// Tests that single-shot encryption works in the modes ML-KEM supports, and fails with
+    /// `AuthNotSupported` in the modes it doesn't
+    macro_rules! test_mlkem_single_shot {
+        ($test_name:ident, $kem_ty:ty) => {
+            #[test]
+            fn $test_name() {
+                type A = ChaCha20Poly1305;
+                type Kdf = HkdfSha256;
+                type Kem = $kem_ty;
+
+                let msg = b"Zero-knowledge proofs are just proofs with extra steps";
+                let aad = b"who's asking";
+                let info = b"lattice-based";
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+                // Base and Psk modes should round-trip
+                for op_mode_kind in &[OpModeKind::Base, OpModeKind::Psk] {
+                    let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kem, Kdf>(*op_mode_kind);
+
+                    let mut ciphertext = *msg;
+                    let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, _>(
+                        &sender_mode,
+                        &pk_recip,
+                        &info[..],
+                        &mut ciphertext[..],
+                        &aad[..],
+                        &mut csprng,
+                    )
+                    .unwrap();
+
+                    // Round-trip the encapped key through its wire format before opening
+                    let encapped_key =
+                        <Kem as KemTrait>::EncappedKey::unmarshal(&encapped_key.marshal()).unwrap();
+
+                    let mut plaintext = ciphertext;
+                    single_shot_open::<A, Kdf, Kem>(
+                        &receiver_mode,
+                        &sk_recip,
+                        &encapped_key,
+                        &info[..],
+                        &mut plaintext[..],
+                        &aad[..],
+                        &tag,
+                    )
+                    .unwrap();
+                    assert_eq!(&plaintext, msg);
+                }
+
+                // Auth mode should fail on both ends
+                let sender_id_keypair = Kem::gen_keypair(&mut csprng);
+                let pk_sender_id = sender_id_keypair.1.clone();
+                let mut ciphertext = *msg;
+                let res = single_shot_seal::<A, Kdf, Kem, _>(
+                    &OpModeS::Auth(sender_id_keypair),
+                    &pk_recip,
+                    &info[..],
+                    &mut ciphertext[..],
+                    &aad[..],
+                    &mut csprng,
+                );
+                assert!(matches!(res, Err(HpkeError::AuthNotSupported)));
+
+                let (_, encapped_key) = Kem::encap(&pk_recip, &mut csprng).unwrap();
+                let res = crate::setup::setup_receiver::<A, Kdf, Kem>(
+                    &OpModeR::Auth(pk_sender_id),
+                    &sk_recip,
+                    &encapped_key,
+                    &info[..],
+                );
+                assert!(matches!(res, Err(HpkeError::AuthNotSupported)));
+            }
+        };
+    }
+
+    test_mlkem_single_shot!(test_single_shot_mlkem768, MlKem768);
+    test_mlkem_single_shot!(test_single_shot_mlkem1024, MlKem1024);
+}
@@ -0,0 +1,457 @@
+use crate::{
+    kdf::labeled_derive_shake256,
+    kem::{mlkem::mlkem768, Kem as KemTrait, MlKem768, SharedSecret},
+    kex::{KeyExchange, Marshallable, Unmarshallable, X25519},
+    util::kem_suite_id,
+    HpkeError,
+};
+
+use core::convert::TryFrom;
+
+use digest::generic_array::{
+    typenum::{self, Unsigned},
+    GenericArray,
+};
+use rand::{CryptoRng, RngCore};
+use sha3::digest::{Digest, ExtendableOutput, Update, XofReader};
+use zeroize::Zeroize;
+
+// X-Wing is ML-KEM-768 and X25519, glued together with SHA3. So the keys and ciphertexts are just
+// the ML-KEM-768 and X25519 ones, side by side.
+type MlKemPublicKey = <MlKem768 as KemTrait>::PublicKey;
+type MlKemPrivateKey = <MlKem768 as KemTrait>::PrivateKey;
+type MlKemEncappedKey = <MlKem768 as KemTrait>::EncappedKey;
+type X25519PublicKey = <X25519 as KeyExchange>::PublicKey;
+type X25519PrivateKey = <X25519 as KeyExchange>::PrivateKey;
+
+// draft-connolly-cfrg-xwing-kem-10 §5.3: The label that domain-separates the combiner
+const XWING_LABEL: &[u8] = b"\\.//^\\";
+
+/// An X-Wing public key. This is the ML-KEM-768 encapsulation key followed by the X25519 pubkey.
+#[derive(Clone)]
+pub struct PublicKey {
+    pk_m: MlKemPublicKey,
+    pk_x: X25519PublicKey,
+}
+
+/// An X-Wing private key. This is stored as the 32-byte seed alongside the keys it expands to.
+#[derive(Clone)]
+pub struct PrivateKey {
+    seed: GenericArray<u8, typenum::U32>,
+    sk_m: MlKemPrivateKey,
+    sk_x: X25519PrivateKey,
+    // We keep the public key around because the combiner needs pk_X
+    pk: PublicKey,
+}
+
+/// An X-Wing encapsulated key. This is the ML-KEM-768 ciphertext followed by the X25519 ephemeral
+/// pubkey.
+#[derive(Clone)]
+pub struct EncappedKey {
+    ct_m: MlKemEncappedKey,
+    ct_x: X25519PublicKey,
+}
+
+impl PrivateKey {
+    // draft-connolly-cfrg-xwing-kem-10 §5.2
+    // def expandDecapsulationKey(sk):
+    //   expanded = SHAKE256(sk, 96)
+    //   (pk_M, sk_M) = ML-KEM-768.KeyGen_internal(expanded[0:32], expanded[32:64])
+    //   sk_X = expanded[64:96]
+    //   pk_X = X25519(sk_X, X25519_BASE)
+    //   return (sk_M, sk_X, pk_M, pk_X)
+    fn from_seed(seed: GenericArray<u8, typenum::U32>) -> PrivateKey {
+        let mut expanded = [0u8; 96];
+        let mut hasher = sha3::Shake256::default();
+        hasher.update(&seed);
+        hasher.finalize_xof().read(&mut expanded);
+
+        // The ML-KEM-768 seed is d || z, which is exactly the first 64 bytes
+        let sk_m = mlkem768::PrivateKey::from_seed(GenericArray::clone_from_slice(&expanded[..64]));
+        // This can't fail. Every 32-byte string is an X25519 private key.
+        let sk_x = X25519PrivateKey::unmarshal(&expanded[64..]).unwrap();
+        expanded.zeroize();
+
+        let pk = PublicKey {
+            pk_m: MlKem768::sk_to_pk(&sk_m),
+            pk_x: X25519::sk_to_pk(&sk_x),
+        };
+
+        PrivateKey {
+            seed,
+            sk_m,
+            sk_x,
+            pk,
+        }
+    }
+}
+
+// The component private keys take care of themselves, but the seed is on us
+impl Drop for PrivateKey {
+    fn drop(&mut self) {
+        self.seed.as_mut_slice().zeroize();
+    }
+}
+
+// draft-connolly-cfrg-xwing-kem-10 §5.3
+// def Combiner(ss_M, ss_X, ct_X, pk_X):
+//   return SHA3-256(concat(
+//     ss_M,
+//     ss_X,
+//     ct_X,
+//     pk_X,
+//     XWingLabel
+//   ))
+/// Hashes the component shared secrets, along with the X25519 ciphertext and pubkey, into the
+/// X-Wing shared secret
+fn combiner(
+    ss_m: &[u8],
+    ss_x: &[u8],
+    ct_x: &X25519PublicKey,
+    pk_x: &X25519PublicKey,
+) -> SharedSecret<XWing> {
+    let mut hasher = sha3::Sha3_256::new();
+    Digest::update(&mut hasher, ss_m);
+    Digest::update(&mut hasher, ss_x);
+    Digest::update(&mut hasher, ct_x.marshal());
+    Digest::update(&mut hasher, pk_x.marshal());
+    Digest::update(&mut hasher, XWING_LABEL);
+
+    SharedSecret(GenericArray::clone_from_slice(&hasher.finalize()))
+}
+
+impl Marshallable for PublicKey {
+    // draft-connolly-cfrg-xwing-kem-10 §5: X-Wing public keys are 1216 bytes
+    type OutputSize = typenum::Sum<
+        <MlKemPublicKey as Marshallable>::OutputSize,
+        <X25519PublicKey as Marshallable>::OutputSize,
+    >;
+
+    // The encoding is pk_M || pk_X
+    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+        GenericArray::clone_from_slice(
+            &[
+                self.pk_m.marshal().as_slice(),
+                self.pk_x.marshal().as_slice(),
+            ]
+            .concat(),
+        )
+    }
+}
+
+impl Unmarshallable for PublicKey {
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        if encoded.len() != Self::size() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        // Split into pk_M || pk_X and unmarshal each half. The ML-KEM half gets its modulus check
+        // here.
+        let (pk_m_bytes, pk_x_bytes) = encoded.split_at(MlKemPublicKey::size());
+        Ok(PublicKey {
+            pk_m: MlKemPublicKey::unmarshal(pk_m_bytes)?,
+            pk_x: X25519PublicKey::unmarshal(pk_x_bytes)?,
+        })
+    }
+}
+
+// draft-connolly-cfrg-xwing-kem-10 §5: The private key is the 32-byte seed
+impl Marshallable for PrivateKey {
+    type OutputSize = typenum::U32;
+
+    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+        self.seed
+    }
+}
+
+impl Unmarshallable for PrivateKey {
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        // Privkeys must be 32 bytes. Every 32-byte string is a valid seed.
+        if encoded.len() != Self::size() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        Ok(PrivateKey::from_seed(GenericArray::clone_from_slice(
+            encoded,
+        )))
+    }
+}
+
+impl Marshallable for EncappedKey {
+    // draft-connolly-cfrg-xwing-kem-10 §5: X-Wing ciphertexts are 1120 bytes
+    type OutputSize = typenum::Sum<
+        <MlKemEncappedKey as Marshallable>::OutputSize,
+        <X25519PublicKey as Marshallable>::OutputSize,
+    >;
+
+    // The encoding is ct_M || ct_X
+    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+        GenericArray::clone_from_slice(
+            &[
+                self.ct_m.marshal().as_slice(),
+                self.ct_x.marshal().as_slice(),
+            ]
+            .concat(),
+        )
+    }
+}
+
+impl Unmarshallable for EncappedKey {
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        if encoded.len() != Self::size() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        // Split into ct_M || ct_X and unmarshal each half
+        let ct_m_len = <MlKemEncappedKey as Marshallable>::OutputSize::to_usize();
+        let (ct_m_bytes, ct_x_bytes) = encoded.split_at(ct_m_len);
+        Ok(EncappedKey {
+            ct_m: MlKemEncappedKey::unmarshal(ct_m_bytes)?,
+            ct_x: X25519PublicKey::unmarshal(ct_x_bytes)?,
+        })
+    }
+}
+
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PublicKey);
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PrivateKey);
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(EncappedKey);
+
+/// Represents X-Wing, the hybrid post-quantum KEM combining ML-KEM-768 and X25519 defined in
+/// draft-connolly-cfrg-xwing-kem. It stays secure as long as either component does. This has no
+/// authenticated encapsulation, so it can only be used in the Base and Psk modes.
+pub struct XWing {}
+
+impl XWing {
+    // draft-connolly-cfrg-xwing-kem-10 §5.4
+    // def EncapsulateDerand(pk, eseed):
+    //   pk_M = pk[0:1184]
+    //   pk_X = pk[1184:1216]
+    //   ek_X = eseed[32:64]
+    //   ct_X = X25519(ek_X, X25519_BASE)
+    //   ss_X = X25519(ek_X, pk_X)
+    //   (ss_M, ct_M) = ML-KEM-768.EncapsDerand(pk_M, eseed[0:32])
+    //   ss = Combiner(ss_M, ss_X, ct_X, pk_X)
+    //   ct = concat(ct_M, ct_X)
+    //   return (ss, ct)
+    /// Encapsulates to the given pubkey, using the 64-byte `eseed` as the randomness
+    fn encap_with_eseed(
+        pk_recip: &PublicKey,
+        eseed: &[u8; 64],
+    ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+        // The X25519 half. Unlike in plain X-Wing, we inherit the all-zero check from
+        // X25519::kex, so a low-order pk_X is rejected with an error.
+        let ek_x = X25519PrivateKey::unmarshal(&eseed[32..]).unwrap();
+        let ct_x = X25519::sk_to_pk(&ek_x);
+        let ss_x = X25519::kex(&ek_x, &pk_recip.pk_x)?;
+
+        // The ML-KEM-768 half. This can't fail, since the first half of eseed is 32 bytes.
+        let m = <&::ml_kem::B32>::try_from(&eseed[..32]).unwrap();
+        let (ss_m, ct_m) = MlKem768::encap_with_randomness(&pk_recip.pk_m, m)?;
+
+        let shared_secret = combiner(ss_m.as_slice(), &ss_x.marshal(), &ct_x, &pk_recip.pk_x);
+        Ok((shared_secret, EncappedKey { ct_m, ct_x }))
+    }
+}
+
+impl KemTrait for XWing {
+    type PublicKey = PublicKey;
+    type PrivateKey = PrivateKey;
+    type EncappedKey = EncappedKey;
+    // draft-connolly-cfrg-xwing-kem-10 §5: X-Wing shared secrets are 32 bytes
+    type NSecret = typenum::U32;
+
+    // draft-ietf-hpke-pq-04 §4: X-Wing's HPKE KEM ID
+    const KEM_ID: u16 = 0x647a;
+
+    // draft-connolly-cfrg-xwing-kem-10 §5.2
+    // def GenerateKeyPair():
+    //   sk = random(32)
+    //   (sk_M, sk_X, pk_M, pk_X) = expandDecapsulationKey(sk)
+    //   return sk, concat(pk_M, pk_X)
+    /// Generates a random X-Wing keypair
+    fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (PrivateKey, PublicKey) {
+        let mut seed = GenericArray::<u8, typenum::U32>::default();
+        csprng.fill_bytes(&mut seed);
+
+        let sk = PrivateKey::from_seed(seed);
+        let pk = Self::sk_to_pk(&sk);
+
+        (sk, pk)
+    }
+
+    // draft-ietf-hpke-pq-04 §4
+    // def DeriveKeyPair(ikm):
+    //   seed = SHAKE256.LabeledDerive(ikm, "DeriveKeyPair", "", 32)
+    //   return KEM.DeriveKeyPair(seed)
+    /// Deterministically derives an X-Wing keypair from the given input keying material
+    fn derive_keypair(ikm: &[u8]) -> (PrivateKey, PublicKey) {
+        let suite_id = kem_suite_id::<Self>();
+
+        let mut seed = GenericArray::<u8, typenum::U32>::default();
+        labeled_derive_shake256(&suite_id, ikm, b"DeriveKeyPair", b"", &mut seed);
+
+        let sk = PrivateKey::from_seed(seed);
+        let pk = Self::sk_to_pk(&sk);
+
+        (sk, pk)
+    }
+
+    /// Converts an X-Wing private key to a public key
+    fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
+        sk.pk.clone()
+    }
+
+    fn encap<R: CryptoRng + RngCore>(
+        pk_recip: &PublicKey,
+        csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+        // draft-connolly-cfrg-xwing-kem-10 §5.4: Encapsulate is EncapsulateDerand with a random
+        // 64-byte eseed
+        let mut eseed = [0u8; 64];
+        csprng.fill_bytes(&mut eseed);
+
+        let res = Self::encap_with_eseed(pk_recip, &eseed);
+        eseed.zeroize();
+        res
+    }
+
+    /// X-Wing has no `AuthEncap`. This always returns `Err(HpkeError::AuthNotSupported)`.
+    fn auth_encap<R: CryptoRng + RngCore>(
+        _pk_recip: &PublicKey,
+        _sender_id_keypair: &(PrivateKey, PublicKey),
+        _csprng: &mut R,
+    ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+        Err(HpkeError::AuthNotSupported)
+    }
+
+    // draft-connolly-cfrg-xwing-kem-10 §5.5
+    // def Decapsulate(ct, sk):
+    //   (sk_M, sk_X, pk_M, pk_X) = expandDecapsulationKey(sk)
+    //   ct_M = ct[0:1088]
+    //   ct_X = ct[1088:1120]
+    //   ss_M = ML-KEM-768.Decapsulate(sk_M, ct_M)
+    //   ss_X = X25519(sk_X, ct_X)
+    //   return Combiner(ss_M, ss_X, ct_X, pk_X)
+    fn decap(
+        sk_recip: &PrivateKey,
+        encapped_key: &EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError> {
+        let ss_m = MlKem768::decap(&sk_recip.sk_m, &encapped_key.ct_m)?;
+        // As in encap, a low-order ct_X is rejected with an error
+        let ss_x = X25519::kex(&sk_recip.sk_x, &encapped_key.ct_x)?;
+
+        Ok(combiner(
+            ss_m.as_slice(),
+            &ss_x.marshal(),
+            &encapped_key.ct_x,
+            &sk_recip.pk.pk_x,
+        ))
+    }
+
+    /// X-Wing has no `AuthDecap`. This always returns `Err(HpkeError::AuthNotSupported)`.
+    fn auth_decap(
+        _sk_recip: &PrivateKey,
+        _pk_sender_id: &PublicKey,
+        _encapped_key: &EncappedKey,
+    ) -> Result<SharedSecret<Self>, HpkeError> {
+        Err(HpkeError::AuthNotSupported)
+    }
+}
+
+// For X-Wing, the ephemeral keying material in the test vectors is exactly the eseed used in
+// EncapsulateDerand
+#[cfg(test)]
+impl crate::kem::DeterministicEncap for XWing {
+    fn encap_deterministic(
+        pk_recip: &PublicKey,
+        sender_id_keypair: Option<&(PrivateKey, PublicKey)>,
+        ikm_eph: &[u8],
+    ) -> Result<(SharedSecret<Self>, EncappedKey), HpkeError> {
+        if sender_id_keypair.is_some() {
+            return Err(HpkeError::AuthNotSupported);
+        }
+        let eseed = <&[u8; 64]>::try_from(ikm_eph).map_err(|_| HpkeError::InvalidEncoding)?;
+        Self::encap_with_eseed(pk_recip, eseed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::XWing;
+    use crate::{
+        aead::ChaCha20Poly1305,
+        kdf::HkdfSha256,
+        kem::Kem as KemTrait,
+        kex::{Marshallable, Unmarshallable},
+        op_mode::OpModeS,
+        setup::{setup_receiver, setup_sender},
+        test_util::{aead_ctx_eq, gen_op_mode_pair, OpModeKind},
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests that an unmarshal-marshal round-trip on a keypair ends up at the same values
+    #[test]
+    fn test_marshal_correctness() {
+        let mut csprng = StdRng::from_entropy();
+
+        // Make a random keypair and marshal it
+        let (sk, pk) = XWing::gen_keypair(&mut csprng);
+        let (sk_bytes, pk_bytes) = (sk.marshal(), pk.marshal());
+        assert_eq!(sk_bytes.len(), 32);
+        assert_eq!(pk_bytes.len(), 1216);
+
+        // Now unmarshal those bytes. The private key should expand to the same public key.
+        let new_sk = <XWing as KemTrait>::PrivateKey::unmarshal(&sk_bytes).unwrap();
+        let new_pk = <XWing as KemTrait>::PublicKey::unmarshal(&pk_bytes).unwrap();
+        assert_eq!(new_sk.marshal(), sk_bytes);
+        assert_eq!(new_pk.marshal(), pk_bytes);
+        assert_eq!(XWing::sk_to_pk(&new_sk).marshal(), pk_bytes);
+    }
+
+    /// Tests that `setup_sender` and `setup_receiver` derive the same context in the modes X-Wing
+    /// supports, and that the auth modes fail with `AuthNotSupported`
+    #[test]
+    fn test_setup_correctness() {
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = XWing;
+
+        let mut csprng = StdRng::from_entropy();
+        let info = b"belt and suspenders";
+
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+        for op_mode_kind in &[OpModeKind::Base, OpModeKind::Psk] {
+            let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kem, Kdf>(*op_mode_kind);
+
+            let (encapped_key, mut sender_ctx) =
+                setup_sender::<A, Kdf, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
+                    .unwrap();
+
+            // Round-trip the encapped key through its wire format before decapping
+            let encapped_key_bytes = encapped_key.marshal();
+            assert_eq!(encapped_key_bytes.len(), 1120);
+            let encapped_key =
+                <Kem as KemTrait>::EncappedKey::unmarshal(&encapped_key_bytes).unwrap();
+
+            let mut receiver_ctx =
+                setup_receiver::<A, Kdf, Kem>(&receiver_mode, &sk_recip, &encapped_key, &info[..])
+                    .unwrap();
+            assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+        }
+
+        let sender_id_keypair = Kem::gen_keypair(&mut csprng);
+        let res = setup_sender::<A, Kdf, Kem, _>(
+            &OpModeS::Auth(sender_id_keypair),
+            &pk_recip,
+            &info[..],
+            &mut csprng,
+        );
+        assert!(matches!(res, Err(HpkeError::AuthNotSupported)));
+    }
+}
@@ -1,4 +1,4 @@
-use crate::HpkeError;
+use crate::{kdf::Kdf as KdfTrait, HpkeError};
 
 use digest::generic_array::{typenum::marker_traits::Unsigned, ArrayLength, GenericArray};
 use rand::{CryptoRng, RngCore};
@@ -29,17 +29,34 @@ pub trait KeyExchange {
 
     fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (Self::PrivateKey, Self::PublicKey);
 
+    /// Deterministically derives a keypair from the given input keying material and KEM suite ID.
+    /// This is the `DeriveKeyPair` function in RFC 9180 §7.1.3. Most users will want to call
+    /// `Kem::derive_keypair` instead, which fills in the KDF and suite ID for you.
+    fn derive_keypair<Kdf: KdfTrait>(
+        suite_id: &[u8],
+        ikm: &[u8],
+    ) -> (Self::PrivateKey, Self::PublicKey);
+
     fn sk_to_pk(sk: &Self::PrivateKey) -> Self::PublicKey;
 
     fn kex(sk: &Self::PrivateKey, pk: &Self::PublicKey) -> Result<Self::KexResult, HpkeError>;
 }
 
-#[cfg(feature = "p256")]
+#[cfg(any(feature = "p256", feature = "p384", feature = "p521"))]
 mod ecdh_nistp;
 #[cfg(feature = "p256")]
 pub use ecdh_nistp::DhP256;
+#[cfg(feature = "p384")]
+pub use ecdh_nistp::DhP384;
+#[cfg(feature = "p521")]
+pub use ecdh_nistp::DhP521;
 
 #[cfg(feature = "x25519-dalek")]
 mod x25519;
 #[cfg(feature = "x25519-dalek")]
 pub use x25519::X25519;
+
+#[cfg(feature = "x448")]
+mod x448;
+#[cfg(feature = "x448")]
+pub use self::x448::X448;
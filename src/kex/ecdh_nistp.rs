@@ -1,269 +1,441 @@
 use crate::{
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
     kex::{KeyExchange, Marshallable, Unmarshallable},
     HpkeError,
 };
 
-use digest::generic_array::GenericArray;
-use p256::{
-    arithmetic::{AffinePoint, ProjectivePoint, Scalar},
-    elliptic_curve::weierstrass::{
-        curve::Curve,
-        point::{UncompressedCurvePoint, UncompressedPointSize},
-    },
-    NistP256,
-};
+use digest::generic_array::{typenum, GenericArray};
 use rand::{CryptoRng, RngCore};
-use subtle::ConstantTimeEq;
-
-/// An ECDH-P256 public key
-#[derive(Clone)]
-pub struct PublicKey(AffinePoint);
-
-// The range invariant below is maintained so that sk_to_pk is a well-defined operation. If you
-// disagree with this decision, fight me.
-/// A ECDH-P256 private key. This is a scalar in the range `[1,p)`.
-#[derive(Clone)]
-pub struct PrivateKey(Scalar);
-
-// A bare DH computation result
-pub struct KexResult(AffinePoint);
 
-// Everything is marshalled and unmarshalled uncompressed
-impl Marshallable for PublicKey {
-    // A fancy way of saying "65 bytes"
-    type OutputSize = UncompressedPointSize<<NistP256 as Curve>::ScalarSize>;
-
-    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
-        GenericArray::clone_from_slice(&self.0.to_uncompressed_pubkey().into_bytes())
-    }
-}
+// All the NIST curves are implemented in the same way, using the RustCrypto curve crates. The only
+// things that differ between curves are the sizes of things, the bitmask used in key generation,
+// and how likely a key generation candidate is to be out of range. So we write the implementation
+// once, as a macro, and instantiate it per curve.
+macro_rules! nistp_dhkex {
+    (
+        $curve_name:expr,
+        $dh_name:ident,
+        $curve_crate:ident,
+        $pubkey_size:ty,
+        $privkey_size:ty,
+        $ss_size:ty,
+        $keygen_bitmask:expr,
+        $candidate_reject_prob:expr,
+        $derive_fail_prob:expr
+    ) => {
+        pub(crate) mod $curve_crate {
+            use super::*;
+
+            use ::$curve_crate::{
+                elliptic_curve::{
+                    ff::{Field, PrimeField},
+                    point::AffineCoordinates,
+                    sec1::{FromEncodedPoint, ToEncodedPoint},
+                },
+                AffinePoint, EncodedPoint, FieldBytes, ProjectivePoint, Scalar,
+            };
+
+            #[doc = concat!("An ECDH-", $curve_name, " public key")]
+            #[derive(Clone)]
+            pub struct PublicKey(AffinePoint);
+
+            // The range invariant below is maintained so that sk_to_pk is a well-defined
+            // operation. If you disagree with this decision, fight me.
+            #[doc = concat!("An ECDH-", $curve_name, " private key. This is a scalar in the range `[1,p)`.")]
+            #[derive(Clone)]
+            pub struct PrivateKey(Scalar);
+
+            // A bare DH computation result
+            pub struct KexResult(AffinePoint);
+
+            // Everything is marshalled and unmarshalled uncompressed
+            impl Marshallable for PublicKey {
+                type OutputSize = $pubkey_size;
+
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    // Uncompressed points are of the form 0x04 || x-coordinate || y-coordinate
+                    GenericArray::clone_from_slice(self.0.to_encoded_point(false).as_bytes())
+                }
+            }
 
-// A helper method for the unmarshall method. The real unmarshall method just runs this and
-// interprets any `None` as an InvalidEncoding error.
-impl PublicKey {
-    fn unmarshal_helper(encoded: &[u8]) -> Option<PublicKey> {
-        // In order to parse as an uncompressed curve point, we first make sure the input length is
-        // correct
-        if encoded.len() != Self::size() {
-            return None;
-        }
+            // Everything is marshalled and unmarshalled uncompressed
+            impl Unmarshallable for PublicKey {
+                fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+                    // In order to parse as an uncompressed curve point, we first make sure the
+                    // input length is correct. This also rules out the compressed encoding and the
+                    // encoding of the point at infinity, both of which have different lengths.
+                    if encoded.len() != Self::size() {
+                        return Err(HpkeError::InvalidEncoding);
+                    }
+
+                    // Parse as an encoded curve point. This checks that the encoded point is
+                    // well-formed, but does not check that the point is on the curve.
+                    let encoded_point =
+                        EncodedPoint::from_bytes(encoded).map_err(|_| HpkeError::InvalidEncoding)?;
+
+                    // Convert to an affine point. This will fail if the point is not on the curve.
+                    let aff = AffinePoint::from_encoded_point(&encoded_point);
+                    if aff.is_some().into() {
+                        Ok(PublicKey(aff.unwrap()))
+                    } else {
+                        Err(HpkeError::InvalidEncoding)
+                    }
+                }
+            }
 
-        // Parse as uncompressed curve point. This checks that the encoded point is well-formed,
-        // but does not check that the point is on the curve.
-        let uncompressed = {
-            let byte_arr = GenericArray::clone_from_slice(encoded);
-            UncompressedCurvePoint::from_bytes(byte_arr)?
-        };
-
-        // Convert to an affine point. This will fail if the point is not on the curve or if the
-        // point is the point at infinity. Both of these are invalid DH pubkeys.
-        let aff = {
-            let pubkey = p256::PublicKey::from(uncompressed);
-            AffinePoint::from_pubkey(&pubkey)
-        };
-
-        if aff.is_some().into() {
-            Some(PublicKey(aff.unwrap()))
-        } else {
-            None
-        }
-    }
-}
+            impl Marshallable for PrivateKey {
+                type OutputSize = $privkey_size;
 
-// Everything is marshalled and unmarshalled uncompressed
-impl Unmarshallable for PublicKey {
-    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
-        // Run the real unmarshal method and treat `None` as an encoding error
-        Self::unmarshal_helper(encoded).ok_or(HpkeError::InvalidEncoding)
-    }
-}
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    GenericArray::clone_from_slice(&self.0.to_repr())
+                }
+            }
 
-impl Marshallable for PrivateKey {
-    // A fancy way of saying "32 bytes"
-    type OutputSize = <NistP256 as Curve>::ScalarSize;
+            impl PrivateKey {
+                // A helper method for unmarshal and key generation. Returns `None` if the given
+                // bytes do not represent a scalar in the range `[1,p)`.
+                fn from_bytes_checked(bytes: &[u8]) -> Option<PrivateKey> {
+                    // This will fail iff the bytes don't represent a number in the range [0,p)
+                    let mut repr = FieldBytes::default();
+                    repr.copy_from_slice(bytes);
+                    let scalar = Scalar::from_repr(repr);
+                    if scalar.is_none().into() {
+                        return None;
+                    }
+                    let scalar = scalar.unwrap();
+
+                    // All private keys must be in the range [1,p). So check that it's nonzero.
+                    if Field::is_zero(&scalar).into() {
+                        return None;
+                    }
+
+                    Some(PrivateKey(scalar))
+                }
+            }
 
-    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
-        GenericArray::clone_from_slice(&self.0.to_bytes())
-    }
-}
+            impl Unmarshallable for PrivateKey {
+                fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+                    // Check the length
+                    if encoded.len() != Self::size() {
+                        return Err(HpkeError::InvalidEncoding);
+                    }
 
-impl Unmarshallable for PrivateKey {
-    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
-        // Check the length
-        if encoded.len() != 32 {
-            return Err(HpkeError::InvalidEncoding);
-        }
+                    // Check that the bytes represent a scalar in the range [1,p)
+                    Self::from_bytes_checked(encoded).ok_or(HpkeError::InvalidEncoding)
+                }
+            }
 
-        // All private keys must be in the range [1,p). It suffices to check that the given array
-        // is not all zeros, since Scalar::from_bytes requires its input to be in reduced form
-        // anyways. This means that if arr ends up being np for some integer n, then it'll get
-        // rejected later.
-        if encoded.ct_eq(&[0u8; 32]).into() {
-            return Err(HpkeError::InvalidEncoding);
-        }
+            #[cfg(feature = "serde")]
+            impl_serde_via_marshal!(PublicKey);
+            #[cfg(feature = "serde")]
+            impl_serde_via_marshal!(PrivateKey);
 
-        // Copy the bytes into a fixed-size array
-        let mut arr = [0u8; 32];
-        arr.copy_from_slice(encoded);
+            // RFC 9180 §7.1.1: For P-256, P-384, and P-521, the size Ndh of the Diffie-Hellman
+            // shared secret is equal to 32, 48, and 66, respectively, corresponding to the
+            // x-coordinate of the resulting elliptic curve point.
+            impl Marshallable for KexResult {
+                type OutputSize = $ss_size;
 
-        // This will fail iff the bytes don't represent a number in the range [0,p)
-        let scalar = Scalar::from_bytes(arr);
-        if scalar.is_none().into() {
-            return Err(HpkeError::InvalidEncoding);
-        }
+                fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
+                    GenericArray::clone_from_slice(&self.0.x())
+                }
+            }
 
-        Ok(PrivateKey(scalar.unwrap()))
-    }
-}
+            // We need this in our marshal-unmarshal tests
+            #[cfg(test)]
+            impl PartialEq for PrivateKey {
+                fn eq(&self, other: &PrivateKey) -> bool {
+                    self.0 == other.0
+                }
+            }
 
-// DH results are marshalled in the same way as public keys
-impl Marshallable for KexResult {
-    // §4.1: Ndh equals Npk
-    type OutputSize = <PublicKey as Marshallable>::OutputSize;
+            // We need this in our marshal-unmarshal tests
+            #[cfg(test)]
+            impl PartialEq for PublicKey {
+                fn eq(&self, other: &PublicKey) -> bool {
+                    self.0 == other.0
+                }
+            }
 
-    fn marshal(&self) -> GenericArray<u8, Self::OutputSize> {
-        // Rewrap and marshal
-        PublicKey(self.0).marshal()
-    }
-}
+            #[cfg(test)]
+            impl core::fmt::Debug for PublicKey {
+                fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
+                    write!(f, "PublicKey({:?})", self.0)
+                }
+            }
 
-/// Dummy type which implements the `KeyExchange` trait
-pub struct DhP256 {}
-
-impl KeyExchange for DhP256 {
-    type PublicKey = PublicKey;
-    type PrivateKey = PrivateKey;
-    type KexResult = KexResult;
-
-    /// Generates an P256 keypair
-    fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (PrivateKey, PublicKey) {
-        // Generate a random scalar. Since some choices might be out of range, just keep generating
-        // until we get a valid one.
-        let mut scalar;
-        loop {
-            let mut bytes = [0u8; 32];
-            csprng.fill_bytes(&mut bytes);
-            scalar = Scalar::from_bytes(bytes);
-            if scalar.is_some().into() {
-                break;
+            /// Dummy type which implements the `KeyExchange` trait
+            pub struct $dh_name {}
+
+            impl KeyExchange for $dh_name {
+                type PublicKey = PublicKey;
+                type PrivateKey = PrivateKey;
+                type KexResult = KexResult;
+
+                #[doc = concat!("Generates an ", $curve_name, " keypair")]
+                fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (PrivateKey, PublicKey) {
+                    // Generate a random scalar. Since some choices might be out of range, just
+                    // keep generating until we get a valid one. We mask off the top bits that can
+                    // never be set in a valid scalar, so that this terminates quickly even for
+                    // P-521.
+                    let mut buf = GenericArray::<u8, $privkey_size>::default();
+                    let sk = loop {
+                        csprng.fill_bytes(&mut buf);
+                        buf[0] &= $keygen_bitmask;
+                        if let Some(sk) = PrivateKey::from_bytes_checked(&buf) {
+                            break sk;
+                        }
+                    };
+
+                    // Derive the secret key's pubkey
+                    let pk = Self::sk_to_pk(&sk);
+
+                    (sk, pk)
+                }
+
+                // RFC 9180 §7.1.3
+                // def DeriveKeyPair(ikm):
+                //   dkp_prk = LabeledExtract("", "dkp_prk", ikm)
+                //   sk = 0
+                //   counter = 0
+                //   while sk == 0 or sk >= order:
+                //     if counter > 255:
+                //       raise DeriveKeyPairError
+                //     bytes = LabeledExpand(dkp_prk, "candidate",
+                //                           I2OSP(counter, 1), Nsk)
+                //     bytes[0] = bytes[0] & bitmask
+                //     sk = OS2IP(bytes)
+                //     counter = counter + 1
+                //   return (sk, pk(sk))
+                //
+                // where `bitmask` is defined to be 0xFF for P-256 and P-384, and 0x01 for P-521
+                #[doc = concat!("Deterministically derives an ", $curve_name, " keypair from the given input keying")]
+                /// material and KEM suite ID. The keying material SHOULD have as many bits of
+                /// entropy as the bit length of a secret key.
+                ///
+                /// Panics
+                /// ======
+                #[doc = concat!("Panics if all 256 candidate scalars are out of range. Each candidate is out of range with probability less than ", $candidate_reject_prob, ", so this happens with probability less than ", $derive_fail_prob, ".")]
+                fn derive_keypair<Kdf: KdfTrait>(
+                    suite_id: &[u8],
+                    ikm: &[u8],
+                ) -> (PrivateKey, PublicKey) {
+                    // Write the label into a byte buffer and extract from the IKM
+                    let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
+
+                    // Try to generate a key 256 times. Practically, this will succeed and return
+                    // early on the first iteration.
+                    let mut buf = GenericArray::<u8, $privkey_size>::default();
+                    for counter in 0u8..=255 {
+                        // This only errors if the output length is 255x the digest size of the
+                        // hash function. Since the length is fixed at compile time, we don't worry
+                        // about it.
+                        hkdf_ctx
+                            .labeled_expand(suite_id, b"candidate", &[counter], &mut buf)
+                            .expect("privkey len is way too big");
+                        buf[0] &= $keygen_bitmask;
+
+                        // Try to convert to a valid secret key. If the conversion succeeded,
+                        // return the keypair. Otherwise, try again.
+                        if let Some(sk) = PrivateKey::from_bytes_checked(&buf) {
+                            let pk = Self::sk_to_pk(&sk);
+                            return (sk, pk);
+                        }
+                    }
+
+                    // The code should never ever get here. See the Panics section above for the
+                    // likelihood that we get 256 bad samples in a row.
+                    panic!("DeriveKeyPair failed all attempts");
+                }
+
+                #[doc = concat!("Converts an ", $curve_name, " private key to a public key")]
+                fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
+                    // The result is never the point at infinity, since the generator has prime
+                    // order and PrivateKeys are guaranteed to never be 0 (see the unmarshal()
+                    // implementation for details)
+                    let pk = ProjectivePoint::GENERATOR * sk.0;
+                    PublicKey(pk.to_affine())
+                }
+
+                /// Does the DH operation. This never fails, since the pubkey and privkey are
+                /// validated on construction.
+                fn kex(sk: &PrivateKey, pk: &PublicKey) -> Result<KexResult, HpkeError> {
+                    // Convert to a projective point so we can do arithmetic
+                    let pk_proj = ProjectivePoint::from(pk.0);
+                    // Do the DH operation. The result is not the point at infinity, because pk is
+                    // not the point at infinity (since this has no affine representation), and sk
+                    // is not 0 mod p (due to the invariant we keep on PrivateKeys)
+                    let dh_res_proj = pk_proj * sk.0;
+
+                    Ok(KexResult(dh_res_proj.to_affine()))
+                }
             }
         }
-
-        // Wrap the scalar and derive its pubkey
-        let sk = PrivateKey(scalar.unwrap());
-        let pk = Self::sk_to_pk(&sk);
-
-        (sk, pk)
-    }
-
-    /// Converts an P256 private key to a public key
-    fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
-        let pk = p256::arithmetic::ProjectivePoint::generator() * &sk.0;
-        // It's safe to unwrap() here, because PrivateKeys are guaranteed to never be 0 (see the
-        // unmarshal() implementation for details)
-        PublicKey(pk.to_affine().unwrap())
-    }
-
-    /// Does the DH operation. Returns `HpkeError::InvalidKeyExchange` if and only if the DH
-    /// result was all zeros. This is required by the HPKE spec.
-    fn kex(sk: &PrivateKey, pk: &PublicKey) -> Result<KexResult, HpkeError> {
-        // Convert to a projective point so we can do arithmetic
-        let pk_proj: ProjectivePoint = pk.0.into();
-        // Do the DH operation
-        let dh_res_proj = pk_proj * &sk.0;
-
-        // We can unwrap here because we know pk is not the point at infinity (since this has no
-        // affine representation), and sk is not 0 mod p (due to the invariant we keep on
-        // PrivateKeys)
-        Ok(KexResult(dh_res_proj.to_affine().unwrap()))
-    }
+    };
 }
 
+#[cfg(feature = "p256")]
+nistp_dhkex!(
+    "P256",
+    DhP256,
+    p256,
+    typenum::U65, // RFC 9180 §7.1: Npk of DHKEM(P-256, HKDF-SHA256) is 65
+    typenum::U32, // RFC 9180 §7.1: Nsk of DHKEM(P-256, HKDF-SHA256) is 32
+    typenum::U32, // RFC 9180 §4.1: Ndh of P-256 is equal to 32
+    0xFF,         // RFC 9180 §7.1.3: The `bitmask` in DeriveKeyPair is 0xFF for P-256
+    // The candidate is out of range iff it's 0 or at least the group order n. 2^256 - n + 1 is
+    // just under 2^224.
+    "2^-32",
+    "2^-8192"
+);
+
+#[cfg(feature = "p384")]
+nistp_dhkex!(
+    "P384",
+    DhP384,
+    p384,
+    typenum::U97, // RFC 9180 §7.1: Npk of DHKEM(P-384, HKDF-SHA384) is 97
+    typenum::U48, // RFC 9180 §7.1: Nsk of DHKEM(P-384, HKDF-SHA384) is 48
+    typenum::U48, // RFC 9180 §4.1: Ndh of P-384 is equal to 48
+    0xFF,         // RFC 9180 §7.1.3: The `bitmask` in DeriveKeyPair is 0xFF for P-384
+    // 2^384 - n + 1 is under 2^190
+    "2^-194",
+    "2^-49664"
+);
+
+#[cfg(feature = "p521")]
+nistp_dhkex!(
+    "P521",
+    DhP521,
+    p521,
+    typenum::U133, // RFC 9180 §7.1: Npk of DHKEM(P-521, HKDF-SHA512) is 133
+    typenum::U66,  // RFC 9180 §7.1: Nsk of DHKEM(P-521, HKDF-SHA512) is 66
+    typenum::U66,  // RFC 9180 §4.1: Ndh of P-521 is equal to 66
+    0x01,          // RFC 9180 §7.1.3: The `bitmask` in DeriveKeyPair is 0x01 for P-521
+    // After masking, the candidate is a 521-bit number, and 2^521 - n + 1 is under 2^259
+    "2^-262",
+    "2^-67072"
+);
+
+#[cfg(feature = "p256")]
+pub use self::p256::DhP256;
+#[cfg(feature = "p384")]
+pub use self::p384::DhP384;
+#[cfg(feature = "p521")]
+pub use self::p521::DhP521;
+
 #[cfg(test)]
 mod tests {
-    use crate::kex::{
-        ecdh_nistp::{DhP256, PrivateKey, PublicKey},
-        KeyExchange, Marshallable, Unmarshallable,
-    };
-
-    use rand::{rngs::StdRng, SeedableRng};
+    use crate::kex::{KeyExchange, Marshallable, Unmarshallable};
 
-    // We need this in our marshal-unmarshal tests
-    impl PartialEq for PrivateKey {
-        fn eq(&self, other: &PrivateKey) -> bool {
-            self.0.to_bytes() == other.0.to_bytes()
-        }
-    }
+    #[cfg(feature = "p256")]
+    use super::DhP256;
+    #[cfg(feature = "p384")]
+    use super::DhP384;
+    #[cfg(feature = "p521")]
+    use super::DhP521;
 
-    // We need this in our marshal-unmarshal tests
-    impl PartialEq for PublicKey {
-        fn eq(&self, other: &PublicKey) -> bool {
-            self.0 == other.0
-        }
-    }
-
-    impl core::fmt::Debug for PublicKey {
-        fn fmt(&self, f: &mut core::fmt::Formatter) -> Result<(), core::fmt::Error> {
-            write!(f, "PublicKey({:?})", self.0)
-        }
-    }
+    use rand::{rngs::StdRng, SeedableRng};
 
-    // Test vector comes from §8.1 of RFC5903
+    // Test vectors come from §8.1 of RFC5903
     // https://tools.ietf.org/html/rfc5903
-    /// Tests the ECDH op against a known answer
-    #[test]
-    fn test_vector_ecdh() {
-        type Kex = DhP256;
-
-        let sk_recip_bytes =
-            hex::decode("C88F01F510D9AC3F70A292DAA2316DE544E9AAB8AFE84049C62A9C57862D1433")
-                .unwrap();
-        let pk_sender_bytes = hex::decode(concat!(
+    #[cfg(feature = "p256")]
+    const P256_PRIVKEYS: &[&str] = &[
+        "C88F01F510D9AC3F70A292DAA2316DE544E9AAB8AFE84049C62A9C57862D1433",
+        "C6EF9C5D78AE012A011164ACB397CE2088685D8F06BF9BE0B283AB46476BEE53",
+    ];
+
+    // The public keys corresponding to the above private keys, in order
+    #[cfg(feature = "p256")]
+    const P256_PUBKEYS: &[&str] = &[
+        concat!(
+            "04",                                                               // Uncompressed
+            "DAD0B65394221CF9B051E1FECA5787D098DFE637FC90B9EF945D0C3772581180", // x-coordinate
+            "5271A0461CDB8252D61F1C456FA3E59AB1F45B33ACCF5F58389E0577B8990BB3"  // y-coordinate
+        ),
+        concat!(
             "04",                                                               // Uncompressed
             "D12DFB5289C8D4F81208B70270398C342296970A0BCCB74C736FC7554494BF63", // x-coordinate
             "56FBF3CA366CC23E8157854C13C58D6AAC23F046ADA30F8353E74F33039872AB", // y-coordinate
-        ))
-        .unwrap();
-        let dh_res_bytes = hex::decode(concat!(
+        ),
+    ];
+
+    // The x-coordinate of DH(privkey0, pubkey1), or equivalently, DH(privkey1, pubkey0)
+    #[cfg(feature = "p256")]
+    const P256_DH_RES_XCOORD: &str =
+        "D6840F6B42F6EDAFD13116E0E12565202FEF8E9ECE7DCE03812464D04B9442DE";
+
+    // Test vectors come from §8.2 of RFC5903
+    // https://tools.ietf.org/html/rfc5903
+    #[cfg(feature = "p384")]
+    const P384_PRIVKEYS: &[&str] = &[
+        concat!(
+            "099F3C7034D4A2C699884D73A375A67F7624EF7C6B3C0F160647B67414DCE655E35B5380",
+            "41E649EE3FAEF896783AB194",
+        ),
+        concat!(
+            "41CB0779B4BDB85D47846725FBEC3C9430FAB46CC8DC5060855CC9BDA0AA2942E0308312",
+            "916B8ED2960E4BD55A7448FC",
+        ),
+    ];
+
+    // The public keys corresponding to the above private keys, in order
+    #[cfg(feature = "p384")]
+    const P384_PUBKEYS: &[&str] = &[
+        concat!(
             "04",                                                               // Uncompressed
-            "D6840F6B42F6EDAFD13116E0E12565202FEF8E9ECE7DCE03812464D04B9442DE", // x-coordinate
-            "522BDE0AF0D8585B8DEF9C183B5AE38F50235206A8674ECB5D98EDB20EB153A2", // y-coordinate
-        ))
-        .unwrap();
+            "667842D7D180AC2CDE6F74F37551F55755C7645C20EF73E31634FE72B4C55EE6", // x-coordinate
+            "DE3AC808ACB4BDB4C88732AEE95F41AA",                                 //   ...cont
+            "9482ED1FC0EEB9CAFC4984625CCFC23F65032149E0E144ADA024181535A0F38E", // y-coordinate
+            "EB9FCFF3C2C947DAE69B4C634573A81C",                                 //   ...cont
+        ),
+        concat!(
+            "04",                                                               // Uncompressed
+            "E558DBEF53EECDE3D3FCCFC1AEA08A89A987475D12FD950D83CFA41732BC509D", // x-coordinate
+            "0D1AC43A0336DEF96FDA41D0774A3571",                                 //   ...cont
+            "DCFBEC7AACF3196472169E838430367F66EEBE3C6E70C416DD5F0C68759DD1FF", // y-coordinate
+            "F83FA40142209DFF5EAAD96DB9E6386C",                                 //   ...cont
+        ),
+    ];
+
+    // The x-coordinate of DH(privkey0, pubkey1), or equivalently, DH(privkey1, pubkey0)
+    #[cfg(feature = "p384")]
+    const P384_DH_RES_XCOORD: &str = concat!(
+        "11187331C279962D93D604243FD592CB9D0A926F422E47187521287E7156C5C4D6031355",
+        "69B9E9D09CF5D4A270F59746",
+    );
+
+    //
+    // Some helper functions for tests
+    //
 
+    /// Tests the ECDH op against a known answer
+    #[allow(dead_code)]
+    fn test_vector_ecdh<Kex: KeyExchange>(
+        sk_recip_hex: &str,
+        pk_sender_hex: &str,
+        dh_res_hex: &str,
+    ) {
         // Unmarshal the pubkey and privkey and do a DH operation
-        let sk_recip = <Kex as KeyExchange>::PrivateKey::unmarshal(&sk_recip_bytes).unwrap();
-        let pk_sender = <Kex as KeyExchange>::PublicKey::unmarshal(&pk_sender_bytes).unwrap();
+        let sk_recip =
+            <Kex as KeyExchange>::PrivateKey::unmarshal(&hex::decode(sk_recip_hex).unwrap())
+                .unwrap();
+        let pk_sender =
+            <Kex as KeyExchange>::PublicKey::unmarshal(&hex::decode(pk_sender_hex).unwrap())
+                .unwrap();
         let derived_dh = <Kex as KeyExchange>::kex(&sk_recip, &pk_sender).unwrap();
 
-        // Assert that the derived DH result matches the test vector
-        assert_eq!(derived_dh.marshal().as_slice(), dh_res_bytes.as_slice());
+        // Assert that the derived DH result matches the test vector. Recall that the HPKE DH
+        // result is just the x-coordinate, so that's all we can compare
+        assert_eq!(
+            derived_dh.marshal().as_slice(),
+            hex::decode(dh_res_hex).unwrap().as_slice()
+        );
     }
 
-    // Test vector comes from §8.1 of RFC5903
-    // https://tools.ietf.org/html/rfc5903
     /// Tests the `sk_to_pk` function against known answers
-    #[test]
-    fn test_vector_corresponding_pubkey() {
-        type Kex = DhP256;
-
-        let sks = [
-            "C88F01F510D9AC3F70A292DAA2316DE544E9AAB8AFE84049C62A9C57862D1433",
-            "C6EF9C5D78AE012A011164ACB397CE2088685D8F06BF9BE0B283AB46476BEE53",
-        ];
-        let pks = [
-            concat!(
-                "04",                                                               // Uncompressed
-                "DAD0B65394221CF9B051E1FECA5787D098DFE637FC90B9EF945D0C3772581180", // x-coordinate
-                "5271A0461CDB8252D61F1C456FA3E59AB1F45B33ACCF5F58389E0577B8990BB3"  // y-coordinate
-            ),
-            concat!(
-                "04",                                                               // Uncompressed
-                "D12DFB5289C8D4F81208B70270398C342296970A0BCCB74C736FC7554494BF63", // x-coordinate
-                "56FBF3CA366CC23E8157854C13C58D6AAC23F046ADA30F8353E74F33039872AB", // y-coordinate
-            ),
-        ];
-
+    #[allow(dead_code)]
+    fn test_vector_corresponding_pubkey<Kex: KeyExchange>(sks: &[&str], pks: &[&str]) {
         for (sk_hex, pk_hex) in sks.iter().zip(pks.iter()) {
             // Unmarshal the hex values
             let sk =
@@ -274,34 +446,30 @@ mod tests {
             // Derive the secret key's corresponding pubkey and check that it matches the given
             // pubkey
             let derived_pk = <Kex as KeyExchange>::sk_to_pk(&sk);
-            assert_eq!(derived_pk, pk);
+            assert_eq!(derived_pk.marshal(), pk.marshal());
         }
     }
 
     /// Tests that an unmarshal-marshal round-trip ends up at the same pubkey
-    #[test]
-    fn test_pubkey_marshal_correctness() {
-        type Kex = DhP256;
-
+    #[allow(dead_code)]
+    fn test_pubkey_marshal_correctness<Kex: KeyExchange>() {
         let mut csprng = StdRng::from_entropy();
 
         // We can't do the same thing as in the X25519 tests, since a completely random point is
         // not likely to lie on the curve. Instead, we just generate a random point, marshal it,
-        // unmarshal it, and test whether it's the same using impl Eq for AffinePoint
+        // unmarshal it, and test whether it's the same by comparing the re-marshalled bytes
 
         let (_, pubkey) = <Kex as KeyExchange>::gen_keypair(&mut csprng);
         let pubkey_bytes = pubkey.marshal();
         let rederived_pubkey = <Kex as KeyExchange>::PublicKey::unmarshal(&pubkey_bytes).unwrap();
 
         // See if the re-marshalled bytes are the same as the input
-        assert_eq!(pubkey, rederived_pubkey);
+        assert_eq!(pubkey_bytes, rederived_pubkey.marshal());
     }
 
     /// Tests that an unmarshal-marshal round-trip on a DH keypair ends up at the same values
-    #[test]
-    fn test_dh_marshal_correctness() {
-        type Kex = DhP256;
-
+    #[allow(dead_code)]
+    fn test_dh_marshal_correctness<Kex: KeyExchange>() {
         let mut csprng = StdRng::from_entropy();
 
         // Make a random keypair and marshal it
@@ -313,7 +481,105 @@ mod tests {
         let new_pk = <Kex as KeyExchange>::PublicKey::unmarshal(&pk_bytes).unwrap();
 
         // See if the unmarshalled values are the same as the initial ones
-        assert!(new_sk == sk, "private key doesn't marshal correctly");
-        assert!(new_pk == pk, "public key doesn't marshal correctly");
+        assert!(
+            new_sk.marshal() == sk_bytes,
+            "private key doesn't marshal correctly"
+        );
+        assert!(
+            new_pk.marshal() == pk_bytes,
+            "public key doesn't marshal correctly"
+        );
+    }
+
+    /// Tests that private keys of zero, and private keys that are not reduced mod p, are rejected
+    #[allow(dead_code)]
+    fn test_privkey_range<Kex: KeyExchange>() {
+        let sk_size = <Kex as KeyExchange>::PrivateKey::size();
+
+        // Zero is not a valid private key
+        let zeros = vec![0u8; sk_size];
+        assert!(<Kex as KeyExchange>::PrivateKey::unmarshal(&zeros).is_err());
+
+        // All ones is larger than the group order for all the NIST curves
+        let ones = vec![0xFFu8; sk_size];
+        assert!(<Kex as KeyExchange>::PrivateKey::unmarshal(&ones).is_err());
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_vector_ecdh_p256() {
+        test_vector_ecdh::<DhP256>(P256_PRIVKEYS[0], P256_PUBKEYS[1], P256_DH_RES_XCOORD);
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn test_vector_ecdh_p384() {
+        test_vector_ecdh::<DhP384>(P384_PRIVKEYS[0], P384_PUBKEYS[1], P384_DH_RES_XCOORD);
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_vector_corresponding_pubkey_p256() {
+        test_vector_corresponding_pubkey::<DhP256>(P256_PRIVKEYS, P256_PUBKEYS);
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn test_vector_corresponding_pubkey_p384() {
+        test_vector_corresponding_pubkey::<DhP384>(P384_PRIVKEYS, P384_PUBKEYS);
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_pubkey_marshal_correctness_p256() {
+        test_pubkey_marshal_correctness::<DhP256>();
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn test_pubkey_marshal_correctness_p384() {
+        test_pubkey_marshal_correctness::<DhP384>();
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_pubkey_marshal_correctness_p521() {
+        test_pubkey_marshal_correctness::<DhP521>();
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_dh_marshal_correctness_p256() {
+        test_dh_marshal_correctness::<DhP256>();
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn test_dh_marshal_correctness_p384() {
+        test_dh_marshal_correctness::<DhP384>();
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_dh_marshal_correctness_p521() {
+        test_dh_marshal_correctness::<DhP521>();
+    }
+
+    #[cfg(feature = "p256")]
+    #[test]
+    fn test_privkey_range_p256() {
+        test_privkey_range::<DhP256>();
+    }
+
+    #[cfg(feature = "p384")]
+    #[test]
+    fn test_privkey_range_p384() {
+        test_privkey_range::<DhP384>();
+    }
+
+    #[cfg(feature = "p521")]
+    #[test]
+    fn test_privkey_range_p521() {
+        test_privkey_range::<DhP521>();
     }
 }
@@ -1,4 +1,5 @@
 use crate::{
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
     kex::{KeyExchange, Marshallable, Unmarshallable},
     HpkeError,
 };
@@ -67,8 +68,13 @@ impl Unmarshallable for PrivateKey {
     }
 }
 
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PublicKey);
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PrivateKey);
+
 impl Marshallable for KexResult {
-    // §7.1: DHKEM(Curve25519) Nzz = 32
+    // RFC 9180 §4.1: Ndh of X25519 is 32
     type OutputSize = typenum::U32;
 
     // Dalek lets us convert shared secrets to to [u8; 32]
@@ -93,6 +99,31 @@ impl KeyExchange for X25519 {
         (PrivateKey(sk), PublicKey(pk))
     }
 
+    // RFC 9180 §7.1.3
+    // def DeriveKeyPair(ikm):
+    //   dkp_prk = LabeledExtract("", "dkp_prk", ikm)
+    //   sk = LabeledExpand(dkp_prk, "sk", "", Nsk)
+    //   return (sk, pk(sk))
+    /// Deterministically derives an X25519 keypair from the given input keying material and KEM
+    /// suite ID. The keying material SHOULD have as many bits of entropy as the bit length of a
+    /// secret key, i.e., 256.
+    fn derive_keypair<Kdf: KdfTrait>(suite_id: &[u8], ikm: &[u8]) -> (PrivateKey, PublicKey) {
+        // Write the label into a byte buffer and extract from the IKM
+        let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
+        // The buffer we hold the candidate scalar bytes in. This is the size of a private key.
+        let mut buf = [0u8; 32];
+        // This only errors if the output length is 255x the digest size of the hash function.
+        // Since the length is fixed at compile time, we don't worry about it.
+        hkdf_ctx
+            .labeled_expand(suite_id, b"sk", b"", &mut buf)
+            .expect("privkey len is way too big");
+
+        let sk = PrivateKey(x25519_dalek::StaticSecret::from(buf));
+        let pk = Self::sk_to_pk(&sk);
+
+        (sk, pk)
+    }
+
     /// Converts an X25519 private key to a public key
     fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
         PublicKey(x25519_dalek::PublicKey::from(&sk.0))
@@ -0,0 +1,294 @@
+use crate::{
+    kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
+    kex::{KeyExchange, Marshallable, Unmarshallable},
+    HpkeError,
+};
+
+use digest::generic_array::{typenum, GenericArray};
+use rand::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+
+// We wrap the types in order to abstract away the x448 dep
+
+/// An X448 public key
+pub struct PublicKey(::x448::PublicKey);
+/// An X448 private key
+pub struct PrivateKey(::x448::Secret);
+
+// A bare DH computation result
+pub struct KexResult(::x448::SharedSecret);
+
+// The x448 crate doesn't derive Clone for its types, so we round-trip through bytes. Neither
+// conversion can fail: the lengths are fixed, and re-clamping an already-clamped secret is a no-op.
+impl Clone for PublicKey {
+    fn clone(&self) -> PublicKey {
+        PublicKey(::x448::PublicKey::from_bytes_unchecked(self.0.as_bytes()).unwrap())
+    }
+}
+
+impl Clone for PrivateKey {
+    fn clone(&self) -> PrivateKey {
+        PrivateKey(::x448::Secret::from(*self.0.as_bytes()))
+    }
+}
+
+impl Marshallable for PublicKey {
+    type OutputSize = typenum::U56;
+
+    // The x448 crate lets us convert pubkeys to [u8; 56]
+    fn marshal(&self) -> GenericArray<u8, typenum::U56> {
+        GenericArray::clone_from_slice(self.0.as_bytes())
+    }
+}
+
+impl Unmarshallable for PublicKey {
+    // The x448 crate also lets us convert &[u8] to pubkeys. We don't reject low-order points here.
+    // Like with X25519, bad points are caught when they're used in kex().
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        if encoded.len() != Self::size() {
+            // Pubkeys must be 56 bytes
+            Err(HpkeError::InvalidEncoding)
+        } else {
+            // This only fails if the length is wrong, which we just checked
+            let pk = ::x448::PublicKey::from_bytes_unchecked(encoded)
+                .ok_or(HpkeError::InvalidEncoding)?;
+            Ok(PublicKey(pk))
+        }
+    }
+}
+
+impl Marshallable for PrivateKey {
+    type OutputSize = typenum::U56;
+
+    // The x448 crate lets us convert scalars to [u8; 56]
+    fn marshal(&self) -> GenericArray<u8, typenum::U56> {
+        GenericArray::clone_from_slice(self.0.as_bytes())
+    }
+}
+impl Unmarshallable for PrivateKey {
+    // The x448 crate also lets us convert &[u8] to scalars. Note that this clamps the scalar.
+    fn unmarshal(encoded: &[u8]) -> Result<Self, HpkeError> {
+        if encoded.len() != Self::size() {
+            // Privkeys must be 56 bytes
+            Err(HpkeError::InvalidEncoding)
+        } else {
+            // This only fails if the length is wrong, which we just checked
+            let sk = ::x448::Secret::from_bytes(encoded).ok_or(HpkeError::InvalidEncoding)?;
+            Ok(PrivateKey(sk))
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PublicKey);
+#[cfg(feature = "serde")]
+impl_serde_via_marshal!(PrivateKey);
+
+impl Marshallable for KexResult {
+    // RFC 9180 §4.1: Ndh of X448 is 56
+    type OutputSize = typenum::U56;
+
+    // The x448 crate lets us convert shared secrets to to [u8; 56]
+    fn marshal(&self) -> GenericArray<u8, typenum::U56> {
+        GenericArray::clone_from_slice(self.0.as_bytes())
+    }
+}
+
+/// Dummy type which implements the `KeyExchange` trait
+pub struct X448 {}
+
+impl KeyExchange for X448 {
+    type PublicKey = PublicKey;
+    type PrivateKey = PrivateKey;
+    type KexResult = KexResult;
+
+    /// Generates an X448 keypair
+    fn gen_keypair<R: CryptoRng + RngCore>(csprng: &mut R) -> (PrivateKey, PublicKey) {
+        let sk = PrivateKey(::x448::Secret::new(csprng));
+        let pk = Self::sk_to_pk(&sk);
+
+        (sk, pk)
+    }
+
+    // RFC 9180 §7.1.3
+    // def DeriveKeyPair(ikm):
+    //   dkp_prk = LabeledExtract("", "dkp_prk", ikm)
+    //   sk = LabeledExpand(dkp_prk, "sk", "", Nsk)
+    //   return (sk, pk(sk))
+    /// Deterministically derives an X448 keypair from the given input keying material and KEM
+    /// suite ID. The keying material SHOULD have as many bits of entropy as the bit length of a
+    /// secret key, i.e., 448.
+    fn derive_keypair<Kdf: KdfTrait>(suite_id: &[u8], ikm: &[u8]) -> (PrivateKey, PublicKey) {
+        // Write the label into a byte buffer and extract from the IKM
+        let (_, hkdf_ctx) = labeled_extract::<Kdf>(&[], suite_id, b"dkp_prk", ikm);
+        // The buffer we hold the candidate scalar bytes in. This is the size of a private key.
+        let mut buf = [0u8; 56];
+        // This only errors if the output length is 255x the digest size of the hash function.
+        // Since the length is fixed at compile time, we don't worry about it.
+        hkdf_ctx
+            .labeled_expand(suite_id, b"sk", b"", &mut buf)
+            .expect("privkey len is way too big");
+
+        let sk = PrivateKey(::x448::Secret::from(buf));
+        let pk = Self::sk_to_pk(&sk);
+
+        (sk, pk)
+    }
+
+    /// Converts an X448 private key to a public key
+    fn sk_to_pk(sk: &PrivateKey) -> PublicKey {
+        PublicKey(::x448::PublicKey::from(&sk.0))
+    }
+
+    /// Does the DH operation. Returns `HpkeError::InvalidKeyExchange` if and only if the DH
+    /// result was all zeros. This is required by the HPKE spec.
+    fn kex(sk: &PrivateKey, pk: &PublicKey) -> Result<KexResult, HpkeError> {
+        // The x448 crate refuses to do a DH with a low-order point. That would produce the all-zero
+        // output anyway, so we treat it the same way.
+        let res =
+            sk.0.as_diffie_hellman(&pk.0)
+                .ok_or(HpkeError::InvalidKeyExchange)?;
+        // "Senders and recipients MUST check whether the shared secret is the all-zero value
+        // and abort if so"
+        if res.as_bytes().ct_eq(&[0u8; 56]).into() {
+            Err(HpkeError::InvalidKeyExchange)
+        } else {
+            Ok(KexResult(res))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::kex::{
+        x448::{PrivateKey, PublicKey, X448},
+        KeyExchange, Marshallable, Unmarshallable,
+    };
+    use crate::HpkeError;
+    use rand::{rngs::StdRng, RngCore, SeedableRng};
+
+    // We need this in our marshal-unmarshal tests
+    impl PartialEq for PrivateKey {
+        fn eq(&self, other: &PrivateKey) -> bool {
+            self.0.as_bytes() == other.0.as_bytes()
+        }
+    }
+
+    // We need this in our marshal-unmarshal tests
+    impl PartialEq for PublicKey {
+        fn eq(&self, other: &PublicKey) -> bool {
+            self.0.as_bytes() == other.0.as_bytes()
+        }
+    }
+
+    // Test vectors come from §6.2 of RFC7748
+    // https://tools.ietf.org/html/rfc7748
+    const ALICE_PRIVKEY: &str = concat!(
+        "9a8f4925d1519f5775cf46b04b5800d4ee9ee8bae8bc5565d498c28dd9c9baf5",
+        "74a9419744897391006382a6f127ab1d9ac2d8c0a598726b",
+    );
+    const ALICE_PUBKEY: &str = concat!(
+        "9b08f7cc31b7e3e67d22d5aea121074a273bd2b83de09c63faa73d2c22c5d9bb",
+        "c836647241d953d40c5b12da88120d53177f80e532c41fa0",
+    );
+    const BOB_PRIVKEY: &str = concat!(
+        "1c306a7ac2a0e2e0990b294470cba339e6453772b075811d8fad0d1d6927c120",
+        "bb5ee8972b0d3e21374c9c921b09d1b0366f10b65173992d",
+    );
+    const BOB_PUBKEY: &str = concat!(
+        "3eb7a829b0cd20f5bcfc0b599b6feccf6da4627107bdb0d4f345b43027d8b972",
+        "fc3e34fb4232a13ca706dcb57aec3dae07bdc1c67bf33609",
+    );
+    const SHARED_SECRET: &str = concat!(
+        "07fff4181ac6cc95ec1c16a94a0f74d12da232ce40a77552281d282bb60c0b56",
+        "fd2464c335543936521c24403085d59a449a5037514a879d",
+    );
+
+    /// Tests the DH op and `sk_to_pk` against known answers
+    #[test]
+    fn test_vector_x448() {
+        type Kex = X448;
+
+        let sk_alice =
+            <Kex as KeyExchange>::PrivateKey::unmarshal(&hex::decode(ALICE_PRIVKEY).unwrap())
+                .unwrap();
+        let sk_bob =
+            <Kex as KeyExchange>::PrivateKey::unmarshal(&hex::decode(BOB_PRIVKEY).unwrap())
+                .unwrap();
+
+        // Check that the pubkeys are derived correctly
+        let pk_alice = <Kex as KeyExchange>::sk_to_pk(&sk_alice);
+        let pk_bob = <Kex as KeyExchange>::sk_to_pk(&sk_bob);
+        assert_eq!(hex::encode(pk_alice.marshal()), ALICE_PUBKEY);
+        assert_eq!(hex::encode(pk_bob.marshal()), BOB_PUBKEY);
+
+        // Check that both sides of the DH arrive at the known shared secret
+        let dh_alice = <Kex as KeyExchange>::kex(&sk_alice, &pk_bob).unwrap();
+        let dh_bob = <Kex as KeyExchange>::kex(&sk_bob, &pk_alice).unwrap();
+        assert_eq!(hex::encode(dh_alice.marshal()), SHARED_SECRET);
+        assert_eq!(hex::encode(dh_bob.marshal()), SHARED_SECRET);
+    }
+
+    /// Tests that doing a DH with a low-order point is rejected
+    #[test]
+    fn test_low_order_kex() {
+        type Kex = X448;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk, _) = Kex::gen_keypair(&mut csprng);
+
+        // The all-zero point, and the point with u-coordinate 1, are both low order
+        let mut one = [0u8; 56];
+        one[0] = 1;
+        for bad_point in [[0u8; 56], one].iter() {
+            let pk = <Kex as KeyExchange>::PublicKey::unmarshal(bad_point).unwrap();
+            assert!(matches!(
+                <Kex as KeyExchange>::kex(&sk, &pk),
+                Err(HpkeError::InvalidKeyExchange)
+            ));
+        }
+    }
+
+    /// Tests that an unmarshal-marshal round-trip ends up at the same pubkey
+    #[test]
+    fn test_pubkey_marshal_correctness() {
+        type Kex = X448;
+
+        let mut csprng = StdRng::from_entropy();
+
+        // Fill a buffer with randomness
+        let orig_bytes = {
+            let mut buf = vec![0u8; <Kex as KeyExchange>::PublicKey::size()];
+            csprng.fill_bytes(buf.as_mut_slice());
+            buf
+        };
+
+        // Make a pubkey with those random bytes. Note, that unmarshal does not clamp the input
+        // bytes. This is why this test passes.
+        let pk = <Kex as KeyExchange>::PublicKey::unmarshal(&orig_bytes).unwrap();
+        let pk_bytes = pk.marshal();
+
+        // See if the re-marshalled bytes are the same as the input
+        assert_eq!(orig_bytes.as_slice(), pk_bytes.as_slice());
+    }
+
+    /// Tests that an unmarshal-marshal round-trip on a DH keypair ends up at the same values
+    #[test]
+    fn test_dh_marshal_correctness() {
+        type Kex = X448;
+
+        let mut csprng = StdRng::from_entropy();
+
+        // Make a random keypair and marshal it
+        let (sk, pk) = Kex::gen_keypair(&mut csprng);
+        let (sk_bytes, pk_bytes) = (sk.marshal(), pk.marshal());
+
+        // Now unmarshal those bytes
+        let new_sk = <Kex as KeyExchange>::PrivateKey::unmarshal(&sk_bytes).unwrap();
+        let new_pk = <Kex as KeyExchange>::PublicKey::unmarshal(&pk_bytes).unwrap();
+
+        // See if the unmarshalled values are the same as the initial ones
+        assert!(new_sk == sk, "private key doesn't marshal correctly");
+        assert!(new_pk == pk, "public key doesn't marshal correctly");
+    }
+}
@@ -18,33 +18,55 @@ pub use digest::generic_array;
 
 // kat_tests tests all the implemented ciphersuites, and thus needs all the dependencies. It also
 // needs std for file IO.
-#[cfg(all(test, feature = "std", feature = "x25519-dalek", feature = "p256"))]
+#[cfg(all(
+    test,
+    feature = "std",
+    feature = "x25519-dalek",
+    feature = "x448",
+    feature = "p256",
+    feature = "p384",
+    feature = "p521",
+    feature = "ml-kem",
+    feature = "xwing"
+))]
 mod kat_tests;
 
-// kat_tests requires serde
-#[cfg(all(test, feature = "std", feature = "x25519-dalek", feature = "p256"))]
-#[macro_use]
-extern crate serde_derive;
-
 #[cfg(test)]
 mod test_util;
 
 //-------- Modules and exports--------//
 
+// This has to come first, since it defines a macro the other modules use
+#[cfg(feature = "serde")]
+#[macro_use]
+mod serde_impls;
+
 pub mod aead;
+pub mod agility;
+#[cfg(feature = "cose")]
+pub mod cose;
+pub mod ech;
+#[cfg(feature = "jose")]
+pub mod jose;
 pub mod kdf;
 pub mod kem;
 pub mod kex;
+pub mod mls;
+pub mod multi_recipient;
+pub mod ohttp;
 pub mod op_mode;
 mod prelude;
+pub mod ratchet;
 pub mod setup;
 pub mod single_shot;
+pub mod stream;
+mod suite;
 mod util;
 
 #[doc(inline)]
 pub use crate::aead::{AeadCtxR, AeadCtxS};
 #[doc(inline)]
-pub use kem::{EncappedKey, Kem};
+pub use kem::{Kem, SharedSecret};
 #[doc(inline)]
 pub use kex::{KeyExchange, Marshallable, Unmarshallable};
 #[doc(inline)]
@@ -52,7 +74,11 @@ pub use op_mode::{OpModeR, OpModeS, Psk, PskBundle};
 #[doc(inline)]
 pub use setup::{setup_receiver, setup_sender};
 #[doc(inline)]
-pub use single_shot::{single_shot_open, single_shot_seal};
+pub use single_shot::{
+    single_shot_open, single_shot_open_to_vec, single_shot_seal, single_shot_seal_to_vec,
+};
+#[doc(inline)]
+pub use suite::{Hpke, ReceiverBuilder, SenderBuilder};
 
 //-------- Top-level types --------//
 
@@ -71,6 +97,22 @@ pub enum HpkeError {
     InvalidKdfLength,
     /// The unmarshaller was given a bad encoding
     InvalidEncoding,
+    /// An Auth or AuthPsk mode was used with a KEM that has no authenticated encapsulation
+    AuthNotSupported,
+    /// A multi-recipient ciphertext had no entry for the given private key
+    RecipientNotFound,
+    /// A stream chunk had the wrong length for its position in the stream
+    InvalidChunkSize,
+    /// A stream ended before its last chunk
+    StreamTruncated,
+    /// A sequence number was already opened, or is too old for the replay window
+    Replayed,
+    /// A sequence number was moved backward
+    InvalidSeq,
+    /// A serialized context or message was made under a different ciphersuite than the one in use
+    SuiteMismatch,
+    /// An encryption context has used up its message or byte limit
+    LimitExceeded,
 }
 
 impl core::fmt::Display for HpkeError {
@@ -82,6 +124,14 @@ impl core::fmt::Display for HpkeError {
             HpkeError::InvalidKeyExchange => "Key exchange validation error",
             HpkeError::InvalidKdfLength => "Too many bytes requested from KDF",
             HpkeError::InvalidEncoding => "Cannot marshal byte sequence: invalid encoding",
+            HpkeError::AuthNotSupported => "KEM does not support authenticated encapsulation",
+            HpkeError::RecipientNotFound => "No entry for this recipient",
+            HpkeError::InvalidChunkSize => "Invalid stream chunk size",
+            HpkeError::StreamTruncated => "Stream ended before its last chunk",
+            HpkeError::Replayed => "Replayed or stale sequence number",
+            HpkeError::InvalidSeq => "Sequence number cannot move backward",
+            HpkeError::SuiteMismatch => "Made under a different ciphersuite than the one in use",
+            HpkeError::LimitExceeded => "Context usage limit exceeded",
         };
         f.write_str(kind)
     }
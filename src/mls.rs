@@ -0,0 +1,446 @@
+//! The HPKE constructions of MLS, RFC 9420 §5.1.3. MLS encrypts Welcome secrets and path secrets
+//! with `EncryptWithLabel`, which is a Base mode single-shot seal whose info string binds a label
+//! and a context.
+//!
+//! MLS ciphersuites use the following HPKE algorithms:
+//!
+//! | MLS ciphersuite | KEM                | KDF          | AEAD               |
+//! |-----------------|--------------------|--------------|--------------------|
+//! | 0x0001          | `X25519HkdfSha256` | `HkdfSha256` | `AesGcm128`        |
+//! | 0x0002          | `DhP256HkdfSha256` | `HkdfSha256` | `AesGcm128`        |
+//! | 0x0003          | `X25519HkdfSha256` | `HkdfSha256` | `ChaCha20Poly1305` |
+//! | 0x0004          | `X448HkdfSha512`   | `HkdfSha512` | `AesGcm256`        |
+//! | 0x0005          | `DhP521HkdfSha512` | `HkdfSha512` | `AesGcm256`        |
+//! | 0x0006          | `X448HkdfSha512`   | `HkdfSha512` | `ChaCha20Poly1305` |
+//! | 0x0007          | `DhP384HkdfSha384` | `HkdfSha384` | `AesGcm256`        |
+
+use crate::prelude::*;
+use crate::{
+    aead::{AeadCipher, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Marshallable, Unmarshallable},
+    op_mode::{OpModeR, OpModeS},
+    single_shot::{single_shot_open, single_shot_seal},
+    HpkeError,
+};
+
+use rand::{CryptoRng, RngCore};
+
+// RFC 9420 §5.1.3: Every label is prefixed with this
+const LABEL_PREFIX: &[u8] = b"MLS 1.0 ";
+
+// RFC 9420 §2.1.2: Variable-size vector lengths are at most 30 bits
+const MAX_VARINT: usize = (1 << 30) - 1;
+
+// RFC 9420 §2.1.2
+/// Writes `bytes` as an `opaque<V>`, i.e., prefixed with its length as an MLS varint
+///
+/// Panics
+/// ======
+/// Panics if `bytes` is 2^30 bytes or longer
+fn write_opaque_v(out: &mut Vec<u8>, bytes: &[u8]) {
+    let len = bytes.len();
+    assert!(len <= MAX_VARINT, "vector too long for an MLS varint");
+
+    // The top two bits of the first byte say how long the varint is
+    if len < 1 << 6 {
+        out.push(len as u8);
+    } else if len < 1 << 14 {
+        out.extend_from_slice(&(0x4000 | len as u16).to_be_bytes());
+    } else {
+        out.extend_from_slice(&(0x8000_0000 | len as u32).to_be_bytes());
+    }
+    out.extend_from_slice(bytes);
+}
+
+// Reads an MLS varint off the front of `bytes` and returns it along with the rest of the bytes.
+// Fails on the reserved 8-byte prefix and on encodings that aren't as short as possible.
+fn read_varint(bytes: &[u8]) -> Result<(usize, &[u8]), HpkeError> {
+    let first = *bytes.first().ok_or(HpkeError::InvalidEncoding)?;
+    let len = match first >> 6 {
+        0 => 1,
+        1 => 2,
+        2 => 4,
+        _ => return Err(HpkeError::InvalidEncoding),
+    };
+    if bytes.len() < len {
+        return Err(HpkeError::InvalidEncoding);
+    }
+
+    let mut value = (first & 0x3F) as usize;
+    for b in &bytes[1..len] {
+        value = (value << 8) | *b as usize;
+    }
+
+    // RFC 9420 §2.1.2: the length MUST be encoded in the smallest number of bytes possible
+    let min_len = if value < 1 << 6 {
+        1
+    } else if value < 1 << 14 {
+        2
+    } else {
+        4
+    };
+    if len != min_len {
+        return Err(HpkeError::InvalidEncoding);
+    }
+
+    Ok((value, &bytes[len..]))
+}
+
+// Reads an opaque<V> off the front of `bytes` and returns it along with the rest of the bytes
+fn read_opaque_v(bytes: &[u8]) -> Result<(&[u8], &[u8]), HpkeError> {
+    let (len, rest) = read_varint(bytes)?;
+    if rest.len() < len {
+        return Err(HpkeError::InvalidEncoding);
+    }
+
+    Ok(rest.split_at(len))
+}
+
+// RFC 9420 §5.1.3
+// struct {
+//   opaque label<V>;
+//   opaque context<V>;
+// } EncryptContext;
+//
+// And its fields are:
+//   label = "MLS 1.0 " + Label;
+//   context = Context;
+/// Returns the TLS-encoded `EncryptContext` for the given label and context. This is the info
+/// string of `EncryptWithLabel`.
+fn encrypt_context(label: &[u8], context: &[u8]) -> Vec<u8> {
+    let full_label = [LABEL_PREFIX, label].concat();
+    let mut out = Vec::with_capacity(full_label.len() + context.len() + 8);
+    write_opaque_v(&mut out, &full_label);
+    write_opaque_v(&mut out, context);
+    out
+}
+
+// RFC 9420 §5.1.3
+// struct {
+//   opaque kem_output<V>;
+//   opaque ciphertext<V>;
+// } HPKECiphertext;
+/// The output of `EncryptWithLabel`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HpkeCiphertext {
+    /// The marshalled encapsulated key
+    pub kem_output: Vec<u8>,
+    /// The AEAD output, i.e., `ciphertext || tag`
+    pub ciphertext: Vec<u8>,
+}
+
+impl HpkeCiphertext {
+    /// Serializes this in the TLS presentation language
+    ///
+    /// Panics
+    /// ======
+    /// Panics if either field is 2^30 bytes or longer
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.kem_output.len() + self.ciphertext.len() + 8);
+        write_opaque_v(&mut out, &self.kem_output);
+        write_opaque_v(&mut out, &self.ciphertext);
+        out
+    }
+
+    /// Parses an `HPKECiphertext` in the TLS presentation language
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext)` on success. If the encoding is malformed, a length isn't
+    /// minimally encoded, or there are trailing bytes, returns `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<HpkeCiphertext, HpkeError> {
+        let (kem_output, rest) = read_opaque_v(bytes)?;
+        let (ciphertext, rest) = read_opaque_v(rest)?;
+        if !rest.is_empty() {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        Ok(HpkeCiphertext {
+            kem_output: kem_output.to_vec(),
+            ciphertext: ciphertext.to_vec(),
+        })
+    }
+}
+
+// RFC 9420 §5.1.3
+// def EncryptWithLabel(PublicKey, Label, Context, Plaintext):
+//   return SealBase(PublicKey, EncryptContext, "", Plaintext)
+/// Encrypts `plaintext` to `pk_recip` under the given MLS label (without the `"MLS 1.0 "` prefix)
+/// and context
+///
+/// Return Value
+/// ============
+/// Returns `Ok(ciphertext)` on success. If an error happened during key exchange, returns
+/// `Err(HpkeError::InvalidKeyExchange)`. If an unspecified error happened during encryption,
+/// returns `Err(HpkeError::Encryption)`.
+pub fn encrypt_with_label<A, Kdf, Kem, R>(
+    pk_recip: &Kem::PublicKey,
+    label: &[u8],
+    context: &[u8],
+    plaintext: &[u8],
+    csprng: &mut R,
+) -> Result<HpkeCiphertext, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    let info = encrypt_context(label, context);
+    let mut ciphertext = plaintext.to_vec();
+    let (encapped_key, tag) = single_shot_seal::<A, Kdf, Kem, R>(
+        &OpModeS::Base,
+        pk_recip,
+        &info,
+        &mut ciphertext,
+        b"",
+        csprng,
+    )?;
+    ciphertext.extend_from_slice(&tag.marshal());
+
+    Ok(HpkeCiphertext {
+        kem_output: encapped_key.marshal().to_vec(),
+        ciphertext,
+    })
+}
+
+// RFC 9420 §5.1.3
+// def DecryptWithLabel(PrivateKey, Label, Context, KEMOutput, Ciphertext):
+//   return OpenBase(KEMOutput, PrivateKey, EncryptContext, "", Ciphertext)
+/// Decrypts a ciphertext made by `encrypt_with_label` with the same label and context
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If `kem_output` doesn't unmarshal or `ciphertext` is
+/// shorter than a tag, returns `Err(HpkeError::InvalidEncoding)`. If an error happened during key
+/// exchange, returns `Err(HpkeError::InvalidKeyExchange)`. If decryption fails, returns
+/// `Err(HpkeError::InvalidTag)`.
+pub fn decrypt_with_label<A, Kdf, Kem>(
+    sk_recip: &Kem::PrivateKey,
+    label: &[u8],
+    context: &[u8],
+    ciphertext: &HpkeCiphertext,
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let encapped_key = Kem::EncappedKey::unmarshal(&ciphertext.kem_output)?;
+    let tag_size = AeadTag::<A>::size();
+    if ciphertext.ciphertext.len() < tag_size {
+        return Err(HpkeError::InvalidEncoding);
+    }
+    let (payload, tag) = ciphertext
+        .ciphertext
+        .split_at(ciphertext.ciphertext.len() - tag_size);
+    let tag = AeadTag::<A>::unmarshal(tag)?;
+
+    let info = encrypt_context(label, context);
+    let mut plaintext = payload.to_vec();
+    single_shot_open::<A, Kdf, Kem>(
+        &OpModeR::Base,
+        sk_recip,
+        &encapped_key,
+        &info,
+        &mut plaintext,
+        b"",
+        &tag,
+    )?;
+
+    Ok(plaintext)
+}
+
+// Every MLS ciphersuite uses a DHKEM
+#[cfg(all(
+    test,
+    any(
+        feature = "x25519-dalek",
+        feature = "x448",
+        feature = "p256",
+        feature = "p384",
+        feature = "p521"
+    )
+))]
+mod test {
+    use super::{
+        decrypt_with_label, encrypt_context, encrypt_with_label, read_varint, write_opaque_v,
+        HpkeCiphertext,
+    };
+    use crate::prelude::*;
+    use crate::{aead::*, kdf::*, kem::*, HpkeError};
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// Tests varint lengths against the examples in RFC 9000 §A.1, which MLS shares
+    #[test]
+    fn test_mls_varint() {
+        assert_eq!(read_varint(&[0x25]).unwrap().0, 37);
+        assert_eq!(read_varint(&[0x7b, 0xbd]).unwrap().0, 15293);
+        assert_eq!(read_varint(&[0x9d, 0x7f, 0x3e, 0x7d]).unwrap().0, 494878333);
+
+        // 37 in two bytes is fine for QUIC, but MLS requires the shortest encoding
+        assert!(matches!(
+            read_varint(&[0x40, 0x25]),
+            Err(HpkeError::InvalidEncoding)
+        ));
+        // The 8-byte prefix is reserved
+        assert!(read_varint(&[0xc2, 0, 0, 0, 0, 0, 0, 0]).is_err());
+
+        // Writing picks the shortest encoding
+        for &len in &[0usize, 63, 64, 16383, 16384] {
+            let mut out = Vec::new();
+            write_opaque_v(&mut out, &vec![0u8; len]);
+            let (read_len, rest) = read_varint(&out).unwrap();
+            assert_eq!(read_len, len);
+            assert_eq!(rest.len(), len);
+        }
+    }
+
+    /// Tests the EncryptContext encoding and the HPKECiphertext round trip
+    #[test]
+    fn test_mls_encoding() {
+        // "MLS 1.0 UpdatePathNode" is 22 bytes
+        let expected = [&[22u8][..], b"MLS 1.0 UpdatePathNode", &[3u8], b"ctx"].concat();
+        assert_eq!(encrypt_context(b"UpdatePathNode", b"ctx"), expected);
+
+        let ct = HpkeCiphertext {
+            kem_output: vec![1u8; 32],
+            ciphertext: vec![2u8; 100],
+        };
+        let bytes = ct.to_bytes();
+        // 1-byte length, then a 2-byte length for the 100-byte field
+        assert_eq!(bytes.len(), 1 + 32 + 2 + 100);
+        assert_eq!(HpkeCiphertext::from_bytes(&bytes).unwrap(), ct);
+        assert!(HpkeCiphertext::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(HpkeCiphertext::from_bytes(&[bytes.clone(), vec![0]].concat()).is_err());
+    }
+
+    /// Tests that `encrypt_with_label` seals under the EncryptContext of RFC 9420 §5.1.3, written
+    /// out by hand, so that a label or context encoding bug can't hide by being the same on both
+    /// sides
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_mls_encrypt_context_binding() {
+        use crate::{
+            aead::AeadTag, kem::Kem as KemTrait, kex::Unmarshallable, op_mode::OpModeR,
+            single_shot::single_shot_open,
+        };
+
+        type A = AesGcm128;
+        type Kdf = HkdfSha256;
+        type Kem = X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let msg = b"path secret";
+        let ct = encrypt_with_label::<A, Kdf, Kem, _>(&pk, b"Welcome", b"ctx", msg, &mut csprng)
+            .unwrap();
+
+        // opaque label<V> = "MLS 1.0 Welcome", which is 15 bytes, then opaque context<V> = "ctx"
+        let info = b"\x0fMLS 1.0 Welcome\x03ctx";
+        let encapped_key = <Kem as KemTrait>::EncappedKey::unmarshal(&ct.kem_output).unwrap();
+        let (payload, tag) = ct.ciphertext.split_at(ct.ciphertext.len() - 16);
+        let tag = AeadTag::<A>::unmarshal(tag).unwrap();
+        let mut pt = payload.to_vec();
+        single_shot_open::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            &sk,
+            &encapped_key,
+            info,
+            &mut pt,
+            b"",
+            &tag,
+        )
+        .unwrap();
+        assert_eq!(&pt[..], &msg[..]);
+    }
+
+    macro_rules! test_mls_correctness {
+        ($test_name:ident, $aead_ty:ty, $kdf_ty:ty, $kem_ty:ty) => {
+            /// Tests that DecryptWithLabel inverts EncryptWithLabel, and only with the same label
+            /// and context
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+                type Kdf = $kdf_ty;
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk, pk) = Kem::gen_keypair(&mut csprng);
+
+                let label = b"Welcome";
+                let context = b"encrypted group info";
+                let msg = b"joiner secret";
+                let ct =
+                    encrypt_with_label::<A, Kdf, Kem, _>(&pk, label, context, msg, &mut csprng)
+                        .unwrap();
+                let ct = HpkeCiphertext::from_bytes(&ct.to_bytes()).unwrap();
+
+                let pt = decrypt_with_label::<A, Kdf, Kem>(&sk, label, context, &ct).unwrap();
+                assert_eq!(&pt[..], &msg[..]);
+
+                assert!(matches!(
+                    decrypt_with_label::<A, Kdf, Kem>(&sk, b"UpdatePathNode", context, &ct),
+                    Err(HpkeError::InvalidTag)
+                ));
+                assert!(matches!(
+                    decrypt_with_label::<A, Kdf, Kem>(&sk, label, b"", &ct),
+                    Err(HpkeError::InvalidTag)
+                ));
+            }
+        };
+    }
+
+    #[cfg(feature = "x25519-dalek")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0001,
+        AesGcm128,
+        HkdfSha256,
+        X25519HkdfSha256
+    );
+    #[cfg(feature = "p256")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0002,
+        AesGcm128,
+        HkdfSha256,
+        DhP256HkdfSha256
+    );
+    #[cfg(feature = "x25519-dalek")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0003,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        X25519HkdfSha256
+    );
+    #[cfg(feature = "x448")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0004,
+        AesGcm256,
+        HkdfSha512,
+        X448HkdfSha512
+    );
+    #[cfg(feature = "p521")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0005,
+        AesGcm256,
+        HkdfSha512,
+        DhP521HkdfSha512
+    );
+    #[cfg(feature = "x448")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0006,
+        ChaCha20Poly1305,
+        HkdfSha512,
+        X448HkdfSha512
+    );
+    #[cfg(feature = "p384")]
+    test_mls_correctness!(
+        test_mls_correctness_0x0007,
+        AesGcm256,
+        HkdfSha384,
+        DhP384HkdfSha384
+    );
+}
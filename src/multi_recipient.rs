@@ -0,0 +1,299 @@
+//! Encrypting one payload to many recipients. The payload is encrypted once under a fresh content
+//! key, and that content key is then sealed to each recipient with a Base mode HPKE context. Each
+//! recipient's entry is labeled with a fingerprint of their public key, so a recipient can find
+//! their entry without trying all of them.
+//!
+//! Note that every recipient learns the content key. So any recipient can make a new payload that
+//! the other recipients will accept as coming from the original sender. If that matters, the
+//! payload needs its own signature.
+
+use crate::prelude::*;
+use crate::{
+    aead::{AeadCipher, AeadKey, AeadNonce},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::Marshallable,
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    HpkeError,
+};
+
+use aead::{Aead as BaseAead, NewAead as BaseNewAead};
+use digest::{generic_array::GenericArray, Digest};
+use rand::{CryptoRng, RngCore};
+use subtle::ConstantTimeEq;
+use zeroize::Zeroize;
+
+/// A fingerprint of a recipient's public key. This is the KDF's hash function applied to the
+/// marshalled public key.
+pub type PkFingerprint<Kdf> = GenericArray<u8, <<Kdf as KdfTrait>::HashImpl as Digest>::OutputSize>;
+
+/// Computes the fingerprint of the given public key
+pub fn pk_fingerprint<Kdf: KdfTrait, Kem: KemTrait>(pk: &Kem::PublicKey) -> PkFingerprint<Kdf> {
+    Kdf::HashImpl::digest(&pk.marshal())
+}
+
+/// One recipient's copy of the content key
+pub struct RecipientEntry<Kdf: KdfTrait, Kem: KemTrait> {
+    /// The fingerprint of the recipient's public key
+    pub fingerprint: PkFingerprint<Kdf>,
+    /// The encapsulated key for this recipient's HPKE context
+    pub encapped_key: Kem::EncappedKey,
+    /// The content key, sealed with this recipient's HPKE context. This is `ciphertext || tag`.
+    pub wrapped_key: Vec<u8>,
+}
+
+// We can't use #[derive(Clone)] because the compiler thinks that Kdf has to be Clone
+impl<Kdf: KdfTrait, Kem: KemTrait> Clone for RecipientEntry<Kdf, Kem> {
+    fn clone(&self) -> Self {
+        RecipientEntry {
+            fingerprint: self.fingerprint.clone(),
+            encapped_key: self.encapped_key.clone(),
+            wrapped_key: self.wrapped_key.clone(),
+        }
+    }
+}
+
+/// A payload encrypted to multiple recipients
+pub struct MultiRecipientCiphertext<Kdf: KdfTrait, Kem: KemTrait> {
+    /// An entry for every recipient, in the order they were given to `multi_recipient_seal`
+    pub recipients: Vec<RecipientEntry<Kdf, Kem>>,
+    /// The payload, encrypted under the content key. This is `ciphertext || tag`.
+    pub ciphertext: Vec<u8>,
+}
+
+// We can't use #[derive(Clone)] because the compiler thinks that Kdf has to be Clone
+impl<Kdf: KdfTrait, Kem: KemTrait> Clone for MultiRecipientCiphertext<Kdf, Kem> {
+    fn clone(&self) -> Self {
+        MultiRecipientCiphertext {
+            recipients: self.recipients.clone(),
+            ciphertext: self.ciphertext.clone(),
+        }
+    }
+}
+
+impl<Kdf: KdfTrait, Kem: KemTrait> MultiRecipientCiphertext<Kdf, Kem> {
+    /// Returns the entry for the recipient with the given public key fingerprint, if there is one
+    pub fn find_recipient(
+        &self,
+        fingerprint: &PkFingerprint<Kdf>,
+    ) -> Option<&RecipientEntry<Kdf, Kem>> {
+        self.recipients
+            .iter()
+            .find(|entry| bool::from(entry.fingerprint.ct_eq(fingerprint)))
+    }
+}
+
+// The content key is only ever used for one encryption, so a fixed nonce is fine
+fn content_nonce<A: AeadCipher>() -> AeadNonce<A> {
+    AeadNonce::<A>::default()
+}
+
+/// Encrypts `plaintext` once, and gives every recipient in `pks_recip` a way to decrypt it.
+/// `info` is used as the info string of every recipient's HPKE context, and `aad` is the
+/// associated data of the payload.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(ciphertext)` on success. If an error happened during key exchange with any of the
+/// recipients, returns `Err(HpkeError::InvalidKeyExchange)`. If an unspecified error happened
+/// during encryption, returns `Err(HpkeError::Encryption)`.
+pub fn multi_recipient_seal<A, Kdf, Kem, R>(
+    pks_recip: &[Kem::PublicKey],
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<MultiRecipientCiphertext<Kdf, Kem>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Pick a fresh content key, and clear it once we're done with it, whether or not sealing
+    // succeeded
+    let mut content_key = AeadKey::<A>::default();
+    csprng.fill_bytes(content_key.as_mut_slice());
+    let res = seal_with_content_key::<A, Kdf, Kem, R>(
+        &content_key,
+        pks_recip,
+        info,
+        plaintext,
+        aad,
+        csprng,
+    );
+    content_key.as_mut_slice().zeroize();
+
+    res
+}
+
+// Does the work of multi_recipient_seal with the given content key
+fn seal_with_content_key<A, Kdf, Kem, R>(
+    content_key: &AeadKey<A>,
+    pks_recip: &[Kem::PublicKey],
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<MultiRecipientCiphertext<Kdf, Kem>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Encrypt the payload with the content key. The AEAD impl owns its copy of the key, same as in
+    // an encryption context.
+    let cipher = <A::AeadImpl as BaseNewAead>::new(content_key.clone());
+    let mut ciphertext = plaintext.to_vec();
+    let tag = cipher
+        .encrypt_in_place_detached(&content_nonce::<A>(), aad, &mut ciphertext)
+        .map_err(|_| HpkeError::Encryption)?;
+    ciphertext.extend_from_slice(&tag);
+
+    // Now seal the content key to every recipient
+    let mut recipients = Vec::with_capacity(pks_recip.len());
+    for pk_recip in pks_recip {
+        let (encapped_key, mut aead_ctx) =
+            setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, pk_recip, info, csprng)?;
+        let wrapped_key = aead_ctx.seal_to_vec(b"", content_key)?;
+
+        recipients.push(RecipientEntry {
+            fingerprint: pk_fingerprint::<Kdf, Kem>(pk_recip),
+            encapped_key,
+            wrapped_key,
+        });
+    }
+
+    Ok(MultiRecipientCiphertext {
+        recipients,
+        ciphertext,
+    })
+}
+
+/// Finds the entry for `sk_recip` in `ciphertext` and decrypts the payload. `info` and `aad` must
+/// be the same as the ones given to `multi_recipient_seal`.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If there is no entry for `sk_recip`'s public key, returns
+/// `Err(HpkeError::RecipientNotFound)`. If an error happened during key exchange, returns
+/// `Err(HpkeError::InvalidKeyExchange)`. If the content key or the payload fails to decrypt,
+/// returns `Err(HpkeError::InvalidTag)`.
+pub fn multi_recipient_open<A, Kdf, Kem>(
+    sk_recip: &Kem::PrivateKey,
+    info: &[u8],
+    ciphertext: &MultiRecipientCiphertext<Kdf, Kem>,
+    aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // Find our entry
+    let fingerprint = pk_fingerprint::<Kdf, Kem>(&Kem::sk_to_pk(sk_recip));
+    let entry = ciphertext
+        .find_recipient(&fingerprint)
+        .ok_or(HpkeError::RecipientNotFound)?;
+
+    // Unwrap the content key
+    let mut aead_ctx =
+        setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, sk_recip, &entry.encapped_key, info)?;
+    let mut content_key_bytes = aead_ctx.open_to_vec(b"", &entry.wrapped_key)?;
+    if content_key_bytes.len() != AeadKey::<A>::default().len() {
+        content_key_bytes.zeroize();
+        return Err(HpkeError::InvalidEncoding);
+    }
+    let mut content_key = AeadKey::<A>::clone_from_slice(&content_key_bytes);
+    content_key_bytes.zeroize();
+
+    // Split the payload into ciphertext || tag and decrypt it
+    let cipher = <A::AeadImpl as BaseNewAead>::new(content_key.clone());
+    content_key.as_mut_slice().zeroize();
+
+    let tag_size = crate::aead::AeadTag::<A>::size();
+    if ciphertext.ciphertext.len() < tag_size {
+        return Err(HpkeError::InvalidEncoding);
+    }
+    let (payload, tag) = ciphertext
+        .ciphertext
+        .split_at(ciphertext.ciphertext.len() - tag_size);
+
+    let mut plaintext = payload.to_vec();
+    cipher
+        .decrypt_in_place_detached(
+            &content_nonce::<A>(),
+            aad,
+            &mut plaintext,
+            GenericArray::from_slice(tag),
+        )
+        .map_err(|_| HpkeError::InvalidTag)?;
+
+    Ok(plaintext)
+}
+
+#[cfg(all(test, feature = "x25519-dalek"))]
+mod test {
+    use super::{multi_recipient_open, multi_recipient_seal, pk_fingerprint};
+    use crate::prelude::*;
+    use crate::{
+        aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, kem::X25519HkdfSha256,
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = ChaCha20Poly1305;
+    type Kdf = HkdfSha256;
+    type Kem = X25519HkdfSha256;
+
+    /// Tests that every recipient can decrypt, and that nobody else can
+    #[test]
+    fn test_multi_recipient_correctness() {
+        let mut csprng = StdRng::from_entropy();
+
+        let info = b"cc: everyone";
+        let aad = b"reply all";
+        let msg = b"please remove me from this list";
+
+        let keypairs: Vec<_> = (0..5).map(|_| Kem::gen_keypair(&mut csprng)).collect();
+        let pks: Vec<_> = keypairs.iter().map(|(_, pk)| pk.clone()).collect();
+
+        let ciphertext =
+            multi_recipient_seal::<A, Kdf, Kem, _>(&pks, info, msg, aad, &mut csprng).unwrap();
+        assert_eq!(ciphertext.recipients.len(), 5);
+
+        // Every recipient should find their own entry and decrypt
+        for (sk, pk) in keypairs.iter() {
+            let fingerprint = pk_fingerprint::<Kdf, Kem>(pk);
+            assert!(ciphertext.find_recipient(&fingerprint).is_some());
+
+            let plaintext =
+                multi_recipient_open::<A, Kdf, Kem>(sk, info, &ciphertext, aad).unwrap();
+            assert_eq!(&plaintext[..], &msg[..]);
+        }
+
+        // Someone not on the list has no entry
+        let (sk_outsider, _) = Kem::gen_keypair(&mut csprng);
+        assert!(matches!(
+            multi_recipient_open::<A, Kdf, Kem>(&sk_outsider, info, &ciphertext, aad),
+            Err(HpkeError::RecipientNotFound)
+        ));
+
+        // A modified payload shouldn't open
+        let mut bad_ciphertext = ciphertext.clone();
+        bad_ciphertext.ciphertext[0] ^= 1;
+        assert!(matches!(
+            multi_recipient_open::<A, Kdf, Kem>(&keypairs[0].0, info, &bad_ciphertext, aad),
+            Err(HpkeError::InvalidTag)
+        ));
+
+        // The wrong info string shouldn't open
+        assert!(matches!(
+            multi_recipient_open::<A, Kdf, Kem>(&keypairs[0].0, b"", &ciphertext, aad),
+            Err(HpkeError::InvalidTag)
+        ));
+    }
+}
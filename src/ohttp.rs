@@ -0,0 +1,642 @@
+//! Oblivious HTTP message encapsulation, as defined in RFC 9458. A client encapsulates a binary
+//! HTTP request to a gateway's key configuration, and the gateway encapsulates its response under
+//! keys derived from the request's HPKE context. Only Base mode is used.
+//!
+//! Key configurations are published in the `application/ohttp-keys` format, which is handled by
+//! [`parse_key_configs`] and [`serialize_key_configs`].
+
+use crate::prelude::*;
+use crate::{
+    aead::{AeadCipher, AeadCtxR, AeadCtxS, AeadKey, AeadNonce, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    kex::{Marshallable, Unmarshallable},
+    op_mode::{OpModeR, OpModeS},
+    setup::{setup_receiver, setup_sender},
+    HpkeError,
+};
+
+use aead::{Aead as BaseAead, NewAead as BaseNewAead};
+use byteorder::{BigEndian, ByteOrder};
+use digest::generic_array::GenericArray;
+use rand::{CryptoRng, RngCore};
+use zeroize::Zeroize;
+
+// RFC 9458 §4.3 and §4.4
+const REQUEST_LABEL: &[u8] = b"message/bhttp request";
+const RESPONSE_LABEL: &[u8] = b"message/bhttp response";
+
+/// The length of an encapsulated request's header: key ID, KEM ID, KDF ID, and AEAD ID
+pub const REQUEST_HEADER_LEN: usize = 7;
+
+/// A KDF and AEAD pair that a key configuration supports
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SymmetricSuite {
+    /// The HPKE KDF ID
+    pub kdf_id: u16,
+    /// The HPKE AEAD ID
+    pub aead_id: u16,
+}
+
+impl SymmetricSuite {
+    /// Returns the suite for the given AEAD and KDF
+    pub fn of<A: AeadCipher, Kdf: KdfTrait>() -> SymmetricSuite {
+        SymmetricSuite {
+            kdf_id: Kdf::KDF_ID,
+            aead_id: A::AEAD_ID,
+        }
+    }
+}
+
+// RFC 9458 §3.1
+// Key Config {
+//   Key Identifier (8),
+//   HPKE KEM ID (16),
+//   HPKE Public Key (Npk * 8),
+//   HPKE Symmetric Algorithms Length (16) = 4..65532,
+//   HPKE Symmetric Algorithms (32) ...,
+// }
+/// A gateway's public key, along with the symmetric algorithms it accepts with that key
+pub struct KeyConfig<Kem: KemTrait> {
+    /// The identifier the gateway uses to pick its private key
+    pub key_id: u8,
+    /// The gateway's public key
+    pub pk: Kem::PublicKey,
+    /// The symmetric algorithms the gateway accepts, in order of preference. This cannot be
+    /// empty.
+    pub symmetric_suites: Vec<SymmetricSuite>,
+}
+
+// We can't use #[derive(Clone)] because the compiler thinks that Kem has to be Clone
+impl<Kem: KemTrait> Clone for KeyConfig<Kem> {
+    fn clone(&self) -> Self {
+        KeyConfig {
+            key_id: self.key_id,
+            pk: self.pk.clone(),
+            symmetric_suites: self.symmetric_suites.clone(),
+        }
+    }
+}
+
+impl<Kem: KemTrait> KeyConfig<Kem> {
+    /// Returns whether this configuration accepts the given AEAD and KDF
+    pub fn supports<A: AeadCipher, Kdf: KdfTrait>(&self) -> bool {
+        self.symmetric_suites
+            .contains(&SymmetricSuite::of::<A, Kdf>())
+    }
+
+    /// Serializes this configuration in the format of RFC 9458 §3.1
+    ///
+    /// Panics
+    /// ======
+    /// Panics if `symmetric_suites` has more than 16383 entries
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let suites_len = 4 * self.symmetric_suites.len();
+        assert!(suites_len <= 65532, "too many symmetric suites");
+
+        let mut out = Vec::with_capacity(3 + Kem::PublicKey::size() + 2 + suites_len);
+        out.push(self.key_id);
+        out.extend_from_slice(&u16_bytes(Kem::KEM_ID));
+        out.extend_from_slice(&self.pk.marshal());
+        out.extend_from_slice(&u16_bytes(suites_len as u16));
+        for suite in self.symmetric_suites.iter() {
+            out.extend_from_slice(&u16_bytes(suite.kdf_id));
+            out.extend_from_slice(&u16_bytes(suite.aead_id));
+        }
+
+        out
+    }
+
+    /// Parses a configuration in the format of RFC 9458 §3.1
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(config)` on success. If the configuration's KEM is not `Kem`, returns
+    /// `Err(HpkeError::SuiteMismatch)`. If the encoding is malformed or has trailing bytes, or
+    /// the public key is invalid, returns `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes(bytes: &[u8]) -> Result<KeyConfig<Kem>, HpkeError> {
+        if bytes.len() < 3 {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let key_id = bytes[0];
+        if BigEndian::read_u16(&bytes[1..3]) != Kem::KEM_ID {
+            return Err(HpkeError::SuiteMismatch);
+        }
+
+        let rest = &bytes[3..];
+        let pk_len = Kem::PublicKey::size();
+        if rest.len() < pk_len + 2 {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let (pk_bytes, rest) = rest.split_at(pk_len);
+        let pk = Kem::PublicKey::unmarshal(pk_bytes)?;
+
+        // The symmetric algorithms are a nonempty list of 4-byte entries, and they're the last
+        // thing in the config
+        let (suites_len, suites_bytes) = rest.split_at(2);
+        let suites_len = BigEndian::read_u16(suites_len) as usize;
+        if suites_len == 0 || !suites_len.is_multiple_of(4) || suites_bytes.len() != suites_len {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let symmetric_suites = suites_bytes
+            .chunks(4)
+            .map(|suite| SymmetricSuite {
+                kdf_id: BigEndian::read_u16(&suite[0..2]),
+                aead_id: BigEndian::read_u16(&suite[2..4]),
+            })
+            .collect();
+
+        Ok(KeyConfig {
+            key_id,
+            pk,
+            symmetric_suites,
+        })
+    }
+}
+
+// RFC 9458 §3.2: The "application/ohttp-keys" format is a series of key configurations, each
+// prefixed with its 2-byte length
+/// Serializes the given configurations in the `application/ohttp-keys` format
+///
+/// Panics
+/// ======
+/// Panics if any configuration is more than 65535 bytes long
+pub fn serialize_key_configs<Kem: KemTrait>(configs: &[KeyConfig<Kem>]) -> Vec<u8> {
+    let mut out = Vec::new();
+    for config in configs {
+        let config_bytes = config.to_bytes();
+        assert!(config_bytes.len() <= 0xFFFF, "key config too long");
+        out.extend_from_slice(&u16_bytes(config_bytes.len() as u16));
+        out.extend_from_slice(&config_bytes);
+    }
+
+    out
+}
+
+/// Parses a list of configurations in the `application/ohttp-keys` format. Configurations for a
+/// KEM other than `Kem` are skipped, as RFC 9458 §3.2 says clients should ignore configurations
+/// they don't support.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(configs)` on success. If the list or any configuration for `Kem` is malformed,
+/// returns `Err(HpkeError::InvalidEncoding)`.
+pub fn parse_key_configs<Kem: KemTrait>(
+    mut bytes: &[u8],
+) -> Result<Vec<KeyConfig<Kem>>, HpkeError> {
+    let mut configs = Vec::new();
+    while !bytes.is_empty() {
+        if bytes.len() < 2 {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let config_len = BigEndian::read_u16(&bytes[0..2]) as usize;
+        if bytes.len() < 2 + config_len {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let config_bytes = &bytes[2..2 + config_len];
+        bytes = &bytes[2 + config_len..];
+
+        match KeyConfig::<Kem>::from_bytes(config_bytes) {
+            Ok(config) => configs.push(config),
+            Err(HpkeError::SuiteMismatch) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+
+    Ok(configs)
+}
+
+// RFC 9458 §4.3
+//   hdr = concat(encode(1, key_id),
+//                encode(2, kem_id),
+//                encode(2, kdf_id),
+//                encode(2, aead_id))
+/// The header of an encapsulated request. A gateway can parse this to choose its private key and
+/// algorithms before calling [`decapsulate_request`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RequestHeader {
+    /// The identifier of the gateway's key
+    pub key_id: u8,
+    /// The HPKE KEM ID
+    pub kem_id: u16,
+    /// The HPKE KDF ID
+    pub kdf_id: u16,
+    /// The HPKE AEAD ID
+    pub aead_id: u16,
+}
+
+impl RequestHeader {
+    /// Returns the header for a request under the given key ID and algorithms
+    pub fn of<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait>(key_id: u8) -> RequestHeader {
+        RequestHeader {
+            key_id,
+            kem_id: Kem::KEM_ID,
+            kdf_id: Kdf::KDF_ID,
+            aead_id: A::AEAD_ID,
+        }
+    }
+
+    /// Serializes this header
+    pub fn to_bytes(&self) -> [u8; REQUEST_HEADER_LEN] {
+        let mut out = [0u8; REQUEST_HEADER_LEN];
+        out[0] = self.key_id;
+        BigEndian::write_u16(&mut out[1..3], self.kem_id);
+        BigEndian::write_u16(&mut out[3..5], self.kdf_id);
+        BigEndian::write_u16(&mut out[5..7], self.aead_id);
+        out
+    }
+
+    /// Parses the header at the front of an encapsulated request. Anything after the first
+    /// `REQUEST_HEADER_LEN` bytes is ignored.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(header)` on success. If `enc_request` is shorter than `REQUEST_HEADER_LEN`,
+    /// returns `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes(enc_request: &[u8]) -> Result<RequestHeader, HpkeError> {
+        if enc_request.len() < REQUEST_HEADER_LEN {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        Ok(RequestHeader {
+            key_id: enc_request[0],
+            kem_id: BigEndian::read_u16(&enc_request[1..3]),
+            kdf_id: BigEndian::read_u16(&enc_request[3..5]),
+            aead_id: BigEndian::read_u16(&enc_request[5..7]),
+        })
+    }
+
+    // RFC 9458 §4.3
+    //   info = concat(encode_str("message/bhttp request"),
+    //                 encode(1, 0),
+    //                 hdr)
+    fn info(&self) -> Vec<u8> {
+        [REQUEST_LABEL, &[0u8], &self.to_bytes()].concat()
+    }
+}
+
+/// The client's state after sending a request, used to open the gateway's response
+pub struct ClientResponseCtx<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait> {
+    encapped_key: Kem::EncappedKey,
+    ctx: AeadCtxS<A, Kdf>,
+}
+
+/// The gateway's state after opening a request, used to encapsulate its response
+pub struct ServerResponseCtx<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait> {
+    encapped_key: Kem::EncappedKey,
+    ctx: AeadCtxR<A, Kdf>,
+}
+
+/// Encapsulates `request`, a binary HTTP message, to the gateway described by `config`
+///
+/// Return Value
+/// ============
+/// Returns `Ok((enc_request, response_ctx))` on success, where `enc_request` is
+/// `hdr || enc || ct` and `response_ctx` opens the gateway's response. If `config` doesn't
+/// accept `A` and `Kdf`, returns `Err(HpkeError::SuiteMismatch)`. If an error happened during key
+/// exchange, returns `Err(HpkeError::InvalidKeyExchange)`. If an unspecified error happened
+/// during encryption, returns `Err(HpkeError::Encryption)`.
+pub fn encapsulate_request<A, Kdf, Kem, R>(
+    config: &KeyConfig<Kem>,
+    request: &[u8],
+    csprng: &mut R,
+) -> Result<(Vec<u8>, ClientResponseCtx<A, Kdf, Kem>), HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    if !config.supports::<A, Kdf>() {
+        return Err(HpkeError::SuiteMismatch);
+    }
+
+    // RFC 9458 §4.3
+    //   enc, sctxt = SetupBaseS(pkR, info)
+    //   ct = sctxt.Seal("", request)
+    //   enc_request = concat(hdr, enc, ct)
+    let hdr = RequestHeader::of::<A, Kdf, Kem>(config.key_id);
+    let (encapped_key, mut ctx) =
+        setup_sender::<A, Kdf, Kem, R>(&OpModeS::Base, &config.pk, &hdr.info(), csprng)?;
+    let ct = ctx.seal_to_vec(b"", request)?;
+    let enc_request = [&hdr.to_bytes()[..], &encapped_key.marshal(), &ct].concat();
+
+    Ok((enc_request, ClientResponseCtx { encapped_key, ctx }))
+}
+
+/// Opens an encapsulated request that was sent to the key with ID `key_id` and private key
+/// `sk_recip`
+///
+/// Return Value
+/// ============
+/// Returns `Ok((request, response_ctx))` on success, where `response_ctx` encapsulates the
+/// response. If the request's key ID isn't `key_id`, returns `Err(HpkeError::RecipientNotFound)`.
+/// If the request's algorithms aren't `A`, `Kdf`, and `Kem`, returns
+/// `Err(HpkeError::SuiteMismatch)`. If the request is too short or has an invalid encapsulated
+/// key, returns `Err(HpkeError::InvalidEncoding)`. If an error happened during key exchange,
+/// returns `Err(HpkeError::InvalidKeyExchange)`. If the request fails to decrypt, returns
+/// `Err(HpkeError::InvalidTag)`.
+pub fn decapsulate_request<A, Kdf, Kem>(
+    key_id: u8,
+    sk_recip: &Kem::PrivateKey,
+    enc_request: &[u8],
+) -> Result<(Vec<u8>, ServerResponseCtx<A, Kdf, Kem>), HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    let hdr = RequestHeader::from_bytes(enc_request)?;
+    if hdr.key_id != key_id {
+        return Err(HpkeError::RecipientNotFound);
+    }
+    if hdr != RequestHeader::of::<A, Kdf, Kem>(key_id) {
+        return Err(HpkeError::SuiteMismatch);
+    }
+
+    let rest = &enc_request[REQUEST_HEADER_LEN..];
+    let enc_len = Kem::EncappedKey::size();
+    if rest.len() < enc_len {
+        return Err(HpkeError::InvalidEncoding);
+    }
+    let (enc, ct) = rest.split_at(enc_len);
+    let encapped_key = Kem::EncappedKey::unmarshal(enc)?;
+
+    // RFC 9458 §4.3
+    //   rctxt = SetupBaseR(enc, skR, info)
+    //   request, error = rctxt.Open("", ct)
+    let mut ctx =
+        setup_receiver::<A, Kdf, Kem>(&OpModeR::Base, sk_recip, &encapped_key, &hdr.info())?;
+    let request = ctx.open_to_vec(b"", ct)?;
+
+    Ok((request, ServerResponseCtx { encapped_key, ctx }))
+}
+
+// max(Nn, Nk). This is both the length of the exported secret and of the response nonce.
+fn response_nonce_len<A: AeadCipher>() -> usize {
+    core::cmp::max(
+        AeadNonce::<A>::default().len(),
+        AeadKey::<A>::default().len(),
+    )
+}
+
+// RFC 9458 §4.4
+//   secret = context.Export("message/bhttp response", max(Nn, Nk))
+//   salt = concat(enc, response_nonce)
+//   prk = Extract(salt, secret)
+//   aead_key = Expand(prk, "key", Nk)
+//   aead_nonce = Expand(prk, "nonce", Nn)
+/// Derives the response's AEAD and nonce from the exported secret
+fn response_cipher<A: AeadCipher, Kdf: KdfTrait>(
+    secret: &[u8],
+    enc: &[u8],
+    response_nonce: &[u8],
+) -> Result<(A::AeadImpl, AeadNonce<A>), HpkeError> {
+    let salt = [enc, response_nonce].concat();
+    let (_, prk) = hkdf::Hkdf::<Kdf::HashImpl>::extract(Some(&salt), secret);
+
+    let mut key = AeadKey::<A>::default();
+    let mut nonce = AeadNonce::<A>::default();
+    let res = prk
+        .expand(b"key", key.as_mut_slice())
+        .and_then(|_| prk.expand(b"nonce", nonce.as_mut_slice()))
+        .map_err(|_| HpkeError::InvalidKdfLength)
+        .map(|_| <A::AeadImpl as BaseNewAead>::new(key.clone()));
+    key.as_mut_slice().zeroize();
+
+    res.map(|cipher| (cipher, nonce))
+}
+
+impl<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait> ServerResponseCtx<A, Kdf, Kem> {
+    /// Encapsulates `response`, a binary HTTP message, for the client that sent the request
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(enc_response)` on success, where `enc_response` is `response_nonce || ct`. If
+    /// an unspecified error happened during encryption, returns `Err(HpkeError::Encryption)`.
+    pub fn encapsulate_response<R: CryptoRng + RngCore>(
+        self,
+        response: &[u8],
+        csprng: &mut R,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut response_nonce = vec![0u8; response_nonce_len::<A>()];
+        csprng.fill_bytes(&mut response_nonce);
+        self.encapsulate_response_with_nonce(response, response_nonce)
+    }
+
+    // RFC 9458 §4.4
+    //   ct = Seal(aead_key, aead_nonce, "", response)
+    //   enc_response = concat(response_nonce, ct)
+    fn encapsulate_response_with_nonce(
+        self,
+        response: &[u8],
+        response_nonce: Vec<u8>,
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut secret = vec![0u8; response_nonce_len::<A>()];
+        self.ctx.export(RESPONSE_LABEL, &mut secret)?;
+        let res = response_cipher::<A, Kdf>(&secret, &self.encapped_key.marshal(), &response_nonce);
+        secret.zeroize();
+        let (cipher, nonce) = res?;
+
+        let mut enc_response = response_nonce;
+        let ct_start = enc_response.len();
+        enc_response.extend_from_slice(response);
+        let tag = cipher
+            .encrypt_in_place_detached(&nonce, b"", &mut enc_response[ct_start..])
+            .map_err(|_| HpkeError::Encryption)?;
+        enc_response.extend_from_slice(&tag);
+
+        Ok(enc_response)
+    }
+}
+
+impl<A: AeadCipher, Kdf: KdfTrait, Kem: KemTrait> ClientResponseCtx<A, Kdf, Kem> {
+    /// Opens the gateway's encapsulated response to this context's request
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(response)` on success. If `enc_response` is too short, returns
+    /// `Err(HpkeError::InvalidEncoding)`. If the response fails to decrypt, returns
+    /// `Err(HpkeError::InvalidTag)`.
+    pub fn open_response(self, enc_response: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        let nonce_len = response_nonce_len::<A>();
+        let tag_len = AeadTag::<A>::size();
+        if enc_response.len() < nonce_len + tag_len {
+            return Err(HpkeError::InvalidEncoding);
+        }
+        let (response_nonce, rest) = enc_response.split_at(nonce_len);
+        let (ct, tag) = rest.split_at(rest.len() - tag_len);
+
+        let mut secret = vec![0u8; nonce_len];
+        self.ctx.export(RESPONSE_LABEL, &mut secret)?;
+        let res = response_cipher::<A, Kdf>(&secret, &self.encapped_key.marshal(), response_nonce);
+        secret.zeroize();
+        let (cipher, nonce) = res?;
+
+        let mut response = ct.to_vec();
+        cipher
+            .decrypt_in_place_detached(&nonce, b"", &mut response, GenericArray::from_slice(tag))
+            .map_err(|_| HpkeError::InvalidTag)?;
+
+        Ok(response)
+    }
+}
+
+fn u16_bytes(x: u16) -> [u8; 2] {
+    let mut buf = [0u8; 2];
+    BigEndian::write_u16(&mut buf, x);
+    buf
+}
+
+#[cfg(all(test, feature = "x25519-dalek"))]
+mod test {
+    use super::{
+        decapsulate_request, encapsulate_request, parse_key_configs, serialize_key_configs,
+        KeyConfig, RequestHeader, SymmetricSuite,
+    };
+    use crate::prelude::*;
+    use crate::{
+        aead::{AesGcm128, ChaCha20Poly1305},
+        kdf::{HkdfSha256, HkdfSha384},
+        kem::{Kem as KemTrait, X25519HkdfSha256},
+        kex::{Marshallable, Unmarshallable},
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type A = AesGcm128;
+    type Kdf = HkdfSha256;
+    type Kem = X25519HkdfSha256;
+
+    // The worked example in RFC 9458 Appendix A
+    const RFC_SK: &str = "3c168975674b2fa8e465970b79c8dcf09f1c741626480bd4c6162fc5b6a98e1a";
+    const RFC_KEY_CONFIG: &str = "01002031e1f05a740102115220e9af918f738674aec95f54db6e04eb705aae8e\
+                                  79815500080001000100010003";
+    const RFC_REQUEST: &str = "00034745540568747470730b6578616d706c652e636f6d012f";
+    const RFC_ENC_REQUEST: &str = "010020000100014b28f881333e7c164ffc499ad9796f877f4e1051ee6d31ba\
+                                   d19dec96c208b4726374e469135906992e1268c594d2a10c695d858c40a026\
+                                   e7965e7d86b83dd440b2c0185204b4d63525";
+    const RFC_RESPONSE: &str = "0140c8";
+    const RFC_RESPONSE_NONCE: &str = "c789e7151fcba46158ca84b04464910d";
+    const RFC_ENC_RESPONSE: &str = "c789e7151fcba46158ca84b04464910d86f9013e404feea014e7be4a441f23\
+                                    4f857fbd";
+
+    fn unhex(s: &str) -> Vec<u8> {
+        hex::decode(s).unwrap()
+    }
+
+    /// Tests the key config and the gateway's side of the exchange against RFC 9458 Appendix A
+    #[test]
+    fn test_ohttp_rfc_example() {
+        let sk = <Kem as KemTrait>::PrivateKey::unmarshal(&unhex(RFC_SK)).unwrap();
+
+        // The key config parses, has the gateway's public key, and serializes back the same
+        let config = KeyConfig::<Kem>::from_bytes(&unhex(RFC_KEY_CONFIG)).unwrap();
+        assert_eq!(config.key_id, 1);
+        assert_eq!(config.pk.marshal(), Kem::sk_to_pk(&sk).marshal());
+        assert_eq!(
+            config.symmetric_suites,
+            vec![
+                SymmetricSuite::of::<AesGcm128, HkdfSha256>(),
+                SymmetricSuite::of::<ChaCha20Poly1305, HkdfSha256>(),
+            ]
+        );
+        assert_eq!(config.to_bytes(), unhex(RFC_KEY_CONFIG));
+
+        // The gateway recovers the request
+        let (request, response_ctx) =
+            decapsulate_request::<A, Kdf, Kem>(1, &sk, &unhex(RFC_ENC_REQUEST)).unwrap();
+        assert_eq!(request, unhex(RFC_REQUEST));
+
+        // With the example's response nonce, the gateway makes the same encapsulated response
+        let enc_response = response_ctx
+            .encapsulate_response_with_nonce(&unhex(RFC_RESPONSE), unhex(RFC_RESPONSE_NONCE))
+            .unwrap();
+        assert_eq!(enc_response, unhex(RFC_ENC_RESPONSE));
+    }
+
+    /// Tests that a request and response round trip, and that the gateway rejects requests that
+    /// aren't for it
+    #[test]
+    fn test_ohttp_roundtrip() {
+        let mut csprng = StdRng::from_entropy();
+        let (sk, pk) = Kem::gen_keypair(&mut csprng);
+        let config = KeyConfig::<Kem> {
+            key_id: 7,
+            pk,
+            symmetric_suites: vec![SymmetricSuite::of::<A, Kdf>()],
+        };
+
+        let request = b"GET /weather";
+        let response = b"200 cloudy";
+        let (enc_request, client_ctx) =
+            encapsulate_request::<A, Kdf, Kem, _>(&config, request, &mut csprng).unwrap();
+        assert_eq!(
+            RequestHeader::from_bytes(&enc_request).unwrap(),
+            RequestHeader::of::<A, Kdf, Kem>(7)
+        );
+
+        // The wrong key ID, suite, or a modified request is rejected
+        assert!(matches!(
+            decapsulate_request::<A, Kdf, Kem>(8, &sk, &enc_request),
+            Err(HpkeError::RecipientNotFound)
+        ));
+        assert!(matches!(
+            decapsulate_request::<ChaCha20Poly1305, Kdf, Kem>(7, &sk, &enc_request),
+            Err(HpkeError::SuiteMismatch)
+        ));
+        let mut bad_request = enc_request.clone();
+        *bad_request.last_mut().unwrap() ^= 1;
+        assert!(matches!(
+            decapsulate_request::<A, Kdf, Kem>(7, &sk, &bad_request),
+            Err(HpkeError::InvalidTag)
+        ));
+
+        let (opened_request, server_ctx) =
+            decapsulate_request::<A, Kdf, Kem>(7, &sk, &enc_request).unwrap();
+        assert_eq!(&opened_request[..], &request[..]);
+
+        let enc_response = server_ctx
+            .encapsulate_response(response, &mut csprng)
+            .unwrap();
+        let opened_response = client_ctx.open_response(&enc_response).unwrap();
+        assert_eq!(&opened_response[..], &response[..]);
+
+        // A client can't use a suite the config doesn't list
+        assert!(matches!(
+            encapsulate_request::<A, HkdfSha384, Kem, _>(&config, request, &mut csprng),
+            Err(HpkeError::SuiteMismatch)
+        ));
+    }
+
+    /// Tests the application/ohttp-keys list format
+    #[test]
+    fn test_ohttp_key_config_list() {
+        let config = KeyConfig::<Kem>::from_bytes(&unhex(RFC_KEY_CONFIG)).unwrap();
+        let mut second = config.clone();
+        second.key_id = 2;
+
+        let mut list = serialize_key_configs(&[config.clone(), second]);
+        let parsed = parse_key_configs::<Kem>(&list).unwrap();
+        assert_eq!(parsed.len(), 2);
+        assert_eq!(parsed[0].to_bytes(), config.to_bytes());
+        assert_eq!(parsed[1].key_id, 2);
+
+        // A config for some other KEM is skipped
+        let mut other_kem = unhex(RFC_KEY_CONFIG);
+        other_kem[2] = 0x21;
+        list.extend_from_slice(&[0, other_kem.len() as u8]);
+        list.extend_from_slice(&other_kem);
+        assert_eq!(parse_key_configs::<Kem>(&list).unwrap().len(), 2);
+
+        // A truncated list or an empty symmetric suite list doesn't parse
+        assert!(parse_key_configs::<Kem>(&list[..list.len() - 1]).is_err());
+        let mut no_suites = unhex(RFC_KEY_CONFIG);
+        no_suites.truncate(no_suites.len() - 10);
+        no_suites.extend_from_slice(&[0, 0]);
+        assert!(matches!(
+            KeyConfig::<Kem>::from_bytes(&no_suites),
+            Err(HpkeError::InvalidEncoding)
+        ));
+    }
+}
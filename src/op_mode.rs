@@ -1,5 +1,5 @@
 use crate::prelude::*;
-use crate::{kdf::Kdf as KdfTrait, kex::KeyExchange, util::static_zeros};
+use crate::{kdf::Kdf as KdfTrait, kem::Kem as KemTrait};
 
 use core::marker::PhantomData;
 
@@ -55,21 +55,21 @@ impl<Kdf: KdfTrait> Clone for PskBundle<Kdf> {
 /// The operation mode of the receiver's side of HPKE. This determines what information is folded
 /// into the encryption context derived in the `setup_receiver` functions. You can include a
 /// preshared key, the identity key of the sender, both, or neither.
-pub enum OpModeR<Kex: KeyExchange, Kdf: KdfTrait> {
+pub enum OpModeR<Kem: KemTrait, Kdf: KdfTrait> {
     /// No extra information included
     Base,
     /// A preshared key known to the sender and receiver
     Psk(PskBundle<Kdf>),
     /// The identity public key of the sender
-    Auth(Kex::PublicKey),
+    Auth(Kem::PublicKey),
     /// Both of the above
-    AuthPsk(Kex::PublicKey, PskBundle<Kdf>),
+    AuthPsk(Kem::PublicKey, PskBundle<Kdf>),
 }
 
 // Helper function for setup_receiver
-impl<'a, Kex: KeyExchange, Kdf: KdfTrait> OpModeR<Kex, Kdf> {
+impl<Kem: KemTrait, Kdf: KdfTrait> OpModeR<Kem, Kdf> {
     /// Returns the sender's identity pubkey if it's specified
-    pub(crate) fn get_pk_sender_id(&self) -> Option<&Kex::PublicKey> {
+    pub(crate) fn get_pk_sender_id(&self) -> Option<&Kem::PublicKey> {
         match self {
             OpModeR::Auth(pk) => Some(pk),
             OpModeR::AuthPsk(pk, _) => Some(pk),
@@ -81,21 +81,21 @@ impl<'a, Kex: KeyExchange, Kdf: KdfTrait> OpModeR<Kex, Kdf> {
 /// The operation mode of the sender's side of HPKE. This determines what information is folded
 /// into the encryption context derived in the `setup_sender` functions. You can include a
 /// preshared key, the identity key of the sender, both, or neither.
-pub enum OpModeS<Kex: KeyExchange, Kdf: KdfTrait> {
+pub enum OpModeS<Kem: KemTrait, Kdf: KdfTrait> {
     /// No extra information included
     Base,
     /// A preshared key known to the sender and receiver
     Psk(PskBundle<Kdf>),
     /// The identity keypair of the sender
-    Auth((Kex::PrivateKey, Kex::PublicKey)),
+    Auth((Kem::PrivateKey, Kem::PublicKey)),
     /// Both of the above
-    AuthPsk((Kex::PrivateKey, Kex::PublicKey), PskBundle<Kdf>),
+    AuthPsk((Kem::PrivateKey, Kem::PublicKey), PskBundle<Kdf>),
 }
 
 // Helpers functions for setup_sender and testing
-impl<Kex: KeyExchange, Kdf: KdfTrait> OpModeS<Kex, Kdf> {
+impl<Kem: KemTrait, Kdf: KdfTrait> OpModeS<Kem, Kdf> {
     /// Returns the sender's identity pubkey if it's specified
-    pub(crate) fn get_sender_id_keypair(&self) -> Option<&(Kex::PrivateKey, Kex::PublicKey)> {
+    pub(crate) fn get_sender_id_keypair(&self) -> Option<&(Kem::PrivateKey, Kem::PublicKey)> {
         match self {
             OpModeS::Auth(keypair) => Some(keypair),
             OpModeS::AuthPsk(keypair, _) => Some(keypair),
@@ -105,18 +105,18 @@ impl<Kex: KeyExchange, Kdf: KdfTrait> OpModeS<Kex, Kdf> {
 }
 
 /// Represents the convenience methods necessary for getting default values out of the operation
-/// mode. These are defined in draft02 §6.1.
-pub(crate) trait OpMode<Kex: KeyExchange> {
+/// mode. These are defined in RFC 9180 §5.1.
+pub(crate) trait OpMode<Kem: KemTrait> {
     /// Gets the mode ID (hardcoded based on variant)
     fn mode_id(&self) -> u8;
-    /// If this is a PSK mode, returns the PSK. Otherwise returns zeros.
+    /// If this is a PSK mode, returns the PSK. Otherwise returns the empty string.
     fn get_psk_bytes(&self) -> &[u8];
     /// If this is a PSK mode, returns the PSK ID. Otherwise returns the empty string.
     fn get_psk_id(&self) -> &[u8];
 }
 
-impl<Kex: KeyExchange, Kdf: KdfTrait> OpMode<Kex> for OpModeR<Kex, Kdf> {
-    // Defined in draft02 §5.0
+impl<Kem: KemTrait, Kdf: KdfTrait> OpMode<Kem> for OpModeR<Kem, Kdf> {
+    // RFC 9180 §5: Table 1
     fn mode_id(&self) -> u8 {
         match self {
             OpModeR::Base => 0x00,
@@ -126,20 +126,19 @@ impl<Kex: KeyExchange, Kdf: KdfTrait> OpMode<Kex> for OpModeR<Kex, Kdf> {
         }
     }
 
-    // Returns the preshared key bytes if it's set in the mode, otherwise returns
-    // [0u8; Kdf::HashImpl::OutputSize]
+    // Returns the preshared key bytes if it's set in the mode, otherwise returns the empty string
     fn get_psk_bytes(&self) -> &[u8] {
-        // draft02 §6.1: default_psk = zero(Nh)
+        // RFC 9180 §5.1: default_psk = ""
         match self {
             OpModeR::Psk(bundle) => &bundle.psk.bytes,
             OpModeR::AuthPsk(_, bundle) => &bundle.psk.bytes,
-            _ => static_zeros::<Kdf>(),
+            _ => b"",
         }
     }
 
     // Returns the preshared key ID if it's set in the mode, otherwise returns the emtpy string
     fn get_psk_id(&self) -> &[u8] {
-        // draft02 §6.1: default_pskID = zero(0)
+        // RFC 9180 §5.1: default_psk_id = ""
         match self {
             OpModeR::Psk(p) => &p.psk_id,
             OpModeR::AuthPsk(_, p) => &p.psk_id,
@@ -150,8 +149,8 @@ impl<Kex: KeyExchange, Kdf: KdfTrait> OpMode<Kex> for OpModeR<Kex, Kdf> {
 
 // I know there's a bunch of code reuse here, but it's not so much that I feel the need to abstract
 // something away
-impl<Kex: KeyExchange, Kdf: KdfTrait> OpMode<Kex> for OpModeS<Kex, Kdf> {
-    // Defined in draft02 §5.0
+impl<Kem: KemTrait, Kdf: KdfTrait> OpMode<Kem> for OpModeS<Kem, Kdf> {
+    // RFC 9180 §5: Table 1
     fn mode_id(&self) -> u8 {
         match self {
             OpModeS::Base => 0x00,
@@ -161,20 +160,19 @@ impl<Kex: KeyExchange, Kdf: KdfTrait> OpMode<Kex> for OpModeS<Kex, Kdf> {
         }
     }
 
-    // Returns the preshared key bytes if it's set in the mode, otherwise returns
-    // [0u8; Kdf::Hashfunction::OutputSize]
+    // Returns the preshared key bytes if it's set in the mode, otherwise returns the empty string
     fn get_psk_bytes(&self) -> &[u8] {
-        // draft02 §6.1: default_psk = zero(Nh)
+        // RFC 9180 §5.1: default_psk = ""
         match self {
             OpModeS::Psk(bundle) => &bundle.psk.bytes,
             OpModeS::AuthPsk(_, bundle) => &bundle.psk.bytes,
-            _ => static_zeros::<Kdf>(),
+            _ => b"",
         }
     }
 
     // Returns the preshared key ID if it's set in the mode, otherwise returns the emtpy string
     fn get_psk_id(&self) -> &[u8] {
-        // draft02 §6.1: default_pskID = zero(0)
+        // RFC 9180 §5.1: default_psk_id = ""
         match self {
             OpModeS::Psk(p) => &p.psk_id,
             OpModeS::AuthPsk(_, p) => &p.psk_id,
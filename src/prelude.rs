@@ -4,11 +4,15 @@
 
 #[cfg(not(feature = "std"))]
 mod reexports {
+    pub use alloc::boxed::Box;
     pub use alloc::vec::Vec;
 }
 
+// String is only used in tests
 #[cfg(feature = "std")]
+#[allow(unused_imports)]
 mod reexports {
+    pub use std::boxed::Box;
     pub use std::string::String;
     pub use std::vec::Vec;
 }
@@ -0,0 +1,323 @@
+//! Rekeying for long-lived encryption contexts. A ratcheting context moves through a series of
+//! epochs. Epoch 0 uses the key and base nonce that came out of HPKE setup. Every later epoch uses
+//! a key and base nonce derived from the context's exporter secret and the epoch number, and
+//! starts over at sequence number 0. When a context moves to a new epoch, the old key and base
+//! nonce are erased.
+//!
+//! The sender moves to the next epoch every `rekey_interval` messages, or whenever `rekey` is
+//! called. Every sealed message comes with a `RatchetPosition`, i.e., the epoch and sequence number
+//! it was sealed at, which the sender MUST transmit alongside the ciphertext. The receiver follows
+//! the sender forward using those positions. Messages may be dropped, but they must arrive in
+//! order, since the receiver never goes back to an earlier epoch or sequence number.
+//!
+//! Note that the exporter secret stays the same across epochs, so someone who learns the current
+//! state of a context can still derive the keys of earlier epochs. Rekeying limits how much is
+//! encrypted under one key. It does not give forward secrecy.
+
+use crate::{
+    aead::{AeadCipher, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    HpkeError,
+};
+
+use byteorder::{BigEndian, ByteOrder};
+use core::num::NonZeroU64;
+
+/// Where a message was sealed in a ratcheting context
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RatchetPosition {
+    /// The rekeying epoch
+    pub epoch: u64,
+    /// The sequence number within the epoch
+    pub seq: u64,
+}
+
+impl RatchetPosition {
+    /// Encodes this position as `epoch || seq`, each as a big-endian u64
+    pub fn to_bytes(&self) -> [u8; 16] {
+        let mut buf = [0u8; 16];
+        BigEndian::write_u64(&mut buf[..8], self.epoch);
+        BigEndian::write_u64(&mut buf[8..], self.seq);
+        buf
+    }
+
+    /// Decodes a position encoded by `to_bytes`
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(position)` on success. If `encoded` is not 16 bytes long, returns
+    /// `Err(HpkeError::InvalidEncoding)`.
+    pub fn from_bytes(encoded: &[u8]) -> Result<RatchetPosition, HpkeError> {
+        if encoded.len() != 16 {
+            return Err(HpkeError::InvalidEncoding);
+        }
+
+        Ok(RatchetPosition {
+            epoch: BigEndian::read_u64(&encoded[..8]),
+            seq: BigEndian::read_u64(&encoded[8..]),
+        })
+    }
+}
+
+/// A sender's context that rekeys itself
+pub struct RatchetCtxS<A: AeadCipher, K: KdfTrait> {
+    ctx: AeadCtxS<A, K>,
+    epoch: u64,
+    rekey_interval: Option<NonZeroU64>,
+}
+
+impl<A: AeadCipher, K: KdfTrait> RatchetCtxS<A, K> {
+    /// Makes a ratcheting context out of a fresh sender context. If `rekey_interval` is
+    /// `Some(n)`, the context moves to the next epoch after every `n` messages. If it's `None`,
+    /// the context only moves when `rekey` is called.
+    pub fn new(ctx: AeadCtxS<A, K>, rekey_interval: Option<NonZeroU64>) -> RatchetCtxS<A, K> {
+        RatchetCtxS {
+            ctx,
+            epoch: 0,
+            rekey_interval,
+        }
+    }
+
+    /// Returns the current epoch
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Returns the position the next message will be sealed at
+    pub fn position(&self) -> RatchetPosition {
+        RatchetPosition {
+            epoch: self.epoch,
+            seq: self.ctx.seq(),
+        }
+    }
+
+    /// Moves to the next epoch, erasing the current key and base nonce
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the epoch counter would overflow, returns
+    /// `Err(HpkeError::SeqOverflow)`.
+    pub fn rekey(&mut self) -> Result<(), HpkeError> {
+        let next_epoch = self.epoch.checked_add(1).ok_or(HpkeError::SeqOverflow)?;
+        self.ctx = self.ctx.rekeyed(next_epoch);
+        self.epoch = next_epoch;
+
+        Ok(())
+    }
+
+    /// Does a "detached seal in place" like `AeadCtxS::seal`, first rekeying if the current epoch
+    /// is used up. The returned position MUST be sent along with the ciphertext.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok((position, tag))` on success. Otherwise, this fails in the same ways as
+    /// `rekey` and `AeadCtxS::seal`.
+    pub fn seal(
+        &mut self,
+        plaintext: &mut [u8],
+        aad: &[u8],
+    ) -> Result<(RatchetPosition, AeadTag<A>), HpkeError> {
+        if let Some(interval) = self.rekey_interval {
+            if self.ctx.seq() >= interval.get() {
+                self.rekey()?;
+            }
+        }
+
+        let position = self.position();
+        let tag = self.ctx.seal(plaintext, aad)?;
+
+        Ok((position, tag))
+    }
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. This is the
+    /// same in every epoch.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer length is more than 255x the digest size of the
+    /// underlying hash function, returns an `Err(HpkeError::InvalidKdfLength)`.
+    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        self.ctx.export(info, out_buf)
+    }
+}
+
+/// A receiver's context that follows a `RatchetCtxS` through its epochs
+pub struct RatchetCtxR<A: AeadCipher, K: KdfTrait> {
+    ctx: AeadCtxR<A, K>,
+    epoch: u64,
+}
+
+impl<A: AeadCipher, K: KdfTrait> RatchetCtxR<A, K> {
+    /// Makes a ratcheting context out of a fresh receiver context
+    pub fn new(ctx: AeadCtxR<A, K>) -> RatchetCtxR<A, K> {
+        RatchetCtxR { ctx, epoch: 0 }
+    }
+
+    /// Returns the current epoch
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Does a "detached open in place" of a message sealed at `position`. If `position` is in a
+    /// later epoch, this moves to that epoch, but only if the message is authentic.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If `position` is earlier than the position after the last
+    /// message opened, returns `Err(HpkeError::Replayed)`. If the tag fails to validate, returns
+    /// `Err(HpkeError::InvalidTag)`, and the context is unchanged. Otherwise, this fails in the
+    /// same ways as `AeadCtxR::open_at`.
+    pub fn open(
+        &mut self,
+        position: RatchetPosition,
+        ciphertext: &mut [u8],
+        aad: &[u8],
+        tag: &AeadTag<A>,
+    ) -> Result<(), HpkeError> {
+        if position.epoch < self.epoch {
+            // The keys for that epoch are gone
+            return Err(HpkeError::Replayed);
+        } else if position.epoch > self.epoch {
+            // Try the message under the new epoch's context. Only switch if it opens.
+            let mut new_ctx = self.ctx.rekeyed(position.epoch);
+            new_ctx.open_at(position.seq, ciphertext, aad, tag)?;
+            new_ctx.set_seq(position.seq.saturating_add(1))?;
+
+            self.ctx = new_ctx;
+            self.epoch = position.epoch;
+        } else {
+            if position.seq < self.ctx.seq() {
+                return Err(HpkeError::Replayed);
+            }
+            self.ctx.open_at(position.seq, ciphertext, aad, tag)?;
+            self.ctx.set_seq(position.seq.saturating_add(1))?;
+        }
+
+        Ok(())
+    }
+
+    /// Fills a given buffer with secret bytes derived from this encryption context. This is the
+    /// same in every epoch.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(())` on success. If the buffer length is more than 255x the digest size of the
+    /// underlying hash function, returns an `Err(HpkeError::InvalidKdfLength)`.
+    pub fn export(&self, info: &[u8], out_buf: &mut [u8]) -> Result<(), HpkeError> {
+        self.ctx.export(info, out_buf)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{RatchetCtxR, RatchetCtxS, RatchetPosition};
+    use crate::{
+        aead::{AesGcm128, AesGcm256, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        prelude::*,
+        test_util::gen_ctx_simple_pair,
+        HpkeError,
+    };
+
+    use core::num::NonZeroU64;
+
+    macro_rules! test_ratchet_correctness {
+        ($test_name:ident, $aead_ty:ty) => {
+            /// Tests that a receiver follows a sender across epochs, including across dropped
+            /// messages, and rejects anything from the past
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+                type Kdf = HkdfSha256;
+
+                let (ctx_s, ctx_r) = gen_ctx_simple_pair::<A, Kdf>();
+                let mut sender = RatchetCtxS::new(ctx_s, NonZeroU64::new(3));
+                let mut receiver = RatchetCtxR::new(ctx_r);
+
+                // Seal 10 messages. That's epochs 0, 1, 2 with 3 each, then 1 in epoch 3.
+                let msg = b"telemetry";
+                let mut sealed = Vec::new();
+                for _ in 0..10 {
+                    let mut ciphertext = *msg;
+                    let (position, tag) = sender.seal(&mut ciphertext[..], b"").unwrap();
+                    sealed.push((position, ciphertext, tag));
+                }
+                assert_eq!(sealed[9].0, RatchetPosition { epoch: 3, seq: 0 });
+
+                // Force a rekey and seal one more
+                sender.rekey().unwrap();
+                let mut ciphertext = *msg;
+                let (position, tag) = sender.seal(&mut ciphertext[..], b"").unwrap();
+                assert_eq!(position, RatchetPosition { epoch: 4, seq: 0 });
+                sealed.push((position, ciphertext, tag));
+
+                // Positions survive the wire
+                let position_bytes = sealed[5].0.to_bytes();
+                assert_eq!(
+                    RatchetPosition::from_bytes(&position_bytes).unwrap(),
+                    sealed[5].0
+                );
+
+                // Epochs use different keys
+                assert_ne!(sealed[0].1, sealed[3].1);
+
+                // Open some, dropping a few along the way (including all of epoch 2)
+                for &i in &[0usize, 2, 3, 9, 10] {
+                    let (position, mut ciphertext, tag) = sealed[i].clone();
+                    receiver
+                        .open(position, &mut ciphertext[..], b"", &tag)
+                        .unwrap();
+                    assert_eq!(&ciphertext, msg);
+                }
+                assert_eq!(receiver.epoch(), 4);
+
+                // Earlier messages are rejected
+                let (position, mut ciphertext, tag) = sealed[4].clone();
+                assert!(matches!(
+                    receiver.open(position, &mut ciphertext[..], b"", &tag),
+                    Err(HpkeError::Replayed)
+                ));
+
+                // Exports are the same in every epoch
+                let mut secret1 = [0u8; 32];
+                let mut secret2 = [0u8; 32];
+                sender.export(b"ratchet", &mut secret1).unwrap();
+                receiver.export(b"ratchet", &mut secret2).unwrap();
+                assert_eq!(secret1, secret2);
+            }
+        };
+    }
+
+    test_ratchet_correctness!(test_ratchet_correctness_aes128, AesGcm128);
+    test_ratchet_correctness!(test_ratchet_correctness_aes256, AesGcm256);
+    test_ratchet_correctness!(test_ratchet_correctness_chacha, ChaCha20Poly1305);
+
+    /// Tests that a forged message claiming a later epoch doesn't move the receiver
+    #[test]
+    fn test_ratchet_forgery() {
+        let (ctx_s, ctx_r) = gen_ctx_simple_pair::<ChaCha20Poly1305, HkdfSha256>();
+        let mut sender = RatchetCtxS::new(ctx_s, None);
+        let mut receiver = RatchetCtxR::new(ctx_r);
+
+        let mut ciphertext = *b"legit";
+        let (position, tag) = sender.seal(&mut ciphertext[..], b"").unwrap();
+
+        // Claim the message is from far in the future
+        let mut forged = ciphertext;
+        let forged_position = RatchetPosition {
+            epoch: 1000,
+            seq: 0,
+        };
+        assert!(matches!(
+            receiver.open(forged_position, &mut forged[..], b"", &tag),
+            Err(HpkeError::InvalidTag)
+        ));
+        assert_eq!(receiver.epoch(), 0);
+
+        // The real message still opens
+        receiver
+            .open(position, &mut ciphertext[..], b"", &tag)
+            .unwrap();
+        assert_eq!(&ciphertext, b"legit");
+    }
+}
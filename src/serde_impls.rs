@@ -0,0 +1,198 @@
+//! Serde support for keys, encapsulated keys, and tags. Everything here is serialized as its
+//! marshalled bytes. Human-readable formats get a lowercase hex string, and binary formats get a
+//! byte string. Deserialization goes through `Unmarshallable::unmarshal`, so everything that
+//! deserializes has passed the same checks as anything unmarshalled by hand.
+
+use crate::{
+    kex::{Marshallable, Unmarshallable},
+    prelude::*,
+};
+
+use core::{fmt, marker::PhantomData};
+
+use serde::{
+    de::{Error as DeError, SeqAccess, Visitor},
+    Deserializer, Serializer,
+};
+use zeroize::{Zeroize, Zeroizing};
+
+/// Implements `Serialize` and `Deserialize` for a non-generic type that is `Marshallable` and
+/// `Unmarshallable`
+macro_rules! impl_serde_via_marshal {
+    ($ty:ty) => {
+        impl serde::Serialize for $ty {
+            fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+                $crate::serde_impls::serialize_marshallable(self, serializer)
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $ty {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                $crate::serde_impls::deserialize_unmarshallable(deserializer)
+            }
+        }
+    };
+}
+
+/// Serializes the marshalled form of `value`. Every intermediate buffer is zeroized, since
+/// `value` might be a private key.
+pub(crate) fn serialize_marshallable<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+where
+    T: Marshallable,
+    S: Serializer,
+{
+    let mut bytes = value.marshal();
+    let res = if serializer.is_human_readable() {
+        let encoded = Zeroizing::new(hex::encode(&bytes));
+        serializer.serialize_str(&encoded)
+    } else {
+        serializer.serialize_bytes(&bytes)
+    };
+    bytes.as_mut_slice().zeroize();
+
+    res
+}
+
+/// Deserializes bytes and unmarshals them. Every intermediate buffer is zeroized, since the
+/// bytes might be a private key.
+pub(crate) fn deserialize_unmarshallable<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+where
+    T: Unmarshallable,
+    D: Deserializer<'de>,
+{
+    // Human-readable formats are self-describing, so we let them tell us whether they have a hex
+    // string or a list of bytes
+    let visitor = UnmarshallableVisitor(PhantomData);
+    if deserializer.is_human_readable() {
+        deserializer.deserialize_any(visitor)
+    } else {
+        deserializer.deserialize_bytes(visitor)
+    }
+}
+
+struct UnmarshallableVisitor<T>(PhantomData<T>);
+
+impl<'de, T: Unmarshallable> Visitor<'de> for UnmarshallableVisitor<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "a {}-byte value, hex-encoded if a string", T::size())
+    }
+
+    fn visit_str<E: DeError>(self, v: &str) -> Result<T, E> {
+        // Don't echo the input in the error. It might be a secret.
+        let bytes = Zeroizing::new(hex::decode(v).map_err(|_| E::custom("invalid hex"))?);
+        T::unmarshal(&bytes).map_err(E::custom)
+    }
+
+    fn visit_bytes<E: DeError>(self, v: &[u8]) -> Result<T, E> {
+        T::unmarshal(v).map_err(E::custom)
+    }
+
+    fn visit_byte_buf<E: DeError>(self, v: Vec<u8>) -> Result<T, E> {
+        let bytes = Zeroizing::new(v);
+        T::unmarshal(&bytes).map_err(E::custom)
+    }
+
+    // Some binary formats write bytes as a sequence of u8s
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<T, A::Error> {
+        let mut bytes = Zeroizing::new(Vec::with_capacity(T::size()));
+        while let Some(b) = seq.next_element::<u8>()? {
+            // Stop early on something way too long
+            if bytes.len() == T::size() {
+                return Err(A::Error::invalid_length(T::size() + 1, &self));
+            }
+            bytes.push(b);
+        }
+
+        T::unmarshal(&bytes).map_err(A::Error::custom)
+    }
+}
+
+#[cfg(all(test, feature = "x25519-dalek", feature = "p256"))]
+mod test {
+    use crate::{
+        aead::{AeadTag, ChaCha20Poly1305},
+        kem::{DhP256HkdfSha256, Kem as KemTrait, X25519HkdfSha256},
+        kex::{Marshallable, Unmarshallable},
+    };
+
+    #[cfg(not(feature = "std"))]
+    use alloc::string::String;
+    #[cfg(feature = "std")]
+    use std::string::String;
+
+    use rand::{rngs::StdRng, SeedableRng};
+    use serde::{de::DeserializeOwned, Serialize};
+
+    /// Round trips the value through JSON and checks the result marshals the same. Returns the
+    /// JSON.
+    fn json_roundtrip<T>(value: &T) -> String
+    where
+        T: Marshallable + Unmarshallable + Serialize + DeserializeOwned,
+    {
+        let json = serde_json::to_string(value).unwrap();
+        let parsed: T = serde_json::from_str(&json).unwrap();
+        assert_eq!(parsed.marshal(), value.marshal());
+
+        json
+    }
+
+    macro_rules! test_serde_roundtrip {
+        ($test_name:ident, $kem_ty:ty) => {
+            /// Tests that keys and encapsulated keys round trip through a human-readable format
+            /// as hex strings, and through the byte-sequence path that binary formats use
+            #[test]
+            fn $test_name() {
+                type Kem = $kem_ty;
+
+                let mut csprng = StdRng::from_entropy();
+                let (sk, pk) = Kem::gen_keypair(&mut csprng);
+                let (_, encapped_key) = Kem::encap(&pk, &mut csprng).unwrap();
+
+                let json = json_roundtrip(&pk);
+                assert_eq!(json, format!("\"{}\"", hex::encode(pk.marshal())));
+                json_roundtrip(&sk);
+                json_roundtrip(&encapped_key);
+
+                // A JSON array of numbers goes through the same path as binary formats
+                let array = serde_json::to_string(&pk.marshal().to_vec()).unwrap();
+                let parsed: <Kem as KemTrait>::PublicKey = serde_json::from_str(&array).unwrap();
+                assert_eq!(parsed.marshal(), pk.marshal());
+
+                // Wrong lengths and bad hex don't deserialize
+                let short = format!("\"{}\"", hex::encode(&pk.marshal()[1..]));
+                assert!(serde_json::from_str::<<Kem as KemTrait>::PublicKey>(&short).is_err());
+                assert!(serde_json::from_str::<<Kem as KemTrait>::PublicKey>("\"zz\"").is_err());
+            }
+        };
+    }
+
+    test_serde_roundtrip!(test_serde_roundtrip_x25519, X25519HkdfSha256);
+    test_serde_roundtrip!(test_serde_roundtrip_p256, DhP256HkdfSha256);
+
+    /// Tests that deserialization runs the same validation as `unmarshal`
+    #[test]
+    fn test_serde_validation() {
+        type Kem = DhP256HkdfSha256;
+
+        // The point at infinity isn't a valid P-256 public key
+        let mut bad_pk = vec![0u8; <Kem as KemTrait>::PublicKey::size()];
+        assert!(<Kem as KemTrait>::PublicKey::unmarshal(&bad_pk).is_err());
+        let json = format!("\"{}\"", hex::encode(&bad_pk));
+        assert!(serde_json::from_str::<<Kem as KemTrait>::PublicKey>(&json).is_err());
+
+        // Zero isn't a valid P-256 private key
+        bad_pk.truncate(<Kem as KemTrait>::PrivateKey::size());
+        let json = format!("\"{}\"", hex::encode(&bad_pk));
+        assert!(serde_json::from_str::<<Kem as KemTrait>::PrivateKey>(&json).is_err());
+    }
+
+    /// Tests that tags round trip
+    #[test]
+    fn test_serde_tag() {
+        let tag = AeadTag::<ChaCha20Poly1305>::unmarshal(&[7u8; 16]).unwrap();
+        let json = json_roundtrip(&tag);
+        assert_eq!(json, format!("\"{}\"", "07".repeat(16)));
+    }
+}
@@ -1,38 +1,22 @@
 use crate::{
     aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS},
     kdf::{labeled_extract, Kdf as KdfTrait, LabeledExpand},
-    kem::{self, EncappedKey, Kem as KemTrait, SharedSecret},
-    kex::KeyExchange,
+    kem::{Kem as KemTrait, SharedSecret},
     op_mode::{OpMode, OpModeR, OpModeS},
-    util::static_zeros,
+    util::full_suite_id,
     HpkeError,
 };
 
-use byteorder::{BigEndian, ByteOrder};
 use digest::{generic_array::GenericArray, Digest};
 use rand::{CryptoRng, RngCore};
 
-/* struct {
-        // Mode and algorithms
-        uint8 mode;
-        uint16 kem_id;
-        uint16 kdf_id;
-        uint16 aead_id;
-
-        // Cryptographic hash of application-supplied pskID
-        opaque pskID_hash[Nh];
-
-        // Cryptographic hash of application-supplied info
-        opaque info_hash[Nh];
-    } HPKEContext;
-*/
-
 /// Secret generated in `derive_enc_ctx` and stored in `AeadCtx`
 pub(crate) type ExporterSecret<K> =
     GenericArray<u8, <<K as KdfTrait>::HashImpl as Digest>::OutputSize>;
 
-// This is the KeySchedule function defined in draft02 §6.1. It runs a KDF over all the parameters,
-// inputs, and secrets, and spits out a key-nonce pair to be used for symmetric encryption
+// This is the KeySchedule function defined in RFC 9180 §5.1. It runs a KDF over all the
+// parameters, inputs, and secrets, and spits out a key-nonce pair to be used for symmetric
+// encryption
 fn derive_enc_ctx<A, Kdf, Kem, O>(
     mode: &O,
     shared_secret: SharedSecret<Kem>,
@@ -42,92 +26,73 @@ where
     A: Aead,
     Kdf: KdfTrait,
     Kem: KemTrait,
-    O: OpMode<Kem::Kex>,
+    O: OpMode<Kem>,
 {
-    // A helper function that writes to a buffer and returns a slice containing the unwritten
-    // portion. If this crate were allowed to use std, we'd just use std::io::Write instead.
-    fn write_to_buf<'a>(buf: &'a mut [u8], to_write: &[u8]) -> &'a mut [u8] {
-        buf[..to_write.len()].copy_from_slice(to_write);
-        &mut buf[to_write.len()..]
-    }
+    // Put together the binding context used for all KDF operations
+    let suite_id = full_suite_id::<A, Kdf, Kem>();
 
     // In KeySchedule(),
-    //     ciphersuite = concat(encode_big_endian(kem_id, 2),
-    //                          encode_big_endian(kdf_id, 2),
-    //                          encode_big_endian(aead_id, 2))
-    //     pskID_hash = LabeledExtract(zero(Nh), "pskID", pskID)
-    //     info_hash = LabeledExtract(zero(Nh), "info_hash", info)
-    //     schedule_context = concat(ciphersuite, mode, pskID_hash, info_hash)
-
-    // Pick a buffer to hold 3 u16s, a u8, and 2 digests, as described above
-    let mut buf = [0u8; 3 * 2 + 1 + 2 * 512];
-    let buf_len = buf.len();
-
-    let sched_context: &[u8] = {
-        // Write the ciphersuite identifiers to the buffer. Forgive the explicit indexing.
-        BigEndian::write_u16(&mut buf[..2], Kem::KEM_ID);
-        BigEndian::write_u16(&mut buf[2..4], Kdf::KDF_ID);
-        BigEndian::write_u16(&mut buf[4..6], A::AEAD_ID);
-
-        //  Define a cursor with which to write to the above buffer
-        let mut cursor = &mut buf[6..];
-
-        // Use the helper function to write the mode identifier (1 byte, so endianness doesn't
-        // matter)
-        cursor = write_to_buf(cursor, &[mode.mode_id()]);
-
-        let zeros = static_zeros::<Kdf>();
-        let (psk_id_hash, _) = labeled_extract::<Kdf>(zeros, b"pskID_hash", mode.get_psk_id());
-        let (info_hash, _) = labeled_extract::<Kdf>(zeros, b"info_hash", info);
-
-        cursor = write_to_buf(cursor, psk_id_hash.as_slice());
-        cursor = write_to_buf(cursor, info_hash.as_slice());
-
-        let bytes_written = buf_len - cursor.len();
-        &buf[..bytes_written]
-    };
+    //   psk_id_hash = LabeledExtract("", "psk_id_hash", psk_id)
+    //   info_hash = LabeledExtract("", "info_hash", info)
+    //   key_schedule_context = concat(mode, psk_id_hash, info_hash)
+    let (psk_id_hash, _) =
+        labeled_extract::<Kdf>(&[], &suite_id, b"psk_id_hash", mode.get_psk_id());
+    let (info_hash, _) = labeled_extract::<Kdf>(&[], &suite_id, b"info_hash", info);
+    let key_schedule_context = [
+        &[mode.mode_id()],
+        psk_id_hash.as_slice(),
+        info_hash.as_slice(),
+    ]
+    .concat();
 
     // In KeySchedule(),
-    //   extracted_psk = LabeledExtract(zero(Nh), "psk", psk)
-    //   secret = LabeledExtract(extracted_psk, "zz", zz)
-    //   key = LabeledExpand(secret, "key", context, Nk)
-    //   nonce = LabeledExpand(secret, "nonce", context, Nn)
-    //   exporter_secret = LabeledExpand(secret, "exp", context, Nh)
-    //   return Context(key, nonce, exporter_secret)
+    //   secret = LabeledExtract(shared_secret, "secret", psk)
+    //   key = LabeledExpand(secret, "key", key_schedule_context, Nk)
+    //   base_nonce = LabeledExpand(secret, "base_nonce", key_schedule_context, Nn)
+    //   exporter_secret = LabeledExpand(secret, "exp", key_schedule_context, Nh)
+    //   return Context<ROLE>(key, base_nonce, 0, exporter_secret)
     //
     // Instead of `secret` we derive an HKDF context which we run .expand() on to derive the
     // key-nonce pair.
-    let (extracted_psk, _) =
-        labeled_extract::<Kdf>(static_zeros::<Kdf>(), b"psk_hash", mode.get_psk_bytes());
-    let (_, secret_ctx) = labeled_extract::<Kdf>(&extracted_psk, b"secret", &shared_secret);
+    let (_, secret_ctx) =
+        labeled_extract::<Kdf>(&shared_secret.0, &suite_id, b"secret", mode.get_psk_bytes());
 
     // Empty fixed-size buffers
     let mut key = crate::aead::AeadKey::<A>::default();
-    let mut nonce = crate::aead::AeadNonce::<A>::default();
+    let mut base_nonce = crate::aead::AeadNonce::<A>::default();
     let mut exporter_secret = <ExporterSecret<Kdf> as Default>::default();
 
-    // Fill the key, nonce, and exporter secret. This only errors if the output values are 255x the
-    // digest size of the hash function. Since these values are fixed at compile time, we don't
-    // worry about it.
+    // Fill the key, base nonce, and exporter secret. This only errors if the output values are
+    // 255x the digest size of the hash function. Since these values are fixed at compile time, we
+    // don't worry about it.
     secret_ctx
-        .labeled_expand(b"key", &sched_context, key.as_mut_slice())
+        .labeled_expand(&suite_id, b"key", &key_schedule_context, key.as_mut_slice())
         .expect("aead key len is way too big");
     secret_ctx
-        .labeled_expand(b"nonce", &sched_context, nonce.as_mut_slice())
+        .labeled_expand(
+            &suite_id,
+            b"base_nonce",
+            &key_schedule_context,
+            base_nonce.as_mut_slice(),
+        )
         .expect("nonce len is way too big");
     secret_ctx
-        .labeled_expand(b"exp", &sched_context, exporter_secret.as_mut_slice())
+        .labeled_expand(
+            &suite_id,
+            b"exp",
+            &key_schedule_context,
+            exporter_secret.as_mut_slice(),
+        )
         .expect("exporter secret len is way too big");
 
-    AeadCtx::new(key, nonce, exporter_secret)
+    AeadCtx::new(key, base_nonce, exporter_secret, suite_id)
 }
 
-// From draft02 §6.5:
-//     def SetupAuthPSKI(pkR, info, psk, pskID, skI):
-//       zz, enc = AuthEncap(pkR, skI)
-//       pkIm = Marshal(pk(skI))
-//       return enc, KeySchedule(mode_psk_auth, pkR, zz, enc, info,
-//                               psk, pskID, pkIm)
+// RFC 9180 §5.1.4
+// def SetupAuthPSKS(pkR, info, psk, psk_id, skS):
+//   shared_secret, enc = AuthEncap(pkR, skS)
+//   return enc, KeyScheduleS(mode_auth_psk, shared_secret, info,
+//                            psk, psk_id)
 /// Initiates an encryption context to the given recipient. Does an "authenticated" encapsulation
 /// if `sk_sender_id` is set. This ties the sender identity to the shared secret.
 ///
@@ -137,33 +102,33 @@ where
 /// encryption context. If an error happened during key exchange, returns
 /// `Err(HpkeError::InvalidKeyExchange)`. This is the only possible error.
 pub fn setup_sender<A, Kdf, Kem, R>(
-    mode: &OpModeS<Kem::Kex, Kdf>,
-    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    mode: &OpModeS<Kem, Kdf>,
+    pk_recip: &Kem::PublicKey,
     info: &[u8],
     csprng: &mut R,
-) -> Result<(EncappedKey<Kem::Kex>, AeadCtxS<A, Kdf>), HpkeError>
+) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError>
 where
     A: Aead,
     Kdf: KdfTrait,
     Kem: KemTrait,
     R: CryptoRng + RngCore,
 {
-    // If the identity key is set, use it
-    let sender_id_keypair = mode.get_sender_id_keypair();
-    // Do the encapsulation
-    let (shared_secret, encapped_key) = kem::encap::<Kem, _>(pk_recip, sender_id_keypair, csprng)?;
+    // Do the encapsulation. If the identity key is set, use it.
+    let (shared_secret, encapped_key) = match mode.get_sender_id_keypair() {
+        Some(sender_id_keypair) => Kem::auth_encap(pk_recip, sender_id_keypair, csprng)?,
+        None => Kem::encap(pk_recip, csprng)?,
+    };
     // Use everything to derive an encryption context
     let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
 
     Ok((encapped_key, enc_ctx.into()))
 }
 
-//  From draft02 §6.5:
-//     def SetupAuthPSKR(enc, skR, info, psk, pskID, pkI):
-//       zz = AuthDecap(enc, skR, pkI)
-//       pkIm = Marshal(pkI)
-//       return KeySchedule(mode_psk_auth, pk(skR), zz, enc, info,
-//                          psk, pskID, pkIm)
+// RFC 9180 §5.1.4
+// def SetupAuthPSKR(enc, skR, info, psk, psk_id, pkS):
+//   shared_secret = AuthDecap(enc, skR, pkS)
+//   return KeyScheduleR(mode_auth_psk, shared_secret, info,
+//                       psk, psk_id)
 /// Initiates an encryption context given a private key `sk` and an encapsulated key which was
 /// encapsulated to `sk`'s corresponding public key
 ///
@@ -172,9 +137,9 @@ where
 /// On success, returns an encryption context. If an error happened during key exchange, returns
 /// `Err(HpkeError::InvalidKeyExchange)`. This is the only possible error.
 pub fn setup_receiver<A, Kdf, Kem>(
-    mode: &OpModeR<Kem::Kex, Kdf>,
-    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
-    encapped_key: &EncappedKey<Kem::Kex>,
+    mode: &OpModeR<Kem, Kdf>,
+    sk_recip: &Kem::PrivateKey,
+    encapped_key: &Kem::EncappedKey,
     info: &[u8],
 ) -> Result<AeadCtxR<A, Kdf>, HpkeError>
 where
@@ -182,10 +147,11 @@ where
     Kdf: KdfTrait,
     Kem: KemTrait,
 {
-    // If the identity key is set, use it
-    let pk_sender_id: Option<&<Kem::Kex as KeyExchange>::PublicKey> = mode.get_pk_sender_id();
-    // Do the decapsulation
-    let shared_secret = kem::decap::<Kem>(sk_recip, pk_sender_id, encapped_key)?;
+    // Do the decapsulation. If the identity key is set, use it.
+    let shared_secret = match mode.get_pk_sender_id() {
+        Some(pk_sender_id) => Kem::auth_decap(sk_recip, pk_sender_id, encapped_key)?,
+        None => Kem::decap(sk_recip, encapped_key)?,
+    };
 
     // Use everything to derive an encryption context
     let enc_ctx = derive_enc_ctx::<_, _, Kem, _>(mode, shared_secret, info);
@@ -196,12 +162,23 @@ where
 mod test {
     use super::{setup_receiver, setup_sender};
     use crate::test_util::{aead_ctx_eq, gen_op_mode_pair, OpModeKind};
-    use crate::{aead::ChaCha20Poly1305, kdf::HkdfSha256, kem::Kem as KemTrait, kex::KeyExchange};
+    use crate::{aead::ChaCha20Poly1305, kem::Kem as KemTrait};
+
+    #[cfg(any(feature = "x25519-dalek", feature = "p256", feature = "ml-kem"))]
+    use crate::kdf::HkdfSha256;
 
     use rand::{rngs::StdRng, SeedableRng};
 
     /// This tests that `setup_sender` and `setup_receiver` derive the same context. We do this by
     /// testing that `gen_ctx_kem_pair` returns identical encryption contexts
+    // This goes through the auth modes, so it's only for DHKEMs
+    #[cfg(any(
+        feature = "x25519-dalek",
+        feature = "x448",
+        feature = "p256",
+        feature = "p384",
+        feature = "p521"
+    ))]
     macro_rules! test_setup_correctness {
         ($test_name:ident, $aead_ty:ty, $kdf_ty:ty, $kem_ty:ty) => {
             #[test]
@@ -209,14 +186,13 @@ mod test {
                 type A = $aead_ty;
                 type Kdf = $kdf_ty;
                 type Kem = $kem_ty;
-                type Kex = <Kem as KemTrait>::Kex;
 
                 let mut csprng = StdRng::from_entropy();
 
                 let info = b"why would you think in a million years that that would actually work";
 
                 // Generate the receiver's long-term keypair
-                let (sk_recip, pk_recip) = <Kex as KeyExchange>::gen_keypair(&mut csprng);
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
 
                 // Try a full setup for all the op modes
                 for op_mode_kind in &[
@@ -226,7 +202,7 @@ mod test {
                     OpModeKind::AuthPsk,
                 ] {
                     // Generate a mutually agreeing op mode pair
-                    let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kex, Kdf>(*op_mode_kind);
+                    let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kem, Kdf>(*op_mode_kind);
 
                     // Construct the sender's encryption context, and get an encapped key
                     let (encapped_key, mut aead_ctx1) = setup_sender::<A, _, Kem, _>(
@@ -261,6 +237,14 @@ mod test {
         crate::kem::X25519HkdfSha256
     );
 
+    #[cfg(feature = "x448")]
+    test_setup_correctness!(
+        test_setup_correctness_x448,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::X448HkdfSha512
+    );
+
     #[cfg(feature = "p256")]
     test_setup_correctness!(
         test_setup_correctness_p256,
@@ -269,6 +253,22 @@ mod test {
         crate::kem::DhP256HkdfSha256
     );
 
+    #[cfg(feature = "p384")]
+    test_setup_correctness!(
+        test_setup_correctness_p384,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha384,
+        crate::kem::DhP384HkdfSha384
+    );
+
+    #[cfg(feature = "p521")]
+    test_setup_correctness!(
+        test_setup_correctness_p521,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::DhP521HkdfSha512
+    );
+
     /// Tests that using different input data gives you different encryption contexts
     macro_rules! test_setup_soundness {
         ($test_name:ident, $aead:ty, $kdf:ty, $kem:ty) => {
@@ -277,17 +277,16 @@ mod test {
                 type A = $aead;
                 type Kdf = $kdf;
                 type Kem = $kem;
-                type Kex = <Kem as KemTrait>::Kex;
 
                 let mut csprng = StdRng::from_entropy();
 
                 let info = b"why would you think in a million years that that would actually work";
 
                 // Generate the receiver's long-term keypair
-                let (sk_recip, pk_recip) = <Kex as KeyExchange>::gen_keypair(&mut csprng);
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
 
                 // Generate a mutually agreeing op mode pair
-                let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kex, Kdf>(OpModeKind::Base);
+                let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kem, Kdf>(OpModeKind::Base);
 
                 // Construct the sender's encryption context normally
                 let (encapped_key, sender_ctx) =
@@ -308,7 +307,7 @@ mod test {
 
                 // Now make a receiver with the wrong secret key and ensure it doesn't match the
                 // sender
-                let (bad_sk, _) = <Kex as KeyExchange>::gen_keypair(&mut csprng);
+                let (bad_sk, _) = Kem::gen_keypair(&mut csprng);
                 let mut aead_ctx2 =
                     setup_receiver::<_, _, Kem>(&receiver_mode, &bad_sk, &encapped_key, &info[..])
                         .unwrap();
@@ -351,6 +350,14 @@ mod test {
         crate::kem::X25519HkdfSha256
     );
 
+    #[cfg(feature = "x448")]
+    test_setup_soundness!(
+        test_setup_soundness_x448,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::X448HkdfSha512
+    );
+
     #[cfg(feature = "p256")]
     test_setup_soundness!(
         test_setup_soundness_p256,
@@ -358,4 +365,73 @@ mod test {
         HkdfSha256,
         crate::kem::DhP256HkdfSha256
     );
+
+    #[cfg(feature = "p384")]
+    test_setup_soundness!(
+        test_setup_soundness_p384,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha384,
+        crate::kem::DhP384HkdfSha384
+    );
+
+    #[cfg(feature = "p521")]
+    test_setup_soundness!(
+        test_setup_soundness_p521,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::DhP521HkdfSha512
+    );
+
+    #[cfg(feature = "ml-kem")]
+    test_setup_soundness!(
+        test_setup_soundness_mlkem768,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::MlKem768
+    );
+
+    #[cfg(feature = "ml-kem")]
+    test_setup_soundness!(
+        test_setup_soundness_mlkem1024,
+        ChaCha20Poly1305,
+        HkdfSha256,
+        crate::kem::MlKem1024
+    );
+
+    /// Tests that an export-only sender and receiver export the same secrets, in every op mode
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_setup_export_only() {
+        type A = crate::aead::ExportOnly;
+        type Kdf = HkdfSha256;
+        type Kem = crate::kem::X25519HkdfSha256;
+
+        let mut csprng = StdRng::from_entropy();
+
+        let info = b"only here for the secrets";
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+        for op_mode_kind in &[
+            OpModeKind::Base,
+            OpModeKind::Auth,
+            OpModeKind::Psk,
+            OpModeKind::AuthPsk,
+        ] {
+            let (sender_mode, receiver_mode) = gen_op_mode_pair::<Kem, Kdf>(*op_mode_kind);
+
+            let (encapped_key, sender_ctx) =
+                setup_sender::<A, _, Kem, _>(&sender_mode, &pk_recip, &info[..], &mut csprng)
+                    .unwrap();
+            let receiver_ctx =
+                setup_receiver::<A, _, Kem>(&receiver_mode, &sk_recip, &encapped_key, &info[..])
+                    .unwrap();
+
+            // Both sides should export the same secret
+            let mut secret1 = [0u8; 32];
+            let mut secret2 = [0u8; 32];
+            sender_ctx.export(b"exporter ctx", &mut secret1).unwrap();
+            receiver_ctx.export(b"exporter ctx", &mut secret2).unwrap();
+            assert_eq!(secret1, secret2);
+        }
+    }
 }
@@ -1,9 +1,10 @@
 use crate::{
-    aead::{Aead, AeadTag},
+    aead::{AeadCipher, AeadTag},
     kdf::Kdf as KdfTrait,
-    kem::{EncappedKey, Kem as KemTrait},
-    kex::KeyExchange,
+    kem::Kem as KemTrait,
+    kex::{Marshallable, Unmarshallable},
     op_mode::{OpModeR, OpModeS},
+    prelude::*,
     setup::{setup_receiver, setup_sender},
     HpkeError,
 };
@@ -24,15 +25,15 @@ use rand::{CryptoRng, RngCore};
 /// `Err(HpkeError::InvalidKeyExchange)`. If an unspecified error happened during encryption,
 /// returns `Err(HpkeError::Encryption)`. In this case, the contents of `plaintext` is undefined.
 pub fn single_shot_seal<A, Kdf, Kem, R>(
-    mode: &OpModeS<Kem::Kex, Kdf>,
-    pk_recip: &<Kem::Kex as KeyExchange>::PublicKey,
+    mode: &OpModeS<Kem, Kdf>,
+    pk_recip: &Kem::PublicKey,
     info: &[u8],
     plaintext: &mut [u8],
     aad: &[u8],
     csprng: &mut R,
-) -> Result<(EncappedKey<Kem::Kex>, AeadTag<A>), HpkeError>
+) -> Result<(Kem::EncappedKey, AeadTag<A>), HpkeError>
 where
-    A: Aead,
+    A: AeadCipher,
     Kdf: KdfTrait,
     Kem: KemTrait,
     R: CryptoRng + RngCore,
@@ -59,16 +60,16 @@ where
 /// `Err(HpkeError::InvalidKeyExchange)`. If an unspecified error happened during decryption,
 /// returns `Err(HpkeError::Encryption)`. In this case, the contents of `ciphertext` is undefined.
 pub fn single_shot_open<A, Kdf, Kem>(
-    mode: &OpModeR<Kem::Kex, Kdf>,
-    sk_recip: &<Kem::Kex as KeyExchange>::PrivateKey,
-    encapped_key: &EncappedKey<Kem::Kex>,
+    mode: &OpModeR<Kem, Kdf>,
+    sk_recip: &Kem::PrivateKey,
+    encapped_key: &Kem::EncappedKey,
     info: &[u8],
     ciphertext: &mut [u8],
     aad: &[u8],
     tag: &AeadTag<A>,
 ) -> Result<(), HpkeError>
 where
-    A: Aead,
+    A: AeadCipher,
     Kdf: KdfTrait,
     Kem: KemTrait,
 {
@@ -78,18 +79,96 @@ where
     aead_ctx.open(ciphertext, aad, tag)
 }
 
-#[cfg(test)]
+/// Does a `setup_sender` and `AeadCtxS::seal_to_vec` in one shot, and returns everything the
+/// recipient needs as a single bytestring. See `single_shot_seal` for more detail.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(enc || ciphertext || tag)` on success, where `enc` is the marshalled encapsulated
+/// key. This fails in the same ways as `single_shot_seal`.
+pub fn single_shot_seal_to_vec<A, Kdf, Kem, R>(
+    mode: &OpModeS<Kem, Kdf>,
+    pk_recip: &Kem::PublicKey,
+    info: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+    csprng: &mut R,
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+    R: CryptoRng + RngCore,
+{
+    // Encap a key
+    let (encapped_key, mut aead_ctx) =
+        setup_sender::<A, Kdf, Kem, R>(mode, pk_recip, info, csprng)?;
+    // Encrypt
+    let ciphertext = aead_ctx.seal_to_vec(aad, plaintext)?;
+
+    // Prepend the encapped key
+    Ok([encapped_key.marshal().as_slice(), &ciphertext].concat())
+}
+
+/// Does a `setup_receiver` and `AeadCtxR::open_to_vec` in one shot, taking the bytestring output
+/// by `single_shot_seal_to_vec`. See `single_shot_open` for more detail.
+///
+/// Return Value
+/// ============
+/// Returns `Ok(plaintext)` on success. If `wire_bytes` is too short to be of the form
+/// `enc || ciphertext || tag`, or `enc` is not a valid encapsulated key, returns
+/// `Err(HpkeError::InvalidEncoding)`. Otherwise, this fails in the same ways as
+/// `single_shot_open`.
+pub fn single_shot_open_to_vec<A, Kdf, Kem>(
+    mode: &OpModeR<Kem, Kdf>,
+    sk_recip: &Kem::PrivateKey,
+    info: &[u8],
+    wire_bytes: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, HpkeError>
+where
+    A: AeadCipher,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // Split off the encapped key. The tag length gets checked in open_to_vec.
+    let enc_size = Kem::EncappedKey::size();
+    if wire_bytes.len() < enc_size {
+        return Err(HpkeError::InvalidEncoding);
+    }
+    let (enc_bytes, ciphertext) = wire_bytes.split_at(enc_size);
+    let encapped_key = Kem::EncappedKey::unmarshal(enc_bytes)?;
+
+    // Decap the key
+    let mut aead_ctx = setup_receiver::<A, Kdf, Kem>(mode, sk_recip, &encapped_key, info)?;
+    // Decrypt
+    aead_ctx.open_to_vec(aad, ciphertext)
+}
+
+// These tests go through the auth modes, so they're only for DHKEMs. The ML-KEM single-shot tests
+// are in kem/mlkem.rs.
+#[cfg(all(
+    test,
+    any(
+        feature = "x25519-dalek",
+        feature = "x448",
+        feature = "p256",
+        feature = "p384",
+        feature = "p521"
+    )
+))]
 mod test {
     use super::{single_shot_open, single_shot_seal};
     use crate::{
         aead::ChaCha20Poly1305,
-        kdf::HkdfSha256,
         kem::Kem as KemTrait,
-        kex::KeyExchange,
         op_mode::{OpModeR, OpModeS},
         test_util::gen_psk_bundle,
     };
 
+    #[cfg(any(feature = "x25519-dalek", feature = "p256"))]
+    use crate::kdf::HkdfSha256;
+
     use rand::{rngs::StdRng, SeedableRng};
 
     macro_rules! test_single_shot_correctness {
@@ -102,7 +181,6 @@ mod test {
                 type A = $aead;
                 type Kdf = $kdf;
                 type Kem = $kem;
-                type Kex = <Kem as KemTrait>::Kex;
 
                 let msg = b"Good night, a-ding ding ding ding ding";
                 let aad = b"Five four three two one";
@@ -114,17 +192,17 @@ mod test {
                 let psk_bundle = gen_psk_bundle::<Kdf>();
 
                 // Generate the sender's and receiver's long-term keypairs
-                let (sk_sender_id, pk_sender_id) = Kex::gen_keypair(&mut csprng);
-                let (sk_recip, pk_recip) = Kex::gen_keypair(&mut csprng);
+                let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
+                let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
 
                 // Construct the sender's encryption context, and get an encapped key
-                let sender_mode = OpModeS::<Kex, _>::AuthPsk(
+                let sender_mode = OpModeS::<Kem, _>::AuthPsk(
                     (sk_sender_id, pk_sender_id.clone()),
                     psk_bundle.clone(),
                 );
 
                 // Use the encapped key to derive the reciever's encryption context
-                let receiver_mode = OpModeR::<Kex, _>::AuthPsk(pk_sender_id, psk_bundle);
+                let receiver_mode = OpModeR::<Kem, _>::AuthPsk(pk_sender_id, psk_bundle);
 
                 // Encrypt with the first context
                 let mut ciphertext = msg.clone();
@@ -167,6 +245,14 @@ mod test {
         crate::kem::X25519HkdfSha256
     );
 
+    #[cfg(feature = "x448")]
+    test_single_shot_correctness!(
+        test_single_shot_correctness_x448,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::X448HkdfSha512
+    );
+
     #[cfg(feature = "p256")]
     test_single_shot_correctness!(
         test_single_shot_correctness_p256,
@@ -174,4 +260,71 @@ mod test {
         HkdfSha256,
         crate::kem::DhP256HkdfSha256
     );
+
+    #[cfg(feature = "p384")]
+    test_single_shot_correctness!(
+        test_single_shot_correctness_p384,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha384,
+        crate::kem::DhP384HkdfSha384
+    );
+
+    #[cfg(feature = "p521")]
+    test_single_shot_correctness!(
+        test_single_shot_correctness_p521,
+        ChaCha20Poly1305,
+        crate::kdf::HkdfSha512,
+        crate::kem::DhP521HkdfSha512
+    );
+
+    /// Tests that `single_shot_open_to_vec` can open a `single_shot_seal_to_vec` output, and that
+    /// it rejects wire bytes that are too short to hold an encapped key
+    #[cfg(feature = "x25519-dalek")]
+    #[test]
+    fn test_single_shot_to_vec_correctness() {
+        use super::{single_shot_open_to_vec, single_shot_seal_to_vec};
+        use crate::HpkeError;
+
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+        type Kem = crate::kem::X25519HkdfSha256;
+
+        let msg = b"one more time";
+        let aad = b"with feeling";
+        let info = b"the whole thing on the wire";
+
+        let mut csprng = StdRng::from_entropy();
+        let (sk_recip, pk_recip) = Kem::gen_keypair(&mut csprng);
+
+        let wire_bytes = single_shot_seal_to_vec::<A, Kdf, Kem, _>(
+            &OpModeS::Base,
+            &pk_recip,
+            info,
+            msg,
+            aad,
+            &mut csprng,
+        )
+        .expect("single_shot_seal_to_vec() failed");
+        // The output is a 32-byte encapped key, the ciphertext, and a 16-byte tag
+        assert_eq!(wire_bytes.len(), 32 + msg.len() + 16);
+
+        let res = single_shot_open_to_vec::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            &sk_recip,
+            info,
+            &[0u8; 31],
+            aad,
+        );
+        assert!(matches!(res, Err(HpkeError::InvalidEncoding)));
+
+        let decrypted = single_shot_open_to_vec::<A, Kdf, Kem>(
+            &OpModeR::Base,
+            &sk_recip,
+            info,
+            &wire_bytes,
+            aad,
+        )
+        .expect("single_shot_open_to_vec() failed");
+        assert_eq!(&decrypted[..], &msg[..]);
+    }
 }
@@ -0,0 +1,511 @@
+//! Chunked encryption of long messages, in the style of the STREAM construction. The plaintext is
+//! split into chunks of a fixed size, and every chunk is sealed with the same HPKE encryption
+//! context. Since the context's nonce depends on its sequence number, chunks that are reordered,
+//! dropped, or repeated will fail to open.
+//!
+//! Every chunk's AAD is a one-byte flag followed by the caller's AAD. The flag is `0x01` for the
+//! last chunk and `0x00` for all the others. Every chunk but the last has exactly `chunk_size`
+//! bytes of plaintext, and the last chunk has strictly fewer (possibly zero). So a receiver knows
+//! a chunk is the last one by its length, and the flag makes sure an attacker can't cut a stream
+//! short and pass off an earlier chunk as the last.
+//!
+//! `StreamSealer` and `StreamOpener` take chunks one at a time and work without `std`. With the
+//! `std` feature, `StreamWriter` and `StreamReader` wrap them around an `io::Write` and an
+//! `io::Read`, respectively.
+
+use crate::prelude::*;
+use crate::{
+    aead::{AeadCipher, AeadCtxR, AeadCtxS, AeadTag},
+    kdf::Kdf as KdfTrait,
+    kex::Marshallable,
+    HpkeError,
+};
+
+const NOT_LAST_CHUNK: u8 = 0x00;
+const LAST_CHUNK: u8 = 0x01;
+
+// Computes flag || aad
+fn chunk_aad(flag: u8, aad: &[u8]) -> Vec<u8> {
+    let mut chunk_aad = Vec::with_capacity(1 + aad.len());
+    chunk_aad.push(flag);
+    chunk_aad.extend_from_slice(aad);
+    chunk_aad
+}
+
+/// Splits a message into chunks and seals them one at a time
+pub struct StreamSealer<A: AeadCipher, K: KdfTrait> {
+    ctx: AeadCtxS<A, K>,
+    chunk_size: usize,
+}
+
+impl<A: AeadCipher, K: KdfTrait> StreamSealer<A, K> {
+    /// Makes a sealer that encrypts chunks of `chunk_size` bytes with the given context. The
+    /// context should not be used for anything else.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(sealer)` on success. If `chunk_size` is 0, returns
+    /// `Err(HpkeError::InvalidChunkSize)`.
+    pub fn new(ctx: AeadCtxS<A, K>, chunk_size: usize) -> Result<StreamSealer<A, K>, HpkeError> {
+        if chunk_size == 0 {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        Ok(StreamSealer { ctx, chunk_size })
+    }
+
+    /// Returns the number of plaintext bytes in every chunk but the last
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Seals a chunk that is not the last one. `chunk` MUST be exactly `chunk_size` bytes long.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext_and_tag)` on success. If `chunk` is not `chunk_size` bytes long,
+    /// returns `Err(HpkeError::InvalidChunkSize)`. Otherwise, this fails in the same ways as
+    /// `AeadCtxS::seal`.
+    pub fn seal_chunk(&mut self, chunk: &[u8], aad: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        if chunk.len() != self.chunk_size {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        self.ctx.seal_to_vec(&chunk_aad(NOT_LAST_CHUNK, aad), chunk)
+    }
+
+    /// Seals the last chunk and consumes the sealer. `chunk` MUST be strictly shorter than
+    /// `chunk_size`. If the message length is a multiple of `chunk_size`, the last chunk is empty.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(ciphertext_and_tag)` on success. If `chunk` is not shorter than `chunk_size`,
+    /// returns `Err(HpkeError::InvalidChunkSize)`. Otherwise, this fails in the same ways as
+    /// `AeadCtxS::seal`.
+    pub fn seal_last_chunk(mut self, chunk: &[u8], aad: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        if chunk.len() >= self.chunk_size {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        self.ctx.seal_to_vec(&chunk_aad(LAST_CHUNK, aad), chunk)
+    }
+}
+
+/// Opens the chunks made by a `StreamSealer`, one at a time
+pub struct StreamOpener<A: AeadCipher, K: KdfTrait> {
+    ctx: AeadCtxR<A, K>,
+    chunk_size: usize,
+}
+
+impl<A: AeadCipher, K: KdfTrait> StreamOpener<A, K> {
+    /// Makes an opener that decrypts chunks of `chunk_size` bytes with the given context. The
+    /// context should not be used for anything else.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(opener)` on success. If `chunk_size` is 0, returns
+    /// `Err(HpkeError::InvalidChunkSize)`.
+    pub fn new(ctx: AeadCtxR<A, K>, chunk_size: usize) -> Result<StreamOpener<A, K>, HpkeError> {
+        if chunk_size == 0 {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        Ok(StreamOpener { ctx, chunk_size })
+    }
+
+    /// Returns the number of plaintext bytes in every chunk but the last
+    pub fn chunk_size(&self) -> usize {
+        self.chunk_size
+    }
+
+    /// Returns the number of ciphertext bytes in every chunk but the last. This is `chunk_size`
+    /// plus the size of a tag. A chunk of any other length is the last one.
+    pub fn ciphertext_chunk_size(&self) -> usize {
+        self.chunk_size + AeadTag::<A>::size()
+    }
+
+    /// Opens a chunk that is not the last one. `ciphertext` MUST be exactly
+    /// `ciphertext_chunk_size` bytes long.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If `ciphertext` is not `ciphertext_chunk_size` bytes
+    /// long, returns `Err(HpkeError::InvalidChunkSize)`. If the chunk is out of order, was sealed
+    /// as the last chunk, or fails to validate, returns `Err(HpkeError::InvalidTag)`. Otherwise,
+    /// this fails in the same ways as `AeadCtxR::open`.
+    pub fn open_chunk(&mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        if ciphertext.len() != self.ciphertext_chunk_size() {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        self.ctx
+            .open_to_vec(&chunk_aad(NOT_LAST_CHUNK, aad), ciphertext)
+    }
+
+    /// Opens the last chunk and consumes the opener. `ciphertext` MUST be strictly shorter than
+    /// `ciphertext_chunk_size`. The message is only complete once this succeeds.
+    ///
+    /// Return Value
+    /// ============
+    /// Returns `Ok(plaintext)` on success. If `ciphertext` is not shorter than
+    /// `ciphertext_chunk_size`, returns `Err(HpkeError::InvalidChunkSize)`. If the chunk is out
+    /// of order, was not sealed as the last chunk, or fails to validate, returns
+    /// `Err(HpkeError::InvalidTag)`. Otherwise, this fails in the same ways as
+    /// `AeadCtxR::open_to_vec`.
+    pub fn open_last_chunk(mut self, ciphertext: &[u8], aad: &[u8]) -> Result<Vec<u8>, HpkeError> {
+        if ciphertext.len() >= self.ciphertext_chunk_size() {
+            return Err(HpkeError::InvalidChunkSize);
+        }
+
+        self.ctx
+            .open_to_vec(&chunk_aad(LAST_CHUNK, aad), ciphertext)
+    }
+}
+
+#[cfg(feature = "std")]
+pub use self::io::{StreamReader, StreamWriter};
+
+#[cfg(feature = "std")]
+mod io {
+    use super::{StreamOpener, StreamSealer};
+    use crate::{aead::AeadCipher, kdf::Kdf as KdfTrait, HpkeError};
+
+    use std::{io, vec::Vec};
+
+    fn to_io_error(e: HpkeError) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, e)
+    }
+
+    /// Encrypts everything written to it and writes the resulting chunks to an underlying writer.
+    ///
+    /// The stream MUST be ended with `finish`. Otherwise the last chunk is never written, and the
+    /// receiver will reject the stream as truncated.
+    pub struct StreamWriter<W: io::Write, A: AeadCipher, K: KdfTrait> {
+        inner: W,
+        sealer: StreamSealer<A, K>,
+        aad: Vec<u8>,
+        // Plaintext that hasn't made a full chunk yet. This is always shorter than chunk_size.
+        buf: Vec<u8>,
+    }
+
+    impl<W: io::Write, A: AeadCipher, K: KdfTrait> StreamWriter<W, A, K> {
+        /// Makes a writer that seals chunks with `sealer`, binding each one to `aad`, and writes
+        /// them to `inner`
+        pub fn new(inner: W, sealer: StreamSealer<A, K>, aad: &[u8]) -> StreamWriter<W, A, K> {
+            let buf = Vec::with_capacity(sealer.chunk_size());
+            StreamWriter {
+                inner,
+                sealer,
+                aad: aad.to_vec(),
+                buf,
+            }
+        }
+
+        /// Seals and writes the last chunk, and returns the underlying writer
+        pub fn finish(mut self) -> io::Result<W> {
+            let last_chunk = self
+                .sealer
+                .seal_last_chunk(&self.buf, &self.aad)
+                .map_err(to_io_error)?;
+            self.inner.write_all(&last_chunk)?;
+            self.inner.flush()?;
+
+            Ok(self.inner)
+        }
+    }
+
+    impl<W: io::Write, A: AeadCipher, K: KdfTrait> io::Write for StreamWriter<W, A, K> {
+        fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+            // Top off the buffer
+            let chunk_size = self.sealer.chunk_size();
+            let num_to_take = core::cmp::min(chunk_size - self.buf.len(), data.len());
+            self.buf.extend_from_slice(&data[..num_to_take]);
+
+            // If the buffer is full, it's not the last chunk. Seal it and write it out.
+            if self.buf.len() == chunk_size {
+                let chunk = self
+                    .sealer
+                    .seal_chunk(&self.buf, &self.aad)
+                    .map_err(to_io_error)?;
+                self.inner.write_all(&chunk)?;
+                self.buf.clear();
+            }
+
+            Ok(num_to_take)
+        }
+
+        /// Flushes the underlying writer. This does not write out a partial chunk.
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    /// Reads chunks from an underlying reader and returns the decrypted contents.
+    ///
+    /// Reading returns an error of kind `InvalidData` if a chunk fails to open, or if the
+    /// underlying reader ends before the last chunk. Plaintext is only released after the chunk
+    /// it came from has been authenticated, but a stream is only known to be complete once this
+    /// has returned `Ok(0)`.
+    pub struct StreamReader<R: io::Read, A: AeadCipher, K: KdfTrait> {
+        inner: R,
+        // This is None once the last chunk has been opened
+        opener: Option<StreamOpener<A, K>>,
+        aad: Vec<u8>,
+        // Plaintext from the most recent chunk that hasn't been read yet
+        buf: Vec<u8>,
+        buf_pos: usize,
+    }
+
+    impl<R: io::Read, A: AeadCipher, K: KdfTrait> StreamReader<R, A, K> {
+        /// Makes a reader that reads chunks from `inner` and opens them with `opener`, checking
+        /// that each one is bound to `aad`
+        pub fn new(inner: R, opener: StreamOpener<A, K>, aad: &[u8]) -> StreamReader<R, A, K> {
+            StreamReader {
+                inner,
+                opener: Some(opener),
+                aad: aad.to_vec(),
+                buf: Vec::new(),
+                buf_pos: 0,
+            }
+        }
+
+        /// Returns the underlying reader
+        pub fn into_inner(self) -> R {
+            self.inner
+        }
+
+        // Reads and opens the next chunk into buf
+        fn next_chunk(&mut self) -> io::Result<()> {
+            let opener = match self.opener.as_mut() {
+                Some(o) => o,
+                None => return Ok(()),
+            };
+
+            // Read until we have a full chunk or we hit the end
+            let ciphertext_chunk_size = opener.ciphertext_chunk_size();
+            let mut ciphertext = vec![0u8; ciphertext_chunk_size];
+            let mut num_read = 0;
+            while num_read < ciphertext_chunk_size {
+                match self.inner.read(&mut ciphertext[num_read..]) {
+                    Ok(0) => break,
+                    Ok(n) => num_read += n,
+                    Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(e) => return Err(e),
+                }
+            }
+            ciphertext.truncate(num_read);
+
+            self.buf = if num_read == ciphertext_chunk_size {
+                // A full chunk is never the last one
+                opener
+                    .open_chunk(&ciphertext, &self.aad)
+                    .map_err(to_io_error)?
+            } else if num_read == 0 {
+                // The stream ended on a chunk boundary without a last chunk
+                return Err(to_io_error(HpkeError::StreamTruncated));
+            } else {
+                // A short chunk is the last one
+                let opener = self.opener.take().unwrap();
+                opener
+                    .open_last_chunk(&ciphertext, &self.aad)
+                    .map_err(to_io_error)?
+            };
+            self.buf_pos = 0;
+
+            Ok(())
+        }
+    }
+
+    impl<R: io::Read, A: AeadCipher, K: KdfTrait> io::Read for StreamReader<R, A, K> {
+        fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+            // Get more plaintext if we're out. Chunks can be empty, so this might take a few.
+            while self.buf_pos == self.buf.len() && self.opener.is_some() {
+                self.next_chunk()?;
+            }
+
+            let num_to_copy = core::cmp::min(self.buf.len() - self.buf_pos, out.len());
+            out[..num_to_copy].copy_from_slice(&self.buf[self.buf_pos..self.buf_pos + num_to_copy]);
+            self.buf_pos += num_to_copy;
+
+            Ok(num_to_copy)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{StreamOpener, StreamSealer};
+    use crate::{
+        aead::{AesGcm128, AesGcm256, ChaCha20Poly1305},
+        kdf::HkdfSha256,
+        prelude::*,
+        test_util::gen_ctx_simple_pair,
+        HpkeError,
+    };
+
+    /// Seals `msg` in chunks of `chunk_size`, returning the list of ciphertext chunks
+    fn seal_chunks<A: crate::aead::AeadCipher>(
+        sealer: StreamSealer<A, HkdfSha256>,
+        msg: &[u8],
+        aad: &[u8],
+    ) -> Vec<Vec<u8>> {
+        let mut sealer = sealer;
+        let chunk_size = sealer.chunk_size();
+        let mut chunks = Vec::new();
+
+        // All the full chunks go first, then the remainder (which might be empty)
+        let num_full_chunks = msg.len() / chunk_size;
+        for chunk in msg.chunks(chunk_size).take(num_full_chunks) {
+            chunks.push(sealer.seal_chunk(chunk, aad).unwrap());
+        }
+        chunks.push(
+            sealer
+                .seal_last_chunk(&msg[num_full_chunks * chunk_size..], aad)
+                .unwrap(),
+        );
+
+        chunks
+    }
+
+    /// Opens the given chunks, treating the final one as the last
+    fn open_chunks<A: crate::aead::AeadCipher>(
+        opener: StreamOpener<A, HkdfSha256>,
+        chunks: &[Vec<u8>],
+        aad: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut opener = opener;
+        let mut plaintext = Vec::new();
+
+        let (last, rest) = chunks.split_last().unwrap();
+        for chunk in rest {
+            plaintext.extend(opener.open_chunk(chunk, aad)?);
+        }
+        plaintext.extend(opener.open_last_chunk(last, aad)?);
+
+        Ok(plaintext)
+    }
+
+    macro_rules! test_stream_correctness {
+        ($test_name:ident, $aead_ty:ty) => {
+            /// Tests that chunked messages round trip, including ones whose length is a multiple of
+            /// the chunk size, and that reordered, dropped, and relabeled chunks are rejected
+            #[test]
+            fn $test_name() {
+                type A = $aead_ty;
+                type Kdf = HkdfSha256;
+
+                let chunk_size = 16;
+                let aad = b"backup-2020-03-01.tar";
+
+                for &msg_len in &[0usize, 1, 15, 16, 17, 64, 100] {
+                    let msg: Vec<u8> = (0..msg_len).map(|i| i as u8).collect();
+
+                    let (ctx_s, ctx_r) = gen_ctx_simple_pair::<A, Kdf>();
+                    let sealer = StreamSealer::new(ctx_s, chunk_size).unwrap();
+                    let opener = StreamOpener::new(ctx_r, chunk_size).unwrap();
+
+                    let chunks = seal_chunks(sealer, &msg, aad);
+                    assert_eq!(chunks.len(), msg_len / chunk_size + 1);
+
+                    let plaintext = open_chunks(opener, &chunks, aad).unwrap();
+                    assert_eq!(plaintext, msg);
+                }
+
+                // Now make a message with a few chunks and mess with it
+                let msg = [0xAAu8; 40];
+                let (ctx_s, ctx_r) = gen_ctx_simple_pair::<A, Kdf>();
+                let chunks = seal_chunks(StreamSealer::new(ctx_s, chunk_size).unwrap(), &msg, aad);
+                let new_opener = || StreamOpener::new(ctx_r.clone(), chunk_size).unwrap();
+
+                // Swapping two chunks fails
+                let mut swapped = chunks.clone();
+                swapped.swap(0, 1);
+                assert!(matches!(
+                    open_chunks(new_opener(), &swapped, aad),
+                    Err(HpkeError::InvalidTag)
+                ));
+
+                // Dropping a chunk from the middle fails
+                let dropped = vec![chunks[0].clone(), chunks[2].clone()];
+                assert!(matches!(
+                    open_chunks(new_opener(), &dropped, aad),
+                    Err(HpkeError::InvalidTag)
+                ));
+
+                // Cutting the stream short and passing off a full chunk as the last one fails
+                let mut truncated_opener = new_opener();
+                truncated_opener.open_chunk(&chunks[0], aad).unwrap();
+                assert!(matches!(
+                    truncated_opener.open_last_chunk(&chunks[1][..chunk_size], aad),
+                    Err(HpkeError::InvalidTag)
+                ));
+
+                // Changing the AAD fails
+                assert!(matches!(
+                    open_chunks(new_opener(), &chunks, b"backup-2020-03-02.tar"),
+                    Err(HpkeError::InvalidTag)
+                ));
+
+                // Chunks of the wrong size are rejected outright
+                let (ctx_s, _) = gen_ctx_simple_pair::<A, Kdf>();
+                let mut sealer = StreamSealer::new(ctx_s, chunk_size).unwrap();
+                assert!(matches!(
+                    sealer.seal_chunk(&msg[..chunk_size - 1], aad),
+                    Err(HpkeError::InvalidChunkSize)
+                ));
+                assert!(matches!(
+                    sealer.seal_last_chunk(&msg[..chunk_size], aad),
+                    Err(HpkeError::InvalidChunkSize)
+                ));
+                assert!(matches!(
+                    new_opener().open_chunk(&chunks[2], aad),
+                    Err(HpkeError::InvalidChunkSize)
+                ));
+            }
+        };
+    }
+
+    test_stream_correctness!(test_stream_correctness_aes128, AesGcm128);
+    test_stream_correctness!(test_stream_correctness_aes256, AesGcm256);
+    test_stream_correctness!(test_stream_correctness_chacha, ChaCha20Poly1305);
+
+    /// Tests that the io adapters round trip, and that the reader notices a missing last chunk
+    #[cfg(feature = "std")]
+    #[test]
+    fn test_stream_io() {
+        use super::{StreamReader, StreamWriter};
+        use std::io::{Read, Write};
+
+        type A = ChaCha20Poly1305;
+        type Kdf = HkdfSha256;
+
+        let chunk_size = 64;
+        let aad = b"";
+        let msg: Vec<u8> = (0..1000u32).map(|i| (i % 251) as u8).collect();
+
+        let (ctx_s, ctx_r) = gen_ctx_simple_pair::<A, Kdf>();
+
+        // Write the message in uneven pieces
+        let sealer = StreamSealer::new(ctx_s, chunk_size).unwrap();
+        let mut writer = StreamWriter::new(Vec::new(), sealer, aad);
+        for piece in msg.chunks(37) {
+            writer.write_all(piece).unwrap();
+        }
+        let ciphertext = writer.finish().unwrap();
+
+        // Read it all back
+        let opener = StreamOpener::new(ctx_r.clone(), chunk_size).unwrap();
+        let mut reader = StreamReader::new(&ciphertext[..], opener, aad);
+        let mut plaintext = Vec::new();
+        reader.read_to_end(&mut plaintext).unwrap();
+        assert_eq!(plaintext, msg);
+
+        // Chop off the last chunk. The reader should notice.
+        let num_full_chunks = msg.len() / chunk_size;
+        let truncated = &ciphertext[..num_full_chunks * (chunk_size + 16)];
+        let opener = StreamOpener::new(ctx_r, chunk_size).unwrap();
+        let mut reader = StreamReader::new(truncated, opener, aad);
+        let err = reader.read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,611 @@
+use crate::prelude::*;
+use crate::{
+    aead::{Aead, AeadCipher, AeadCtxR, AeadCtxS},
+    kdf::Kdf as KdfTrait,
+    kem::Kem as KemTrait,
+    op_mode::{OpModeR, OpModeS, Psk, PskBundle},
+    setup::{setup_receiver, setup_sender},
+    util::full_suite_id,
+    HpkeError,
+};
+
+use core::marker::PhantomData;
+
+use rand::{CryptoRng, RngCore};
+
+/// An HPKE ciphersuite, fixed at the type level. This is zero-sized. It exists so that the KEM,
+/// KDF, and AEAD are chosen once, rather than at every call to `setup_sender`, `single_shot_open`,
+/// etc.
+///
+/// The methods here follow the `Setup<MODE>S/R` and `Seal<MODE>`/`Open<MODE>` functions of RFC
+/// 9180 §5.1 and §6. Ciphertexts from `seal_*` have the tag appended, as in `seal_to_vec`. For
+/// anything more involved, `sender()` and `receiver()` return builders that take the info string,
+/// PSK, and sender identity key one at a time.
+pub struct Hpke<Kem: KemTrait, Kdf: KdfTrait, A: Aead> {
+    marker: PhantomData<(Kem, Kdf, A)>,
+}
+
+// We can't use #[derive(Clone, Copy, Default)] because the compiler thinks that the type
+// parameters have to be Clone, Copy, and Default
+impl<Kem: KemTrait, Kdf: KdfTrait, A: Aead> Clone for Hpke<Kem, Kdf, A> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<Kem: KemTrait, Kdf: KdfTrait, A: Aead> Copy for Hpke<Kem, Kdf, A> {}
+
+impl<Kem: KemTrait, Kdf: KdfTrait, A: Aead> Default for Hpke<Kem, Kdf, A> {
+    fn default() -> Self {
+        Hpke::new()
+    }
+}
+
+impl<Kem: KemTrait, Kdf: KdfTrait, A: Aead> Hpke<Kem, Kdf, A> {
+    /// Makes a new ciphersuite. This is free, since the ciphersuite is zero-sized.
+    pub fn new() -> Self {
+        Hpke {
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns the `suite_id` of this ciphersuite, as defined in RFC 9180 §5.1. This is
+    /// `"HPKE" || I2OSP(kem_id, 2) || I2OSP(kdf_id, 2) || I2OSP(aead_id, 2)`.
+    pub fn suite_id(&self) -> [u8; 10] {
+        full_suite_id::<A, Kdf, Kem>()
+    }
+
+    /// Returns a builder for a sender's encryption context in this ciphersuite
+    pub fn sender(&self) -> SenderBuilder<'_, Kem, Kdf, A> {
+        SenderBuilder {
+            info: &[],
+            psk: None,
+            sk_sender_id: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Returns a builder for a receiver's encryption context in this ciphersuite
+    pub fn receiver(&self) -> ReceiverBuilder<'_, Kem, Kdf, A> {
+        ReceiverBuilder {
+            info: &[],
+            psk: None,
+            pk_sender_id: None,
+            marker: PhantomData,
+        }
+    }
+
+    /// Does `SetupBaseS` from RFC 9180 §5.1.1. See `setup::setup_sender` for the return value.
+    pub fn setup_base_sender<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError> {
+        self.sender().info(info).setup(pk_recip, csprng)
+    }
+
+    /// Does `SetupBaseR` from RFC 9180 §5.1.1. See `setup::setup_receiver` for the return value.
+    pub fn setup_base_receiver(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+    ) -> Result<AeadCtxR<A, Kdf>, HpkeError> {
+        self.receiver().info(info).setup(encapped_key, sk_recip)
+    }
+
+    /// Does `SetupPSKS` from RFC 9180 §5.1.2. See `setup::setup_sender` for the return value.
+    pub fn setup_psk_sender<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError> {
+        self.sender()
+            .info(info)
+            .psk(psk, psk_id)
+            .setup(pk_recip, csprng)
+    }
+
+    /// Does `SetupPSKR` from RFC 9180 §5.1.2. See `setup::setup_receiver` for the return value.
+    pub fn setup_psk_receiver(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+    ) -> Result<AeadCtxR<A, Kdf>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .psk(psk, psk_id)
+            .setup(encapped_key, sk_recip)
+    }
+
+    /// Does `SetupAuthS` from RFC 9180 §5.1.3. See `setup::setup_sender` for the return value.
+    pub fn setup_auth_sender<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        sk_sender_id: &Kem::PrivateKey,
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError> {
+        self.sender()
+            .info(info)
+            .auth(sk_sender_id)
+            .setup(pk_recip, csprng)
+    }
+
+    /// Does `SetupAuthR` from RFC 9180 §5.1.3. See `setup::setup_receiver` for the return value.
+    pub fn setup_auth_receiver(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        pk_sender_id: &Kem::PublicKey,
+    ) -> Result<AeadCtxR<A, Kdf>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .auth(pk_sender_id)
+            .setup(encapped_key, sk_recip)
+    }
+
+    /// Does `SetupAuthPSKS` from RFC 9180 §5.1.4. See `setup::setup_sender` for the return value.
+    pub fn setup_auth_psk_sender<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        sk_sender_id: &Kem::PrivateKey,
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError> {
+        self.sender()
+            .info(info)
+            .psk(psk, psk_id)
+            .auth(sk_sender_id)
+            .setup(pk_recip, csprng)
+    }
+
+    /// Does `SetupAuthPSKR` from RFC 9180 §5.1.4. See `setup::setup_receiver` for the return
+    /// value.
+    pub fn setup_auth_psk_receiver(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        pk_sender_id: &Kem::PublicKey,
+    ) -> Result<AeadCtxR<A, Kdf>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .psk(psk, psk_id)
+            .auth(pk_sender_id)
+            .setup(encapped_key, sk_recip)
+    }
+}
+
+// The single-shot APIs only make sense for AEADs that can encrypt
+impl<Kem: KemTrait, Kdf: KdfTrait, A: AeadCipher> Hpke<Kem, Kdf, A> {
+    // RFC 9180 §6.1
+    // def Seal<MODE>(pkR, info, aad, pt, ...):
+    //   enc, ctx = Setup<MODE>S(pkR, info, ...)
+    //   ct = ctx.Seal(aad, pt)
+    //   return enc, ct
+    /// Does `SealBase` from RFC 9180 §6.1. Returns the encapsulated key and `ciphertext || tag`.
+    pub fn seal_base<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError> {
+        self.sender()
+            .info(info)
+            .seal(pk_recip, aad, plaintext, csprng)
+    }
+
+    // RFC 9180 §6.1
+    // def Open<MODE>(enc, skR, info, aad, ct, ...):
+    //   ctx = Setup<MODE>R(enc, skR, info, ...)
+    //   return ctx.Open(aad, ct)
+    /// Does `OpenBase` from RFC 9180 §6.1. Takes `ciphertext || tag` and returns the plaintext.
+    pub fn open_base(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .open(encapped_key, sk_recip, aad, ciphertext)
+    }
+
+    /// Does `SealPSK` from RFC 9180 §6.1. Returns the encapsulated key and `ciphertext || tag`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn seal_psk<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError> {
+        self.sender()
+            .info(info)
+            .psk(psk, psk_id)
+            .seal(pk_recip, aad, plaintext, csprng)
+    }
+
+    /// Does `OpenPSK` from RFC 9180 §6.1. Takes `ciphertext || tag` and returns the plaintext.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_psk(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .psk(psk, psk_id)
+            .open(encapped_key, sk_recip, aad, ciphertext)
+    }
+
+    /// Does `SealAuth` from RFC 9180 §6.1. Returns the encapsulated key and `ciphertext || tag`.
+    pub fn seal_auth<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        sk_sender_id: &Kem::PrivateKey,
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError> {
+        self.sender()
+            .info(info)
+            .auth(sk_sender_id)
+            .seal(pk_recip, aad, plaintext, csprng)
+    }
+
+    /// Does `OpenAuth` from RFC 9180 §6.1. Takes `ciphertext || tag` and returns the plaintext.
+    pub fn open_auth(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        pk_sender_id: &Kem::PublicKey,
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .auth(pk_sender_id)
+            .open(encapped_key, sk_recip, aad, ciphertext)
+    }
+
+    /// Does `SealAuthPSK` from RFC 9180 §6.1. Returns the encapsulated key and
+    /// `ciphertext || tag`.
+    #[allow(clippy::too_many_arguments)]
+    pub fn seal_auth_psk<R: CryptoRng + RngCore>(
+        &self,
+        pk_recip: &Kem::PublicKey,
+        info: &[u8],
+        aad: &[u8],
+        plaintext: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        sk_sender_id: &Kem::PrivateKey,
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError> {
+        self.sender()
+            .info(info)
+            .psk(psk, psk_id)
+            .auth(sk_sender_id)
+            .seal(pk_recip, aad, plaintext, csprng)
+    }
+
+    /// Does `OpenAuthPSK` from RFC 9180 §6.1. Takes `ciphertext || tag` and returns the
+    /// plaintext.
+    #[allow(clippy::too_many_arguments)]
+    pub fn open_auth_psk(
+        &self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        info: &[u8],
+        aad: &[u8],
+        ciphertext: &[u8],
+        psk: &[u8],
+        psk_id: &[u8],
+        pk_sender_id: &Kem::PublicKey,
+    ) -> Result<Vec<u8>, HpkeError> {
+        self.receiver()
+            .info(info)
+            .psk(psk, psk_id)
+            .auth(pk_sender_id)
+            .open(encapped_key, sk_recip, aad, ciphertext)
+    }
+}
+
+/// Makes a `PskBundle` out of the raw PSK and PSK ID
+fn make_psk_bundle<Kdf: KdfTrait>((psk, psk_id): (&[u8], &[u8])) -> PskBundle<Kdf> {
+    PskBundle {
+        psk: Psk::from_bytes(psk.to_vec()),
+        psk_id: psk_id.to_vec(),
+    }
+}
+
+/// Builds a sender's encryption context. The op mode is determined by which of `psk` and `auth`
+/// are set. Made with `Hpke::sender`.
+pub struct SenderBuilder<'a, Kem: KemTrait, Kdf: KdfTrait, A: Aead> {
+    info: &'a [u8],
+    psk: Option<(&'a [u8], &'a [u8])>,
+    sk_sender_id: Option<&'a Kem::PrivateKey>,
+    marker: PhantomData<(Kdf, A)>,
+}
+
+impl<'a, Kem: KemTrait, Kdf: KdfTrait, A: Aead> SenderBuilder<'a, Kem, Kdf, A> {
+    /// Sets the info string. This defaults to the empty string.
+    pub fn info(mut self, info: &'a [u8]) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets the preshared key and its ID. This makes the mode Psk (or AuthPsk, if `auth` is also
+    /// set).
+    pub fn psk(mut self, psk: &'a [u8], psk_id: &'a [u8]) -> Self {
+        self.psk = Some((psk, psk_id));
+        self
+    }
+
+    /// Sets the sender's identity private key. This makes the mode Auth (or AuthPsk, if `psk` is
+    /// also set).
+    pub fn auth(mut self, sk_sender_id: &'a Kem::PrivateKey) -> Self {
+        self.sk_sender_id = Some(sk_sender_id);
+        self
+    }
+
+    /// Assembles the op mode from the builder's fields
+    fn op_mode(&self) -> OpModeS<Kem, Kdf> {
+        // OpModeS wants the whole keypair, so recompute the pubkey
+        let sender_id_keypair = self.sk_sender_id.map(|sk| (sk.clone(), Kem::sk_to_pk(sk)));
+
+        match (self.psk.map(make_psk_bundle::<Kdf>), sender_id_keypair) {
+            (None, None) => OpModeS::Base,
+            (Some(bundle), None) => OpModeS::Psk(bundle),
+            (None, Some(keypair)) => OpModeS::Auth(keypair),
+            (Some(bundle), Some(keypair)) => OpModeS::AuthPsk(keypair, bundle),
+        }
+    }
+
+    /// Does a `setup_sender` with everything set so far. See `setup::setup_sender` for the return
+    /// value.
+    pub fn setup<R: CryptoRng + RngCore>(
+        self,
+        pk_recip: &Kem::PublicKey,
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, AeadCtxS<A, Kdf>), HpkeError> {
+        setup_sender::<A, Kdf, Kem, R>(&self.op_mode(), pk_recip, self.info, csprng)
+    }
+}
+
+impl<'a, Kem: KemTrait, Kdf: KdfTrait, A: AeadCipher> SenderBuilder<'a, Kem, Kdf, A> {
+    /// Does a `setup` followed by a `seal_to_vec`. Returns the encapsulated key and
+    /// `ciphertext || tag`.
+    pub fn seal<R: CryptoRng + RngCore>(
+        self,
+        pk_recip: &Kem::PublicKey,
+        aad: &[u8],
+        plaintext: &[u8],
+        csprng: &mut R,
+    ) -> Result<(Kem::EncappedKey, Vec<u8>), HpkeError> {
+        let (encapped_key, mut aead_ctx) = self.setup(pk_recip, csprng)?;
+        let ciphertext = aead_ctx.seal_to_vec(aad, plaintext)?;
+
+        Ok((encapped_key, ciphertext))
+    }
+}
+
+/// Builds a receiver's encryption context. The op mode is determined by which of `psk` and `auth`
+/// are set. Made with `Hpke::receiver`.
+pub struct ReceiverBuilder<'a, Kem: KemTrait, Kdf: KdfTrait, A: Aead> {
+    info: &'a [u8],
+    psk: Option<(&'a [u8], &'a [u8])>,
+    pk_sender_id: Option<&'a Kem::PublicKey>,
+    marker: PhantomData<(Kdf, A)>,
+}
+
+impl<'a, Kem: KemTrait, Kdf: KdfTrait, A: Aead> ReceiverBuilder<'a, Kem, Kdf, A> {
+    /// Sets the info string. This defaults to the empty string.
+    pub fn info(mut self, info: &'a [u8]) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets the preshared key and its ID. This makes the mode Psk (or AuthPsk, if `auth` is also
+    /// set).
+    pub fn psk(mut self, psk: &'a [u8], psk_id: &'a [u8]) -> Self {
+        self.psk = Some((psk, psk_id));
+        self
+    }
+
+    /// Sets the sender's identity public key. This makes the mode Auth (or AuthPsk, if `psk` is
+    /// also set).
+    pub fn auth(mut self, pk_sender_id: &'a Kem::PublicKey) -> Self {
+        self.pk_sender_id = Some(pk_sender_id);
+        self
+    }
+
+    /// Assembles the op mode from the builder's fields
+    fn op_mode(&self) -> OpModeR<Kem, Kdf> {
+        match (self.psk.map(make_psk_bundle::<Kdf>), self.pk_sender_id) {
+            (None, None) => OpModeR::Base,
+            (Some(bundle), None) => OpModeR::Psk(bundle),
+            (None, Some(pk)) => OpModeR::Auth(pk.clone()),
+            (Some(bundle), Some(pk)) => OpModeR::AuthPsk(pk.clone(), bundle),
+        }
+    }
+
+    /// Does a `setup_receiver` with everything set so far. See `setup::setup_receiver` for the
+    /// return value.
+    pub fn setup(
+        self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+    ) -> Result<AeadCtxR<A, Kdf>, HpkeError> {
+        setup_receiver::<A, Kdf, Kem>(&self.op_mode(), sk_recip, encapped_key, self.info)
+    }
+}
+
+impl<'a, Kem: KemTrait, Kdf: KdfTrait, A: AeadCipher> ReceiverBuilder<'a, Kem, Kdf, A> {
+    /// Does a `setup` followed by an `open_to_vec`. Takes `ciphertext || tag` and returns the
+    /// plaintext.
+    pub fn open(
+        self,
+        encapped_key: &Kem::EncappedKey,
+        sk_recip: &Kem::PrivateKey,
+        aad: &[u8],
+        ciphertext: &[u8],
+    ) -> Result<Vec<u8>, HpkeError> {
+        let mut aead_ctx = self.setup(encapped_key, sk_recip)?;
+        aead_ctx.open_to_vec(aad, ciphertext)
+    }
+}
+
+#[cfg(all(test, feature = "x25519-dalek"))]
+mod test {
+    use super::Hpke;
+    use crate::{
+        aead::{ChaCha20Poly1305, ExportOnly},
+        kdf::HkdfSha256,
+        kem::{Kem as KemTrait, X25519HkdfSha256},
+        test_util::aead_ctx_eq,
+        HpkeError,
+    };
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    type Suite = Hpke<X25519HkdfSha256, HkdfSha256, ChaCha20Poly1305>;
+
+    /// Tests that the suite ID is "HPKE" followed by the KEM, KDF, and AEAD IDs
+    #[test]
+    fn test_suite_id() {
+        assert_eq!(&Suite::new().suite_id(), b"HPKE\x00\x20\x00\x01\x00\x03");
+        assert_eq!(
+            &Hpke::<X25519HkdfSha256, HkdfSha256, ExportOnly>::new().suite_id(),
+            b"HPKE\x00\x20\x00\x01\xff\xff"
+        );
+    }
+
+    /// Tests that every mode's seal and open agree, and that mismatched modes don't
+    #[test]
+    fn test_single_shot_correctness() {
+        let mut csprng = StdRng::from_entropy();
+        let hpke = Suite::new();
+
+        let info = b"one suite to rule them all";
+        let aad = b"and in the type system bind them";
+        let msg = b"my precious";
+        let (psk, psk_id) = (&[0x42u8; 32][..], &b"the one psk"[..]);
+
+        let (sk_recip, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+        let (sk_sender, pk_sender) = X25519HkdfSha256::gen_keypair(&mut csprng);
+
+        let (enc, ct) = hpke
+            .seal_base(&pk_recip, info, aad, msg, &mut csprng)
+            .unwrap();
+        let pt = hpke.open_base(&enc, &sk_recip, info, aad, &ct).unwrap();
+        assert_eq!(&pt[..], &msg[..]);
+
+        let (enc, ct) = hpke
+            .seal_psk(&pk_recip, info, aad, msg, psk, psk_id, &mut csprng)
+            .unwrap();
+        let pt = hpke
+            .open_psk(&enc, &sk_recip, info, aad, &ct, psk, psk_id)
+            .unwrap();
+        assert_eq!(&pt[..], &msg[..]);
+        // Opening a Psk ciphertext in Base mode should fail
+        assert!(matches!(
+            hpke.open_base(&enc, &sk_recip, info, aad, &ct),
+            Err(HpkeError::InvalidTag)
+        ));
+
+        let (enc, ct) = hpke
+            .seal_auth(&pk_recip, info, aad, msg, &sk_sender, &mut csprng)
+            .unwrap();
+        let pt = hpke
+            .open_auth(&enc, &sk_recip, info, aad, &ct, &pk_sender)
+            .unwrap();
+        assert_eq!(&pt[..], &msg[..]);
+        // Opening with the wrong sender identity should fail
+        assert!(matches!(
+            hpke.open_auth(&enc, &sk_recip, info, aad, &ct, &pk_recip),
+            Err(HpkeError::InvalidTag)
+        ));
+
+        let (enc, ct) = hpke
+            .seal_auth_psk(
+                &pk_recip,
+                info,
+                aad,
+                msg,
+                psk,
+                psk_id,
+                &sk_sender,
+                &mut csprng,
+            )
+            .unwrap();
+        let pt = hpke
+            .open_auth_psk(&enc, &sk_recip, info, aad, &ct, psk, psk_id, &pk_sender)
+            .unwrap();
+        assert_eq!(&pt[..], &msg[..]);
+    }
+
+    /// Tests that the builders agree with the setup methods, in whatever order the fields are set
+    #[test]
+    fn test_builder_correctness() {
+        let mut csprng = StdRng::from_entropy();
+        let hpke = Suite::new();
+
+        let info = b"build me up buttercup";
+        let (psk, psk_id) = (&[0x24u8; 32][..], &b"buttercup"[..]);
+
+        let (sk_recip, pk_recip) = X25519HkdfSha256::gen_keypair(&mut csprng);
+        let (sk_sender, pk_sender) = X25519HkdfSha256::gen_keypair(&mut csprng);
+
+        let (enc, mut sender_ctx) = hpke
+            .sender()
+            .auth(&sk_sender)
+            .psk(psk, psk_id)
+            .info(info)
+            .setup(&pk_recip, &mut csprng)
+            .unwrap();
+        let mut receiver_ctx = hpke
+            .setup_auth_psk_receiver(&enc, &sk_recip, info, psk, psk_id, &pk_sender)
+            .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+
+        let (enc, mut sender_ctx) = hpke
+            .setup_psk_sender(&pk_recip, info, psk, psk_id, &mut csprng)
+            .unwrap();
+        let mut receiver_ctx = hpke
+            .receiver()
+            .psk(psk, psk_id)
+            .info(info)
+            .setup(&enc, &sk_recip)
+            .unwrap();
+        assert!(aead_ctx_eq(&mut sender_ctx, &mut receiver_ctx));
+    }
+}
@@ -1,7 +1,7 @@
 use crate::{
-    aead::{Aead, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce},
+    aead::{Aead, AeadCipher, AeadCtx, AeadCtxR, AeadCtxS, AeadKey, AeadNonce},
     kdf::Kdf as KdfTrait,
-    kex::KeyExchange,
+    kem::Kem as KemTrait,
     op_mode::{OpModeR, OpModeS, Psk, PskBundle},
     setup::ExporterSecret,
 };
@@ -48,13 +48,24 @@ pub(crate) fn gen_ctx_simple_pair<A: Aead, Kdf: KdfTrait>() -> (AeadCtxS<A, Kdf>
         buf
     };
 
-    let ctx1 = AeadCtx::new(key.clone(), nonce.clone(), exporter_secret.clone());
-    let ctx2 = AeadCtx::new(key.clone(), nonce.clone(), exporter_secret.clone());
+    // The suite ID is just binding context for exports. Since both contexts share it, any value
+    // will do.
+    let suite_id = *b"HPKEXXYYZZ";
+
+    let ctx1 = AeadCtx::new(
+        key.clone(),
+        nonce.clone(),
+        exporter_secret.clone(),
+        suite_id,
+    );
+    let ctx2 = AeadCtx::new(key, nonce, exporter_secret, suite_id);
 
     (ctx1.into(), ctx2.into())
 }
 
+// The auth modes go unused when ML-KEM is the only KEM, since it doesn't support them
 #[derive(Clone, Copy)]
+#[allow(dead_code)]
 pub(crate) enum OpModeKind {
     Base,
     Auth,
@@ -63,11 +74,11 @@ pub(crate) enum OpModeKind {
 }
 
 /// Makes an agreeing pair of `OpMode`s of the specified variant
-pub(crate) fn gen_op_mode_pair<Kex: KeyExchange, Kdf: KdfTrait>(
+pub(crate) fn gen_op_mode_pair<Kem: KemTrait, Kdf: KdfTrait>(
     kind: OpModeKind,
-) -> (OpModeS<Kex, Kdf>, OpModeR<Kex, Kdf>) {
+) -> (OpModeS<Kem, Kdf>, OpModeR<Kem, Kdf>) {
     let mut csprng = StdRng::from_entropy();
-    let (sk_sender_id, pk_sender_id) = Kex::gen_keypair(&mut csprng);
+    let (sk_sender_id, pk_sender_id) = Kem::gen_keypair(&mut csprng);
     let psk_bundle = gen_psk_bundle::<Kdf>();
 
     match kind {
@@ -97,7 +108,7 @@ pub(crate) fn gen_op_mode_pair<Kex: KeyExchange, Kdf: KdfTrait>(
 
 /// Evaluates the equivalence of two encryption contexts by doing some encryption-decryption
 /// round trips. Returns `true` iff the contexts are equal after 1000 iterations
-pub(crate) fn aead_ctx_eq<A: Aead, K: KdfTrait>(
+pub(crate) fn aead_ctx_eq<A: AeadCipher, K: KdfTrait>(
     sender: &mut AeadCtxS<A, K>,
     receiver: &mut AeadCtxR<A, K>,
 ) -> bool {
@@ -124,12 +135,12 @@ pub(crate) fn aead_ctx_eq<A: Aead, K: KdfTrait>(
         // Encrypt the plaintext
         let tag = sender
             .seal(&mut plaintext[..], &aad)
-            .expect(&format!("seal() #{} failed", i));
+            .unwrap_or_else(|_| panic!("seal() #{} failed", i));
         // Rename for clarity
         let mut ciphertext = plaintext;
 
         // Now to decrypt on the other side
-        if let Err(_) = receiver.open(&mut ciphertext[..], &aad, &tag) {
+        if receiver.open(&mut ciphertext[..], &aad, &tag).is_err() {
             // An error occurred in decryption. These encryption contexts are not identical.
             return false;
         }
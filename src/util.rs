@@ -1,14 +1,48 @@
-use crate::kdf::Kdf;
+use crate::{aead::Aead, kdf::Kdf as KdfTrait, kem::Kem as KemTrait};
 
-use digest::{generic_array::typenum::Unsigned, Digest};
+use byteorder::{BigEndian, ByteOrder};
 
-/// For use with `static_zeros`. This only needs to be as long as the longest hash function digest.
-/// 128 bytes should be enough for anybody.
-const ZEROS: &[u8] = &[0u8; 128];
+/// Represents a ciphersuite context. That's "KEMXX", where `XX` is the KEM ID
+pub(crate) type KemSuiteId = [u8; 5];
 
-/// Returns an immutable slice into a static array of zeros. The slice length is the digest length
-/// of the underlying hash function. This function is defined so we don't have to keep allocating
-/// the default value of a `Psk`, which is `[0u8; HashImpl::OutputSize]`.
-pub(crate) fn static_zeros<K: Kdf>() -> &'static [u8] {
-    &ZEROS[..<<K as Kdf>::HashImpl as Digest>::OutputSize::to_usize()]
+/// Represents a ciphersuite context. That's "HPKEXXYYZZ", where `XX` is the KEM ID, `YY` is the
+/// KDF ID, and `ZZ` is the AEAD ID
+pub(crate) type FullSuiteId = [u8; 10];
+
+// RFC 9180 §4.1
+//     suite_id = concat("KEM", I2OSP(kem_id, 2))
+/// Constructs the `suite_id` used as binding context in all functions in `kem`
+pub(crate) fn kem_suite_id<Kem: KemTrait>() -> KemSuiteId {
+    // XX is the KEM ID
+    let mut suite_id = *b"KEMXX";
+
+    // Write the KEM ID to the buffer. Forgive the explicit indexing.
+    BigEndian::write_u16(&mut suite_id[3..5], Kem::KEM_ID);
+
+    suite_id
+}
+
+// RFC 9180 §5.1
+//     suite_id = concat(
+//       "HPKE",
+//       I2OSP(kem_id, 2),
+//       I2OSP(kdf_id, 2),
+//       I2OSP(aead_id, 2)
+//     )
+/// Constructs the `suite_id` used as binding context in all functions in `setup` and `aead`
+pub(crate) fn full_suite_id<A, Kdf, Kem>() -> FullSuiteId
+where
+    A: Aead,
+    Kdf: KdfTrait,
+    Kem: KemTrait,
+{
+    // XX is the KEM ID, YY is the KDF ID, ZZ is the AEAD ID
+    let mut suite_id = *b"HPKEXXYYZZ";
+
+    // Write the ciphersuite identifiers to the buffer. Forgive the explicit indexing.
+    BigEndian::write_u16(&mut suite_id[4..6], Kem::KEM_ID);
+    BigEndian::write_u16(&mut suite_id[6..8], Kdf::KDF_ID);
+    BigEndian::write_u16(&mut suite_id[8..10], A::AEAD_ID);
+
+    suite_id
 }